@@ -0,0 +1,80 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+// Dependencies whose versions `curved version` reports, the ones that shape how curves come out
+const REPORTED_DEPENDENCIES: [(&str, &str); 2] = [
+    ("image", "CURVED_IMAGE_VERSION"),
+    ("splines", "CURVED_SPLINES_VERSION"),
+];
+
+fn main() {
+    println!("cargo:rustc-env=CURVED_GIT_HASH={}", git_hash());
+    watch_git_head();
+
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    let lock = fs::read_to_string("Cargo.lock").unwrap_or_default();
+    for (name, var) in REPORTED_DEPENDENCIES {
+        let version = locked_version(&lock, name).unwrap_or_else(|| "unknown".to_string());
+        println!("cargo:rustc-env={}={}", var, version);
+    }
+
+    // cargo hands the build script every enabled feature as CARGO_FEATURE_<NAME>
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    let features = if features.is_empty() {
+        "none".to_string()
+    } else {
+        features.join(", ")
+    };
+    println!("cargo:rustc-env=CURVED_FEATURES={}", features);
+}
+
+// The short hash of the commit being built, "unknown" outside a git checkout
+fn git_hash() -> String {
+    Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+// Rebuild when a new commit is checked out or made, so the hash doesn't go stale
+fn watch_git_head() {
+    let head = Path::new(".git/HEAD");
+    if !head.exists() {
+        return;
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    if let Some(reference) = fs::read_to_string(head)
+        .ok()
+        .and_then(|head| head.strip_prefix("ref: ").map(|r| r.trim().to_string()))
+    {
+        let reference = Path::new(".git").join(reference);
+        if reference.exists() {
+            println!("cargo:rerun-if-changed={}", reference.display());
+        }
+    }
+}
+
+// The version of `name` in Cargo.lock, the first one listed if there are several
+fn locked_version(lock: &str, name: &str) -> Option<String> {
+    let name_line = format!("name = \"{}\"", name);
+    let mut lines = lock.lines();
+    lines.find(|line| *line == name_line)?;
+    lines
+        .next()?
+        .strip_prefix("version = \"")?
+        .strip_suffix('"')
+        .map(str::to_string)
+}
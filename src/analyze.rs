@@ -1,19 +1,313 @@
+use std::collections::HashMap;
 use std::fs;
 use std::io::Write;
+use std::path::PathBuf;
+use std::time::Instant;
 
-use anyhow::{anyhow, Result};
-use image::{DynamicImage, GenericImageView, ImageBuffer, Luma, Rgb, SubImage};
+use anyhow::{anyhow, Context, Result};
+use image::{
+    DynamicImage, GenericImageView, ImageBuffer, Luma, LumaA, Rgb, RgbImage, Rgba, SubImage,
+};
 use imageproc::drawing::{draw_filled_rect_mut, draw_hollow_rect_mut};
+use imageproc::geometric_transformations::{
+    rotate_about_center, Interpolation as RotateInterpolation,
+};
 use imageproc::map::map_pixels;
 use imageproc::rect::Rect;
 use splines::{Interpolation, Key, Spline};
 
-use super::step_description::StepDescription;
+use super::apply;
+use super::cancel::Cancel;
+use super::curve::{self, curve_from_points, sample_curve, sample_curve_lut};
+use super::generate;
+use super::index_strip;
+use super::luma::{self, LumaWeights};
+use super::step_description::{Layout, StepDescription};
 
 pub struct AnalyzeResults {
     pub normalized_image: DynamicImage,
     pub curve: Spline<f64, f64>,
+    // the measured response of the process, (input tone, normalized observed tone) for each step
+    pub response: Vec<(u16, u16)>,
+    // `response` as a curve, leaving out the `rejected` steps. `curve` undoes it
+    pub response_curve: Spline<f64, f64>,
+    // a bucket for every tone, drawn at fewer buckets with `rebucket`
     pub histogram: Vec<u32>,
+    // per channel histograms of the original scan, only present for color scans, like `histogram`
+    pub rgb_histograms: Option<[Vec<u32>; 3]>,
+    // (input tone, standard deviation) of the normalized sample behind each point on the curve
+    pub uncertainty: Vec<(f64, f64)>,
+    // the square in the scan each entry of `response` was measured from
+    pub patch_areas: Vec<Rect>,
+    // how trustworthy each of `patch_areas` was to sample, the color it's outlined in
+    pub patch_status: Vec<PatchStatus>,
+    // steps of `response` left out of the curve as outliers, see `reject_outliers`
+    pub rejected: Vec<usize>,
+    // the scan with the grid lines analyze found drawn over it, only rendered when debugging
+    pub detected_lines: Option<RgbImage>,
+    // how the scan's tones were stretched out before the curve was fit
+    pub normalization: Normalization,
+    // the input tone past which the response stops changing, see `detect_ink_limit`. Only looked
+    // for when asked to with `AnalyzeParams::detect_ink_limit`
+    pub ink_limit: Option<u16>,
+    // the effective gamma of the process over its mid tones, see `measured_gamma`
+    pub gamma: Option<f64>,
+}
+
+// How `normalize_image` stretched the scan's tones out to the full range
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Normalization {
+    // the darkest and lightest sample measured from the patches
+    pub sample_min: u16,
+    pub sample_max: u16,
+    // the scan values stretched out to black and white, see `reference_points`
+    pub black: u16,
+    pub white: u16,
+    // what every tone above the black point was multiplied by
+    pub factor: f32,
+    // whether the scan was read as a negative, running from white to black
+    pub inverted: bool,
+}
+
+// Options controlling how a scan is analyzed
+#[derive(clap::Args, Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct AnalyzeParams {
+    /// Invert the scan before analyzing it
+    #[arg(long)]
+    pub invert: bool,
+
+    /// How the scanned wedge marks the boundaries between steps
+    #[arg(short, long, value_enum, default_value_t)]
+    pub layout: Layout,
+
+    /// Force the curve to map pure black and pure white through unchanged
+    #[arg(long)]
+    pub anchor_endpoints: bool,
+
+    /// Only read every Nth pixel (in each direction) of a square when sampling. Faster on huge
+    /// scans at the cost of a little accuracy, 1 reads every pixel
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub subsample_factor: u32,
+
+    /// How the pixels of a square are reduced to its tone. The median isn't moved by a speck of
+    /// dust, a scratch, or a hair across a few of them, the mean is how earlier versions sampled
+    #[arg(long, value_enum, default_value_t)]
+    pub sample_strategy: SampleStrategy,
+
+    /// How much red, green, and blue contribute to the tone of a color scan, as r,g,b
+    #[arg(long, default_value_t)]
+    pub luma_weights: LumaWeights,
+
+    /// Number of steps in the scanned wedge
+    #[arg(long, default_value_t = 101, value_parser = clap::value_parser!(u32).range(2..))]
+    pub steps: u32,
+
+    /// Number of columns the steps of the scanned wedge are laid out in
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    pub columns: u32,
+
+    /// Number of rows the steps were laid out in, as generated with --rows. By default as few as
+    /// the columns need
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub rows: Option<u32>,
+
+    /// Limit how steep the curve can get between neighboring steps, as a multiple of the identity
+    /// curve's slope. Keeps a noisy scan from producing a curve that exaggerates grain
+    #[arg(long, value_parser = parse_positive)]
+    pub max_slope: Option<f64>,
+
+    /// Look for where the response flattens out toward the top of the input range, the most ink
+    /// or density the process can lay down, and cap the curve's output there rather than asking
+    /// for more than the process can give
+    #[arg(long)]
+    pub detect_ink_limit: bool,
+
+    /// Leave steps out of the curve that are further than this many median absolute deviations
+    /// from what their neighbors predict, dust or scratches on a single square
+    #[arg(long, value_parser = parse_positive)]
+    pub reject_outliers: Option<f64>,
+
+    /// Stretch from the 1st and 99th percentile of the sampled pixels instead of their darkest and
+    /// lightest, so dust or a reflection in the wedge doesn't skew normalization
+    #[arg(long)]
+    pub robust: bool,
+
+    /// The scan value normalized to black, overrides the one measured from the wedge
+    #[arg(long)]
+    pub black_point: Option<u16>,
+
+    /// The scan value normalized to white, overrides the one measured from the wedge
+    #[arg(long)]
+    pub white_point: Option<u16>,
+
+    /// Weigh each step by how many of its pixels could be sampled, rather than trusting every
+    /// step equally. A step partly covered by a grid line leans on its neighbors in proportion
+    #[arg(long)]
+    pub weight_by_area: bool,
+
+    /// Leave the fully transparent pixels of the scan out of every sample, so dust or damage
+    /// masked out by hand in an image editor doesn't skew its patch
+    #[arg(long)]
+    pub alpha_mask: bool,
+
+    /// A curve, as json like analyze writes, mapping each tone the scanner reads to the tone it
+    /// should have read, as measured from a calibration target. The scan (and any --base) is
+    /// corrected through it before anything else, so the scanner's own response isn't measured
+    /// as part of the process
+    #[arg(long)]
+    pub scanner_profile: Option<PathBuf>,
+
+    /// Width in pixels of the grid lines, as generated with --grid-thickness
+    #[arg(long, default_value_t = generate::DEFAULT_GRID_THICKNESS, value_parser = clap::value_parser!(u32).range(1..))]
+    pub grid_thickness: u32,
+
+    /// The tone the grid lines were generated in with --grid-tone. The lines are then found by
+    /// looking for that tone rather than assumed to sit along the edges of the scan
+    #[arg(long)]
+    pub grid_tone: Option<u16>,
+
+    /// A curve, as json like analyze writes, of the response to correct toward in place of a
+    /// straight line. Each input tone is mapped to the tone it should print as
+    #[arg(long)]
+    pub target: Option<PathBuf>,
+
+    /// A scan of the blank film base, the same size as the scan, whose density is taken out of
+    /// every pixel before sampling. Leaves only the density the process added
+    #[arg(long)]
+    pub base: Option<PathBuf>,
+
+    /// Look for a periodic ripple down the rows of the scan, the horizontal banding some scanners
+    /// add, along the grid's vertical lines and take it out of every row before sampling
+    #[arg(long)]
+    pub debanding: bool,
+
+    /// Find each patch by the dots generated with --corner-dots at its corners, rather than
+    /// assuming the patches lie on a regular grid. Follows a scan that's warped or skewed
+    #[arg(long)]
+    pub corner_dots: bool,
+
+    /// Read the steps, columns, and rows from the strip generated with --index-strip along the
+    /// bottom of the scan, rather than --steps, --columns, and --rows
+    #[arg(long)]
+    pub index_strip: bool,
+
+    /// Straighten a scan laid on the scanner a little crooked along its grid lines before anything
+    /// is sampled. Measuring the angle takes a while on a large scan, so it's only done when asked
+    #[arg(long)]
+    pub deskew: bool,
+
+    /// With --deskew, the most the scan can be rotated, in degrees either way. A scan turned more
+    /// is an error
+    #[arg(long, default_value_t = DEFAULT_MAX_SKEW_DEGREES, value_parser = parse_skew)]
+    pub max_skew_degrees: f64,
+}
+
+pub fn parse_positive(s: &str) -> Result<f64> {
+    check_positive(s.parse()?)
+}
+
+pub fn check_positive(value: f64) -> Result<f64> {
+    if value.is_nan() || value <= 0. {
+        return Err(anyhow!("must be above 0, got {}", value));
+    }
+    Ok(value)
+}
+
+// a hand placed scan is rarely more than a degree or two out
+pub const DEFAULT_MAX_SKEW_DEGREES: f64 = 5.;
+
+// the skew range the scan is searched over, a grid turned a quarter turn looks the same again
+const SKEW_SEARCH_DEGREES: f64 = 45.;
+
+pub fn parse_skew(s: &str) -> Result<f64> {
+    check_skew(s.parse()?)
+}
+
+fn check_skew(value: f64) -> Result<f64> {
+    if !(0. ..=SKEW_SEARCH_DEGREES).contains(&value) {
+        return Err(anyhow!(
+            "must be from 0 to {} degrees, got {}",
+            SKEW_SEARCH_DEGREES,
+            value
+        ));
+    }
+    Ok(value)
+}
+
+impl AnalyzeParams {
+    /* Checks the ranges the command line holds its arguments to, for params read from a file or
+     * a session that never went through it
+     */
+    pub fn validate(&self) -> Result<()> {
+        let at_least = |name: &str, value: u32, min: u32| {
+            if value < min {
+                return Err(anyhow!("{} must be at least {}, got {}", name, min, value));
+            }
+            Ok(())
+        };
+        at_least("subsample_factor", self.subsample_factor, 1)?;
+        at_least("steps", self.steps, 2)?;
+        at_least("columns", self.columns, 1)?;
+        if let Some(rows) = self.rows {
+            at_least("rows", rows, 1)?;
+        }
+        at_least("grid_thickness", self.grid_thickness, 1)?;
+        if let Some(max_slope) = self.max_slope {
+            check_positive(max_slope).context("max_slope")?;
+        }
+        if let Some(reject_outliers) = self.reject_outliers {
+            check_positive(reject_outliers).context("reject_outliers")?;
+        }
+        check_skew(self.max_skew_degrees).context("max_skew_degrees")?;
+        self.luma_weights.validate().context("luma_weights")?;
+        self.step_description()?;
+        Ok(())
+    }
+
+    // The wedge these params expect to find in the scan, an error if its steps don't fit its rows
+    pub fn step_description(&self) -> Result<StepDescription> {
+        let step_description =
+            StepDescription::standard(self.steps, self.columns).with_layout(self.layout);
+        match self.rows {
+            Some(rows) => step_description.with_rows(rows),
+            None => Ok(step_description),
+        }
+    }
+}
+
+impl Default for AnalyzeParams {
+    fn default() -> Self {
+        Self {
+            invert: false,
+            layout: Layout::default(),
+            anchor_endpoints: false,
+            subsample_factor: 1,
+            sample_strategy: SampleStrategy::default(),
+            luma_weights: LumaWeights::default(),
+            steps: 101,
+            columns: 10,
+            rows: None,
+            max_slope: None,
+            detect_ink_limit: false,
+            reject_outliers: None,
+            robust: false,
+            black_point: None,
+            white_point: None,
+            weight_by_area: false,
+            alpha_mask: false,
+            scanner_profile: None,
+            grid_thickness: generate::DEFAULT_GRID_THICKNESS,
+            grid_tone: None,
+            target: None,
+            base: None,
+            debanding: false,
+            corner_dots: false,
+            index_strip: false,
+            deskew: false,
+            max_skew_degrees: DEFAULT_MAX_SKEW_DEGREES,
+        }
+    }
 }
 
 /* analyze takes a path to a scanned image (input) and a path to a
@@ -28,21 +322,229 @@ pub struct AnalyzeResults {
  */
 pub fn analyze(
     image: &DynamicImage,
-    invert_image: bool,
+    params: &AnalyzeParams,
+    debug: bool,
+) -> anyhow::Result<AnalyzeResults> {
+    analyze_cancellable(image, params, debug, &Cancel::default())
+}
+
+// `analyze`, giving up with `Cancelled` between stages and between squares once `cancel` is set
+pub fn analyze_cancellable(
+    image: &DynamicImage,
+    params: &AnalyzeParams,
+    debug: bool,
+    cancel: &Cancel,
+) -> anyhow::Result<AnalyzeResults> {
+    analyze_observed(image, params, debug, cancel, &mut ())
+}
+
+/* Watches an analysis as it runs, to show its progress or the data behind each stage
+ *
+ * Every method does nothing unless it's implemented. They're called on the thread doing the
+ * analysis, in the order the stages run.
+ */
+pub trait AnalyzeObserver {
+    // Each stage as it starts, named as `--debug` times them
+    fn stage(&mut self, _name: &'static str) {}
+
+    // The grid of squares found in the scan
+    fn grid(&mut self, _grid: &GridAnalysis) {}
+
+    // The tone sampled from each patch, in the order they're laid out, and the standard deviation
+    // of the pixels behind each
+    fn samples(&mut self, _values: &[u16], _deviations: &[f64]) {}
+
+    // The scan stretched between its black and white points, and inverted when asked
+    fn normalized(&mut self, _image: &ImageBuffer<Luma<u16>, Vec<u16>>) {}
+
+    // The fitted curve
+    fn curve(&mut self, _curve: &Spline<f64, f64>) {}
+}
+
+// Watches nothing
+impl AnalyzeObserver for () {}
+
+/* `params` with the steps, columns, and rows read from the scan's index strip when it asks for
+ * that, as they are otherwise
+ */
+fn indexed_params(
+    image: &DynamicImage,
+    params: &AnalyzeParams,
+    debug: bool,
+) -> Result<AnalyzeParams> {
+    if !params.index_strip {
+        return Ok(params.clone());
+    }
+    let index = index_strip::decode(&image.to_luma16())?;
+    if debug {
+        println!(
+            "index strip: {} steps in {} columns and {} rows",
+            index.steps, index.columns, index.rows
+        );
+        if let Some(range) = index.printable_range {
+            println!("printable range: {}-{}", range.low, range.high);
+        }
+    }
+    Ok(AnalyzeParams {
+        steps: index.steps,
+        columns: index.columns,
+        rows: Some(index.rows),
+        ..params.clone()
+    })
+}
+
+// The 16bit tones of `image` analysis works from, through the scanner profile and less the base
+fn working_tones(
+    image: &DynamicImage,
+    params: &AnalyzeParams,
+) -> Result<ImageBuffer<Luma<u16>, Vec<u16>>> {
+    let scanner_profile = match &params.scanner_profile {
+        Some(path) => Some(apply::lut_16bit(&curve::load_curve(path)?)?),
+        None => None,
+    };
+
+    let mut image_16 = scanned_tones(image, params.luma_weights, scanner_profile.as_deref());
+    if let Some(base) = &params.base {
+        let base = scanned_tones(
+            &image::open(base)?,
+            params.luma_weights,
+            scanner_profile.as_deref(),
+        );
+        subtract_base(&mut image_16, &base)?;
+    }
+    Ok(image_16)
+}
+
+// Where the grid of squares lies in `image_8`, looked for the way `params` says it was drawn
+fn find_grid(
+    image_8: &ImageBuffer<Luma<u8>, Vec<u8>>,
+    step_description: &StepDescription,
+    params: &AnalyzeParams,
+) -> Result<GridAnalysis> {
+    match step_description.layout {
+        Layout::Grid => match params.grid_tone {
+            Some(tone) => find_grid_lines(image_8, tone, params.invert),
+            None => analyze_grid(image_8, step_description, params.grid_thickness),
+        },
+        Layout::Separators => analyze_separators(image_8),
+    }
+}
+
+/* The grid found in a scan and the window each patch would be sampled from
+ *
+ * The first and cheapest stage of `analyze` on its own, to check the grid was found where it
+ * should be before sampling and normalizing the whole scan.
+ */
+pub struct GridDetection {
+    pub grid: GridAnalysis,
+    pub sampled_areas: Vec<Rect>,
+}
+
+// Finds the grid in `image` as `analyze` would with `params`, without sampling any of it
+pub fn detect_grid(image: &DynamicImage, params: &AnalyzeParams) -> Result<GridDetection> {
+    let deskewed = deskew(image, params, false)?;
+    let image = deskewed.as_ref().unwrap_or(image);
+    let params = &indexed_params(image, params, false)?;
+    let step_description = params.step_description()?;
+    let image_16 = working_tones(image, params)?;
+    let image_8 = DynamicImage::ImageLuma16(image_16.clone()).to_luma8();
+
+    let grid = find_grid(&image_8, &step_description, params)?;
+    check_geometry(&image_16, &step_description, &grid)?;
+    let patches = if params.corner_dots {
+        dotted_patch_areas(&image_8, &step_description, &grid)?
+    } else {
+        patch_areas(&step_description, &grid)
+    };
+    Ok(GridDetection {
+        sampled_areas: sample_windows(&step_description, &patches),
+        grid,
+    })
+}
+
+// `image` with the grid lines `detection` found in magenta and its sample windows in green
+pub fn draw_grid_detection(image: &DynamicImage, detection: &GridDetection) -> RgbImage {
+    let mut image_rgb = draw_detected_lines(&image.to_luma8(), &detection.grid);
+    let green = Rgb([0, 255, 0]);
+    for area in &detection.sampled_areas {
+        draw_hollow_rect_mut(&mut image_rgb, *area, green);
+    }
+    image_rgb
+}
+
+// `analyze_cancellable`, telling `observer` about each stage as it goes
+pub fn analyze_observed(
+    image: &DynamicImage,
+    params: &AnalyzeParams,
     debug: bool,
+    cancel: &Cancel,
+    observer: &mut dyn AnalyzeObserver,
 ) -> anyhow::Result<AnalyzeResults> {
-    let step_description = StepDescription::new(101, 10, 1000, u16::MAX as u32);
+    let deskewed = timed(debug, observer, "deskew", || deskew(image, params, debug))?;
+    let image = deskewed.as_ref().unwrap_or(image);
+    let params = &indexed_params(image, params, debug)?;
+    let step_description = params.step_description()?;
     let input_values = step_description.input_values();
 
-    // convert to a 16bit Greyscale image this is our working set
-    let image_16 = image.to_luma16();
+    // this is our working set
+    let mut image_16 = working_tones(image, params)?;
 
     // convert to 8bit greyscale used for edge / line detection
-    let image_8 = image.to_luma8();
+    let image_8 = DynamicImage::ImageLuma16(image_16.clone()).to_luma8();
 
-    let grid_analysis = analyze_grid(&image_8)?;
-    let sampled_areas = sampled_areas(&step_description, &grid_analysis);
-    let samples = collect_samples(&image_16, &sampled_areas);
+    let grid_analysis = timed(debug, observer, "grid analysis", || {
+        find_grid(&image_8, &step_description, params)
+    })?;
+    check_geometry(&image_16, &step_description, &grid_analysis)?;
+    observer.grid(&grid_analysis);
+    if debug {
+        println!(
+            "grid origin: ({}, {}) square size: {}",
+            grid_analysis.origin_x, grid_analysis.origin_y, grid_analysis.square_size
+        );
+    }
+    if params.debanding {
+        let banding = timed(debug, observer, "debanding", || {
+            detect_banding(&image_16, &step_description, &grid_analysis)
+        });
+        if let Some(banding) = banding {
+            if debug {
+                println!(
+                    "banding: every {:.1} rows, {:.0} tones either way",
+                    banding.period,
+                    banding.amplitude()
+                );
+            }
+            remove_banding(&mut image_16, &banding);
+        }
+    }
+    let detected_lines = debug.then(|| draw_detected_lines(&image_8, &grid_analysis));
+    let patches = if params.corner_dots {
+        timed(debug, observer, "corner dots", || {
+            dotted_patch_areas(&image_8, &step_description, &grid_analysis)
+        })?
+    } else {
+        patch_areas(&step_description, &grid_analysis)
+    };
+    let sampled_areas = sample_windows(&step_description, &patches);
+    let mut mask = SampleMask::new(&grid_analysis, image_16.dimensions());
+    if params.alpha_mask {
+        mask.mask_transparent(image)?;
+    }
+    let mut samples = timed(debug, observer, "sampling", || {
+        collect_samples(
+            &image_16,
+            &sampled_areas,
+            params.subsample_factor,
+            params.sample_strategy,
+            &mask,
+            cancel,
+        )
+    })?;
+    if params.weight_by_area {
+        weight_by_area(&mut samples);
+    }
+    observer.samples(&samples.values, &samples.deviations);
 
     if debug {
         println!("Found: {} samples", samples.values.len());
@@ -51,457 +553,2908 @@ pub fn analyze(
         println!("dynamic range: {}", samples.max - samples.min);
     }
 
+    let (black, white) = reference_points(&image_16, &sampled_areas, &samples, params)?;
+    if debug {
+        println!("black point: {}", black);
+        println!("white point: {}", white);
+    }
+
     let NormalizedResults {
         image: normalized_image,
         samples: normalized_samples,
-    } = normalize_image(&step_description, &image_16, &samples, invert_image);
+        deviations: normalized_deviations,
+        normalization,
+    } = timed(debug, observer, "normalization", || {
+        normalize_image(
+            &step_description,
+            &image_16,
+            &samples,
+            (black, white),
+            params.invert,
+        )
+    });
+    observer.normalized(&normalized_image);
 
-    let curve_points = linearize_inputs(&input_values, &normalized_samples)?;
+    cancel.check()?;
+
+    let response: Vec<(u16, u16)> = input_values
+        .iter()
+        .copied()
+        .zip(normalized_samples.iter().copied())
+        .collect();
+    let rejected = match params.reject_outliers {
+        Some(threshold) => reject_outliers(&response, threshold),
+        None => Vec::new(),
+    };
+    if debug && !rejected.is_empty() {
+        println!("rejected outlier steps: {:?}", rejected);
+    }
+    let kept = without_steps(&response, &rejected);
+    let ink_limit = params
+        .detect_ink_limit
+        .then(|| detect_ink_limit(&kept))
+        .flatten();
     if debug {
-        println!("curve_points\n{:?}", curve_points);
+        if let Some(ink_limit) = ink_limit {
+            println!("ink limit: {}", ink_limit);
+        }
+    }
+    let gamma = measured_gamma(&kept);
+    if debug {
+        if let Some(gamma) = gamma {
+            println!("measured gamma: {:.3}", gamma);
+        }
+    }
+    let curve = timed(debug, observer, "fit", || {
+        fit_curve(&input_values, &kept, params, ink_limit, debug)
+    })?;
+    observer.curve(&curve);
+    let response_curve = curve_from_points(&kept, Interpolation::default())?;
+    cancel.check()?;
+
+    // normalization puts the samples in the order of the response, the patches need to match
+    let mut patch_areas = patches;
+    if !params.invert {
+        patch_areas.reverse();
     }
-    let curve = best_fit_spline(&curve_points);
-    let histogram = create_histogram(&normalized_image, &grid_analysis, &step_description);
 
-    let normalized_image_with_rects =
-        draw_sampled_areas(&DynamicImage::ImageLuma16(normalized_image), &sampled_areas)?;
+    let (histogram, rgb_histograms) = timed(debug, observer, "histogram", || {
+        let histogram = create_histogram(
+            &normalized_image,
+            &grid_analysis,
+            &step_description,
+            TONE_BUCKETS,
+        );
+        let rgb_histograms = if image.color().has_color() {
+            Some(create_histograms_rgb(
+                &image.to_rgb16(),
+                &grid_analysis,
+                &step_description,
+                TONE_BUCKETS,
+            ))
+        } else {
+            None
+        };
+        (histogram, rgb_histograms)
+    });
+
+    let patch_status = patch_statuses(&samples.deviations, &samples.clipped, &rejected);
+    let normalized_image_with_rects = timed(debug, observer, "rendering", || {
+        draw_sampled_areas(
+            &DynamicImage::ImageLuma16(normalized_image),
+            &sampled_areas,
+            &patch_status,
+        )
+    })?;
 
     Ok(AnalyzeResults {
         normalized_image: DynamicImage::ImageRgb8(normalized_image_with_rects),
         histogram,
+        rgb_histograms,
+        response,
+        response_curve,
+        uncertainty: input_values
+            .iter()
+            .map(|v| *v as f64)
+            .zip(normalized_deviations)
+            .collect(),
+        patch_areas,
+        patch_status,
+        rejected,
+        detected_lines,
+        normalization,
+        ink_limit,
+        gamma,
         curve,
     })
 }
 
-/* Generate a spline (that can later be sampled from) based on the a vector of 2D points. Used for
- * creating the correction curve.
+/* Fits the correction curve to the measured `response`, (input tone, normalized observed tone)
+ * pairs, producing a point on the curve for every one of `input_values`. The curve's output is
+ * capped at `ink_limit` when there is one, see `detect_ink_limit`.
+ *
+ * The response doesn't need an entry for every input, which lets `refit` leave some out.
  */
-fn best_fit_spline(curve: &Vec<(u16, u16)>) -> Spline<f64, f64> {
-    Spline::from_vec(
-        curve
-            .into_iter()
-            .map(|(input_density, output_density)| {
-                Key::new(
-                    *input_density as f64,
-                    *output_density as f64,
-                    Interpolation::default(),
-                )
-            })
-            .collect(),
-    )
+fn fit_curve(
+    input_values: &[u16],
+    response: &[(u16, u16)],
+    params: &AnalyzeParams,
+    ink_limit: Option<u16>,
+    debug: bool,
+) -> Result<Spline<f64, f64>> {
+    let target = params
+        .target
+        .as_deref()
+        .map(curve::load_curve)
+        .transpose()?;
+    let mut curve_points = linearize_inputs(input_values, response, target.as_ref())?;
+    if params.anchor_endpoints {
+        anchor_endpoints(
+            &mut curve_points,
+            params.step_description()?.max_tone as u16,
+        );
+    }
+    if let Some(ink_limit) = ink_limit {
+        cap_output(&mut curve_points, ink_limit);
+    }
+    if let Some(max_slope) = params.max_slope {
+        limit_slope(&mut curve_points, max_slope);
+    }
+    if debug {
+        println!("curve_points\n{:?}", curve_points);
+    }
+    curve_from_points(&curve_points, Interpolation::default())
 }
 
-/* This is hardly "sampled" at this point. Instead it just finds the mean value
- * of ALL of the pixels in the given Rect
+/* Fits the curve again without the steps of `results.response` listed in `excluded`, for when a
+ * patch of the scan turns out to be spoiled by dust or a fold. `params` should be the ones the
+ * results were analyzed with.
  */
-fn sampled_mean(image: SubImage<&ImageBuffer<Luma<u16>, Vec<u16>>>) -> u16 {
-    let (width, height) = image.dimensions();
-    let mut total: u64 = 0;
-    let count = (width * height) as u64;
+pub fn refit(
+    results: &AnalyzeResults,
+    params: &AnalyzeParams,
+    excluded: &[usize],
+) -> Result<Spline<f64, f64>> {
+    let input_values: Vec<u16> = results.response.iter().map(|(input, _)| *input).collect();
+    let kept = without_steps(&results.response, excluded);
+    if kept.len() < 2 {
+        return Err(anyhow!("need at least 2 steps left to fit a curve"));
+    }
+    // the excluded steps may have been what set the limit, so it's looked for again without them
+    let ink_limit = params
+        .detect_ink_limit
+        .then(|| detect_ink_limit(&kept))
+        .flatten();
+    fit_curve(&input_values, &kept, params, ink_limit, false)
+}
 
-    for x in 0..width {
-        for y in 0..height {
-            let pixel = image.get_pixel(x, y);
-            total += pixel[0] as u64
+// `response` without the steps in `excluded`
+fn without_steps(response: &[(u16, u16)], excluded: &[usize]) -> Vec<(u16, u16)> {
+    response
+        .iter()
+        .enumerate()
+        .filter(|(step, _)| !excluded.contains(step))
+        .map(|(_, point)| *point)
+        .collect()
+}
+
+// Deviations smaller than this share of the range are never outliers, however smooth the rest is
+const MIN_OUTLIER_DEVIATION: f64 = 0.005;
+
+/* Finds the steps of `response` that are out of line with their neighbors, returned in order
+ *
+ * Each step is compared against the line through its two nearest neighbors (the two on one side
+ * for the ends). A step further from that than `threshold` times the median absolute deviation of
+ * every step is rejected. One outlier throws off its neighbors' predictions too, so the worst step
+ * is rejected first and the rest measured again without it, until every step is in line or a
+ * quarter of them have been rejected.
+ */
+fn reject_outliers(response: &[(u16, u16)], threshold: f64) -> Vec<usize> {
+    let mut kept: Vec<usize> = (0..response.len()).collect();
+    let mut rejected = Vec::new();
+    let floor = MIN_OUTLIER_DEVIATION * u16::MAX as f64;
+
+    // it takes a few points to say what the trend is, and with a quarter of the wedge out of line
+    // the scan is bad rather than spotted with outliers
+    while kept.len() > 4 && rejected.len() < response.len() / 4 {
+        let residuals: Vec<f64> = (0..kept.len())
+            .map(|i| {
+                let (a, b) = match i {
+                    0 => (1, 2),
+                    i if i == kept.len() - 1 => (i - 2, i - 1),
+                    i => (i - 1, i + 1),
+                };
+                let point = |n: usize| {
+                    let (input, value) = response[kept[n]];
+                    (input as f64, value as f64)
+                };
+                let ((x, y), (xa, ya), (xb, yb)) = (point(i), point(a), point(b));
+                let expected = if xb == xa {
+                    (ya + yb) / 2.
+                } else {
+                    ya + (yb - ya) * (x - xa) / (xb - xa)
+                };
+                y - expected
+            })
+            .collect();
+
+        let median = |values: &mut Vec<f64>| {
+            values.sort_by(f64::total_cmp);
+            values[values.len() / 2]
+        };
+        let center = median(&mut residuals.clone());
+        let mad = median(&mut residuals.iter().map(|r| (r - center).abs()).collect());
+
+        let (worst, deviation) = residuals
+            .iter()
+            .map(|r| (r - center).abs())
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .unwrap();
+        if deviation <= (threshold * mad).max(floor) {
+            break;
         }
+        rejected.push(kept.remove(worst));
     }
 
-    return (total / count) as u16;
+    rejected.sort_unstable();
+    rejected
 }
 
-fn draw_sampled_areas(
-    image: &DynamicImage,
-    rects: &Vec<Rect>,
-) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>> {
-    let mut image_rgb = image.to_rgb8();
-    let green = image::Rgb::<u8>([0, 255, 0]);
-    for r in rects {
-        draw_hollow_rect_mut(&mut image_rgb, *r, green)
+// Runs `stage`, printing how long it took when debugging
+fn timed<T>(
+    debug: bool,
+    observer: &mut dyn AnalyzeObserver,
+    name: &'static str,
+    stage: impl FnOnce() -> T,
+) -> T {
+    observer.stage(name);
+    let start = Instant::now();
+    let result = stage();
+    if debug {
+        println!("{} took {:?}", name, start.elapsed());
     }
-    Ok(image_rgb)
+    result
 }
 
-pub fn generate_small_sample(curve: &Spline<f64, f64>) -> Result<Vec<(u8, u8)>> {
-    let mut samples: Vec<(u8, u8)> = Vec::new();
-
-    for i in (0..u16::MAX).step_by(4096) {
-        let sample = curve
-            .clamped_sample(i as f64)
-            .ok_or(anyhow!("failed to sample spline"))?;
+/* Pins the first and last curve points to the extremes of the domain, (0, 0) and (max, max), so
+ * that pure black and pure white pass through the curve unchanged. Clipping in the scan tends to
+ * leave the ends of the curve slightly off, which shifts the density of the whole print.
+ */
+fn anchor_endpoints(curve: &mut [(u16, u16)], max: u16) {
+    if let Some(first) = curve.first_mut() {
+        *first = (0, 0);
+    }
+    if let Some(last) = curve.last_mut() {
+        *last = (max, max);
+    }
+}
 
-        let clamped_i = (i as f32 / 256.).floor() as u8;
-        let clamped_sample = (sample / 256.).floor() as u8;
+// How far a step's response can wander from the last step's and still count as part of its plateau
+const INK_LIMIT_TOLERANCE: f64 = 0.01;
 
-        // divide by 256 to bring it back into [0,256]
-        samples.push((clamped_i, clamped_sample));
+/* The input tone where `response` flattens out toward the top of the input range, where more ink
+ * stops adding density, or None if it keeps changing right up to the last step
+ *
+ * The plateau is the run of steps at the top end whose response is within `INK_LIMIT_TOLERANCE`
+ * of the last step's, and the limit is the first of them. It takes at least two steps to call a
+ * run flat, and a response that's flat from the very first step has no limit worth capping at.
+ */
+fn detect_ink_limit(response: &[(u16, u16)]) -> Option<u16> {
+    let (_, last) = *response.last()?;
+    let tolerance = INK_LIMIT_TOLERANCE * u16::MAX as f64;
+    let plateau = response
+        .iter()
+        .rev()
+        .take_while(|(_, value)| (*value as f64 - last as f64).abs() <= tolerance)
+        .count();
+    if plateau < 2 || plateau == response.len() {
+        return None;
     }
-    Ok(samples)
+    Some(response[response.len() - plateau].0)
 }
 
-pub fn write_small_csv(file: &mut fs::File, curve: &Spline<f64, f64>) -> Result<()> {
-    let samples = generate_small_sample(curve)?;
+// The share of the input range at either end left out of `measured_gamma`, where noise dominates
+const GAMMA_FIT_MARGIN: f64 = 0.1;
 
-    for (i, x) in samples.into_iter() {
-        writeln!(file, "{}, {}", i, x)?;
+/* The gamma of the power law that best fits `response`, output = input^gamma with both as
+ * fractions of the full range, or None without enough mid tones to fit
+ *
+ * A single number for the contrast of a process, above 1 where it darkens the mid tones and below
+ * 1 where it lightens them, which makes sessions and papers easy to compare. It's the least squares
+ * slope of the response in log-log through (1, 1), fit over the mid tones only since the logs of
+ * the deepest shadows blow their noise up.
+ */
+pub fn measured_gamma(response: &[(u16, u16)]) -> Option<f64> {
+    let max = u16::MAX as f64;
+    let logs: Vec<(f64, f64)> = response
+        .iter()
+        .map(|(input, output)| (*input as f64 / max, *output as f64 / max))
+        .filter(|(input, output)| {
+            (GAMMA_FIT_MARGIN..=1. - GAMMA_FIT_MARGIN).contains(input) && *output > 0.
+        })
+        .map(|(input, output)| (input.ln(), output.ln()))
+        .collect();
+    if logs.len() < 2 {
+        return None;
     }
-    Ok(())
+    let xy: f64 = logs.iter().map(|(x, y)| x * y).sum();
+    let xx: f64 = logs.iter().map(|(x, _)| x * x).sum();
+    Some(xy / xx)
 }
 
-pub fn draw_curve(
-    image: &mut ImageBuffer<image::Rgb<u8>, Vec<u8>>,
-    curve: &Spline<f64, f64>,
-) -> Result<()> {
-    for i in (0..u16::MAX).step_by(64) {
-        let green = image::Rgb::<u8>([0, 255, 0]);
-        let sample = curve
-            .clamped_sample(i as f64)
-            .ok_or(anyhow!("failed to sample spline"))?;
-        // divide by 64 to bring it back into [0,1024]
-        let y = 1023 - (sample / 64.) as u32;
-        let x = (i / 64) as u32;
-        image.put_pixel(x, y, green);
+// Caps every point of `curve` at `limit`, so it never asks for more than the process can print
+fn cap_output(curve: &mut [(u16, u16)], limit: u16) {
+    for point in curve.iter_mut() {
+        point.1 = point.1.min(limit);
     }
-    Ok(())
 }
 
-/* Draws a histogram ontop of `image`
+/* Pulls in any point that makes the curve steeper than `max_slope` between it and its neighbors,
+ * so a single noisy step can't turn into a spike that exaggerates grain.
  *
- * expects the image to be 1024x1024
+ * A forward pass keeps each point within reach of the one before it, then a backward pass does the
+ * same against the one after it. After the backward pass every segment is within bounds, running
+ * both spreads the correction over both sides of a spike rather than just one.
  */
-pub fn draw_histogram(
-    image: &mut ImageBuffer<image::Rgb<u8>, Vec<u8>>,
-    histogram: &Vec<u32>,
-) -> anyhow::Result<()> {
-    let grey = image::Rgb::<u8>([128, 128, 128]);
+fn limit_slope(curve: &mut [(u16, u16)], max_slope: f64) {
+    let clamp_to = |point: (u16, u16), neighbor: (u16, u16)| {
+        let reach = max_slope * (point.0 as f64 - neighbor.0 as f64).abs();
+        let value = (point.1 as f64).clamp(neighbor.1 as f64 - reach, neighbor.1 as f64 + reach);
+        (point.0, value.round() as u16)
+    };
 
-    // The first and last buckets tend to get filled with stuff like
-    // lines and letters, not useful. Remove them.
-    let histogram_minus = &histogram[1..256];
+    for i in 1..curve.len() {
+        curve[i] = clamp_to(curve[i], curve[i - 1]);
+    }
+    for i in (0..curve.len().saturating_sub(1)).rev() {
+        curve[i] = clamp_to(curve[i], curve[i + 1]);
+    }
+}
 
-    let max = histogram_minus
-        .into_iter()
-        .max()
-        .ok_or(anyhow!("could not find maximum histogram value"))?;
+/* The pixels of the scan sampling leaves out
+ *
+ * Those on the columns and rows grid lines run along, so a line running into a patch doesn't drag
+ * its tone toward the line's, and any masked out by hand with `mask_transparent`.
+ */
+#[derive(Default)]
+struct SampleMask {
+    columns: Vec<bool>,
+    rows: Vec<bool>,
+    // every pixel of the scan row by row, true where it's transparent. Empty unless masked
+    transparent: Vec<bool>,
+    width: u32,
+}
 
-    for (i, value) in histogram_minus.into_iter().enumerate() {
-        if i == 0 || i == 256 {
-            continue;
+impl SampleMask {
+    fn new(grid_analysis: &GridAnalysis, (width, height): (u32, u32)) -> Self {
+        let mask = |lines: &[u32], length: u32| {
+            let mut mask = vec![false; length as usize];
+            for line in lines.iter().filter(|line| **line < length) {
+                mask[*line as usize] = true;
+            }
+            mask
+        };
+        Self {
+            columns: mask(&grid_analysis.line_columns, width),
+            rows: mask(&grid_analysis.line_rows, height),
+            transparent: Vec::new(),
+            width,
         }
+    }
 
-        let scaled_percentage = (((*value as f32) / (*max as f32)) * 1024.) as u32;
+    // Also leaves out every fully transparent pixel of `image`, the scan being sampled
+    fn mask_transparent(&mut self, image: &DynamicImage) -> Result<()> {
+        if !image.color().has_alpha() {
+            return Err(anyhow!(
+                "the scan has no alpha channel to mask with, save it as a png or tiff with \
+                 transparency"
+            ));
+        }
+        self.width = image.width();
+        self.transparent = image
+            .to_luma_alpha16()
+            .pixels()
+            .map(|p| p[1] == 0)
+            .collect();
+        Ok(())
+    }
 
-        let x = (i * 4) as i32;
-        let rect = Rect::at(x, (1024 - scaled_percentage) as i32).of_size(4, scaled_percentage);
-        draw_filled_rect_mut(image, rect, grey);
+    fn covers(&self, x: u32, y: u32) -> bool {
+        let on = |mask: &[bool], i: u32| mask.get(i as usize).copied().unwrap_or(false);
+        on(&self.columns, x)
+            || on(&self.rows, y)
+            || (x < self.width && on(&self.transparent, y * self.width + x))
     }
-    Ok(())
 }
 
-/* Look through the haystack of (input_density, output_density) for the input density with the
- * output density that most closely matches needle.
- *
- * An exact match is unlikely, so instead build a range of values. First searching from the start
- * forward until finding the first output density larger than needle. Then reversing the search to
- * find the first output density smaller than needle. Interpolate the two input densities for our
- * resulting value.
- *
- * Issue: If the highs or lows completely or nearly clip then I think we end up with clumbs at the top and
- * bottom. Need a way to avoid this.
- *
- * for example of our distrbution looks like
- *
- * > [(0, 0), (5,0), (10,0), (15, 5), (20,10), (25, 15), (30, 20), (35, 20), (40, 20)]
- *
- * searching for the output value that match 2 we would look forward and find the first greater
- * output (5) and record (10,0) as our match. Then looking backward we would find (10,0) as the
- * first lesser value and record (15,5) as our match.
+/* The pixels in the given Rect that get sampled, along with how many of them are valid
  *
+ * With a `subsample_factor` of 1 this reads ALL of the pixels. Larger factors only read every Nth
+ * pixel in each direction, on a high resolution scan the squares are so large and so flat that
+ * the difference is negligible. Pixels `mask` covers aren't valid and are left out, unless it
+ * covers every pixel, then they're all read and the count is 0.
+ */
+fn sampled_pixels(
+    image: SubImage<&ImageBuffer<Luma<u16>, Vec<u16>>>,
+    subsample_factor: u32,
+    mask: &SampleMask,
+) -> (Vec<u16>, u64) {
+    let (width, height) = image.dimensions();
+    let (offset_x, offset_y) = image.offsets();
+    let step = subsample_factor.max(1) as usize;
+    let read = |valid: &dyn Fn(u32, u32) -> bool| -> Vec<u16> {
+        (0..width)
+            .step_by(step)
+            .flat_map(|x| (0..height).step_by(step).map(move |y| (x, y)))
+            .filter(|(x, y)| valid(*x, *y))
+            .map(|(x, y)| image.get_pixel(x, y)[0])
+            .collect()
+    };
+
+    let pixels = read(&|x, y| !mask.covers(offset_x + x, offset_y + y));
+    match pixels.len() as u64 {
+        0 => (read(&|_, _| true), 0),
+        count => (pixels, count),
+    }
+}
+
+/* How the pixels sampled from a square are reduced to its one tone
  *
+ * Median: the middle pixel, a few pixels of dust or scratches at either extreme don't move it
+ * Mean: every pixel averaged, each one pulling the tone toward it
  */
-fn find_closest_matching_input_density(
-    haystack: &Vec<(u16, u16)>,
-    needle: u16,
-) -> anyhow::Result<u16> {
-    let mut lower_bound_density: Option<u16> = None;
-    let mut upper_bound_density: Option<u16> = None;
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, clap::ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+pub enum SampleStrategy {
+    #[default]
+    Median,
+    Mean,
+}
 
-    // search forward, find the first output_density /greater/ than needle
-    // our lower bound will then be the input density immediately prior
-    for (i, (_, output_density)) in haystack.into_iter().enumerate() {
-        if *output_density > needle {
-            if i == 0 {
-                lower_bound_density = Some(0);
-            } else {
-                lower_bound_density = Some(haystack[i - 1].0);
-            }
+impl SampleStrategy {
+    fn sample(self, pixels: &[u16]) -> u16 {
+        match self {
+            SampleStrategy::Median => sampled_median(pixels),
+            SampleStrategy::Mean => sampled_mean(pixels),
+        }
+    }
+}
 
-            break;
+// The mean value of the sampled `pixels`
+fn sampled_mean(pixels: &[u16]) -> u16 {
+    let total: u64 = pixels.iter().map(|p| *p as u64).sum();
+    (total / pixels.len().max(1) as u64) as u16
+}
+
+// The median value of the sampled `pixels`, between the middle two when there's an even number
+fn sampled_median(pixels: &[u16]) -> u16 {
+    let mut sorted = pixels.to_vec();
+    sorted.sort_unstable();
+    let middle = sorted.len() / 2;
+    match sorted.len() {
+        0 => 0,
+        len if len % 2 == 0 => sampled_mean(&sorted[middle - 1..=middle]),
+        _ => sorted[middle],
+    }
+}
+
+// The standard deviation of the sampled `pixels`
+fn sampled_deviation(pixels: &[u16], mean: u16) -> f64 {
+    let total: f64 = pixels
+        .iter()
+        .map(|p| (*p as f64 - mean as f64).powi(2))
+        .sum();
+    (total / pixels.len().max(1) as f64).sqrt()
+}
+
+/* The scan with every pixel on a grid line analyze found painted over in magenta, like focus
+ * peaking on a camera. When the grid was found correctly the magenta covers the printed lines
+ * exactly, printed lines peeking out beside the magenta mean the grid is off.
+ */
+fn draw_detected_lines(image: &ImageBuffer<Luma<u8>, Vec<u8>>, grid: &GridAnalysis) -> RgbImage {
+    let mut image_rgb = DynamicImage::ImageLuma8(image.clone()).to_rgb8();
+    let magenta = Rgb([255, 0, 255]);
+    let (width, height) = image_rgb.dimensions();
+    for x in grid.line_columns.iter().filter(|x| **x < width) {
+        for y in 0..height {
+            image_rgb.put_pixel(*x, y, magenta);
+        }
+    }
+    for y in grid.line_rows.iter().filter(|y| **y < height) {
+        for x in 0..width {
+            image_rgb.put_pixel(x, *y, magenta);
         }
     }
+    image_rgb
+}
 
-    // search backwards, find the first output_density /lesser/ than needle
-    // our upper bound will then be the input density immediately prior
-    for (i, (_, output_density)) in haystack.into_iter().rev().enumerate() {
-        if *output_density < needle {
-            if i == 0 {
-                upper_bound_density = Some(u16::MAX);
+// Share of a patch's pixels at black or white past which the patch is clipped
+const CLIPPED_PATCH_SHARE: f64 = 0.5;
+
+// How many times the median deviation of every patch a patch's deviation has to reach to be noisy
+const HIGH_VARIANCE_FACTOR: f64 = 3.;
+
+// Deviations smaller than this share of the range are never high, however smooth the rest are
+const MIN_HIGH_VARIANCE: f64 = 0.01;
+
+// How trustworthy the sample of each patch is, shown by the color its window is outlined in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PatchStatus {
+    Good,
+    // the pixels behind the sample vary far more than most patches', dust, a scratch, or grain
+    HighVariance,
+    // most of the pixels are at black or white, the scanner's exposure ran out of range
+    Clipped,
+    // left out of the curve by `AnalyzeParams::reject_outliers`
+    Rejected,
+}
+
+impl PatchStatus {
+    pub fn color(self) -> Rgb<u8> {
+        match self {
+            PatchStatus::Good => Rgb([0, 255, 0]),
+            PatchStatus::HighVariance => Rgb([255, 255, 0]),
+            PatchStatus::Clipped => Rgb([255, 0, 0]),
+            PatchStatus::Rejected => Rgb([128, 128, 128]),
+        }
+    }
+}
+
+/* The status of every patch, the worst that applies to each: rejected, then clipped, then high
+ * variance
+ *
+ * A deviation is high past `HIGH_VARIANCE_FACTOR` times the median of every patch's, and at least
+ * `MIN_HIGH_VARIANCE` of the range so a clean scan doesn't flag its slightly grainier patches.
+ */
+fn patch_statuses(deviations: &[f64], clipped: &[bool], rejected: &[usize]) -> Vec<PatchStatus> {
+    let mut sorted = deviations.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let median = sorted.get(sorted.len() / 2).copied().unwrap_or(0.);
+    let high = (HIGH_VARIANCE_FACTOR * median).max(MIN_HIGH_VARIANCE * u16::MAX as f64);
+
+    deviations
+        .iter()
+        .zip(clipped)
+        .enumerate()
+        .map(|(step, (deviation, clipped))| {
+            if rejected.contains(&step) {
+                PatchStatus::Rejected
+            } else if *clipped {
+                PatchStatus::Clipped
+            } else if *deviation > high {
+                PatchStatus::HighVariance
             } else {
-                upper_bound_density = Some(haystack[haystack.len() - i].0);
+                PatchStatus::Good
             }
+        })
+        .collect()
+}
 
-            break;
+// Outlines each of `rects` on `image` in the color of its status
+fn draw_sampled_areas(
+    image: &DynamicImage,
+    rects: &[Rect],
+    statuses: &[PatchStatus],
+) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>> {
+    let mut image_rgb = image.to_rgb8();
+    for (r, status) in rects.iter().zip(statuses) {
+        draw_hollow_rect_mut(&mut image_rgb, *r, status.color())
+    }
+    Ok(image_rgb)
+}
+
+pub fn generate_small_sample(curve: &Spline<f64, f64>) -> Result<Vec<(u8, u8)>> {
+    let mut samples: Vec<(u8, u8)> = Vec::new();
+
+    for i in (0..u16::MAX).step_by(4096) {
+        let sample = sample_curve(curve, i as f64)?;
+
+        let clamped_i = (i as f32 / 256.).floor() as u8;
+        let clamped_sample = (sample / 256.).floor() as u8;
+
+        // divide by 256 to bring it back into [0,256]
+        samples.push((clamped_i, clamped_sample));
+    }
+    Ok(samples)
+}
+
+pub fn write_small_csv(file: &mut fs::File, curve: &Spline<f64, f64>) -> Result<()> {
+    let samples = generate_small_sample(curve)?;
+
+    for (i, x) in samples.into_iter() {
+        writeln!(file, "{}, {}", i, x)?;
+    }
+    Ok(())
+}
+
+// number of entries written to a .cal file, one per 8bit device value
+const CAL_STEPS: u32 = 256;
+
+/* Writes the curve as a minimal Argyll CMS calibration (.cal) file
+ *
+ * The file is a CGATS text file describing an RGB output device with the same curve on each
+ * channel. The data table has CAL_STEPS rows and four columns:
+ *
+ *  - RGB_I: the device input value, evenly spaced over [0, 1]
+ *  - RGB_R, RGB_G, RGB_B: the corrected value for that input, also in [0, 1]
+ *
+ * Values are the curve sampled at input * 65535 and divided back down by 65535.
+ */
+pub fn write_cal(writer: &mut impl Write, curve: &Spline<f64, f64>) -> Result<()> {
+    let max = u16::MAX as f64;
+
+    writeln!(writer, "CAL")?;
+    writeln!(writer)?;
+    writeln!(writer, "DESCRIPTOR \"Argyll Device Calibration State\"")?;
+    writeln!(writer, "ORIGINATOR \"curved\"")?;
+    writeln!(writer, "KEYWORD \"DEVICE_CLASS\"")?;
+    writeln!(writer, "DEVICE_CLASS \"OUTPUT\"")?;
+    writeln!(writer, "KEYWORD \"COLOR_REP\"")?;
+    writeln!(writer, "COLOR_REP \"RGB\"")?;
+    writeln!(writer)?;
+    writeln!(writer, "NUMBER_OF_FIELDS 4")?;
+    writeln!(writer, "BEGIN_DATA_FORMAT")?;
+    writeln!(writer, "RGB_I RGB_R RGB_G RGB_B")?;
+    writeln!(writer, "END_DATA_FORMAT")?;
+    writeln!(writer)?;
+    writeln!(writer, "NUMBER_OF_SETS {}", CAL_STEPS)?;
+    writeln!(writer, "BEGIN_DATA")?;
+    for (input, sample) in sample_curve_lut(curve, CAL_STEPS as usize)? {
+        let input = input / max;
+        let output = (sample / max).clamp(0., 1.);
+        writeln!(
+            writer,
+            "{:.6} {:.6} {:.6} {:.6}",
+            input, output, output, output
+        )?;
+    }
+    writeln!(writer, "END_DATA")?;
+    Ok(())
+}
+
+pub fn draw_curve(
+    image: &mut ImageBuffer<image::Rgb<u8>, Vec<u8>>,
+    curve: &Spline<f64, f64>,
+) -> Result<()> {
+    draw_curve_colored(image, curve, image::Rgb::<u8>([0, 255, 0]))
+}
+
+// `draw_curve` in `color`
+pub fn draw_curve_colored(
+    image: &mut ImageBuffer<image::Rgb<u8>, Vec<u8>>,
+    curve: &Spline<f64, f64>,
+    color: image::Rgb<u8>,
+) -> Result<()> {
+    for (x, y) in curve_plot_points(curve)? {
+        image.put_pixel(x as u32, 1023 - y as u32, color);
+    }
+    Ok(())
+}
+
+/* Where `curve` is plotted on a 1024x1024 chart, one (x, y) point per column with y counting up
+ * from the bottom
+ */
+pub fn curve_plot_points(curve: &Spline<f64, f64>) -> Result<Vec<(f64, f64)>> {
+    (0..u16::MAX)
+        .step_by(64)
+        .map(|i| {
+            let sample = sample_curve(curve, i as f64)?;
+            // divide by 64 to bring it back into [0,1024]
+            Ok(((i / 64) as f64, (sample / 64.).clamp(0., 1023.)))
+        })
+        .collect()
+}
+
+/* Draws a shaded band around the curve showing how noisy the samples behind it were
+ *
+ * `uncertainty` is a list of (input tone, standard deviation) pairs, between them the width of the
+ * band is linearly interpolated. The band spans one standard deviation either side of the curve.
+ * Draw it before the curve so the curve sits on top.
+ *
+ * expects the image to be 1024x1024
+ */
+pub fn draw_uncertainty_band(
+    image: &mut ImageBuffer<image::Rgb<u8>, Vec<u8>>,
+    curve: &Spline<f64, f64>,
+    uncertainty: &[(f64, f64)],
+) -> Result<()> {
+    let band_color = image::Rgb::<u8>([0, 90, 0]);
+    let band = Spline::from_vec(
+        uncertainty
+            .iter()
+            .map(|(input, deviation)| Key::new(*input, *deviation, Interpolation::Linear))
+            .collect(),
+    );
+
+    for x in 0..1024 {
+        let i = (x * 64) as f64;
+        let sample = sample_curve(curve, i)?;
+        let deviation = band.clamped_sample(i).unwrap_or(0.);
+
+        // divide by 64 to bring it back into [0,1024]
+        let top = ((sample + deviation) / 64.).clamp(0., 1023.) as u32;
+        let bottom = ((sample - deviation) / 64.).clamp(0., 1023.) as u32;
+        for y in bottom..=top {
+            image.put_pixel(x, 1023 - y, band_color);
         }
     }
+    Ok(())
+}
 
-    let closest = match (lower_bound_density, upper_bound_density) {
-        (None, None) => {
-            return Err(anyhow!("Unable to map tones, value out of range"));
+/* `image` as 16bit greyscale, each tone corrected through the lookup table of the scanner's
+ * profile when there is one
+ */
+fn scanned_tones(
+    image: &DynamicImage,
+    luma_weights: LumaWeights,
+    scanner_profile: Option<&[u16]>,
+) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+    let mut image_16 = luma::to_luma16(image, luma_weights);
+    if let Some(profile) = scanner_profile {
+        for pixel in image_16.pixels_mut() {
+            pixel[0] = profile[pixel[0] as usize];
         }
-        (Some(l), None) => l,
-        (None, Some(u)) => u,
-        (Some(l), Some(u)) => (((l as u32) + (u as u32)) / 2) as u16,
-    };
+    }
+    image_16
+}
 
-    Ok(closest)
+/* Takes the density of the film base out of every pixel of `image`, `base` being a scan of the
+ * blank base under the same light
+ *
+ * Densities add, so the base's is subtracted from each pixel's. For tones that's dividing by the
+ * light the base lets through, pixel by pixel so uneven light across the scanner bed is taken
+ * out too. Pixels lighter than the base clamp to white.
+ */
+fn subtract_base(
+    image: &mut ImageBuffer<Luma<u16>, Vec<u16>>,
+    base: &ImageBuffer<Luma<u16>, Vec<u16>>,
+) -> Result<()> {
+    if image.dimensions() != base.dimensions() {
+        return Err(anyhow!(
+            "base scan is {}x{} but the scan is {}x{}, they have to match",
+            base.width(),
+            base.height(),
+            image.width(),
+            image.height()
+        ));
+    }
+
+    let max = u16::MAX as u32;
+    for (pixel, base) in image.pixels_mut().zip(base.pixels()) {
+        pixel[0] = match base[0] as u32 {
+            // an opaque base lets no light through, there's nothing behind it to recover
+            0 => 0,
+            base => ((pixel[0] as u32 * max + base / 2) / base).min(max) as u16,
+        };
+    }
+    Ok(())
 }
 
-// simple histogram of the image with 256 buckets
-fn create_histogram(
+// The shortest ripple in rows that counts as banding, anything finer is just scanner noise
+const MIN_BANDING_PERIOD: f64 = 3.;
+
+// How many times stronger than the typical frequency the strongest has to be to count as banding
+const BANDING_PROMINENCE: f64 = 6.;
+
+/* A ripple down the rows of a scan, the tone each row is off by being
+ * `cos * cos(2πy / period) + sin * sin(2πy / period)` for row y
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Banding {
+    period: f64,
+    cos: f64,
+    sin: f64,
+}
+
+impl Banding {
+    // How far the ripple pushes a row's tones either way at its peak
+    fn amplitude(&self) -> f64 {
+        self.cos.hypot(self.sin)
+    }
+
+    fn offset(&self, y: u32) -> f64 {
+        let phase = std::f64::consts::TAU * y as f64 / self.period;
+        self.cos * phase.cos() + self.sin * phase.sin()
+    }
+}
+
+/* Looks for periodic horizontal banding in `image`, None if there's no ripple that stands out
+ *
+ * The vertical grid lines are a single tone within each row of patches, so down them anything
+ * but that tone is noise or banding. Each line pixel's difference from its stretch of line is
+ * taken, skipping where horizontal lines cross, and averaged across the row. The strongest
+ * frequency in that profile, fit as a sinusoid, is the banding if it stands well clear of the
+ * rest. Periods run from `MIN_BANDING_PERIOD` up to a patch tall, longer than that the stretches
+ * would take the ripple out with them.
+ */
+fn detect_banding(
     image: &ImageBuffer<Luma<u16>, Vec<u16>>,
-    grid_analysis: &GridAnalysis,
     step_description: &StepDescription,
-) -> Vec<u32> {
-    let view = image
-        .view(
-            grid_analysis.origin_x,
-            grid_analysis.origin_y,
-            grid_analysis.square_size * step_description.columns,
-            grid_analysis.square_size * step_description.rows,
-        )
-        .to_image();
+    grid_analysis: &GridAnalysis,
+) -> Option<Banding> {
+    let (width, height) = image.dimensions();
+    let square_size = grid_analysis.square_size.max(1);
+    // the row of patches each image row is in, None outside the wedge
+    let patch_row = |y: u32| {
+        let row = y.checked_sub(grid_analysis.origin_y)? / square_size;
+        (row < step_description.rows).then_some(row)
+    };
+    let mut stretches: HashMap<(u32, u32), (f64, u32)> = HashMap::new();
+    let columns: Vec<u32> = grid_analysis
+        .line_columns
+        .iter()
+        .copied()
+        .filter(|x| *x < width)
+        .collect();
+    let crossing = |y: u32| grid_analysis.line_rows.contains(&y);
+    let line_pixels = || {
+        columns.iter().flat_map(move |x| {
+            (0..height)
+                .filter(move |y| !crossing(*y))
+                .filter_map(move |y| {
+                    let row = patch_row(y)?;
+                    // the line only runs down rows with a patch beside it
+                    let col =
+                        (x.saturating_sub(grid_analysis.origin_x) + square_size / 2) / square_size;
+                    (col <= step_description.row_patches(row)).then_some((*x, y, row))
+                })
+        })
+    };
+    for (x, y, row) in line_pixels() {
+        let stretch = stretches.entry((x, row)).or_default();
+        stretch.0 += image.get_pixel(x, y)[0] as f64;
+        stretch.1 += 1;
+    }
 
-    let mut histogram: Vec<u32> = vec![0; 256];
+    let mut profile = vec![(0., 0); height as usize];
+    for (x, y, row) in line_pixels() {
+        let (total, count) = stretches[&(x, row)];
+        profile[y as usize].0 += image.get_pixel(x, y)[0] as f64 - total / count as f64;
+        profile[y as usize].1 += 1;
+    }
+    let profile: Vec<(f64, f64)> = profile
+        .into_iter()
+        .enumerate()
+        .filter(|(_, (_, count))| *count > 0)
+        .map(|(y, (total, count))| (y as f64, total / count as f64))
+        .collect();
+    if profile.len() < 2 * square_size as usize {
+        return None;
+    }
 
-    for (_, _, p) in view.enumerate_pixels() {
-        let bucket = (p[0] / 256) as usize;
-        histogram[bucket] = histogram[bucket].saturating_add(1)
+    // a sinusoid fit at every frequency from one ripple per patch to one per
+    // `MIN_BANDING_PERIOD` rows, in steps of one ripple across the profile
+    let span = height as f64;
+    let lowest = (span / square_size as f64).ceil() as usize;
+    let highest = (span / MIN_BANDING_PERIOD).floor() as usize;
+    let fits: Vec<Banding> = (lowest.max(1)..=highest)
+        .map(|ripples| {
+            let period = span / ripples as f64;
+            let (cos, sin) = profile.iter().fold((0., 0.), |(cos, sin), (y, value)| {
+                let phase = std::f64::consts::TAU * y / period;
+                (cos + value * phase.cos(), sin + value * phase.sin())
+            });
+            let scale = 2. / profile.len() as f64;
+            Banding {
+                period,
+                cos: cos * scale,
+                sin: sin * scale,
+            }
+        })
+        .collect();
+    let mut amplitudes: Vec<f64> = fits.iter().map(Banding::amplitude).collect();
+    amplitudes.sort_by(f64::total_cmp);
+    let typical = *amplitudes.get(amplitudes.len() / 2)?;
+    let strongest = fits
+        .into_iter()
+        .max_by(|a, b| a.amplitude().total_cmp(&b.amplitude()))?;
+    (strongest.amplitude() > BANDING_PROMINENCE * typical).then_some(strongest)
+}
+
+// Takes `banding` out of every row of `image`
+fn remove_banding(image: &mut ImageBuffer<Luma<u16>, Vec<u16>>, banding: &Banding) {
+    for (y, row) in image.enumerate_rows_mut() {
+        let offset = banding.offset(y);
+        for (_, _, pixel) in row {
+            pixel[0] = (pixel[0] as f64 - offset)
+                .round()
+                .clamp(0., u16::MAX as f64) as u16;
+        }
+    }
+}
+
+// Buckets histograms are drawn with unless asked for another count
+pub const HISTOGRAM_BUCKETS: usize = 256;
+
+// Buckets analyze keeps its histograms at, one for every 16bit tone
+const TONE_BUCKETS: usize = u16::MAX as usize + 1;
+
+/* Share of a histogram piled on its darkest or lightest bucket past which that end is clipping
+ *
+ * A clean wedge already has a tenth or so of its pixels at one end, the grid lines, the empty cells
+ * beside the caption, and the black or white patch itself. Past a fifth, patches are running
+ * together at the end.
+ */
+const CLIPPING_WARNING_SHARE: f64 = 0.2;
+
+// Width in pixels of the red band drawn at a clipped end of a histogram
+const CLIPPING_BAND_WIDTH: u32 = 12;
+
+/* Sums the buckets of `histogram` into `buckets` evenly sized buckets
+ *
+ * Buckets can only be merged, not split, asking for more buckets than `histogram` has leaves
+ * empty buckets between the ones that were filled.
+ */
+pub fn rebucket(histogram: &[u32], buckets: usize) -> Vec<u32> {
+    let mut rebucketed = vec![0u32; buckets];
+    for (i, value) in histogram.iter().enumerate() {
+        if let Some(bucket) = rebucketed.get_mut(i * buckets / histogram.len()) {
+            *bucket = bucket.saturating_add(*value);
+        }
     }
+    rebucketed
+}
+
+/* The bars of `histogram` at `buckets` buckets, as (left, width, height) across a 1024x1024 chart
+ *
+ * The first bucket tends to get filled with stuff like lines and letters, not useful, so it gets
+ * no bar and the rest are scaled against the tallest of them. There are no bars when there's
+ * nothing to scale against, an empty histogram or one that's all zeros (a blank scan).
+ */
+pub fn histogram_bars(histogram: &[u32], buckets: usize) -> Vec<(u32, u32, u32)> {
+    let histogram = rebucket(histogram, buckets);
+    let Some(max) = histogram
+        .iter()
+        .skip(1)
+        .copied()
+        .max()
+        .filter(|max| *max > 0)
+    else {
+        return Vec::new();
+    };
 
     histogram
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter_map(|(bucket, value)| {
+            let height = (((*value as f32) / (max as f32)) * 1024.) as u32;
+            let left = (bucket * 1024 / buckets) as u32;
+            let right = ((bucket + 1) * 1024 / buckets) as u32;
+            // an empty bucket has no bar
+            (height > 0).then_some((left, (right - left).max(1), height))
+        })
+        .collect()
 }
 
-struct GridAnalysis {
-    origin_x: u32,
-    origin_y: u32,
-    square_size: u32,
+/* Whether the darkest and lightest buckets of `histogram` hold more than
+ * `CLIPPING_WARNING_SHARE` of it, tones piling up against the ends of the range
+ *
+ * The first bucket never gets a bar, so without this clipping in the shadows would go unseen.
+ */
+pub fn clipped_ends(histogram: &[u32]) -> (bool, bool) {
+    // a single bucket has no ends to pile up against
+    if histogram.len() < 2 {
+        return (false, false);
+    }
+    let total: u64 = histogram.iter().map(|value| *value as u64).sum();
+    let clipped = |value: Option<&u32>| {
+        total > 0
+            && value.is_some_and(|value| *value as f64 / total as f64 > CLIPPING_WARNING_SHARE)
+    };
+    (clipped(histogram.first()), clipped(histogram.last()))
 }
 
-// Analyzes `image` looking for the grid of squares
-//
-// returns the discovered x,y cordinates of the top left corner of the grid, the observed square
-// size, as well as the lines image used for rendering the results
-//
-// Note: Consider making the lines image a function so we don't have to pre-compute?
-fn analyze_grid(image: &ImageBuffer<Luma<u8>, Vec<u8>>) -> Result<GridAnalysis> {
-    // Find the distance between the first two lines. Use it to find our squares
-    let (width, _) = image.dimensions();
-    let square_size = width / 10;
+/* Draws a histogram at `buckets` buckets ontop of `image`, with a red band down either end that's
+ * clipping, see `clipped_ends`
+ *
+ * expects the image to be 1024x1024
+ */
+pub fn draw_histogram(
+    image: &mut ImageBuffer<image::Rgb<u8>, Vec<u8>>,
+    histogram: &[u32],
+    buckets: usize,
+) -> anyhow::Result<()> {
+    let grey = image::Rgb::<u8>([128, 128, 128]);
+    for (left, width, height) in histogram_bars(histogram, buckets) {
+        let rect = Rect::at(left as i32, (1024 - height) as i32).of_size(width, height);
+        draw_filled_rect_mut(image, rect, grey);
+    }
+
+    let red = image::Rgb::<u8>([220, 0, 0]);
+    let (dark, light) = clipped_ends(histogram);
+    for (clipped, left) in [(dark, 0), (light, 1024 - CLIPPING_BAND_WIDTH)] {
+        if clipped {
+            let band = Rect::at(left as i32, 0).of_size(CLIPPING_BAND_WIDTH, 1024);
+            draw_filled_rect_mut(image, band, red);
+        }
+    }
+    Ok(())
+}
+
+/* Draws a histogram at `buckets` buckets ontop of `image` by adding `color` to what's already
+ * there, so where two histograms overlap their colors mix
+ *
+ * expects the image to be 1024x1024
+ */
+pub fn draw_histogram_additive(
+    image: &mut ImageBuffer<image::Rgb<u8>, Vec<u8>>,
+    histogram: &[u32],
+    buckets: usize,
+    color: image::Rgb<u8>,
+) -> anyhow::Result<()> {
+    for (left, width, height) in histogram_bars(histogram, buckets) {
+        for x in left..left + width {
+            for y in (1024 - height)..1024 {
+                let pixel = image.get_pixel_mut(x, y);
+                for channel in 0..3 {
+                    pixel[channel] = pixel[channel].saturating_add(color[channel]);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/* Draws the red, green, and blue histograms at `buckets` buckets ontop of `image`
+ *
+ * Each channel only lights up its own color component, so where the bars overlap the colors mix
+ * (red and green make yellow, all three make white). A single channel clipping shows up as a
+ * pure colored spike at the edges.
+ *
+ * expects the image to be 1024x1024
+ */
+pub fn draw_histograms_rgb(
+    image: &mut ImageBuffer<image::Rgb<u8>, Vec<u8>>,
+    histograms: &[Vec<u32>; 3],
+    buckets: usize,
+) -> anyhow::Result<()> {
+    for (channel, histogram) in histograms.iter().enumerate() {
+        for (left, width, height) in histogram_bars(histogram, buckets) {
+            for x in left..left + width {
+                for y in (1024 - height)..1024 {
+                    image.get_pixel_mut(x, y)[channel] = 255;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/* Look through the haystack of (input_density, output_density) for the input density with the
+ * output density that most closely matches needle.
+ *
+ * An exact match is unlikely, so instead build a range of values. First searching from the start
+ * forward until finding the first output density larger than needle. Then reversing the search to
+ * find the first output density smaller than needle. Interpolate the two input densities for our
+ * resulting value.
+ *
+ * Issue: If the highs or lows completely or nearly clip then I think we end up with clumbs at the top and
+ * bottom. Need a way to avoid this.
+ *
+ * for example of our distrbution looks like
+ *
+ * > [(0, 0), (5,0), (10,0), (15, 5), (20,10), (25, 15), (30, 20), (35, 20), (40, 20)]
+ *
+ * searching for the output value that match 2 we would look forward and find the first greater
+ * output (5) and record (10,0) as our match. Then looking backward we would find (10,0) as the
+ * first lesser value and record (15,5) as our match.
+ *
+ *
+ */
+fn find_closest_matching_input_density(
+    haystack: &[(u16, u16)],
+    needle: u16,
+) -> anyhow::Result<u16> {
+    let mut lower_bound_density: Option<u16> = None;
+    let mut upper_bound_density: Option<u16> = None;
+
+    // search forward, find the first output_density /greater/ than needle
+    // our lower bound will then be the input density immediately prior
+    for (i, (_, output_density)) in haystack.iter().enumerate() {
+        if *output_density > needle {
+            if i == 0 {
+                lower_bound_density = Some(0);
+            } else {
+                lower_bound_density = Some(haystack[i - 1].0);
+            }
+
+            break;
+        }
+    }
+
+    // search backwards, find the first output_density /lesser/ than needle
+    // our upper bound will then be the input density immediately prior
+    for (i, (_, output_density)) in haystack.iter().rev().enumerate() {
+        if *output_density < needle {
+            if i == 0 {
+                upper_bound_density = Some(u16::MAX);
+            } else {
+                upper_bound_density = Some(haystack[haystack.len() - i].0);
+            }
+
+            break;
+        }
+    }
+
+    let closest = match (lower_bound_density, upper_bound_density) {
+        (None, None) => {
+            return Err(anyhow!("Unable to map tones, value out of range"));
+        }
+        (Some(l), None) => l,
+        (None, Some(u)) => u,
+        (Some(l), Some(u)) => (((l as u32) + (u as u32)) / 2) as u16,
+    };
+
+    Ok(closest)
+}
+
+// simple histogram of the image with `buckets` buckets
+fn create_histogram(
+    image: &ImageBuffer<Luma<u16>, Vec<u16>>,
+    grid_analysis: &GridAnalysis,
+    step_description: &StepDescription,
+    buckets: usize,
+) -> Vec<u32> {
+    let view = image
+        .view(
+            grid_analysis.origin_x,
+            grid_analysis.origin_y,
+            grid_analysis.square_size * step_description.columns,
+            grid_analysis.square_size * step_description.rows,
+        )
+        .to_image();
+
+    let mut histogram: Vec<u32> = vec![0; buckets];
+
+    for (_, _, p) in view.enumerate_pixels() {
+        let bucket = p[0] as usize * buckets / TONE_BUCKETS;
+        histogram[bucket] = histogram[bucket].saturating_add(1)
+    }
+
+    histogram
+}
+
+// simple histograms of each color channel of the image with `buckets` buckets
+//
+// returned in [red, green, blue] order
+fn create_histograms_rgb(
+    image: &ImageBuffer<Rgb<u16>, Vec<u16>>,
+    grid_analysis: &GridAnalysis,
+    step_description: &StepDescription,
+    buckets: usize,
+) -> [Vec<u32>; 3] {
+    let view = image.view(
+        grid_analysis.origin_x,
+        grid_analysis.origin_y,
+        grid_analysis.square_size * step_description.columns,
+        grid_analysis.square_size * step_description.rows,
+    );
+
+    let mut histograms: [Vec<u32>; 3] = [vec![0; buckets], vec![0; buckets], vec![0; buckets]];
+
+    for (_, _, p) in view.pixels() {
+        for (channel, histogram) in histograms.iter_mut().enumerate() {
+            let bucket = p[channel] as usize * buckets / TONE_BUCKETS;
+            histogram[bucket] = histogram[bucket].saturating_add(1)
+        }
+    }
+
+    histograms
+}
+
+// Where the grid of squares sits in a scan, in pixels
+#[derive(Default)]
+pub struct GridAnalysis {
+    // the top left corner of the first square
+    pub origin_x: u32,
+    pub origin_y: u32,
+    pub square_size: u32,
+    // the columns and rows of pixels the grid's lines were found (or assumed) to run along
+    pub line_columns: Vec<u32>,
+    pub line_rows: Vec<u32>,
+}
+
+// How far either side of where an edge of a grid line should be it's looked for, slack for a scan
+// that's a little rotated or not quite a whole number of pixels to a square
+const GRID_EDGE_TOLERANCE: i64 = 2;
+
+/* Analyzes `image` looking for the grid of squares
+ *
+ * returns the discovered x,y cordinates of the top left corner of the grid and the observed square
+ * size, along with the lines it was found by
+ *
+ * Each grid line has an edge along either side, against the patches or the border around the
+ * wedge, so summing the difference between neighboring columns (and rows) of pixels peaks along
+ * every line. The grid is the square size and origin whose lines land on the strongest of those
+ * peaks. Square sizes are tried from the largest that fits the image, a tightly cropped scan, down
+ * to half that for a scan with a border around the wedge. Ties, like an image with no edges at
+ * all, go to the larger squares nearer the top left.
+ */
+fn analyze_grid(
+    image: &ImageBuffer<Luma<u8>, Vec<u8>>,
+    step_description: &StepDescription,
+    thickness: u32,
+) -> Result<GridAnalysis> {
+    let (width, height) = image.dimensions();
+    let (column_edges, row_edges) = edge_profiles(image);
+    let columns = step_description.columns;
+    let rows = step_description.rows;
+
+    let fitted = width / columns;
+    let mut best: Option<(u64, u32, u32)> = None;
+    for size in (fitted / 2..=fitted).rev().filter(|size| *size > 0) {
+        for origin in 0..=width - columns * size {
+            let score = grid_score(&column_edges, origin, size, columns, thickness);
+            if best.is_none_or(|(best, _, _)| score > best) {
+                best = Some((score, size, origin));
+            }
+        }
+    }
+    let (square_size, origin_x) = best.map_or((fitted, 0), |(_, size, origin)| (size, origin));
+
+    let origin_y = (0..=height.saturating_sub(rows * square_size))
+        .rev()
+        .max_by_key(|origin| grid_score(&row_edges, *origin, square_size, rows, thickness))
+        .unwrap_or(0);
+
+    // the lines are assumed to be `thickness` pixels wide along the edge of every square
+    let lines = |origin: u32, count: u32, length: u32| -> Vec<u32> {
+        (0..=count)
+            .flat_map(|n| (0..thickness).map(move |i| origin + n * square_size + i))
+            .filter(|i| *i < length)
+            .collect()
+    };
+
+    Ok(GridAnalysis {
+        origin_x,
+        origin_y,
+        square_size,
+        line_columns: lines(origin_x, columns, width),
+        line_rows: lines(origin_y, rows, height),
+    })
+}
+
+/* The summed difference between each column of pixels and the next, and each row and the next,
+ * peaking along every edge running down or across the image
+ */
+fn edge_profiles(image: &ImageBuffer<Luma<u8>, Vec<u8>>) -> (Vec<u64>, Vec<u64>) {
+    let (width, height) = image.dimensions();
+    let mut columns = vec![0; width.saturating_sub(1) as usize];
+    let mut rows = vec![0; height.saturating_sub(1) as usize];
+    for (x, y, across, down) in pixel_edges(image, 1) {
+        if x + 1 < width {
+            columns[x as usize] += across;
+        }
+        if y + 1 < height {
+            rows[y as usize] += down;
+        }
+    }
+    (columns, rows)
+}
+
+/* Every `stride`th pixel of every `stride`th row of `image` as (x, y, across, down), its difference
+ * from the pixel to its right and the one below it, 0 past the edge of the image
+ */
+fn pixel_edges(
+    image: &ImageBuffer<Luma<u8>, Vec<u8>>,
+    stride: u32,
+) -> impl Iterator<Item = (u32, u32, u64, u64)> + '_ {
+    let (width, height) = image.dimensions();
+    let stride = stride as usize;
+    (0..height).step_by(stride).flat_map(move |y| {
+        (0..width).step_by(stride).map(move |x| {
+            let p = image.get_pixel(x, y)[0];
+            let difference = |x: u32, y: u32| match image.get_pixel_checked(x, y) {
+                Some(q) => p.abs_diff(q[0]) as u64,
+                None => 0,
+            };
+            (x, y, difference(x + 1, y), difference(x, y + 1))
+        })
+    })
+}
+
+/* How strong the edges of `profile` are along both sides of `count + 1` grid lines `thickness`
+ * wide, `size` apart starting at `origin`
+ *
+ * Each edge is the strongest within `GRID_EDGE_TOLERANCE` of where it should be, discounted the
+ * further off it is so the grid lands exactly on the lines when it can.
+ */
+fn grid_score(profile: &[u64], origin: u32, size: u32, count: u32, thickness: u32) -> u64 {
+    (0..=count)
+        .map(|n| (origin + n * size) as i64)
+        // profile[i] is the edge between pixels i and i + 1
+        .flat_map(|line| [line - 1, line + thickness as i64 - 1])
+        .map(|edge| {
+            (-GRID_EDGE_TOLERANCE..=GRID_EDGE_TOLERANCE)
+                .filter_map(|offset| {
+                    let strength = profile.get(usize::try_from(edge + offset).ok()?)?;
+                    let weight = (GRID_EDGE_TOLERANCE + 1 - offset.abs()) as u64;
+                    Some(strength * weight)
+                })
+                .max()
+                .unwrap_or(0)
+        })
+        .sum()
+}
+
+// Steps the skew is searched in, coarsely over the whole range then finely around the best
+const SKEW_COARSE_STEP: f64 = 0.5;
+const SKEW_FINE_STEP: f64 = 0.05;
+
+// Differences between neighboring pixels weaker than this are grain rather than part of a line
+const SKEW_EDGE_THRESHOLD: u64 = 16;
+
+// Roughly how many pixels across the skew is measured from, larger scans are sampled more sparsely
+const SKEW_SAMPLE_SIZE: u32 = 1000;
+
+/* `image` turned straight when it was scanned a little rotated, None when it's already straight
+ * or `AnalyzeParams::deskew` wasn't asked for
+ *
+ * The grid's lines should run straight down and across the scan, errors if they're turned more
+ * than `max_skew_degrees` from that.
+ */
+fn deskew(
+    image: &DynamicImage,
+    params: &AnalyzeParams,
+    debug: bool,
+) -> Result<Option<DynamicImage>> {
+    if !params.deskew {
+        return Ok(None);
+    }
+    let image_8 = image.to_luma8();
+    let skew = skew_degrees(&image_8);
+    if debug {
+        println!("skew: {:.2} degrees", skew);
+    }
+    if skew.abs() > params.max_skew_degrees {
+        return Err(anyhow!(
+            "the scan is rotated {:.1} degrees, more than --max-skew-degrees {} allows, straighten \
+             it and scan again",
+            skew,
+            params.max_skew_degrees
+        ));
+    }
+    if skew == 0. {
+        return Ok(None);
+    }
+
+    // the corners the turn brings into the image are filled with the tone around its edge, or left
+    // transparent so --alpha-mask skips them
+    let tone = edge_tone(&image_8);
+    let theta = -skew.to_radians() as f32;
+    let color = image.color();
+    Ok(Some(match (color.has_color(), color.has_alpha()) {
+        (false, false) => DynamicImage::ImageLuma16(rotate_about_center(
+            &image.to_luma16(),
+            theta,
+            RotateInterpolation::Bilinear,
+            Luma([tone]),
+        )),
+        (false, true) => DynamicImage::ImageLumaA16(rotate_about_center(
+            &image.to_luma_alpha16(),
+            theta,
+            RotateInterpolation::Bilinear,
+            LumaA([tone, 0]),
+        )),
+        (true, false) => DynamicImage::ImageRgb16(rotate_about_center(
+            &image.to_rgb16(),
+            theta,
+            RotateInterpolation::Bilinear,
+            Rgb([tone; 3]),
+        )),
+        (true, true) => DynamicImage::ImageRgba16(rotate_about_center(
+            &image.to_rgba16(),
+            theta,
+            RotateInterpolation::Bilinear,
+            Rgba([tone, tone, tone, 0]),
+        )),
+    }))
+}
+
+// The mean 16bit tone of the pixels around the edge of `image`
+fn edge_tone(image: &ImageBuffer<Luma<u8>, Vec<u8>>) -> u16 {
+    let (width, height) = image.dimensions();
+    let edge: Vec<u64> = image
+        .enumerate_pixels()
+        .filter(|(x, y, _)| *x == 0 || *y == 0 || *x + 1 == width || *y + 1 == height)
+        .map(|(_, _, p)| p[0] as u64)
+        .collect();
+    let mean = edge.iter().sum::<u64>() / (edge.len() as u64).max(1);
+    mean as u16 * 257
+}
+
+/* How far clockwise in degrees the lines of `image` are turned from running straight down and
+ * across it
+ *
+ * Finds the lines by the same edges as `analyze_grid`. Each edge is projected along the angle being
+ * tried, edges between pixels side by side onto the x axis and those between pixels one above the
+ * other onto the y axis, and the angle that piles them up most sharply is the one the lines run
+ * at. Ties, like an image with no edges at all, go to the smallest turn.
+ */
+fn skew_degrees(image: &ImageBuffer<Luma<u8>, Vec<u8>>) -> f64 {
+    let (width, height) = image.dimensions();
+    let stride = (width.max(height) / SKEW_SAMPLE_SIZE).max(1);
+    let (mut across, mut down) = (Vec::new(), Vec::new());
+    for (x, y, across_edge, down_edge) in pixel_edges(image, stride) {
+        if across_edge >= SKEW_EDGE_THRESHOLD {
+            across.push((x as f64, y as f64, across_edge as f64));
+        }
+        if down_edge >= SKEW_EDGE_THRESHOLD {
+            down.push((x as f64, y as f64, down_edge as f64));
+        }
+    }
+
+    // the projection of an edge lands anywhere from a whole height before the image to one after
+    let bins = (width + 2 * height).max(2 * width + height) as usize + 1;
+    let score = |degrees: f64| {
+        let slope = degrees.to_radians().tan();
+        let mut columns = vec![0.; bins];
+        let mut rows = vec![0.; bins];
+        let offset = width.max(height) as f64;
+        for (x, y, strength) in &across {
+            columns[(x + y * slope + offset).round() as usize] += strength;
+        }
+        for (x, y, strength) in &down {
+            rows[(y - x * slope + offset).round() as usize] += strength;
+        }
+        columns
+            .iter()
+            .chain(&rows)
+            .map(|sum| sum * sum)
+            .sum::<f64>()
+    };
+    let best = |candidates: Vec<f64>| {
+        let mut best = (0f64, f64::MIN);
+        for degrees in candidates {
+            let score = score(degrees);
+            if score > best.1 || (score == best.1 && degrees.abs() < best.0.abs()) {
+                best = (degrees, score);
+            }
+        }
+        best.0
+    };
+
+    // counted in fine steps so the straight angle is tried exactly
+    let limit = (SKEW_SEARCH_DEGREES / SKEW_FINE_STEP).round() as i32;
+    let coarse_step = (SKEW_COARSE_STEP / SKEW_FINE_STEP).round() as i32;
+    let coarse = best(
+        (-limit..=limit)
+            .step_by(coarse_step as usize)
+            .map(|n| n as f64 * SKEW_FINE_STEP)
+            .collect(),
+    );
+    let coarse = (coarse / SKEW_FINE_STEP).round() as i32;
+    best(
+        (coarse - coarse_step..=coarse + coarse_step)
+            .filter(|n| n.abs() <= limit)
+            .map(|n| n as f64 * SKEW_FINE_STEP)
+            .collect(),
+    )
+}
+
+/* Fails unless every square of the grid that was found lies inside `image` and is big enough to
+ * sample
+ *
+ * Catches a thumbnail or the wrong file before sampling would read outside the image or divide by
+ * an empty square.
+ */
+fn check_geometry(
+    image: &ImageBuffer<Luma<u16>, Vec<u16>>,
+    step_description: &StepDescription,
+    grid_analysis: &GridAnalysis,
+) -> Result<()> {
+    let (width, height) = image.dimensions();
+    if grid_analysis.square_size == 0 {
+        return Err(anyhow!(
+            "image too small for a {}-column wedge, it's only {}x{} pixels",
+            step_description.columns,
+            width,
+            height
+        ));
+    }
+
+    let grid_width = grid_analysis.square_size as u64 * step_description.columns as u64;
+    let grid_height = grid_analysis.square_size as u64 * step_description.rows as u64;
+    if grid_analysis.origin_x as u64 + grid_width > width as u64
+        || grid_analysis.origin_y as u64 + grid_height > height as u64
+    {
+        return Err(anyhow!(
+            "a {}x{} grid of {} pixel squares doesn't fit in the {}x{} image, check --steps, \
+             --columns, and --rows match the wedge",
+            step_description.columns,
+            step_description.rows,
+            grid_analysis.square_size,
+            width,
+            height
+        ));
+    }
+    Ok(())
+}
+
+/* Finds the grid of a wedge generated with the separators layout
+ *
+ * Every patch boundary is a thin line made of alternating pure black and pure white runs. So we
+ * walk each column (and each row) of the image and look for lines that are mostly extreme tones
+ * with a healthy amount of /both/ extremes. That rules out the patches themselves, which are only
+ * extreme at the very ends of the ramp, as well as the flat margins and paper around the wedge.
+ *
+ * The origin is the first separator found on each axis and the square size is the typical
+ * distance between separators.
+ */
+fn analyze_separators(image: &ImageBuffer<Luma<u8>, Vec<u8>>) -> Result<GridAnalysis> {
+    let (width, height) = image.dimensions();
+
+    let (min, max) = image.pixels().fold((u8::MAX, u8::MIN), |(min, max), p| {
+        (min.min(p[0]), max.max(p[0]))
+    });
+    if max <= min {
+        return Err(anyhow!(
+            "Unable to find separators, the image is a single tone"
+        ));
+    }
+
+    // anything within 10% of the observed extremes counts as a separator tone
+    let tolerance = (max - min) / 10;
+    let black_threshold = min.saturating_add(tolerance);
+    let white_threshold = max.saturating_sub(tolerance);
+
+    let mut columns = vec![SeparatorProfile::default(); width as usize];
+    let mut rows = vec![SeparatorProfile::default(); height as usize];
+    for (x, y, p) in image.enumerate_pixels() {
+        if p[0] <= black_threshold {
+            columns[x as usize].black += 1;
+            rows[y as usize].black += 1;
+        } else if p[0] >= white_threshold {
+            columns[x as usize].white += 1;
+            rows[y as usize].white += 1;
+        }
+    }
+
+    grid_from_lines(
+        separator_lines(&columns, height),
+        separator_lines(&rows, width),
+    )
+    .ok_or(anyhow!("Unable to find separators in the image"))
+}
+
+// Within this much of the configured tone (out of 255) a pixel counts as part of a grid line
+const GRID_TONE_TOLERANCE: u8 = 5;
+
+// At least this share of a column or row has to be the grid's tone for it to be a line
+const GRID_LINE_COVERAGE: f64 = 0.8;
+
+/* Finds the lines of a grid generated with every line in `tone`
+ *
+ * The wedge is generated as a negative, so in a scan of the print every tone is flipped, lines
+ * included. Scans that are inverted for analysis are read like the negative, with lines in
+ * `tone`, and the rest like the print. Whichever polarity `invert` expects is looked for first,
+ * then the other, so a scan analyzed with the wrong setting still has its grid found.
+ */
+fn find_grid_lines(
+    image: &ImageBuffer<Luma<u8>, Vec<u8>>,
+    tone: u16,
+    invert: bool,
+) -> Result<GridAnalysis> {
+    let expected = if invert { tone } else { u16::MAX - tone };
+    lines_of_tone(image, expected)
+        .or_else(|| lines_of_tone(image, u16::MAX - expected))
+        .ok_or(anyhow!(
+            "Unable to find grid lines of tone {} in the image",
+            tone
+        ))
+}
+
+/* The grid whose lines are drawn in `tone`, when there is one
+ *
+ * A column (or row) of pixels running along a line is almost all that tone, while one running
+ * through the patches crosses a different tone in every patch so only a few of them can match.
+ * The tolerance is kept tight so a row of patches close to the grid's tone doesn't pass as a line.
+ */
+fn lines_of_tone(image: &ImageBuffer<Luma<u8>, Vec<u8>>, tone: u16) -> Option<GridAnalysis> {
+    let (width, height) = image.dimensions();
+    let target = (tone as u32 * u8::MAX as u32 / u16::MAX as u32) as u8;
+
+    let mut columns = vec![0; width as usize];
+    let mut rows = vec![0; height as usize];
+    for (x, y, p) in image.enumerate_pixels() {
+        if p[0].abs_diff(target) <= GRID_TONE_TOLERANCE {
+            columns[x as usize] += 1;
+            rows[y as usize] += 1;
+        }
+    }
+
+    let lines = |counts: &[u32], length: u32| -> Vec<u32> {
+        (0..counts.len() as u32)
+            .filter(|i| counts[*i as usize] as f64 >= length as f64 * GRID_LINE_COVERAGE)
+            .collect()
+    };
+    grid_from_lines(lines(&columns, height), lines(&rows, width))
+}
+
+/* The grid described by the columns and rows of pixels its lines run along
+ *
+ * The origin is the first line found on each axis and the square size is the typical distance
+ * between lines. None without at least one line on each axis and two on one of them.
+ */
+fn grid_from_lines(line_columns: Vec<u32>, line_rows: Vec<u32>) -> Option<GridAnalysis> {
+    let vertical_lines = run_starts(&line_columns);
+    let horizontal_lines = run_starts(&line_rows);
+
+    let mut spacings: Vec<u32> = vertical_lines
+        .windows(2)
+        .chain(horizontal_lines.windows(2))
+        .map(|w| w[1] - w[0])
+        .collect();
+    spacings.sort();
+
+    Some(GridAnalysis {
+        origin_x: *vertical_lines.first()?,
+        origin_y: *horizontal_lines.first()?,
+        square_size: *spacings.get(spacings.len() / 2)?,
+        line_columns,
+        line_rows,
+    })
+}
+
+#[derive(Clone, Default)]
+struct SeparatorProfile {
+    black: u32,
+    white: u32,
+}
+
+// returns the index of every column (or row) in `profiles` that looks like part of a separator
+fn separator_lines(profiles: &[SeparatorProfile], length: u32) -> Vec<u32> {
+    let is_separator = |p: &SeparatorProfile| {
+        p.black * 5 >= length && p.white * 5 >= length && (p.black + p.white) * 5 >= length * 3
+    };
+
+    profiles
+        .iter()
+        .enumerate()
+        .filter(|(_, profile)| is_separator(profile))
+        .map(|(i, _)| i as u32)
+        .collect()
+}
+
+// returns the index where each run of consecutive `lines` begins
+fn run_starts(lines: &[u32]) -> Vec<u32> {
+    lines
+        .iter()
+        .enumerate()
+        .filter(|(n, line)| *n == 0 || lines[n - 1] + 1 != **line)
+        .map(|(_, line)| *line)
+        .collect()
+}
+
+struct Samples {
+    values: Vec<u16>,
+    // standard deviation of the pixels behind each value
+    deviations: Vec<f64>,
+    // number of valid pixels behind each value, see `sampled_pixels`
+    counts: Vec<u64>,
+    // whether each value's pixels are mostly pinned to either end of the range, see `is_clipped`
+    clipped: Vec<bool>,
+    min: u16,
+    max: u16,
+}
+
+fn collect_samples(
+    image: &ImageBuffer<Luma<u16>, Vec<u16>>,
+    rects: &[Rect],
+    subsample_factor: u32,
+    strategy: SampleStrategy,
+    mask: &SampleMask,
+    cancel: &Cancel,
+) -> Result<Samples> {
+    let mut values: Vec<u16> = vec![0; rects.len()];
+    let mut deviations: Vec<f64> = vec![0.; rects.len()];
+    let mut counts: Vec<u64> = vec![0; rects.len()];
+    let mut clipped: Vec<bool> = vec![false; rects.len()];
+
+    for (i, r) in rects.iter().enumerate() {
+        cancel.check()?;
+        let r = clamp_to_image(r, image.dimensions()).ok_or_else(|| {
+            anyhow!(
+                "step {} lies outside the {}x{} image, check the wedge's geometry",
+                i + 1,
+                image.width(),
+                image.height()
+            )
+        })?;
+        let view = image.view(r.left() as u32, r.top() as u32, r.width(), r.height());
+        let (pixels, count) = sampled_pixels(view, subsample_factor, mask);
+        let sample = strategy.sample(&pixels);
+
+        values[i] = sample;
+        deviations[i] = sampled_deviation(&pixels, sample);
+        counts[i] = count;
+        clipped[i] = is_clipped(&pixels);
+    }
+
+    Ok(Samples {
+        min: values.iter().copied().min().unwrap_or(u16::MAX),
+        max: values.iter().copied().max().unwrap_or(0),
+        values,
+        deviations,
+        counts,
+        clipped,
+    })
+}
+
+// Whether more than `CLIPPED_PATCH_SHARE` of a patch's `pixels` sit at black or white, where the
+// scanner ran out of range and every tone past it reads the same
+fn is_clipped(pixels: &[u16]) -> bool {
+    let pinned = pixels
+        .iter()
+        .filter(|p| **p == 0 || **p == u16::MAX)
+        .count();
+    !pixels.is_empty() && pinned as f64 / pixels.len() as f64 > CLIPPED_PATCH_SHARE
+}
+
+/* The part of `rect` within an image of `dimensions`, or None when none of it is
+ *
+ * A grid detected slightly off, or nudged by hand, can leave the patches along the edges hanging
+ * past the edge of the scan. Those are sampled from what's left of them.
+ */
+fn clamp_to_image(rect: &Rect, dimensions: (u32, u32)) -> Option<Rect> {
+    let (width, height) = dimensions;
+    if width == 0 || height == 0 {
+        return None;
+    }
+    rect.intersect(Rect::at(0, 0).of_size(width, height))
+}
+
+/* Weighs each sample by how many valid pixels went into it, see `AnalyzeParams::weight_by_area`
+ *
+ * The samples with the most valid pixels are trusted as they are. Any other is blended with the
+ * line through its neighbors on either side (the two on one side for the ends), in proportion to
+ * the share of pixels it's missing. A sample with no valid pixels at all is replaced outright.
+ * When every patch had the same area nothing changes.
+ */
+fn weight_by_area(samples: &mut Samples) {
+    let most = samples.counts.iter().copied().max().unwrap_or(0);
+    let values: Vec<f64> = samples.values.iter().map(|v| *v as f64).collect();
+    if most == 0 || values.len() < 3 {
+        return;
+    }
+
+    let last = values.len() - 1;
+    for (i, count) in samples.counts.iter().enumerate() {
+        let predicted = match i {
+            0 => 2. * values[1] - values[2],
+            i if i == last => 2. * values[last - 1] - values[last - 2],
+            i => (values[i - 1] + values[i + 1]) / 2.,
+        };
+        let weight = *count as f64 / most as f64;
+        let weighted = weight * values[i] + (1. - weight) * predicted;
+        samples.values[i] = weighted.round().clamp(0., u16::MAX as f64) as u16;
+    }
+    samples.min = samples.values.iter().copied().min().unwrap_or(u16::MAX);
+    samples.max = samples.values.iter().copied().max().unwrap_or(0);
+}
+
+// Share of the sampled pixels left out at either end of the range by `AnalyzeParams::robust`
+const ROBUST_PERCENTILE: f64 = 0.01;
+
+/* The scan values to stretch out to black and white, as (black, white)
+ *
+ * Explicit black and white points win, then the robust percentiles, and otherwise the darkest and
+ * lightest sample.
+ */
+fn reference_points(
+    image: &ImageBuffer<Luma<u16>, Vec<u16>>,
+    rects: &[Rect],
+    samples: &Samples,
+    params: &AnalyzeParams,
+) -> Result<(u16, u16)> {
+    let (measured_black, measured_white) = if params.robust {
+        percentile_range(image, rects, params.subsample_factor, ROBUST_PERCENTILE)
+    } else {
+        (samples.min, samples.max)
+    };
+    let black = params.black_point.unwrap_or(measured_black);
+    let white = params.white_point.unwrap_or(measured_white);
+    if black >= white {
+        return Err(anyhow!(
+            "black point ({}) must be below white point ({})",
+            black,
+            white
+        ));
+    }
+    Ok((black, white))
+}
+
+/* The values `percentile` of the way in from either end of every pixel sampled from `rects`
+ *
+ * A handful of stray pixels fall outside the range rather than setting it.
+ */
+fn percentile_range(
+    image: &ImageBuffer<Luma<u16>, Vec<u16>>,
+    rects: &[Rect],
+    subsample_factor: u32,
+    percentile: f64,
+) -> (u16, u16) {
+    let subsample_factor = subsample_factor.max(1);
+    let mut pixels: Vec<u16> = rects
+        .iter()
+        .filter_map(|r| clamp_to_image(r, image.dimensions()))
+        .flat_map(|r| {
+            let view = image.view(r.left() as u32, r.top() as u32, r.width(), r.height());
+            view.pixels()
+                .filter(|(x, y, _)| x % subsample_factor == 0 && y % subsample_factor == 0)
+                .map(|(_, _, p)| p[0])
+                .collect::<Vec<u16>>()
+        })
+        .collect();
+    if pixels.is_empty() {
+        return (0, u16::MAX);
+    }
+    pixels.sort_unstable();
+    let last = pixels.len() - 1;
+    let low = (last as f64 * percentile).round() as usize;
+    let high = (last as f64 * (1. - percentile)).round() as usize;
+    (pixels[low], pixels[high])
+}
+
+struct NormalizedResults {
+    image: ImageBuffer<Luma<u16>, Vec<u16>>,
+    samples: Vec<u16>,
+    deviations: Vec<f64>,
+    normalization: Normalization,
+}
+
+// Now normalize the samples based on the black and white points, usually the minimum and maximum
+// values. We expect for the white point to be greater than zero and the black point
+// to to less than u16::max. Assuming the print was printed to d-max then
+// we want to distribute our observed values evenly between the two
+// before determining curve adjustments. Values past either point are clamped.
+fn normalize_image(
+    step_description: &StepDescription,
+    image: &ImageBuffer<Luma<u16>, Vec<u16>>,
+    samples: &Samples,
+    (black, white): (u16, u16),
+    invert_image: bool,
+) -> NormalizedResults {
+    /* example
+     *
+     * Suppose we have:
+     *  - a full range from [0, 65535] inclusive
+     *  - a subset in the range [256, 25280]
+     *
+     * If we want to expand the subset to fill the full range we can:
+     * subtract the minimum value from all the values in the subset to
+     * bring the minimum value to 0;
+     *
+     * subset - 256 => [0, 65024]
+     *
+     * Then we need to expand those values to fill up to 65535 by multiplying them
+     * by 65535 / our new max (65024)
+     */
+    let normalize_factor = (step_description.max_tone as f32) / ((white - black) as f32);
+
+    // float to int casts saturate, clamping anything past the white point
+    let mut normalized_samples: Vec<u16> = samples
+        .values
+        .iter()
+        .map(|s| (s.saturating_sub(black) as f32 * normalize_factor) as u16)
+        .collect();
+
+    let mut normalized_deviations: Vec<f64> = samples
+        .deviations
+        .iter()
+        .map(|d| d * normalize_factor as f64)
+        .collect();
+
+    // this is dumb but I've changed how I want the order to work
+    // it used to be black to white, this is white to black
+    if !invert_image {
+        normalized_samples.reverse();
+        normalized_deviations.reverse();
+    }
+
+    let normalized_image = map_pixels(image, |_, _, p| {
+        let new_v = p[0].saturating_sub(black);
+        Luma([(new_v as f32 * normalize_factor) as u16])
+    });
+
+    NormalizedResults {
+        image: normalized_image,
+        samples: normalized_samples,
+        deviations: normalized_deviations,
+        normalization: Normalization {
+            sample_min: samples.min,
+            sample_max: samples.max,
+            black,
+            white,
+            factor: normalize_factor,
+            inverted: invert_image,
+        },
+    }
+}
+
+/* Use our own observed values to find where we should place
+ * our points to curve with
+ *
+ * As an example let's assume we have a table like below:
+ *
+ * ---
+ * i    exp     norm
+ * ---
+ * 1    0       0
+ * 2    655     648
+ * ...
+ * 15   6550    5000
+ * ...
+ * 20   9825    6576
+ * ...
+ * 101  65500   64800
+ * ---
+ *
+ * Using this we can figure out how to push our densities around to get a linear relationship.
+ *
+ * like take the 15th step. In a linear relationshop the input density of 6550 would be
+ * perfectly reflected in the observed density 6550 -> 6550. But in our real world test it
+ * didn't.
+ *
+ * So instead we want to search through our observed densities and figure out what input
+ * density did create an ouput of 6550. Looking we can see step 20 was close.
+ *
+ * So when mapping our values when we render a new 15 we want the density to be 9825 so that it
+ * achieve an output density close to 6550.
+ *
+ * We'll find our value by finding the largest input density that is still less than our
+ * target, and the least input density that is still greater than our density. We'll then use
+ * the midpoint.
+ *
+ * With a `target` response the output each input should achieve is read off the target rather
+ * than being the input itself, so the correction produces the target instead of a straight line.
+ */
+fn linearize_inputs(
+    input_values: &[u16],
+    response: &[(u16, u16)],
+    target: Option<&Spline<f64, f64>>,
+) -> Result<Vec<(u16, u16)>> {
+    // without a target assume a linear relationship, so every value of
+    // expected on the x axis should be expected on the y axis. Our observed
+    // values will be different. The curve is the delta.
+    input_values
+        .iter()
+        .copied()
+        .map(|e| {
+            let wanted = match target {
+                Some(target) => sample_curve(target, e as f64)?
+                    .round()
+                    .clamp(0., u16::MAX as f64) as u16,
+                None => e,
+            };
+            find_closest_matching_input_density(response, wanted).map(|c| (e, c))
+        })
+        .collect()
+}
+
+/* Samples the tone of each patch of a wedge straight out of `generate` (not a scan of one), where
+ * the grid sits exactly at the generated margin.
+ */
+pub fn sample_generated(
+    image: &DynamicImage,
+    step_description: &StepDescription,
+) -> Result<Vec<u16>> {
+    let grid_analysis = GridAnalysis {
+        origin_x: generate::MARGIN,
+        origin_y: generate::MARGIN,
+        square_size: step_description.square_size,
+        ..Default::default()
+    };
+    let rects = sampled_areas(step_description, &grid_analysis);
+    // nothing can cancel the default token
+    collect_samples(
+        &image.to_luma16(),
+        &rects,
+        1,
+        SampleStrategy::default(),
+        &SampleMask::default(),
+        &Cancel::default(),
+    )
+    .map(|samples| samples.values)
+}
+
+// extra pixels kept between the sampled area and the step number, scanned numbers bleed a little
+const LABEL_PADDING: u32 = 2;
+
+/* Where to sample within a single square of `square_size` pixels, relative to its top left corner
+ *
+ * Keeps a 10% margin from the edges of the square to avoid any malarky with the grid lines and
+ * the blur at the edges, and stays clear of the step number drawn in the top left corner, sized
+ * from the font metrics and scaled to the scan. What's left is L shaped, so we take the larger of
+ * the strip below the number and the strip beside it.
+ */
+fn sample_window(step_description: &StepDescription, square_size: u32) -> Rect {
+    let scale = square_size as f32 / step_description.square_size as f32;
+    let (label_width, label_height) = generate::label_extent(step_description);
+    let edge = (square_size as f32 * 0.1).ceil() as u32;
+    let far = square_size.saturating_sub(edge);
+
+    let label_width = (((label_width + LABEL_PADDING) as f32 * scale).ceil() as u32).max(edge);
+    let label_height = (((label_height + LABEL_PADDING) as f32 * scale).ceil() as u32).max(edge);
+
+    let below = (
+        edge,
+        label_height,
+        far.saturating_sub(edge),
+        far.saturating_sub(label_height),
+    );
+    let beside = (
+        label_width,
+        edge,
+        far.saturating_sub(label_width),
+        far.saturating_sub(edge),
+    );
+    let (x, y, width, height) = if below.2 * below.3 >= beside.2 * beside.3 {
+        below
+    } else {
+        beside
+    };
+    Rect::at(x as i32, y as i32).of_size(width.max(1), height.max(1))
+}
+
+// The whole square of every patch found in the scan, in patch order
+fn patch_areas(step_description: &StepDescription, grid_analysis: &GridAnalysis) -> Vec<Rect> {
+    (0..step_description.count)
+        .map(|n| {
+            let (col, row) = step_description.cell(n);
+            let x = grid_analysis.origin_x + col * grid_analysis.square_size;
+            let y = grid_analysis.origin_y + row * grid_analysis.square_size;
+            Rect::at(x as i32, y as i32)
+                .of_size(grid_analysis.square_size, grid_analysis.square_size)
+        })
+        .collect()
+}
+
+// The window of each patch that gets sampled, one per patch so a partly filled last row only
+// samples the cells holding patches
+fn sampled_areas(step_description: &StepDescription, grid_analysis: &GridAnalysis) -> Vec<Rect> {
+    sample_windows(
+        step_description,
+        &patch_areas(step_description, grid_analysis),
+    )
+}
+
+// The window of each of `patches` that gets sampled, sized to fit the smaller side of each
+fn sample_windows(step_description: &StepDescription, patches: &[Rect]) -> Vec<Rect> {
+    patches
+        .iter()
+        .map(|patch| {
+            let window = sample_window(step_description, patch.width().min(patch.height()));
+            Rect::at(patch.left() + window.left(), patch.top() + window.top())
+                .of_size(window.width(), window.height())
+        })
+        .collect()
+}
+
+// The least difference between a corner dot's core and ring, in 8bit tones, to count as a dot
+const MIN_CORNER_DOT_CONTRAST: f64 = 64.;
+
+/* The pixels of a corner dot scaled to a scan, relative to its center, and how far from where
+ * the grid puts each corner to look for it
+ *
+ * The ring leaves a pixel's gap around the core, where the scan blurs the two together.
+ */
+struct DotTemplate {
+    core: Vec<(i64, i64)>,
+    ring: Vec<(i64, i64)>,
+    reach: i64,
+    // the spacing of the first, coarse search, refined to the pixel afterwards
+    stride: i64,
+}
+
+impl DotTemplate {
+    fn new(scale: f64) -> Self {
+        let core = (generate::CORNER_DOT_CORE as f64 * scale).max(1.);
+        let radius = (generate::CORNER_DOT_RADIUS as f64 * scale).max(core + 2.);
+        let extent = radius.ceil() as i64;
+        let offsets = (-extent..=extent).flat_map(|y| (-extent..=extent).map(move |x| (x, y)));
+        let distance = |(x, y): &(i64, i64)| ((x * x + y * y) as f64).sqrt();
+        Self {
+            core: offsets
+                .clone()
+                .filter(|offset| distance(offset) <= core)
+                .collect(),
+            ring: offsets
+                .filter(|offset| (core + 1. ..=radius).contains(&distance(offset)))
+                .collect(),
+            reach: (2. * radius).ceil() as i64,
+            stride: (core / 2.).floor().max(1.) as i64,
+        }
+    }
+
+    /* How much a dot centered at (x, y) looks like one, the difference between its core and ring
+     * less how uneven each is, or None with too little of it in the image
+     *
+     * Either polarity counts, a print of a negative flips the dots along with everything else.
+     */
+    fn score(&self, image: &ImageBuffer<Luma<u8>, Vec<u8>>, x: i64, y: i64) -> Option<f64> {
+        let stats = |offsets: &[(i64, i64)]| {
+            let values: Vec<f64> = offsets
+                .iter()
+                .map(|(dx, dy)| (x + dx, y + dy))
+                .filter(|(x, y)| {
+                    (0..image.width() as i64).contains(x) && (0..image.height() as i64).contains(y)
+                })
+                .map(|(x, y)| image.get_pixel(x as u32, y as u32)[0] as f64)
+                .collect();
+            // a dot cut off by the edge of the scan still shows a quarter of itself
+            if values.len() * 4 < offsets.len() {
+                return None;
+            }
+            let mean = values.iter().sum::<f64>() / values.len() as f64;
+            let variance =
+                values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64;
+            Some((mean, variance.sqrt()))
+        };
+        let (core_mean, core_deviation) = stats(&self.core)?;
+        let (ring_mean, ring_deviation) = stats(&self.ring)?;
+        Some((ring_mean - core_mean).abs() - core_deviation - ring_deviation)
+    }
+
+    // The center of the dot nearest (x, y), searched coarsely then to the pixel
+    fn locate(&self, image: &ImageBuffer<Luma<u8>, Vec<u8>>, x: i64, y: i64) -> Option<(i64, i64)> {
+        let best = |candidates: Vec<(i64, i64)>| {
+            candidates
+                .into_iter()
+                .filter_map(|(x, y)| Some((self.score(image, x, y)?, (x, y))))
+                .max_by(|a, b| a.0.total_cmp(&b.0))
+        };
+        let around = |(x, y): (i64, i64), reach: i64, stride: usize| -> Vec<(i64, i64)> {
+            (-reach..=reach)
+                .step_by(stride)
+                .flat_map(|dy| {
+                    (-reach..=reach)
+                        .step_by(stride)
+                        .map(move |dx| (x + dx, y + dy))
+                })
+                .collect()
+        };
+        let (_, coarse) = best(around((x, y), self.reach, self.stride as usize))?;
+        let (score, center) = best(around(coarse, self.stride, 1))?;
+        (score >= MIN_CORNER_DOT_CONTRAST).then_some(center)
+    }
+}
+
+/* Every patch found by the dots `generate --corner-dots` draws at its corners, in patch order
+ *
+ * Each dot is looked for near where `grid_analysis` puts its corner, so the grid only has to be
+ * roughly right. A patch is the largest rectangle within its four dots, so a warped or skewed
+ * scan samples each patch where it actually lies.
+ */
+fn dotted_patch_areas(
+    image: &ImageBuffer<Luma<u8>, Vec<u8>>,
+    step_description: &StepDescription,
+    grid_analysis: &GridAnalysis,
+) -> Result<Vec<Rect>> {
+    let template =
+        DotTemplate::new(grid_analysis.square_size as f64 / step_description.square_size as f64);
+    let mut dots: HashMap<(u32, u32), (i64, i64)> = HashMap::new();
+    let mut dot = |corner: (u32, u32)| -> Result<(i64, i64)> {
+        if let Some(center) = dots.get(&corner) {
+            return Ok(*center);
+        }
+        let (col, row) = corner;
+        let x = (grid_analysis.origin_x + col * grid_analysis.square_size) as i64;
+        let y = (grid_analysis.origin_y + row * grid_analysis.square_size) as i64;
+        let center = template.locate(image, x, y).ok_or_else(|| {
+            anyhow!(
+                "found no corner dot near ({}, {}), was the wedge generated with --corner-dots?",
+                x,
+                y
+            )
+        })?;
+        dots.insert(corner, center);
+        Ok(center)
+    };
+
+    (0..step_description.count)
+        .map(|n| {
+            let [top_left, top_right, bottom_left, bottom_right] =
+                step_description.patch_corners(n);
+            let (top_left, top_right) = (dot(top_left)?, dot(top_right)?);
+            let (bottom_left, bottom_right) = (dot(bottom_left)?, dot(bottom_right)?);
+            let left = top_left.0.max(bottom_left.0);
+            let right = top_right.0.min(bottom_right.0);
+            let top = top_left.1.max(top_right.1);
+            let bottom = bottom_left.1.min(bottom_right.1);
+            if right <= left || bottom <= top {
+                return Err(anyhow!("the corner dots of step {} cross over", n + 1));
+            }
+            Ok(Rect::at(left as i32, top as i32)
+                .of_size((right - left) as u32, (bottom - top) as u32))
+        })
+        .collect()
+}
+
+/* The standard wedge drawn with `generate_params` and cropped to its steps like a scan of it, and
+ * the params that analyze it, for the tests that need a wedge to measure
+ */
+#[cfg(test)]
+pub fn test_wedge_with(
+    generate_params: &generate::GenerateParams,
+) -> (DynamicImage, AnalyzeParams) {
+    let step_description = StepDescription::default();
+    let wedge = generate::generate_with_params(None, None, Layout::Grid, generate_params)
+        .unwrap()
+        .crop_imm(
+            generate::MARGIN,
+            generate::MARGIN,
+            step_description.width,
+            step_description.total_height(),
+        );
+    let params = AnalyzeParams {
+        invert: true,
+        ..AnalyzeParams::default()
+    };
+    (wedge, params)
+}
+
+#[cfg(test)]
+pub fn test_wedge() -> (DynamicImage, AnalyzeParams) {
+    test_wedge_with(&generate::GenerateParams::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::generate;
+    use image::LumaA;
+
+    #[test]
+    fn test_sampled_median() {
+        // a uniform patch with a few pixels of dust and a scratch at either extreme
+        let mut pixels = vec![20000; 100];
+        pixels[..3].fill(u16::MAX);
+        pixels[50..52].fill(0);
+        pixels[90] = u16::MAX;
+        assert_eq!(sampled_median(&pixels), 20000);
+        assert_eq!(SampleStrategy::Median.sample(&pixels), 20000);
+        assert!(sampled_mean(&pixels) > 21000, "{}", sampled_mean(&pixels));
+        assert_eq!(SampleStrategy::Mean.sample(&pixels), sampled_mean(&pixels));
+
+        assert_eq!(sampled_median(&[]), 0);
+        assert_eq!(sampled_median(&[3, 1, 2]), 2);
+        assert_eq!(sampled_median(&[4, 1, 2, 3]), 2);
+    }
+
+    #[test]
+    fn test_percentile_range_off_image() {
+        let buffer: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_fn(100, 100, |x, _| Luma([x as u16 * 100]));
+        // one patch hangs past the right edge and another lies wholly outside the scan
+        let rects = [
+            Rect::at(0, 0).of_size(20, 20),
+            Rect::at(90, 50).of_size(20, 20),
+            Rect::at(200, 200).of_size(20, 20),
+        ];
+        assert_eq!(percentile_range(&buffer, &rects, 1, 0.), (0, 9900));
+        assert_eq!(percentile_range(&buffer, &rects, 0, 0.), (0, 9900));
+        assert_eq!(percentile_range(&buffer, &rects[2..], 1, 0.), (0, u16::MAX));
+    }
+
+    #[test]
+    fn test_sampled_mean_zero() {
+        let buffer: ImageBuffer<Luma<u16>, Vec<u16>> = ImageBuffer::new(100, 100);
+        let sub_image = SubImage::new(&buffer, 10, 10, 10, 10);
+        let (pixels, _) = sampled_pixels(sub_image, 1, &SampleMask::default());
+        assert_eq!(sampled_mean(&pixels), 0);
+    }
+
+    #[test]
+    fn test_sampled_mean() {
+        let mut buffer: ImageBuffer<Luma<u16>, Vec<u16>> = ImageBuffer::new(100, 100);
+        for x in 0..100 {
+            for y in 0..100 {
+                buffer.put_pixel(x, y, Luma([(x * y) as u16]))
+            }
+        }
+        let sub_image = SubImage::new(&buffer, 10, 10, 10, 10);
+        let (pixels, count) = sampled_pixels(sub_image, 1, &SampleMask::default());
+        assert_eq!(count, 100);
+        assert_eq!(sampled_mean(&pixels), 210);
+    }
+
+    #[test]
+    fn test_create_histograms_rgb() {
+        let step_description = StepDescription::new(4, 2, 20, u16::MAX as u32);
+        let grid_analysis = GridAnalysis {
+            origin_x: 0,
+            origin_y: 0,
+            square_size: 10,
+            ..Default::default()
+        };
+
+        // left half is pure red, right half is a dim green with a bright blue
+        let mut buffer: ImageBuffer<Rgb<u16>, Vec<u16>> = ImageBuffer::new(20, 20);
+        for (x, _, p) in buffer.enumerate_pixels_mut() {
+            *p = if x < 10 {
+                Rgb([u16::MAX, 0, 0])
+            } else {
+                Rgb([0, 512, 40000])
+            };
+        }
+
+        let [red, green, blue] =
+            create_histograms_rgb(&buffer, &grid_analysis, &step_description, 256);
+
+        assert_eq!(red[255], 200);
+        assert_eq!(red[0], 200);
+        assert_eq!(green[0], 200);
+        assert_eq!(green[2], 200);
+        assert_eq!(blue[0], 200);
+        assert_eq!(blue[156], 200);
+        for histogram in [red, green, blue] {
+            assert_eq!(histogram.len(), 256);
+            assert_eq!(histogram.iter().sum::<u32>(), 400);
+        }
+    }
+
+    #[test]
+    fn test_analyze_separators() {
+        let image = generate(None, None, Layout::Separators).unwrap();
+        let grid_analysis = analyze_separators(&image.to_luma8()).unwrap();
+
+        // generate leaves a 10 pixel margin and draws 100 pixel squares
+        assert_eq!(grid_analysis.origin_x, 10);
+        assert_eq!(grid_analysis.origin_y, 10);
+        assert_eq!(grid_analysis.square_size, 100);
+
+        // every separator line was found, and they're what the overlay paints
+        for n in 0..=10 {
+            assert!(
+                grid_analysis.line_columns.contains(&(10 + n * 100)),
+                "{}",
+                n
+            );
+        }
+        let overlay = draw_detected_lines(&image.to_luma8(), &grid_analysis);
+        let magenta = Rgb([255, 0, 255]);
+        assert_eq!(*overlay.get_pixel(510, 555), magenta);
+        assert_ne!(*overlay.get_pixel(555, 555), magenta);
+    }
+
+    #[test]
+    fn test_analyze_separators_ignores_grid_layout() {
+        let image = generate(None, None, Layout::Grid).unwrap();
+        assert!(analyze_separators(&image.to_luma8()).is_err());
+    }
+
+    #[test]
+    fn test_find_grid_lines() {
+        let generate_params = generate::GenerateParams {
+            grid_thickness: 6,
+            grid_tone: Some(20000),
+            ..generate::GenerateParams::default()
+        };
+        let (wedge, _) = test_wedge_with(&generate_params);
+
+        let grid = find_grid_lines(&wedge.to_luma8(), 20000, true).unwrap();
+        assert_eq!(
+            (grid.origin_x, grid.origin_y, grid.square_size),
+            (0, 0, 100)
+        );
+        // the line along the right edge of the grid is cropped off
+        assert_eq!(
+            run_starts(&grid.line_columns),
+            (0..10).map(|n| n * 100).collect::<Vec<_>>()
+        );
+        assert!((100..106).all(|x| grid.line_columns.contains(&x)));
+        assert!(!grid.line_columns.contains(&106));
+        assert!(
+            find_grid_lines(&DynamicImage::new_luma8(100, 100).to_luma8(), 20000, true).is_err()
+        );
+
+        let params = AnalyzeParams {
+            invert: true,
+            grid_thickness: 6,
+            grid_tone: Some(20000),
+            ..AnalyzeParams::default()
+        };
+        let results = analyze(&wedge, &params, false).unwrap();
+        assert_eq!(results.response.len(), 101);
+        assert!(results.response.windows(2).all(|w| w[0].1 <= w[1].1));
+    }
+
+    #[test]
+    fn test_find_grid_lines_either_polarity() {
+        let generate_params = generate::GenerateParams {
+            grid_thickness: 6,
+            grid_tone: Some(20000),
+            ..generate::GenerateParams::default()
+        };
+        let (negative, _) = test_wedge_with(&generate_params);
+        // a perfect print of the negative, lines and all flipped
+        let mut print = negative.clone();
+        print.invert();
+
+        // found whichever way the scan is set to be read
+        for image in [&negative, &print] {
+            for invert in [true, false] {
+                let grid = find_grid_lines(&image.to_luma8(), 20000, invert).unwrap();
+                assert_eq!(
+                    (grid.origin_x, grid.origin_y, grid.square_size),
+                    (0, 0, 100)
+                );
+            }
+        }
+
+        // the print read as a print measures the same as the negative read inverted
+        let params = AnalyzeParams {
+            invert: true,
+            grid_thickness: 6,
+            grid_tone: Some(20000),
+            ..AnalyzeParams::default()
+        };
+        let from_negative = analyze(&negative, &params, false).unwrap();
+        let from_print = analyze(
+            &print,
+            &AnalyzeParams {
+                invert: false,
+                ..params
+            },
+            false,
+        )
+        .unwrap();
+        // a step that falls between two tones rounds up either way, landing 1 apart once flipped
+        for (negative, print) in from_negative.response.iter().zip(&from_print.response) {
+            assert_eq!(negative.0, print.0);
+            assert!(
+                negative.1.abs_diff(print.1) <= 1,
+                "{:?} {:?}",
+                negative,
+                print
+            );
+        }
+    }
+
+    #[test]
+    fn test_sampled_areas_skip_numbers() {
+        let step_description = StepDescription::default();
+        let image = generate(None, None, Layout::Grid).unwrap().to_luma16();
+        let grid_analysis = GridAnalysis {
+            origin_x: generate::MARGIN,
+            origin_y: generate::MARGIN,
+            square_size: step_description.square_size,
+            ..Default::default()
+        };
+
+        let rects = sampled_areas(&step_description, &grid_analysis);
+        // more than the old fixed 25% inset gave us
+        assert!(rects[0].width() * rects[0].height() > 50 * 50);
+
+        // every patch is a flat tone, so anything else inside the window is a number or a line
+        for (n, rect) in rects.iter().enumerate() {
+            let tone = step_description.tone(n as u32);
+            for x in rect.left()..=rect.right() {
+                for y in rect.top()..=rect.bottom() {
+                    assert_eq!(image.get_pixel(x as u32, y as u32)[0], tone, "patch {}", n);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_sampled_areas_partial_row() {
+        // 25 steps in 10 columns leaves 5 in the last row
+        let step_description = StepDescription::new(25, 10, 1000, u16::MAX as u32);
+        let grid_analysis = GridAnalysis {
+            origin_x: generate::MARGIN,
+            origin_y: generate::MARGIN,
+            square_size: step_description.square_size,
+            ..Default::default()
+        };
+
+        let rects = sampled_areas(&step_description, &grid_analysis);
+        let patches = generate::patches(&step_description, generate::MARGIN, generate::MARGIN);
+        assert_eq!(rects.len(), patches.len());
+        for (rect, patch) in rects.iter().zip(&patches) {
+            assert!(rect.left() >= patch.x as i32 && rect.top() >= patch.y as i32);
+            assert!(rect.right() < (patch.x + patch.width) as i32);
+            assert!(rect.bottom() < (patch.y + patch.height) as i32);
+        }
+        assert_eq!(patches.last().unwrap().x, generate::MARGIN + 400);
+    }
+
+    #[test]
+    fn test_draw_histogram_degenerate() {
+        for histogram in [vec![], vec![0; 256], vec![7]] {
+            let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(1024, 1024);
+            draw_histogram(&mut image, &histogram, HISTOGRAM_BUCKETS).unwrap();
+            draw_histograms_rgb(
+                &mut image,
+                &[histogram.clone(), histogram.clone(), histogram],
+                HISTOGRAM_BUCKETS,
+            )
+            .unwrap();
+            assert!(image.pixels().all(|p| p.0 == [0, 0, 0]));
+        }
+
+        // a lone spike, like a clean digital wedge, leaves every other bucket without a bar
+        let mut spike = vec![0; 256];
+        spike[100] = 5;
+        let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(1024, 1024);
+        draw_histogram(&mut image, &spike, HISTOGRAM_BUCKETS).unwrap();
+        assert!(image.pixels().any(|p| p.0 != [0, 0, 0]));
+    }
+
+    #[test]
+    fn test_rebucket() {
+        let mut histogram = vec![0; TONE_BUCKETS];
+        histogram[0] = 3;
+        histogram[255] = 4;
+        histogram[256] = 5;
+        histogram[u16::MAX as usize] = 6;
+
+        assert_eq!(rebucket(&histogram, 1), vec![18]);
+        let coarse = rebucket(&histogram, 256);
+        assert_eq!((coarse[0], coarse[1], coarse[255]), (7, 5, 6));
+        assert_eq!(coarse.iter().sum::<u32>(), 18);
+        // buckets that don't divide evenly still keep every count
+        assert_eq!(rebucket(&histogram, 100).iter().sum::<u32>(), 18);
+        // splitting can't recover tones that were merged
+        assert_eq!(rebucket(&[1, 2], 4), vec![1, 0, 2, 0]);
+
+        // at the most buckets the chart fits each bar is a pixel wide
+        let bars = histogram_bars(&histogram, 1024);
+        assert_eq!(bars.len(), 3);
+        assert!(bars.iter().all(|(_, width, _)| *width == 1));
+    }
+
+    #[test]
+    fn test_anchor_endpoints() {
+        let mut curve = vec![(0, 1200), (32000, 30000), (65500, 64000)];
+        anchor_endpoints(&mut curve, u16::MAX);
+        assert_eq!(curve, vec![(0, 0), (32000, 30000), (u16::MAX, u16::MAX)]);
+
+        let spline = curve_from_points(&curve, Interpolation::default()).unwrap();
+        assert_eq!(spline.clamped_sample(0.), Some(0.));
+        assert_eq!(
+            spline.clamped_sample(u16::MAX as f64),
+            Some(u16::MAX as f64)
+        );
+    }
+
+    #[test]
+    fn test_analyze_observed() {
+        #[derive(Default)]
+        struct Recorder {
+            stages: Vec<&'static str>,
+            square_size: u32,
+            samples: usize,
+            normalized: (u32, u32),
+            curve: Vec<(f64, f64)>,
+        }
+
+        impl AnalyzeObserver for Recorder {
+            fn stage(&mut self, name: &'static str) {
+                self.stages.push(name);
+            }
+
+            fn grid(&mut self, grid: &GridAnalysis) {
+                self.square_size = grid.square_size;
+            }
+
+            fn samples(&mut self, values: &[u16], deviations: &[f64]) {
+                assert_eq!(values.len(), deviations.len());
+                self.samples = values.len();
+            }
+
+            fn normalized(&mut self, image: &ImageBuffer<Luma<u16>, Vec<u16>>) {
+                self.normalized = image.dimensions();
+            }
+
+            fn curve(&mut self, curve: &Spline<f64, f64>) {
+                self.curve = curve.keys().iter().map(|key| (key.t, key.value)).collect();
+            }
+        }
+
+        let (image, params) = test_wedge();
+        let mut recorder = Recorder::default();
+        let results =
+            analyze_observed(&image, &params, false, &Cancel::default(), &mut recorder).unwrap();
+
+        assert_eq!(
+            recorder.stages,
+            vec![
+                "deskew",
+                "grid analysis",
+                "sampling",
+                "normalization",
+                "fit",
+                "histogram",
+                "rendering"
+            ]
+        );
+        assert_eq!(recorder.square_size, 100);
+        assert_eq!(recorder.samples, 101);
+        assert_eq!(recorder.normalized, (1000, 1100));
+        let curve: Vec<(f64, f64)> = results
+            .curve
+            .keys()
+            .iter()
+            .map(|key| (key.t, key.value))
+            .collect();
+        assert_eq!(recorder.curve, curve);
+    }
+
+    #[test]
+    fn test_refit_excluding_steps() {
+        let (wedge, params) = test_wedge();
+        let mut results = analyze(&wedge, &params, false).unwrap();
+        assert_eq!(results.patch_areas.len(), results.response.len());
+        let clean = refit(&results, &params, &[]).unwrap();
+        // the measured response runs through every step that was measured
+        for (input, observed) in &results.response {
+            let sampled = results
+                .response_curve
+                .clamped_sample(*input as f64)
+                .unwrap();
+            assert!((sampled - *observed as f64).abs() < 1., "{}", input);
+        }
+
+        // a speck of dust on one patch drags its reading way off
+        results.response[50].1 = 0;
+        let dusty = refit(&results, &params, &[]).unwrap();
+        let excluded = refit(&results, &params, &[50]).unwrap();
+
+        let at = |curve: &Spline<f64, f64>| curve.clamped_sample(results.response[50].0 as f64);
+        assert_ne!(at(&dusty), at(&clean));
+        assert!((at(&excluded).unwrap() - at(&clean).unwrap()).abs() < 1000.);
+
+        let all: Vec<usize> = (0..results.response.len()).collect();
+        assert!(refit(&results, &params, &all).is_err());
+    }
+
+    #[test]
+    fn test_reject_outliers() {
+        // a gently curving response with a little noise
+        let mut response: Vec<(u16, u16)> = (0..101u32)
+            .map(|n| {
+                let tone = n as f64 / 100.;
+                let noise = [0., 40., -40., 20.][n as usize % 4];
+                let value = tone.powf(1.2) * u16::MAX as f64 * 0.98 + 600. + noise;
+                ((n * 655) as u16, value as u16)
+            })
+            .collect();
+        assert!(reject_outliers(&response, 5.).is_empty());
 
-    Ok(GridAnalysis {
-        origin_x: 0,
-        origin_y: 0,
-        square_size,
-    })
-}
+        // a speck of dust on step 40 and a scratch on the last step
+        response[40].1 -= 8000;
+        response[100].1 -= 9000;
+        assert_eq!(reject_outliers(&response, 5.), vec![40, 100]);
 
-struct Samples {
-    values: Vec<u16>,
-    min: u16,
-    max: u16,
-}
+        // and the curve is fit as if they weren't there
+        let params = AnalyzeParams::default();
+        let input_values: Vec<u16> = response.iter().map(|(input, _)| *input).collect();
+        let kept = without_steps(&response, &[40, 100]);
+        assert_eq!(kept.len(), 99);
+        assert!(fit_curve(&input_values, &kept, &params, None, false).is_ok());
+    }
 
-fn collect_samples(image: &ImageBuffer<Luma<u16>, Vec<u16>>, rects: &Vec<Rect>) -> Samples {
-    let mut values: Vec<u16> = vec![0; rects.len()];
-    let mut max: u16 = 0;
-    let mut min: u16 = u16::MAX;
+    #[test]
+    fn test_limit_slope() {
+        // a smooth ramp with a couple of noisy spikes in it
+        let mut curve: Vec<(u16, u16)> = (0..11).map(|i| (i * 6000, i * 6000)).collect();
+        curve[3].1 = 40000;
+        curve[7].1 = 2000;
 
-    for (i, r) in rects.iter().enumerate() {
-        let view = image.view(r.left() as u32, r.top() as u32, r.width(), r.height());
-        let sample = sampled_mean(view);
+        limit_slope(&mut curve, 2.);
 
-        values[i] = sample;
-        if sample > max {
-            max = sample;
-        }
-        if sample < min {
-            min = sample;
+        for pair in curve.windows(2) {
+            let slope = (pair[1].1 as f64 - pair[0].1 as f64) / (pair[1].0 - pair[0].0) as f64;
+            // rounding to whole tones can push a segment a hair over
+            assert!(slope.abs() <= 2. + 1e-3, "{:?} slope {}", pair, slope);
         }
+        // the well behaved ends are left alone
+        assert_eq!(curve[0], (0, 0));
+        assert_eq!(curve[10], (60000, 60000));
     }
 
-    Samples { values, max, min }
-}
+    #[test]
+    fn test_ink_limit() {
+        // a process that follows its input up to step 6 and only wanders up and down past it
+        let input_values: Vec<u16> = (0..=8).map(|n| n * 8191).collect();
+        let mut response: Vec<(u16, u16)> = input_values
+            .iter()
+            .map(|v| (*v, (*v as u32 * 65000 / 49146) as u16))
+            .collect();
+        response[7].1 = 65535;
+        response[8].1 = 65200;
+        assert_eq!(detect_ink_limit(&response), Some(49146));
 
-struct NormalizedResults {
-    image: ImageBuffer<Luma<u16>, Vec<u16>>,
-    samples: Vec<u16>,
-}
+        let params = AnalyzeParams::default();
+        let capped = fit_curve(
+            &input_values,
+            &response,
+            &params,
+            detect_ink_limit(&response),
+            false,
+        )
+        .unwrap();
+        let uncapped = fit_curve(&input_values, &response, &params, None, false).unwrap();
+        for input in input_values {
+            let output = sample_curve(&capped, input as f64).unwrap();
+            assert!(output <= 49146. + 1e-6, "{} maps to {}", input, output);
+        }
+        // chasing the noise in the plateau asks for more than the limit
+        assert!(sample_curve(&uncapped, 65528.).unwrap() > 49146.);
 
-// Now normalize the samples based on the maximum and minimum values
-// We expect for the max observed to be greater than zero and the minimum
-// to to less than u16::max. Assuming the print was printed to d-max then
-// we want to distribute our observed values evenly between max and min
-// before determining curve adjustments
-fn normalize_image(
-    step_description: &StepDescription,
-    image: &ImageBuffer<Luma<u16>, Vec<u16>>,
-    samples: &Samples,
-    invert_image: bool,
-) -> NormalizedResults {
-    /* example
-     *
-     * Suppose we have:
-     *  - a full range from [0, 65535] inclusive
-     *  - a subset in the range [256, 25280]
-     *
-     * If we want to expand the subset to fill the full range we can:
-     * subtract the minimum value from all the values in the subset to
-     * bring the minimum value to 0;
-     *
-     * subset - 256 => [0, 65024]
-     *
-     * Then we need to expand those values to fill up to 65535 by multiplying them
-     * by 65535 / our new max (65024)
-     */
-    let normalize_factor =
-        (step_description.max_tone as f32) / ((samples.max - samples.min) as f32);
+        // a response that keeps climbing, or is flat throughout, has no limit
+        let linear: Vec<(u16, u16)> = (0..=10).map(|n| (n * 6000, n * 6000)).collect();
+        assert_eq!(detect_ink_limit(&linear), None);
+        let flat: Vec<(u16, u16)> = (0..=10).map(|n| (n * 6000, 30000)).collect();
+        assert_eq!(detect_ink_limit(&flat), None);
+    }
 
-    let mut normalized_samples: Vec<u16> = samples
-        .values
-        .iter()
-        .map(|s| ((s - samples.min) as f32 * normalize_factor) as u16)
-        .collect();
+    #[test]
+    fn test_measured_gamma() {
+        for gamma in [0.5, 1., 1.8, 2.2] {
+            let response: Vec<(u16, u16)> = (0..=100)
+                .map(|n| {
+                    let input = n as f64 / 100.;
+                    let output = input.powf(gamma) * u16::MAX as f64;
+                    ((input * u16::MAX as f64) as u16, output.round() as u16)
+                })
+                .collect();
+            let measured = measured_gamma(&response).unwrap();
+            assert!(
+                (measured - gamma).abs() < 0.01,
+                "{} for {}",
+                measured,
+                gamma
+            );
+        }
 
-    // this is dumb but I've changed how I want the order to work
-    // it used to be black to white, this is white to black
-    if !invert_image {
-        normalized_samples.reverse();
+        // nothing in the mid tones to fit
+        assert_eq!(measured_gamma(&[(0, 0), (u16::MAX, u16::MAX)]), None);
     }
 
-    let normalized_image = map_pixels(image, |_, _, p| {
-        let new_v = p[0].saturating_sub(samples.min);
-        Luma([(new_v as f32 * normalize_factor) as u16])
-    });
+    #[test]
+    fn test_sampled_mean_subsampled() {
+        // a smooth gradient, like a large flat patch with a little scanner falloff
+        let buffer: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_fn(400, 400, |x, y| Luma([(20000 + x * 10 + y * 5) as u16]));
 
-    NormalizedResults {
-        image: normalized_image,
-        samples: normalized_samples,
+        let mean = |subsample_factor| {
+            let view = SubImage::new(&buffer, 100, 100, 200, 200);
+            sampled_mean(&sampled_pixels(view, subsample_factor, &SampleMask::default()).0)
+        };
+        let exact = mean(1);
+        for subsample_factor in [2, 4, 8] {
+            let subsampled = mean(subsample_factor);
+            // within half a percent
+            assert!(
+                (exact as i32 - subsampled as i32).abs() <= exact as i32 / 200,
+                "{} vs {} with a factor of {}",
+                exact,
+                subsampled,
+                subsample_factor
+            );
+        }
     }
-}
-
-/* Use our own observed values to find where we should place
- * our points to curve with
- *
- * As an example let's assume we have a table like below:
- *
- * ---
- * i    exp     norm
- * ---
- * 1    0       0
- * 2    655     648
- * ...
- * 15   6550    5000
- * ...
- * 20   9825    6576
- * ...
- * 101  65500   64800
- * ---
- *
- * Using this we can figure out how to push our densities around to get a linear relationship.
- *
- * like take the 15th step. In a linear relationshop the input density of 6550 would be
- * perfectly reflected in the observed density 6550 -> 6550. But in our real world test it
- * didn't.
- *
- * So instead we want to search through our observed densities and figure out what input
- * density did create an ouput of 6550. Looking we can see step 20 was close.
- *
- * So when mapping our values when we render a new 15 we want the density to be 9825 so that it
- * achieve an output density close to 6550.
- *
- * We'll find our value by finding the largest input density that is still less than our
- * target, and the least input density that is still greater than our density. We'll then use
- * the midpoint.
- */
-fn linearize_inputs(
-    input_values: &Vec<u16>,
-    normalized_samples: &Vec<u16>,
-) -> Result<Vec<(u16, u16)>> {
-    // assume a linear relationship, so every value of expected on the x
-    // axis should be expected on the y axis. Our observed values will be
-    // different. The curve is the delta.
-    let input_values_with_samples: Vec<(u16, u16)> = input_values
-        .clone()
-        .into_iter()
-        .zip(normalized_samples.into_iter().copied())
-        .collect();
 
-    input_values
-        .clone()
-        .into_iter()
-        .map(|e| find_closest_matching_input_density(&input_values_with_samples, e).map(|c| (e, c)))
-        .collect()
-}
+    #[test]
+    fn test_write_cal() {
+        let curve = curve_from_points(
+            &[(0, 0), (32768, 16384), (u16::MAX, u16::MAX)],
+            Interpolation::default(),
+        )
+        .unwrap();
+        let mut buffer: Vec<u8> = Vec::new();
+        write_cal(&mut buffer, &curve).unwrap();
+        let cal = String::from_utf8(buffer).unwrap();
 
-fn sampled_areas(step_description: &StepDescription, grid_analysis: &GridAnalysis) -> Vec<Rect> {
-    let mut n: usize = 0;
-    let mut rects = Vec::new();
+        assert!(cal.starts_with("CAL\n"));
+        assert!(cal.contains("RGB_I RGB_R RGB_G RGB_B"));
+        assert!(cal.contains("NUMBER_OF_SETS 256"));
 
-    // 10% margin around the whole square
-    let margin = (grid_analysis.square_size as f32 * 0.25).floor() as u32;
-    let analyzed_size = grid_analysis.square_size - (2 * margin);
+        let rows: Vec<Vec<f64>> = cal
+            .split("BEGIN_DATA\n")
+            .nth(1)
+            .unwrap()
+            .lines()
+            .take_while(|line| *line != "END_DATA")
+            .map(|line| line.split(' ').map(|v| v.parse().unwrap()).collect())
+            .collect();
 
-    for row in 0..step_description.rows {
-        for col in 0..step_description.columns {
-            if n >= step_description.count as usize {
-                break;
+        assert_eq!(rows.len(), 256);
+        for row in rows {
+            let expected = curve.clamped_sample(row[0] * u16::MAX as f64).unwrap();
+            for output in &row[1..] {
+                assert!((output * u16::MAX as f64 - expected).abs() < 1.);
             }
-            let x = grid_analysis.origin_x + (col * grid_analysis.square_size) + margin;
-            let y = grid_analysis.origin_y + (row * grid_analysis.square_size) + margin;
-
-            // this is a "window" of the square, stepped in 25-30 pixels on each side so as
-            // to avoid any malarky with the ednge of the square or the number on the top
-            // left corner
-            let rect = Rect::at(x as i32, y as i32).of_size(analyzed_size, analyzed_size);
-            rects.push(rect);
-            n += 1;
         }
     }
-    rects
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
     #[test]
-    fn test_sampled_mean_zero() {
-        let buffer: ImageBuffer<Luma<u16>, Vec<u16>> = ImageBuffer::new(100, 100);
-        let sub_image = SubImage::new(&buffer, 10, 10, 10, 10);
-        let result = sampled_mean(sub_image);
-        assert_eq!(result, 0);
+    fn test_robust_reference_points() {
+        // a dark patch and a light patch, each with a stray pixel of dust or glare
+        let mut image: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_fn(20, 10, |x, _| Luma([if x < 10 { 1000 } else { 50000 }]));
+        image.put_pixel(3, 3, Luma([0]));
+        image.put_pixel(13, 3, Luma([u16::MAX]));
+        let rects = [
+            Rect::at(0, 0).of_size(10, 10),
+            Rect::at(10, 0).of_size(10, 10),
+        ];
+        // averaged, so the stray pixels pull the samples they're in
+        let samples = collect_samples(
+            &image,
+            &rects,
+            1,
+            SampleStrategy::Mean,
+            &SampleMask::default(),
+            &Cancel::default(),
+        )
+        .unwrap();
+
+        let params = AnalyzeParams::default();
+        let (black, white) = reference_points(&image, &rects, &samples, &params).unwrap();
+        assert!(black < 1000);
+        assert!(white > 50000);
+
+        let robust = AnalyzeParams {
+            robust: true,
+            ..AnalyzeParams::default()
+        };
+        assert_eq!(
+            reference_points(&image, &rects, &samples, &robust).unwrap(),
+            (1000, 50000)
+        );
+
+        // explicit points win over measured ones, and must leave some range
+        let explicit = AnalyzeParams {
+            black_point: Some(2000),
+            ..robust.clone()
+        };
+        assert_eq!(
+            reference_points(&image, &rects, &samples, &explicit).unwrap(),
+            (2000, 50000)
+        );
+        let backwards = AnalyzeParams {
+            black_point: Some(60000),
+            ..robust
+        };
+        assert!(reference_points(&image, &rects, &samples, &backwards).is_err());
+
+        let normalized = normalize_image(
+            &StepDescription::default(),
+            &image,
+            &samples,
+            (2000, 50000),
+            true,
+        );
+        let normalization = normalized.normalization;
+        assert_eq!((normalization.black, normalization.white), (2000, 50000));
+        assert_eq!(
+            (normalization.sample_min, normalization.sample_max),
+            (samples.min, samples.max)
+        );
+        assert_eq!(normalization.factor, u16::MAX as f32 / 48000.);
+        assert!(normalization.inverted);
     }
 
     #[test]
-    fn test_sampled_mean() {
-        let mut buffer: ImageBuffer<Luma<u16>, Vec<u16>> = ImageBuffer::new(100, 100);
-        for x in 0..100 {
-            for y in 0..100 {
-                buffer.put_pixel(x, y, Luma([(x * y) as u16]))
-            }
-        }
-        let sub_image = SubImage::new(&buffer, 10, 10, 10, 10);
-        let result = sampled_mean(sub_image);
-        assert_eq!(result, 210);
+    fn test_sampled_deviation() {
+        let deviation = |image: &ImageBuffer<Luma<u16>, Vec<u16>>| {
+            let view = SubImage::new(image, 0, 0, 10, 10);
+            sampled_deviation(&sampled_pixels(view, 1, &SampleMask::default()).0, 500)
+        };
+        let flat: ImageBuffer<Luma<u16>, Vec<u16>> = ImageBuffer::from_pixel(10, 10, Luma([500]));
+        assert_eq!(deviation(&flat), 0.);
+
+        // half at 400, half at 600
+        let split: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_fn(10, 10, |x, _| Luma([if x < 5 { 400 } else { 600 }]));
+        assert_eq!(deviation(&split), 100.);
     }
 
     #[test]
@@ -557,4 +3510,717 @@ mod tests {
         result = find_closest_matching_input_density(&haystack, 9).unwrap();
         assert_eq!(result, 5);
     }
+
+    #[test]
+    fn test_weight_by_area() {
+        // five patches stepping up by 1000, a dark grid line runs down the middle of the third
+        let mut image: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_fn(50, 10, |x, _| Luma([1000 * (x / 10 + 1) as u16]));
+        let rects: Vec<Rect> = (0..5)
+            .map(|n| Rect::at(n * 10, 0).of_size(10, 10))
+            .collect();
+        for x in 22..28 {
+            for y in 0..10 {
+                image.put_pixel(x, y, Luma([0]));
+            }
+        }
+        let grid_analysis = GridAnalysis {
+            line_columns: (22..28).collect(),
+            ..Default::default()
+        };
+        let mask = SampleMask::new(&grid_analysis, image.dimensions());
+
+        // the line is left out of the sample, leaving less of the third patch
+        let mut samples = collect_samples(
+            &image,
+            &rects,
+            1,
+            SampleStrategy::default(),
+            &mask,
+            &Cancel::default(),
+        )
+        .unwrap();
+        assert_eq!(samples.counts, vec![100, 100, 40, 100, 100]);
+        assert_eq!(samples.values, vec![1000, 2000, 3000, 4000, 5000]);
+
+        // weighting pulls the smaller patch toward its neighbors and leaves the rest alone
+        samples.values[2] = 3600;
+        weight_by_area(&mut samples);
+        assert_eq!(samples.values, vec![1000, 2000, 3240, 4000, 5000]);
+        assert_eq!((samples.min, samples.max), (1000, 5000));
+
+        // a defect masked out by hand is left out of its patch like a line
+        let mut masked = DynamicImage::ImageLumaA16(ImageBuffer::from_fn(50, 10, |x, y| {
+            let tone = image.get_pixel(x, y)[0];
+            match (x, y) {
+                (12..15, 3..6) => LumaA([u16::MAX, 0]),
+                _ => LumaA([tone, u16::MAX]),
+            }
+        }));
+        let mut mask = SampleMask::default();
+        mask.mask_transparent(&masked).unwrap();
+        let masked_samples = collect_samples(
+            &masked.to_luma16(),
+            &rects,
+            1,
+            SampleStrategy::default(),
+            &mask,
+            &Cancel::default(),
+        )
+        .unwrap();
+        assert_eq!(masked_samples.values[1], 2000);
+        assert_eq!(masked_samples.counts[1], 91);
+        // once it's opaque again the defect drags the mean of the patch up
+        masked
+            .as_mut_luma_alpha16()
+            .unwrap()
+            .pixels_mut()
+            .for_each(|p| p[1] = u16::MAX);
+        let mut mask = SampleMask::default();
+        mask.mask_transparent(&masked).unwrap();
+        let unmasked_samples = collect_samples(
+            &masked.to_luma16(),
+            &rects,
+            1,
+            SampleStrategy::Mean,
+            &mask,
+            &Cancel::default(),
+        )
+        .unwrap();
+        assert!(unmasked_samples.values[1] > 2000);
+        assert!(SampleMask::default()
+            .mask_transparent(&DynamicImage::new_luma16(1, 1))
+            .is_err());
+
+        // equal areas are all trusted the same, weighting changes nothing
+        let mut samples = collect_samples(
+            &image,
+            &rects,
+            1,
+            SampleStrategy::default(),
+            &SampleMask::default(),
+            &Cancel::default(),
+        )
+        .unwrap();
+        let unweighted = samples.values.clone();
+        weight_by_area(&mut samples);
+        assert_eq!(samples.values, unweighted);
+    }
+
+    #[test]
+    fn test_collect_samples_past_edge() {
+        let image: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_fn(20, 10, |x, _| Luma([1000 * (x / 10 + 1) as u16]));
+        let mask = SampleMask::default();
+
+        // the second patch hangs past the right and bottom edges, only what's inside is sampled
+        let rects = vec![
+            Rect::at(0, 0).of_size(10, 10),
+            Rect::at(14, 4).of_size(10, 10),
+        ];
+        let samples = collect_samples(
+            &image,
+            &rects,
+            1,
+            SampleStrategy::default(),
+            &mask,
+            &Cancel::default(),
+        )
+        .unwrap();
+        assert_eq!(samples.values, vec![1000, 2000]);
+        assert_eq!(samples.counts, vec![100, 36]);
+
+        // a patch off the image entirely can't be sampled at all
+        let rects = vec![
+            Rect::at(0, 0).of_size(10, 10),
+            Rect::at(30, 0).of_size(10, 10),
+        ];
+        match collect_samples(
+            &image,
+            &rects,
+            1,
+            SampleStrategy::default(),
+            &mask,
+            &Cancel::default(),
+        ) {
+            Err(e) => assert!(e.to_string().contains("step 2")),
+            Ok(_) => panic!("sampled a patch outside the image"),
+        }
+    }
+
+    #[test]
+    fn test_target_response() {
+        // a process that already prints every tone as it should
+        let input_values: Vec<u16> = (0..=10).map(|n| n * 6000).collect();
+        let response: Vec<(u16, u16)> = input_values.iter().map(|v| (*v, *v)).collect();
+
+        // aim for a darker response than linear, output = input^2
+        let max = u16::MAX as f64;
+        let target = curve_from_points(
+            &(0..=16)
+                .map(|n| {
+                    let tone = n as f64 / 16.;
+                    ((tone * max) as u16, (tone * tone * max) as u16)
+                })
+                .collect::<Vec<_>>(),
+            Interpolation::Linear,
+        )
+        .unwrap();
+
+        let linear = linearize_inputs(&input_values, &response, None).unwrap();
+        let targeted = linearize_inputs(&input_values, &response, Some(&target)).unwrap();
+        for ((input, plain), (_, aimed)) in linear.iter().zip(&targeted) {
+            let wanted = sample_curve(&target, *input as f64).unwrap();
+            // the identity process needs no correction to be linear, only to reach the target
+            assert!((*plain as i32 - *input as i32).abs() <= 6000, "{}", input);
+            assert!(
+                (*aimed as f64 - wanted).abs() <= 6000.,
+                "{} {}",
+                aimed,
+                wanted
+            );
+            assert!(*aimed <= *plain);
+        }
+        assert!(targeted[5].1 < linear[5].1);
+    }
+
+    #[test]
+    fn test_subtract_base() {
+        // a base letting through 80% of the light, density 0.1
+        let base: ImageBuffer<Luma<u16>, Vec<u16>> = ImageBuffer::from_pixel(4, 1, Luma([52428]));
+        let mut scan =
+            ImageBuffer::from_fn(4, 1, |x, _| Luma([[0, 13107, 52428, u16::MAX][x as usize]]));
+        subtract_base(&mut scan, &base).unwrap();
+        // what the process let through on top of the base, and white past it
+        let tones: Vec<u16> = scan.pixels().map(|p| p[0]).collect();
+        assert_eq!(tones, vec![0, 16384, u16::MAX, u16::MAX]);
+
+        // every density has the base's taken off
+        let density = |tone: u16| generate::density(tone, u16::MAX as u32).unwrap();
+        assert!((density(13107) - density(16384) - density(52428)).abs() < 0.001);
+
+        let mut mismatched = ImageBuffer::new(3, 1);
+        assert!(subtract_base(&mut mismatched, &base).is_err());
+    }
+
+    #[test]
+    fn test_scanner_profile() {
+        // a scanner that reads every tone too light, and the profile measured to undo it
+        let gamma = |tone: f64, exponent: f64| (tone / 65535.).powf(exponent) * 65535.;
+        let scanner: Vec<(u16, u16)> = (0..=64)
+            .map(|n| {
+                let tone = (n * 1024).min(65535) as f64;
+                (tone as u16, gamma(tone, 1. / 2.2).round() as u16)
+            })
+            .collect();
+        let profile: Vec<(u16, u16)> = scanner.iter().map(|(t, read)| (*read, *t)).collect();
+        let scanner = curve_from_points(&scanner, Interpolation::Linear).unwrap();
+        let profile_path = std::env::temp_dir().join("curved-test-scanner-profile.json");
+        curve::save_curve(
+            &profile_path,
+            &curve_from_points(&profile, Interpolation::Linear).unwrap(),
+        )
+        .unwrap();
+
+        let (wedge, params) = test_wedge();
+        let scanned = apply::apply(&wedge, &scanner, LumaWeights::default()).unwrap();
+        let expected = analyze(&wedge, &params, false).unwrap().response;
+        let uncorrected = analyze(&scanned, &params, false).unwrap().response;
+        let corrected = analyze(
+            &scanned,
+            &AnalyzeParams {
+                scanner_profile: Some(profile_path.clone()),
+                ..params
+            },
+            false,
+        )
+        .unwrap()
+        .response;
+        std::fs::remove_file(profile_path).unwrap();
+
+        let worst = |response: &[(u16, u16)]| {
+            response
+                .iter()
+                .zip(&expected)
+                .map(|((_, got), (_, want))| got.abs_diff(*want))
+                .max()
+                .unwrap()
+        };
+        // the scanner's response is taken out before sampling, leaving the wedge's
+        assert!(worst(&corrected) < 200, "{}", worst(&corrected));
+        assert!(worst(&uncorrected) > 5000, "{}", worst(&uncorrected));
+    }
+
+    #[test]
+    fn test_params_round_trip() {
+        let (wedge, params) = test_wedge();
+        let params = AnalyzeParams {
+            max_slope: Some(2.5),
+            reject_outliers: Some(5.),
+            robust: true,
+            detect_ink_limit: true,
+            ..params
+        };
+        let results = analyze(&wedge, &params, false).unwrap();
+
+        // saved with the curve analyze wrote, or on their own
+        let curve_path = std::env::temp_dir().join("curved-test-params-curve.json");
+        curve::save_analyzed_curve(&curve_path, &results.curve, &params).unwrap();
+        let params_path = std::env::temp_dir().join("curved-test-params.json");
+        std::fs::write(&params_path, serde_json::to_string(&params).unwrap()).unwrap();
+        let from_curve = curve::load_params(&curve_path).unwrap();
+        let from_params = curve::load_params(&params_path).unwrap();
+        // the curve still loads as a curve
+        assert_eq!(
+            curve::load_curve(&curve_path).unwrap().keys(),
+            results.curve.keys()
+        );
+        // a curve saved without params has none to give
+        curve::save_curve(&curve_path, &results.curve).unwrap();
+        assert!(curve::load_params(&curve_path).is_err());
+        std::fs::remove_file(curve_path).unwrap();
+        std::fs::remove_file(params_path).unwrap();
+
+        assert_eq!(from_curve, params);
+        assert_eq!(from_params, params);
+        let rerun = analyze(&wedge, &from_curve, false).unwrap();
+        assert_eq!(rerun.curve.keys(), results.curve.keys());
+        assert_eq!(rerun.response, results.response);
+    }
+
+    #[test]
+    fn test_load_params_out_of_range() {
+        let path = std::env::temp_dir().join("curved-test-params-out-of-range.json");
+        for params in [
+            r#"{"columns":0}"#,
+            r#"{"steps":1}"#,
+            r#"{"robust":true,"subsample_factor":0}"#,
+            r#"{"max_skew_degrees":90}"#,
+            r#"{"max_slope":-1}"#,
+            r#"{"grid_thickness":0}"#,
+            r#"{"steps":101,"columns":10,"rows":2}"#,
+        ] {
+            std::fs::write(&path, params).unwrap();
+            assert!(curve::load_params(&path).is_err(), "{} loaded", params);
+        }
+        std::fs::write(&path, r#"{"steps":21,"columns":7}"#).unwrap();
+        assert!(curve::load_params(&path).is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_clipping_warning() {
+        let (wedge, params) = test_wedge();
+        // a print that blows out the top third of the tones to white
+        let blown_out = Spline::from_vec(vec![
+            Key::new(0., 0., Interpolation::Linear),
+            Key::new(44000., 65535., Interpolation::Linear),
+            Key::new(65535., 65535., Interpolation::Linear),
+        ]);
+        let clipped = apply::apply(&wedge, &blown_out, LumaWeights::default()).unwrap();
+
+        let clean = analyze(&wedge, &params, false).unwrap().histogram;
+        let clipped = analyze(&clipped, &params, false).unwrap().histogram;
+        assert_eq!(clipped_ends(&clean), (false, false));
+        assert_eq!(clipped_ends(&clipped), (false, true));
+        assert_eq!(clipped_ends(&[]), (false, false));
+
+        let red = image::Rgb([220, 0, 0]);
+        let drawn = |histogram: &[u32]| {
+            let mut image = RgbImage::new(1024, 1024);
+            draw_histogram(&mut image, histogram, HISTOGRAM_BUCKETS).unwrap();
+            image
+        };
+        let warned = drawn(&clipped);
+        assert_eq!(*warned.get_pixel(1020, 10), red);
+        assert_ne!(*warned.get_pixel(4, 10), red);
+        assert_ne!(*drawn(&clean).get_pixel(1020, 10), red);
+    }
+
+    #[test]
+    fn test_analyze_strip() {
+        let generate_params = generate::GenerateParams {
+            columns: 101,
+            rows: Some(1),
+            ..generate::GenerateParams::default()
+        };
+        let strip = generate::generate_with_params(None, None, Layout::Grid, &generate_params)
+            .unwrap()
+            .crop_imm(10, 10, 10100, 100);
+        let params = AnalyzeParams {
+            invert: true,
+            columns: 101,
+            rows: Some(1),
+            ..AnalyzeParams::default()
+        };
+        let results = analyze(&strip, &params, false).unwrap();
+
+        // the patches read back in order along the strip, as a straight line
+        assert_eq!(results.patch_areas.len(), 101);
+        assert!(results
+            .patch_areas
+            .windows(2)
+            .all(|pair| pair[0].top() == pair[1].top() && pair[0].left() < pair[1].left()));
+        for (input, output) in &results.response {
+            assert!(
+                (*input as i32 - *output as i32).abs() < 1000,
+                "{} {}",
+                input,
+                output
+            );
+        }
+    }
+
+    #[test]
+    fn test_corner_dots() {
+        let generate_params = generate::GenerateParams {
+            corner_dots: true,
+            ..generate::GenerateParams::default()
+        };
+        let uncropped =
+            generate::generate_with_params(None, None, Layout::Grid, &generate_params).unwrap();
+        let (wedge, _) = test_wedge_with(&generate_params);
+        let step_description = StepDescription::default();
+        let grid_analysis = analyze_grid(&wedge.to_luma8(), &step_description, 2).unwrap();
+
+        // the dots mark out the same patches as the grid, give or take a pixel for the dots cut
+        // in half by the edge of the scan
+        let dotted = dotted_patch_areas(&wedge.to_luma8(), &step_description, &grid_analysis);
+        for (dotted, patch) in dotted
+            .unwrap()
+            .iter()
+            .zip(patch_areas(&step_description, &grid_analysis))
+        {
+            assert!((dotted.left() - patch.left()).abs() <= 1);
+            assert!((dotted.top() - patch.top()).abs() <= 1);
+            assert!((dotted.right() - patch.right()).abs() <= 1);
+            assert!((dotted.bottom() - patch.bottom()).abs() <= 1);
+        }
+
+        // shearing the scan moves every patch along with its dots, the uncropped wedge keeps the
+        // dots along the edges whole
+        let luma = uncropped.to_luma8();
+        let (width, height) = luma.dimensions();
+        let sheared =
+            ImageBuffer::from_fn(width + 20, height, |x, y| match x.checked_sub(y / 100) {
+                Some(x) if x < width => *luma.get_pixel(x, y),
+                _ => Luma([0]),
+            });
+        let margin = GridAnalysis {
+            origin_x: generate::MARGIN,
+            origin_y: generate::MARGIN,
+            square_size: 100,
+            ..Default::default()
+        };
+        let dotted = dotted_patch_areas(&sheared, &step_description, &margin).unwrap();
+        for (n, patch) in dotted.iter().enumerate() {
+            // a patch's bottom corners lean further right than its top
+            let (col, row) = step_description.cell(n as u32);
+            let (left, top) = (
+                10 + col * 100 + (10 + (row + 1) * 100) / 100,
+                10 + row * 100,
+            );
+            assert_eq!((patch.left(), patch.top()), (left as i32, top as i32));
+        }
+
+        // and analysis reads them back as a straight line
+        let params = AnalyzeParams {
+            invert: true,
+            corner_dots: true,
+            ..AnalyzeParams::default()
+        };
+        let results = analyze(&wedge, &params, false).unwrap();
+        for (input, output) in &results.response {
+            assert!((*input as i32 - *output as i32).abs() < 1000);
+        }
+
+        // a wedge without dots says so
+        let (plain, _) = test_wedge();
+        let error = dotted_patch_areas(&plain.to_luma8(), &step_description, &grid_analysis);
+        assert!(error.unwrap_err().to_string().contains("--corner-dots"));
+    }
+
+    #[test]
+    fn test_patch_status() {
+        // a clean scan's patches all deviate about the same, step 2 is far noisier
+        let deviations = [200., 250., 4000., 220., 180., 240.];
+        let clipped = [true, false, false, false, false, true];
+        let statuses = patch_statuses(&deviations, &clipped, &[5]);
+        assert_eq!(
+            statuses,
+            [
+                PatchStatus::Clipped,
+                PatchStatus::Good,
+                PatchStatus::HighVariance,
+                PatchStatus::Good,
+                PatchStatus::Good,
+                PatchStatus::Rejected,
+            ]
+        );
+
+        // grainier patches on an otherwise perfectly smooth scan aren't flagged
+        let smooth = patch_statuses(&[0., 0., 300.], &[false; 3], &[]);
+        assert!(smooth.iter().all(|status| *status == PatchStatus::Good));
+
+        assert!(is_clipped(&[0, 0, 0, 100]));
+        assert!(is_clipped(&[u16::MAX, u16::MAX, 65000]));
+        assert!(!is_clipped(&[0, 100, 200, 300]));
+        assert!(!is_clipped(&[]));
+
+        // every window is outlined in its status's color
+        let image = DynamicImage::new_luma16(40, 10);
+        let rects: Vec<Rect> = (0..4)
+            .map(|n| Rect::at(n * 10 + 2, 2).of_size(6, 6))
+            .collect();
+        let statuses = [
+            PatchStatus::Good,
+            PatchStatus::HighVariance,
+            PatchStatus::Clipped,
+            PatchStatus::Rejected,
+        ];
+        let drawn = draw_sampled_areas(&image, &rects, &statuses).unwrap();
+        for (rect, status) in rects.iter().zip(statuses) {
+            let corner = drawn.get_pixel(rect.left() as u32, rect.top() as u32);
+            assert_eq!(*corner, status.color());
+        }
+        let colors: Vec<Rgb<u8>> = statuses.iter().map(|status| status.color()).collect();
+        assert_eq!(
+            colors,
+            [
+                Rgb([0, 255, 0]),
+                Rgb([255, 255, 0]),
+                Rgb([255, 0, 0]),
+                Rgb([128, 128, 128])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_analyze_grid_finds_origin() {
+        let wedge = test_wedge().0.to_luma8();
+        let step_description = StepDescription::default();
+
+        // a tightly cropped scan
+        let grid = analyze_grid(&wedge, &step_description, 2).unwrap();
+        assert_eq!(
+            (grid.origin_x, grid.origin_y, grid.square_size),
+            (0, 0, 100)
+        );
+
+        // the wedge on white paper with an uneven border around it
+        for (left, top) in [(20, 20), (13, 37), (45, 6)] {
+            let mut bordered =
+                ImageBuffer::from_pixel(1000 + left + 30, 1100 + top + 25, Luma([255]));
+            image::imageops::overlay(&mut bordered, &wedge, left as i64, top as i64);
+            let grid = analyze_grid(&bordered, &step_description, 2).unwrap();
+            assert_eq!(
+                (grid.origin_x, grid.origin_y, grid.square_size),
+                (left, top, 100)
+            );
+            assert!(grid.line_columns.contains(&(left + 500)));
+        }
+
+        // and turned a few pixels across its width
+        let mut bordered = ImageBuffer::from_pixel(1040, 1140, Luma([255]));
+        image::imageops::overlay(&mut bordered, &wedge, 20, 20);
+        let rotated = imageproc::geometric_transformations::rotate_about_center(
+            &bordered,
+            0.2f32.to_radians(),
+            imageproc::geometric_transformations::Interpolation::Bilinear,
+            Luma([255]),
+        );
+        let grid = analyze_grid(&rotated, &step_description, 2).unwrap();
+        assert!(grid.origin_x.abs_diff(20) <= 2, "{}", grid.origin_x);
+        assert!(grid.origin_y.abs_diff(20) <= 2, "{}", grid.origin_y);
+        assert_eq!(grid.square_size, 100);
+
+        // analysis samples the right patches through the border
+        let params = AnalyzeParams {
+            invert: true,
+            ..AnalyzeParams::default()
+        };
+        let results = analyze(&DynamicImage::ImageLuma8(rotated), &params, false).unwrap();
+        for (input, output) in &results.response {
+            assert!(
+                (*input as i32 - *output as i32).abs() < 2000,
+                "{} {}",
+                input,
+                output
+            );
+        }
+    }
+
+    #[test]
+    fn test_deskew() {
+        let wedge = test_wedge().0.to_luma8();
+        assert_eq!(skew_degrees(&wedge), 0.);
+
+        // laid on the scanner bed a little crooked
+        let mut bordered = ImageBuffer::from_pixel(1080, 1180, Luma([255]));
+        image::imageops::overlay(&mut bordered, &wedge, 40, 40);
+        let turned = |degrees: f32| {
+            rotate_about_center(
+                &bordered,
+                degrees.to_radians(),
+                RotateInterpolation::Bilinear,
+                Luma([255]),
+            )
+        };
+        let rotated = turned(2.5);
+        let skew = skew_degrees(&rotated);
+        assert!((skew - 2.5).abs() < 0.1, "{}", skew);
+
+        // left as it is unless asked
+        let (_, params) = test_wedge();
+        let rotated_image = DynamicImage::ImageLuma8(rotated.clone());
+        assert!(deskew(&rotated_image, &params, false).unwrap().is_none());
+
+        let params = AnalyzeParams {
+            deskew: true,
+            ..params
+        };
+        let results = analyze(&rotated_image, &params, false).unwrap();
+        for (input, output) in &results.response {
+            assert!(
+                (*input as i32 - *output as i32).abs() < 1000,
+                "{} {}",
+                input,
+                output
+            );
+        }
+        // the sampled patches line up with the straightened scan
+        assert_eq!(results.normalized_image.dimensions(), rotated.dimensions());
+        let detection = detect_grid(&rotated_image, &params).unwrap();
+        assert!(detection.grid.origin_x.abs_diff(40) <= 2);
+        assert!(detection.grid.origin_y.abs_diff(40) <= 2);
+
+        // too crooked to trust
+        let crooked = DynamicImage::ImageLuma8(turned(8.));
+        assert!(analyze(&crooked, &params, false).is_err());
+        let allowed = AnalyzeParams {
+            max_skew_degrees: 10.,
+            ..params
+        };
+        assert!(analyze(&crooked, &allowed, false).is_ok());
+    }
+
+    #[test]
+    fn test_detect_grid() {
+        let (wedge, _) = test_wedge();
+        let step_description = StepDescription::default();
+        let detection = detect_grid(&wedge, &AnalyzeParams::default()).unwrap();
+        assert_eq!((detection.grid.origin_x, detection.grid.origin_y), (0, 0));
+        assert_eq!(detection.grid.square_size, 100);
+
+        // every patch is sampled from a window inside it
+        assert_eq!(
+            detection.sampled_areas.len(),
+            step_description.count as usize
+        );
+        for (n, area) in detection.sampled_areas.iter().enumerate() {
+            let (col, row) = step_description.cell(n as u32);
+            let patch = Rect::at(col as i32 * 100, row as i32 * 100).of_size(100, 100);
+            assert_eq!(patch.intersect(*area), Some(*area));
+        }
+        let overlay = draw_grid_detection(&wedge, &detection);
+        assert!(overlay.pixels().any(|p| *p == Rgb([0, 255, 0])));
+
+        // a thumbnail is caught before anything is sampled
+        let thumbnail = wedge.thumbnail(5, 5);
+        assert!(detect_grid(&thumbnail, &AnalyzeParams::default()).is_err());
+    }
+
+    #[test]
+    fn test_index_strip() {
+        let generate_params = generate::GenerateParams {
+            index_strip: true,
+            columns: 7,
+            optimize_for_range: Some(8.),
+            ..generate::GenerateParams::default()
+        };
+        let step_description = generate_params.step_description(Layout::Grid).unwrap();
+        let wedge = generate::generate_with_params(None, None, Layout::Grid, &generate_params)
+            .unwrap()
+            .crop_imm(
+                generate::MARGIN,
+                generate::MARGIN,
+                step_description.width,
+                step_description.total_height() + index_strip::height(step_description.width),
+            );
+
+        // the standard steps and columns are ignored for the ones in the strip
+        let params = AnalyzeParams {
+            invert: true,
+            index_strip: true,
+            ..AnalyzeParams::default()
+        };
+        let results = analyze(&wedge, &params, false).unwrap();
+        assert_eq!(results.response.len(), step_description.count as usize);
+        for (input, output) in &results.response {
+            assert!((*input as i32 - *output as i32).abs() < 1000);
+        }
+    }
+
+    #[test]
+    fn test_debanding() {
+        let wedge = test_wedge().0.to_luma16();
+        let step_description = StepDescription::default();
+        let grid_analysis = analyze_grid(
+            &DynamicImage::ImageLuma16(wedge.clone()).to_luma8(),
+            &step_description,
+            2,
+        )
+        .unwrap();
+        assert_eq!(
+            detect_banding(&wedge, &step_description, &grid_analysis),
+            None
+        );
+
+        // a ripple every 20 rows, 3000 tones either way, leaving room at the ends to not clip
+        let mut banded = ImageBuffer::from_fn(1000, 1100, |x, y| {
+            let tone = wedge.get_pixel(x, y)[0] as f64 * 0.8 + 6553.;
+            let ripple = 3000. * (std::f64::consts::TAU * y as f64 / 20.).sin();
+            Luma([(tone + ripple).round() as u16])
+        });
+        let sampled_areas = sampled_areas(&step_description, &grid_analysis);
+        let deviation = |image: &ImageBuffer<Luma<u16>, Vec<u16>>| {
+            let samples = collect_samples(
+                image,
+                &sampled_areas,
+                1,
+                SampleStrategy::default(),
+                &SampleMask::default(),
+                &Cancel::default(),
+            )
+            .unwrap();
+            samples.deviations.iter().sum::<f64>() / samples.deviations.len() as f64
+        };
+        let before = deviation(&banded);
+
+        let banding = detect_banding(&banded, &step_description, &grid_analysis).unwrap();
+        assert!((banding.period - 20.).abs() < 0.5, "{:?}", banding);
+        assert!((banding.amplitude() - 3000.).abs() < 300., "{:?}", banding);
+        remove_banding(&mut banded, &banding);
+        let after = deviation(&banded);
+        assert!(after < before / 10., "{} before, {} after", before, after);
+    }
+
+    #[test]
+    fn test_too_small_image() {
+        let thumbnail = DynamicImage::new_luma16(5, 5);
+        for layout in [Layout::Grid, Layout::Separators] {
+            let params = AnalyzeParams {
+                layout,
+                ..AnalyzeParams::default()
+            };
+            assert!(analyze(&thumbnail, &params, false).is_err());
+        }
+
+        let Err(error) = analyze(&thumbnail, &AnalyzeParams::default(), false) else {
+            panic!("analyzed a 5x5 image");
+        };
+        assert!(error
+            .to_string()
+            .contains("too small for a 10-column wedge"));
+    }
 }
@@ -1,13 +1,1049 @@
-use image::{DynamicImage, Luma};
-use imageproc::map::map_pixels;
-use splines::Spline;
-
-// Note, I'd like to catch the possible clamped sample error and return a result here
-// however I'm not sure how to pop the error out of the closure handed to map_pixels;
-pub fn apply(image: &DynamicImage, curve: &Spline<f64, f64>) -> DynamicImage {
-    let input_image_16 = image.to_luma16();
-
-    return DynamicImage::ImageLuma16(map_pixels(&input_image_16, |_x, _y, p| {
-        Luma([curve.clamped_sample(p[0] as f64).unwrap() as u16])
-    }));
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageBuffer, Luma, Rgba};
+use rayon::prelude::*;
+use splines::{Interpolation, Key, Spline};
+
+use super::analyze::parse_positive;
+use super::cancel::Cancel;
+use super::curve::{check_domain, sample_curve, sample_curve_lut, CURVE_DOMAIN_MAX};
+use super::luma::{self, LumaWeights};
+
+/* color images are converted to greyscale with `luma_weights` before the curve is applied
+ *
+ * The output keeps the bit depth of the input, 8bit images come out 8bit and anything deeper 16bit.
+ */
+pub fn apply(
+    image: &DynamicImage,
+    curve: &Spline<f64, f64>,
+    luma_weights: LumaWeights,
+) -> anyhow::Result<DynamicImage> {
+    // nothing can cancel the default token
+    apply_cancellable(image, curve, luma_weights, &Cancel::default())
+}
+
+/* `apply`, giving up with `Cancelled` between rows once `cancel` is set
+ *
+ * Also fails if the curve doesn't span the tones of the image, see `curve::check_domain`.
+ */
+pub fn apply_cancellable(
+    image: &DynamicImage,
+    curve: &Spline<f64, f64>,
+    luma_weights: LumaWeights,
+    cancel: &Cancel,
+) -> anyhow::Result<DynamicImage> {
+    check_domain(curve)?;
+    Ok(match image {
+        // 8bit images are scaled up into the curve's domain, sampled, and scaled back down so
+        // the output keeps the input's bit depth
+        DynamicImage::ImageLuma8(input_image_8) => {
+            DynamicImage::ImageLuma8(map_rows(input_image_8, cancel, |v| {
+                Ok(sample_in_domain(curve, v as f64, u8::MAX as f64)?.round() as u8)
+            })?)
+        }
+        _ => {
+            let input_image_16 = luma::to_luma16(image, luma_weights);
+
+            let curved = DynamicImage::ImageLuma16(map_rows(&input_image_16, cancel, |v| {
+                Ok(sample_curve(curve, v as f64)? as u16)
+            })?);
+            // 8bit color is curved through its 16bit grey, then brought back down
+            match is_16bit(image) {
+                true => curved,
+                false => DynamicImage::ImageLuma8(curved.to_luma8()),
+            }
+        }
+    })
+}
+
+// Whether `image` holds more than 8 bits a channel
+fn is_16bit(image: &DynamicImage) -> bool {
+    let color = image.color();
+    color.bytes_per_pixel() / color.channel_count() > 1
+}
+
+/* Applies `curve` to the value of each pixel, the V of HSV, leaving its hue and saturation alone
+ *
+ * The value is the brightest of a pixel's channels, and scaling all three channels by the same
+ * factor changes only the value, so every channel is scaled by how much the curve moves the
+ * value. Unlike `apply` color images stay in color. The output keeps the input's channel layout
+ * and bit depth.
+ */
+pub fn apply_hsv(image: &DynamicImage, curve: &Spline<f64, f64>) -> anyhow::Result<DynamicImage> {
+    let lut = lut_16bit(curve)?;
+    let mut curved = image.to_rgba16();
+    for Rgba([r, g, b, _]) in curved.pixels_mut() {
+        let value = (*r).max(*g).max(*b);
+        let curved_value = lut[value as usize];
+        for channel in [r, g, b] {
+            *channel = match value {
+                // black has no hue to keep, it becomes the grey the curve maps black to
+                0 => curved_value,
+                _ => (*channel as f64 * curved_value as f64 / value as f64)
+                    .round()
+                    .min(u16::MAX as f64) as u16,
+            };
+        }
+    }
+
+    Ok(in_layout_of(DynamicImage::ImageRgba16(curved), image))
+}
+
+/* Applies a curve to each of the red, green, and blue channels of `image` on its own, `curves` in
+ * that order, leaving alpha alone
+ *
+ * For processes printed from separate negatives for each color, where every channel is its own
+ * tone. The same curve three times corrects them all alike. The output keeps the input's channel
+ * layout and bit depth.
+ */
+pub fn apply_rgb(
+    image: &DynamicImage,
+    curves: [&Spline<f64, f64>; 3],
+) -> anyhow::Result<DynamicImage> {
+    let luts = curves
+        .iter()
+        .map(|curve| lut_16bit(curve))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut curved = image.to_rgba16();
+    for pixel in curved.pixels_mut() {
+        // alpha is the fourth channel, past the last curve
+        for (channel, lut) in pixel.0.iter_mut().zip(&luts) {
+            *channel = lut[*channel as usize];
+        }
+    }
+    Ok(in_layout_of(DynamicImage::ImageRgba16(curved), image))
+}
+
+// `curved` converted back to the channels and bit depth of `image`, the image it was curved from
+fn in_layout_of(curved: DynamicImage, image: &DynamicImage) -> DynamicImage {
+    let color = image.color();
+    match (color.has_color(), color.has_alpha(), is_16bit(image)) {
+        (false, false, false) => DynamicImage::ImageLuma8(curved.to_luma8()),
+        (false, false, true) => DynamicImage::ImageLuma16(curved.to_luma16()),
+        (false, true, false) => DynamicImage::ImageLumaA8(curved.to_luma_alpha8()),
+        (false, true, true) => DynamicImage::ImageLumaA16(curved.to_luma_alpha16()),
+        (true, false, false) => DynamicImage::ImageRgb8(curved.to_rgb8()),
+        (true, false, true) => DynamicImage::ImageRgb16(curved.to_rgb16()),
+        (true, true, false) => DynamicImage::ImageRgba8(curved.to_rgba8()),
+        (true, true, true) => curved,
+    }
+}
+
+// What `apply` maps each 8bit tone to, indexed by tone
+pub fn lut_8bit(curve: &Spline<f64, f64>) -> anyhow::Result<Vec<u8>> {
+    check_domain(curve)?;
+    (0..=u8::MAX)
+        .map(|v| Ok(sample_in_domain(curve, v as f64, u8::MAX as f64)?.round() as u8))
+        .collect()
+}
+
+// What `apply` maps each 16bit tone to, indexed by tone
+pub fn lut_16bit(curve: &Spline<f64, f64>) -> anyhow::Result<Vec<u16>> {
+    check_domain(curve)?;
+    (0..=u16::MAX)
+        .map(|v| Ok(sample_curve(curve, v as f64)? as u16))
+        .collect()
+}
+
+// Images with fewer pixels than this are mapped on one thread, too quick to be worth splitting up
+const PARALLEL_MIN_PIXELS: u64 = 512 * 512;
+
+// Rows of an image mapped together on one thread
+const PARALLEL_CHUNK_ROWS: usize = 16;
+
+/* maps every pixel of `image` through `f`, checking `cancel` between rows
+ *
+ * Large images are split into chunks of rows mapped across every core, a print sized scan is tens
+ * of millions of pixels each sampled from the curve on its own. Either way the output is the same.
+ */
+fn map_rows<T: image::Primitive + Send + Sync>(
+    image: &ImageBuffer<Luma<T>, Vec<T>>,
+    cancel: &Cancel,
+    f: impl Fn(T) -> anyhow::Result<T> + Sync,
+) -> anyhow::Result<ImageBuffer<Luma<T>, Vec<T>>> {
+    if (image.width() as u64 * image.height() as u64) < PARALLEL_MIN_PIXELS {
+        map_rows_serial(image, cancel, f)
+    } else {
+        map_rows_parallel(image, cancel, f)
+    }
+}
+
+// `map_rows` a row at a time on the calling thread
+fn map_rows_serial<T: image::Primitive>(
+    image: &ImageBuffer<Luma<T>, Vec<T>>,
+    cancel: &Cancel,
+    f: impl Fn(T) -> anyhow::Result<T>,
+) -> anyhow::Result<ImageBuffer<Luma<T>, Vec<T>>> {
+    let mut output = ImageBuffer::new(image.width(), image.height());
+    for (input_row, output_row) in image.rows().zip(output.rows_mut()) {
+        cancel.check()?;
+        for (input, output) in input_row.zip(output_row) {
+            *output = Luma([f(input[0])?]);
+        }
+    }
+    Ok(output)
+}
+
+// `map_rows` a chunk of `PARALLEL_CHUNK_ROWS` rows at a time across rayon's threads
+fn map_rows_parallel<T: image::Primitive + Send + Sync>(
+    image: &ImageBuffer<Luma<T>, Vec<T>>,
+    cancel: &Cancel,
+    f: impl Fn(T) -> anyhow::Result<T> + Sync,
+) -> anyhow::Result<ImageBuffer<Luma<T>, Vec<T>>> {
+    let mut output = ImageBuffer::new(image.width(), image.height());
+    let chunk = (image.width() as usize * PARALLEL_CHUNK_ROWS).max(1);
+    output
+        .par_chunks_mut(chunk)
+        .zip(image.as_raw().par_chunks(chunk))
+        .try_for_each(|(output, input)| -> anyhow::Result<()> {
+            cancel.check()?;
+            for (output, input) in output.iter_mut().zip(input) {
+                *output = f(*input)?;
+            }
+            Ok(())
+        })?;
+    Ok(output)
+}
+
+/* Pulls every output of `curve` toward the identity mapping, leaving `strength` of the correction.
+ * 1.0 is the full curve, 0.0 is no correction at all, and anything in between applies part of the
+ * correction for when the full curve over-corrects.
+ */
+pub fn blend_with_identity(curve: &Spline<f64, f64>, strength: f64) -> Spline<f64, f64> {
+    Spline::from_vec(
+        curve
+            .keys()
+            .iter()
+            .map(|key| {
+                Key::new(
+                    key.t,
+                    strength * key.value + (1. - strength) * key.t,
+                    key.interpolation,
+                )
+            })
+            .collect(),
+    )
+}
+
+// How finely `limit_to_range` samples the curve, the curve is rebuilt from this many points
+const RANGE_LIMIT_ENTRIES: usize = 1025;
+
+/* Limits the correction of `curve` to the tones in `range`, everything further than `feather`
+ * outside the range is mapped through unchanged.
+ *
+ * Within `feather` (a fraction of the full range) of either end the curve fades smoothly into the
+ * identity mapping so the edge of the range doesn't leave a visible step. The result is rebuilt
+ * as a linear curve through evenly spaced samples and the edges of the range and feathering.
+ */
+pub fn limit_to_range(
+    curve: &Spline<f64, f64>,
+    range: ToneRange,
+    feather: f64,
+) -> anyhow::Result<Spline<f64, f64>> {
+    let mut inputs: Vec<f64> = sample_curve_lut(curve, RANGE_LIMIT_ENTRIES)?
+        .into_iter()
+        .map(|(input, _)| input)
+        .collect();
+    inputs.extend(
+        [
+            range.low - feather,
+            range.low,
+            range.high,
+            range.high + feather,
+        ]
+        .map(|edge| edge.clamp(0., 1.) * CURVE_DOMAIN_MAX),
+    );
+    inputs.sort_by(f64::total_cmp);
+    inputs.dedup();
+
+    inputs
+        .into_iter()
+        .map(|input| {
+            let weight = range.weight(input / CURVE_DOMAIN_MAX, feather);
+            let output = weight * sample_curve(curve, input)? + (1. - weight) * input;
+            Ok(Key::new(input, output, Interpolation::Linear))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map(Spline::from_vec)
+}
+
+/* Wraps `curve` in gamma conversions, decoding tones from `input_gamma` to linear before the curve
+ * and encoding them to `output_gamma` after it, either skipped when None
+ *
+ * The result has a key for every 16bit tone, with the conversions worked out exactly rather than
+ * sampled from curves of their own. Decoding crushes the shadows into the first few tones, where
+ * interpolating a sampled curve would lose them.
+ */
+pub fn convert_gamma(
+    curve: &Spline<f64, f64>,
+    input_gamma: Option<f64>,
+    output_gamma: Option<f64>,
+) -> anyhow::Result<Spline<f64, f64>> {
+    // `tone` raised to `exponent` as a fraction of the full range
+    let power = |tone: f64, exponent: f64| {
+        (tone.clamp(0., CURVE_DOMAIN_MAX) / CURVE_DOMAIN_MAX).powf(exponent) * CURVE_DOMAIN_MAX
+    };
+    (0..=u16::MAX)
+        .map(|tone| {
+            let input = tone as f64;
+            let linear = input_gamma.map_or(input, |gamma| power(input, gamma));
+            let output = sample_curve(curve, linear)?;
+            let output = output_gamma.map_or(output, |gamma| power(output, 1. / gamma));
+            Ok(Key::new(input, output, Interpolation::Linear))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map(Spline::from_vec)
+}
+
+// A band of tones as fractions of the full range, 0 is black and 1 white
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ToneRange {
+    pub low: f64,
+    pub high: f64,
+}
+
+impl ToneRange {
+    // How much of the correction applies to `tone`, easing from 1 inside to 0 `feather` outside
+    fn weight(&self, tone: f64, feather: f64) -> f64 {
+        let distance = (self.low - tone).max(tone - self.high);
+        if distance <= 0. {
+            1.
+        } else if distance >= feather {
+            0.
+        } else {
+            // smoothstep, so the correction eases in and out without a kink
+            let x = 1. - distance / feather;
+            x * x * (3. - 2. * x)
+        }
+    }
+}
+
+// parses "low,high", as in `--range 0,0.3` for only the darkest 30%
+impl FromStr for ToneRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (low, high) = s
+            .split_once(',')
+            .ok_or(anyhow!("expected a range like 0,0.3, got {}", s))?;
+        let (low, high): (f64, f64) = (low.trim().parse()?, high.trim().parse()?);
+        ToneRange { low, high }.validate()
+    }
+}
+
+impl ToneRange {
+    // The range back, or an error unless it's two increasing values from 0 to 1
+    pub fn validate(self) -> anyhow::Result<Self> {
+        let ToneRange { low, high } = self;
+        if !(0. ..=1.).contains(&low) || !(0. ..=1.).contains(&high) || low >= high {
+            return Err(anyhow!(
+                "range must be two increasing values from 0 to 1, got {},{}",
+                low,
+                high
+            ));
+        }
+        Ok(self)
+    }
+}
+
+// Options controlling how a curve is applied to an image
+#[derive(clap::Args, Debug, Clone)]
+pub struct ApplyParams {
+    /// Resize the corrected image to exactly WxH, e.g. 800x600
+    #[arg(long, conflicts_with = "max_dim")]
+    pub resize: Option<Resize>,
+
+    /// Resize the corrected image to fit within N pixels on its longest side
+    #[arg(long)]
+    pub max_dim: Option<u32>,
+
+    /// How much red, green, and blue contribute to the tone of a color image, as r,g,b
+    #[arg(long, default_value_t)]
+    pub luma_weights: LumaWeights,
+
+    /// How much of the correction to apply, 1.0 is the full curve and 0.0 leaves the image as is
+    #[arg(long, default_value_t = 1.0)]
+    pub strength: f64,
+
+    /// Force the channel layout of the corrected image. By default it keeps the layout --domain
+    /// produces, greyscale for luma and the image's own for hsv and rgb
+    #[arg(long, value_enum)]
+    pub output_color: Option<OutputColor>,
+
+    /// Only correct tones in this range, as low,high fractions of the full range, e.g. 0,0.3 for
+    /// the shadows. Tones outside are left as they are
+    #[arg(long)]
+    pub range: Option<ToneRange>,
+
+    /// How far past the ends of --range the correction fades out, as a fraction of the full range
+    #[arg(long, default_value_t = 0.05, requires = "range")]
+    pub feather: f64,
+
+    /// Read, correct, and write a tiff a strip at a time rather than loading all of it, for scans
+    /// too large to fit in memory. The output is a greyscale tiff laid out in the same strips
+    #[arg(long, conflicts_with_all = ["resize", "max_dim", "output_color"])]
+    pub strips: bool,
+
+    /// The gamma the image is encoded in, e.g. 2.2. Tones are decoded to linear before the curve
+    /// is applied. By default the curve is applied to the tones as they are
+    #[arg(long, value_parser = parse_positive)]
+    pub input_gamma: Option<f64>,
+
+    /// The gamma to encode the corrected image in for the printer, e.g. 1.8. By default the
+    /// curve's output is written as it is
+    #[arg(long, value_parser = parse_positive)]
+    pub output_gamma: Option<f64>,
+
+    /// What the curve is applied to
+    #[arg(long, value_enum, default_value_t, conflicts_with = "strips")]
+    pub domain: Domain,
+}
+
+impl ApplyParams {
+    // the size to resize the corrected image to, if any
+    pub fn size(&self) -> Option<Resize> {
+        self.resize.or(self.max_dim.map(Resize::MaxDimension))
+    }
+
+    /* `curve` with the strength and range of these params applied to it, then wrapped in their
+     * gamma conversions. Strength and range shape the correction in the curve's own linear tones
+     */
+    pub fn adjust_curve(&self, curve: &Spline<f64, f64>) -> anyhow::Result<Spline<f64, f64>> {
+        let curve = blend_with_identity(curve, self.strength);
+        let curve = match self.range {
+            Some(range) => limit_to_range(&curve, range, self.feather.max(0.))?,
+            None => curve,
+        };
+        match (self.input_gamma, self.output_gamma) {
+            (None, None) => Ok(curve),
+            (input_gamma, output_gamma) => convert_gamma(&curve, input_gamma, output_gamma),
+        }
+    }
+}
+
+// What of an image the curve is applied to
+#[derive(Debug, Default, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Domain {
+    /// The image's tone, color images are converted to greyscale with --luma-weights first
+    #[default]
+    Luma,
+    /// The value of HSV, keeping each pixel's hue and saturation so color images stay in color
+    Hsv,
+    /// Red, green, and blue each on their own, for negatives separated by color. Greyscale images
+    /// are corrected as for luma
+    Rgb,
+}
+
+// The channel layout to write a corrected image out with
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum OutputColor {
+    /// A single grey channel
+    Luma,
+    /// Grey copied into red, green, and blue, for tools that only take color images
+    Rgb,
+}
+
+// Converts `image` to the `color` layout, keeping its bit depth
+pub fn convert_output(image: DynamicImage, color: OutputColor) -> DynamicImage {
+    match (color, is_16bit(&image)) {
+        (OutputColor::Luma, false) => DynamicImage::ImageLuma8(image.to_luma8()),
+        (OutputColor::Luma, true) => DynamicImage::ImageLuma16(image.to_luma16()),
+        (OutputColor::Rgb, false) => DynamicImage::ImageRgb8(image.to_rgb8()),
+        (OutputColor::Rgb, true) => DynamicImage::ImageRgb16(image.to_rgb16()),
+    }
+}
+
+// How to resize an image after the curve has been applied
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resize {
+    // exactly this width and height
+    Exact(u32, u32),
+    // fit within this many pixels on the longest side, keeping the aspect ratio
+    MaxDimension(u32),
+}
+
+// parses "WxH", as in `--resize 800x600`
+impl FromStr for Resize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (width, height) = s
+            .split_once('x')
+            .ok_or(anyhow!("expected a size like 800x600, got {}", s))?;
+        Ok(Resize::Exact(width.trim().parse()?, height.trim().parse()?))
+    }
+}
+
+pub fn resize(image: &DynamicImage, size: Resize) -> DynamicImage {
+    match size {
+        Resize::Exact(width, height) => image.resize_exact(width, height, FilterType::Lanczos3),
+        Resize::MaxDimension(max) => image.resize(max, max, FilterType::Lanczos3),
+    }
+}
+
+/* Applies the curve and then resizes the result. The curve is always applied at full resolution,
+ * resampling first would blend neighboring tones together before they are corrected.
+ */
+pub fn apply_resized(
+    image: &DynamicImage,
+    curve: &Spline<f64, f64>,
+    luma_weights: LumaWeights,
+    size: Resize,
+) -> anyhow::Result<DynamicImage> {
+    Ok(resize(&apply(image, curve, luma_weights)?, size))
+}
+
+/* A quick approximation of `apply` for previews, the image is shrunk to fit within `max_dimension`
+ * pixels first so only as many pixels as can be seen go through the curve.
+ *
+ * Shrinking first blends neighboring tones together before they're corrected, unlike
+ * `apply_resized`, but across the smooth tones of a photograph the difference is too small to see
+ * at preview sizes. Save the full resolution `apply`, not this. Images that already fit are
+ * applied as they are.
+ */
+pub fn apply_preview(
+    image: &DynamicImage,
+    curve: &Spline<f64, f64>,
+    luma_weights: LumaWeights,
+    max_dimension: u32,
+) -> anyhow::Result<DynamicImage> {
+    if image.width().max(image.height()) <= max_dimension {
+        return apply(image, curve, luma_weights);
+    }
+    let shrunk = image.resize(max_dimension, max_dimension, FilterType::Triangle);
+    apply(&shrunk, curve, luma_weights)
+}
+
+/* Samples `curve` with a `value` from the range [0, value_max], mapping it onto the curve's
+ * domain before sampling and back into [0, value_max] afterwards.
+ */
+fn sample_in_domain(curve: &Spline<f64, f64>, value: f64, value_max: f64) -> anyhow::Result<f64> {
+    let scale = CURVE_DOMAIN_MAX / value_max;
+    let sample = sample_curve(curve, value * scale)?;
+    Ok((sample / scale).clamp(0., value_max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ColorType, ImageBuffer, Rgb};
+
+    // maps x -> 65535 - x
+    fn inverting_curve() -> Spline<f64, f64> {
+        Spline::from_vec(vec![
+            Key::new(0., CURVE_DOMAIN_MAX, Interpolation::Linear),
+            Key::new(CURVE_DOMAIN_MAX, 0., Interpolation::Linear),
+        ])
+    }
+
+    #[test]
+    fn test_parallel_matches_serial() {
+        let curve = Spline::from_vec(vec![
+            Key::new(0., 0., Interpolation::CatmullRom),
+            Key::new(0., 0., Interpolation::CatmullRom),
+            Key::new(20000., 35000., Interpolation::CatmullRom),
+            Key::new(
+                CURVE_DOMAIN_MAX,
+                CURVE_DOMAIN_MAX,
+                Interpolation::CatmullRom,
+            ),
+            Key::new(
+                CURVE_DOMAIN_MAX,
+                CURVE_DOMAIN_MAX,
+                Interpolation::CatmullRom,
+            ),
+        ]);
+        // large enough to be split up, with a last chunk of rows that comes up short
+        let (width, height) = (700, 403);
+        assert!(width as u64 * height as u64 >= PARALLEL_MIN_PIXELS);
+        let image: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_fn(width, height, |x, y| Luma([(x * 97 + y * 31) as u16]));
+        let sample = |v: u16| Ok(sample_curve(&curve, v as f64)? as u16);
+
+        let cancel = Cancel::default();
+        let serial = map_rows_serial(&image, &cancel, sample).unwrap();
+        let parallel = map_rows_parallel(&image, &cancel, sample).unwrap();
+        assert_eq!(serial.as_raw(), parallel.as_raw());
+        assert_eq!(
+            apply(
+                &DynamicImage::ImageLuma16(image.clone()),
+                &curve,
+                LumaWeights::default()
+            )
+            .unwrap()
+            .as_bytes(),
+            DynamicImage::ImageLuma16(serial).as_bytes()
+        );
+
+        // cancelling still stops it, and a failing sample fails the whole image
+        cancel.cancel();
+        let error = map_rows_parallel(&image, &cancel, sample).unwrap_err();
+        assert!(error.is::<crate::cancel::Cancelled>());
+        let failing = |_: u16| Err(anyhow!("unsampleable"));
+        assert!(map_rows_parallel(&image, &Cancel::default(), failing).is_err());
+    }
+
+    #[test]
+    fn test_apply_keeps_bit_depth() {
+        let rgb_8 = DynamicImage::ImageRgb8(ImageBuffer::from_fn(256, 1, |x, _| Rgb([x as u8; 3])));
+        let rgb_16 = DynamicImage::ImageRgb16(rgb_8.to_rgb16());
+        let weights = LumaWeights::default();
+
+        let curved_8 = apply(&rgb_8, &inverting_curve(), weights).unwrap();
+        assert_eq!(curved_8.color(), ColorType::L8);
+        let curved_16 = apply(&rgb_16, &inverting_curve(), weights).unwrap();
+        assert_eq!(curved_16.color(), ColorType::L16);
+
+        // curved in 16bit then brought down, the same as the 16bit output would be
+        assert_eq!(curved_8.to_luma8(), curved_16.to_luma8());
+        for (x, pixel) in curved_8.to_luma8().pixels().enumerate() {
+            assert_eq!(pixel[0], u8::MAX - x as u8);
+        }
+
+        let grey_alpha = DynamicImage::ImageLumaA8(ImageBuffer::new(1, 1));
+        let curved = apply(&grey_alpha, &inverting_curve(), weights).unwrap();
+        assert_eq!(curved.color(), ColorType::L8);
+    }
+
+    #[test]
+    fn test_apply_rgb() {
+        let identity = Spline::from_vec(vec![
+            Key::new(0., 0., Interpolation::Linear),
+            Key::new(CURVE_DOMAIN_MAX, CURVE_DOMAIN_MAX, Interpolation::Linear),
+        ]);
+        let halving = Spline::from_vec(vec![
+            Key::new(0., 0., Interpolation::Linear),
+            Key::new(
+                CURVE_DOMAIN_MAX,
+                CURVE_DOMAIN_MAX / 2.,
+                Interpolation::Linear,
+            ),
+        ]);
+        let image = DynamicImage::ImageRgba16(ImageBuffer::from_fn(2, 1, |x, _| match x {
+            0 => Rgba([10000, 20000, 30000, 40000]),
+            _ => Rgba([60000, 0, u16::MAX, u16::MAX]),
+        }));
+
+        // each channel goes through its own curve, alpha through none of them
+        let curved = apply_rgb(&image, [&inverting_curve(), &identity, &halving]).unwrap();
+        assert_eq!(curved.color(), ColorType::Rgba16);
+        let curved = curved.to_rgba16();
+        assert_eq!(curved.get_pixel(0, 0).0, [55535, 20000, 15000, 40000]);
+        assert_eq!(curved.get_pixel(1, 0).0, [5535, 0, u16::MAX / 2, u16::MAX]);
+
+        // an 8bit color image stays one
+        let rgb8 = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(1, 1, Rgb([0, 128, 255])));
+        let curved = apply_rgb(&rgb8, [&identity; 3]).unwrap();
+        assert_eq!(curved.color(), ColorType::Rgb8);
+        assert_eq!(curved.to_rgb8().get_pixel(0, 0).0, [0, 128, 255]);
+    }
+
+    #[test]
+    fn test_apply_rejects_short_domain() {
+        // a curve made for tones 0 to 1000 that was never scaled up into 16bit
+        let short = Spline::from_vec(vec![
+            Key::new(0., 0., Interpolation::Linear),
+            Key::new(1000., 1000., Interpolation::Linear),
+        ]);
+        let image = DynamicImage::ImageLuma16(ImageBuffer::from_fn(16, 1, |x, _| {
+            Luma([(x * 4096) as u16])
+        }));
+        let error = apply(&image, &short, LumaWeights::default()).unwrap_err();
+        assert!(error.to_string().contains("0 to 1000"), "{}", error);
+        assert!(apply_hsv(&image, &short).is_err());
+        assert!(lut_8bit(&short).is_err());
+
+        // nor can a curve without any points be applied
+        assert!(apply(&image, &Spline::from_vec(vec![]), LumaWeights::default()).is_err());
+        // a curve trimmed a little short of white still covers the domain
+        let trimmed = Spline::from_vec(vec![
+            Key::new(0., 0., Interpolation::Linear),
+            Key::new(65500., 65500., Interpolation::Linear),
+        ]);
+        assert!(apply(&image, &trimmed, LumaWeights::default()).is_ok());
+
+        // but not a Catmull-Rom curve without the keys to draw its ends
+        let undrawn = Spline::from_vec(vec![
+            Key::new(0., 0., Interpolation::CatmullRom),
+            Key::new(30000., 35000., Interpolation::CatmullRom),
+            Key::new(65535., 65535., Interpolation::CatmullRom),
+        ]);
+        assert!(apply(&image, &undrawn, LumaWeights::default()).is_err());
+        assert!(lut_16bit(&undrawn).is_err());
+    }
+
+    #[test]
+    fn test_apply_8bit_scales_into_curve_domain() {
+        let buffer: ImageBuffer<Luma<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(256, 1, |x, _| Luma([x as u8]));
+        let result = apply(
+            &DynamicImage::ImageLuma8(buffer),
+            &inverting_curve(),
+            LumaWeights::default(),
+        )
+        .unwrap();
+
+        assert_eq!(result.color(), ColorType::L8);
+        let result_8 = result.to_luma8();
+        for x in 0..256 {
+            assert_eq!(result_8.get_pixel(x, 0)[0], 255 - x as u8);
+        }
+    }
+
+    #[test]
+    fn test_apply_hsv() {
+        // (hue in degrees, saturation) of an rgb pixel
+        let hue_saturation = |[r, g, b]: [f64; 3]| {
+            let (max, min) = (r.max(g).max(b), r.min(g).min(b));
+            let chroma = max - min;
+            let hue = if chroma == 0. {
+                0.
+            } else if max == r {
+                60. * ((g - b) / chroma).rem_euclid(6.)
+            } else if max == g {
+                60. * ((b - r) / chroma + 2.)
+            } else {
+                60. * ((r - g) / chroma + 4.)
+            };
+            (hue, if max == 0. { 0. } else { chroma / max })
+        };
+        let colors: Vec<Rgb<u16>> = [
+            [60000, 20000, 10000],
+            [12000, 50000, 31000],
+            [9000, 9000, 40000],
+            [45000, 45000, 45000],
+            [0, 0, 0],
+        ]
+        .into_iter()
+        .map(Rgb)
+        .collect();
+        let image = ImageBuffer::from_fn(colors.len() as u32, 1, |x, _| colors[x as usize]);
+        // darkens the mid tones like a typical correction
+        let darken = Spline::from_vec(vec![
+            Key::new(0., 0., Interpolation::Linear),
+            Key::new(
+                CURVE_DOMAIN_MAX / 2.,
+                CURVE_DOMAIN_MAX / 4.,
+                Interpolation::Linear,
+            ),
+            Key::new(CURVE_DOMAIN_MAX, CURVE_DOMAIN_MAX, Interpolation::Linear),
+        ]);
+
+        let curved = apply_hsv(&DynamicImage::ImageRgb16(image), &darken).unwrap();
+        assert_eq!(curved.color(), ColorType::Rgb16);
+        for (before, after) in colors.iter().zip(curved.to_rgb16().pixels()) {
+            let (before, after) = (before.0.map(|c| c as f64), after.0.map(|c| c as f64));
+            let value = before.iter().copied().fold(0., f64::max);
+            let curved_value = after.iter().copied().fold(0., f64::max);
+            assert!((curved_value - sample_curve(&darken, value).unwrap()).abs() <= 1.);
+
+            let (hue, saturation) = hue_saturation(before);
+            let (curved_hue, curved_saturation) = hue_saturation(after);
+            assert!((hue - curved_hue).abs() < 0.1, "{:?} {:?}", before, after);
+            assert!((saturation - curved_saturation).abs() < 0.001);
+        }
+
+        // 8bit grey keeps its layout and matches applying to luma, give or take rounding
+        let grey: ImageBuffer<Luma<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(256, 1, |x, _| Luma([x as u8]));
+        let grey = DynamicImage::ImageLuma8(grey);
+        let curved = apply_hsv(&grey, &darken).unwrap();
+        assert_eq!(curved.color(), ColorType::L8);
+        let luma = apply(&grey, &darken, LumaWeights::default())
+            .unwrap()
+            .to_luma8();
+        for (hsv, luma) in curved.to_luma8().pixels().zip(luma.pixels()) {
+            assert!(hsv[0].abs_diff(luma[0]) <= 1);
+        }
+    }
+
+    #[test]
+    fn test_chained_curves_match_sequential_apply() {
+        let brighten = Spline::from_vec(vec![
+            Key::new(0., 0., Interpolation::Linear),
+            Key::new(30000., 40000., Interpolation::Linear),
+            Key::new(CURVE_DOMAIN_MAX, CURVE_DOMAIN_MAX, Interpolation::Linear),
+        ]);
+        let image = DynamicImage::ImageLuma16(ImageBuffer::from_fn(256, 256, |x, y| {
+            Luma([(y * 256 + x) as u16])
+        }));
+        let weights = LumaWeights::default();
+
+        let sequential = apply(
+            &apply(&image, &brighten, weights).unwrap(),
+            &inverting_curve(),
+            weights,
+        )
+        .unwrap()
+        .to_luma16();
+        let composed = crate::curve::compose_curves(&[brighten, inverting_curve()]).unwrap();
+        let chained = apply(&image, &composed, weights).unwrap().to_luma16();
+
+        // sequential apply rounds between the curves, the chain doesn't
+        for (a, b) in sequential.pixels().zip(chained.pixels()) {
+            assert!(a[0].abs_diff(b[0]) <= 1, "{} and {}", a[0], b[0]);
+        }
+    }
+
+    #[test]
+    fn test_apply_preview_matches_apply() {
+        let curve = Spline::from_vec(vec![
+            Key::new(0., 0., Interpolation::Linear),
+            Key::new(20000., 40000., Interpolation::Linear),
+            Key::new(CURVE_DOMAIN_MAX, CURVE_DOMAIN_MAX, Interpolation::Linear),
+        ]);
+        // a smooth ramp, like the tones of a photograph
+        let image = DynamicImage::ImageLuma16(ImageBuffer::from_fn(2000, 500, |x, _| {
+            Luma([(x as f64 / 1999. * CURVE_DOMAIN_MAX) as u16])
+        }));
+        let weights = LumaWeights::default();
+
+        let preview = apply_preview(&image, &curve, weights, 400)
+            .unwrap()
+            .to_luma16();
+        assert_eq!(preview.dimensions(), (400, 100));
+        let full = resize(
+            &apply(&image, &curve, weights).unwrap(),
+            Resize::Exact(400, 100),
+        )
+        .to_luma16();
+        // within half a percent
+        for (a, b) in preview.pixels().zip(full.pixels()) {
+            assert!(a[0].abs_diff(b[0]) < 330, "{} and {}", a[0], b[0]);
+        }
+
+        // small images aren't shrunk at all
+        let small = DynamicImage::ImageLuma16(ImageBuffer::from_pixel(10, 10, Luma([1000])));
+        assert_eq!(
+            apply_preview(&small, &curve, weights, 400).unwrap(),
+            apply(&small, &curve, weights).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_blend_with_identity() {
+        let curve = inverting_curve();
+        let half = blend_with_identity(&curve, 0.5);
+        for x in [0., 1000., CURVE_DOMAIN_MAX / 3., CURVE_DOMAIN_MAX] {
+            let full = curve.clamped_sample(x).unwrap();
+            assert_eq!(half.clamped_sample(x).unwrap(), (full + x) / 2.);
+        }
+
+        assert_eq!(blend_with_identity(&curve, 1.).keys(), curve.keys());
+        for key in blend_with_identity(&curve, 0.).keys() {
+            assert_eq!(key.value, key.t);
+        }
+    }
+
+    #[test]
+    fn test_convert_gamma() {
+        let identity = Spline::from_vec(vec![
+            Key::new(0., 0., Interpolation::Linear),
+            Key::new(CURVE_DOMAIN_MAX, CURVE_DOMAIN_MAX, Interpolation::Linear),
+        ]);
+
+        // decoding from 2.2 and encoding to 1.8 through the identity re-encodes every tone
+        let lut = lut_16bit(&convert_gamma(&identity, Some(2.2), Some(1.8)).unwrap()).unwrap();
+        for tone in [0, 1000, 16384, 32768, 50000, u16::MAX] {
+            let expected = (tone as f64 / CURVE_DOMAIN_MAX).powf(2.2 / 1.8) * CURVE_DOMAIN_MAX;
+            assert!(
+                (lut[tone as usize] as f64 - expected).abs() <= 1.,
+                "{} became {}, expected {}",
+                tone,
+                lut[tone as usize],
+                expected
+            );
+        }
+        // darker mid tones, the ends stay put
+        assert!(lut[32768] < 32768);
+        assert_eq!(lut[u16::MAX as usize], u16::MAX);
+
+        // decoding alone leaves the image linear, and the same gamma both ways changes nothing
+        let decoded = lut_16bit(&convert_gamma(&identity, Some(2.2), None).unwrap()).unwrap();
+        let expected = 0.5f64.powf(2.2) * CURVE_DOMAIN_MAX;
+        assert!((decoded[32767] as f64 - expected).abs() <= 1.);
+        let round_trip =
+            lut_16bit(&convert_gamma(&identity, Some(2.2), Some(2.2)).unwrap()).unwrap();
+        for (tone, output) in round_trip.iter().enumerate().step_by(97) {
+            assert!(
+                (*output as i32 - tone as i32).abs() <= 1,
+                "{} became {}",
+                tone,
+                output
+            );
+        }
+    }
+
+    #[test]
+    fn test_limit_to_range() {
+        let curve = inverting_curve();
+        let range: ToneRange = "0,0.3".parse().unwrap();
+        let limited = limit_to_range(&curve, range, 0.05).unwrap();
+
+        // the full correction inside the range, none at all past the feathering
+        for x in [0., 0.1, 0.3] {
+            let input = x * CURVE_DOMAIN_MAX;
+            let expected = sample_curve(&curve, input).unwrap();
+            assert!((sample_curve(&limited, input).unwrap() - expected).abs() < 1e-6);
+        }
+        for x in [0.35, 0.351, 0.5, 0.9, 1.] {
+            let input = x * CURVE_DOMAIN_MAX;
+            assert_eq!(sample_curve(&limited, input).unwrap(), input, "{}", x);
+        }
+        // partway in the feathering, between no correction and the full inversion
+        let input = 0.325 * CURVE_DOMAIN_MAX;
+        let feathered = sample_curve(&limited, input).unwrap();
+        assert!(feathered > input && feathered < sample_curve(&curve, input).unwrap());
+
+        // and highlights of an image pass through unchanged
+        let buffer: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_fn(2, 1, |x, _| Luma([[50000, u16::MAX][x as usize]]));
+        let result = apply(
+            &DynamicImage::ImageLuma16(buffer.clone()),
+            &limited,
+            LumaWeights::default(),
+        )
+        .unwrap();
+        assert_eq!(result.to_luma16(), buffer);
+
+        assert!("0.3,0.1".parse::<ToneRange>().is_err());
+        assert!("0,1.5".parse::<ToneRange>().is_err());
+    }
+
+    #[test]
+    fn test_convert_output() {
+        let buffer: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_fn(2, 1, |x, _| Luma([[1000, u16::MAX][x as usize]]));
+        let corrected = apply(
+            &DynamicImage::ImageLuma16(buffer),
+            &inverting_curve(),
+            LumaWeights::default(),
+        )
+        .unwrap();
+
+        let rgb = convert_output(corrected.clone(), OutputColor::Rgb);
+        assert_eq!(rgb.color(), ColorType::Rgb16);
+        let pixel = rgb.to_rgb16().get_pixel(0, 0).0;
+        assert_eq!(pixel, [u16::MAX - 1000; 3]);
+
+        let luma = convert_output(rgb, OutputColor::Luma);
+        assert_eq!(luma.color(), ColorType::L16);
+        assert_eq!(luma.to_luma16(), corrected.to_luma16());
+
+        let rgb_8 = convert_output(DynamicImage::new_luma8(1, 1), OutputColor::Rgb);
+        assert_eq!(rgb_8.color(), ColorType::Rgb8);
+    }
+
+    #[test]
+    fn test_resize_from_str() {
+        assert_eq!(
+            "800x600".parse::<Resize>().unwrap(),
+            Resize::Exact(800, 600)
+        );
+        assert!("800".parse::<Resize>().is_err());
+        assert!("axb".parse::<Resize>().is_err());
+    }
+
+    #[test]
+    fn test_apply_resized() {
+        // squares the tone, so averaging before or after the curve gives very different results
+        let curve = Spline::from_vec(vec![
+            Key::new(0., 0., Interpolation::Linear),
+            Key::new(
+                CURVE_DOMAIN_MAX / 2.,
+                CURVE_DOMAIN_MAX / 4.,
+                Interpolation::Linear,
+            ),
+            Key::new(CURVE_DOMAIN_MAX, CURVE_DOMAIN_MAX, Interpolation::Linear),
+        ]);
+        let buffer: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_fn(40, 40, |x, _| Luma([if x % 2 == 0 { 0 } else { u16::MAX }]));
+
+        let exact = apply_resized(
+            &DynamicImage::ImageLuma16(buffer.clone()),
+            &curve,
+            LumaWeights::default(),
+            Resize::Exact(10, 5),
+        )
+        .unwrap();
+        assert_eq!((exact.width(), exact.height()), (10, 5));
+
+        let max_dimension = apply_resized(
+            &DynamicImage::ImageLuma16(buffer),
+            &curve,
+            LumaWeights::default(),
+            Resize::MaxDimension(20),
+        )
+        .unwrap();
+        assert_eq!((max_dimension.width(), max_dimension.height()), (20, 20));
+
+        // the curve maps 0 and max through unchanged, so if it was applied first the downsampled
+        // stripes average out to around half. Applied after they would be pushed down to a quarter.
+        let center = max_dimension.to_luma16().get_pixel(10, 10)[0] as f64;
+        assert!(
+            (center - CURVE_DOMAIN_MAX / 2.).abs() < CURVE_DOMAIN_MAX / 16.,
+            "{}",
+            center
+        );
+    }
+
+    #[test]
+    fn test_apply_cancelled() {
+        let buffer: ImageBuffer<Luma<u16>, Vec<u16>> = ImageBuffer::new(10, 10);
+        let cancel = Cancel::default();
+        cancel.cancel();
+        let error = apply_cancellable(
+            &DynamicImage::ImageLuma16(buffer),
+            &inverting_curve(),
+            LumaWeights::default(),
+            &cancel,
+        )
+        .unwrap_err();
+        assert!(error.is::<crate::cancel::Cancelled>());
+    }
+
+    #[test]
+    fn test_apply_16bit() {
+        let buffer: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_fn(3, 1, |x, _| Luma([[0, 1000, u16::MAX][x as usize]]));
+        let result = apply(
+            &DynamicImage::ImageLuma16(buffer),
+            &inverting_curve(),
+            LumaWeights::default(),
+        )
+        .unwrap();
+
+        assert_eq!(result.color(), ColorType::L16);
+        let result_16 = result.to_luma16();
+        assert_eq!(result_16.get_pixel(0, 0)[0], u16::MAX);
+        assert_eq!(result_16.get_pixel(1, 0)[0], u16::MAX - 1000);
+        assert_eq!(result_16.get_pixel(2, 0)[0], 0);
+    }
+
+    #[test]
+    fn test_apply_8bit_curve_to_16bit() {
+        // a curve authored for 8bit tones that darkens everything by half
+        let curve = crate::curve::parse_curve(
+            r#"{"domain_max": 255, "curve": [
+                {"t": 0, "value": 0, "interpolation": "linear"},
+                {"t": 255, "value": 127.5, "interpolation": "linear"}
+            ]}"#,
+        )
+        .unwrap();
+        let buffer: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_fn(3, 1, |x, _| Luma([[0, 30000, u16::MAX][x as usize]]));
+        let result = apply(
+            &DynamicImage::ImageLuma16(buffer),
+            &curve,
+            LumaWeights::default(),
+        )
+        .unwrap()
+        .to_luma16();
+
+        assert_eq!(result.get_pixel(0, 0)[0], 0);
+        assert_eq!(result.get_pixel(1, 0)[0], 15000);
+        assert_eq!(result.get_pixel(2, 0)[0], u16::MAX / 2);
+    }
 }
@@ -0,0 +1,110 @@
+use anyhow::{anyhow, Result};
+use image::{ImageBuffer, Rgb, RgbImage};
+
+use super::analyze::{self, AnalyzeResults};
+
+// Colors the two scans are drawn in, histograms in a darker shade of the same hue
+pub const BEFORE_COLOR: Rgb<u8> = Rgb([255, 143, 0]);
+pub const AFTER_COLOR: Rgb<u8> = Rgb([0, 190, 255]);
+
+// How the measured response of a process changed between two scans of the same wedge
+pub struct Comparison {
+    // (input tone, measured after - measured before) for each step, as a percentage of the range
+    pub deltas: Vec<(u16, f64)>,
+}
+
+impl Comparison {
+    pub fn new(before: &AnalyzeResults, after: &AnalyzeResults) -> Result<Self> {
+        if before.response.len() != after.response.len() {
+            return Err(anyhow!(
+                "scans have different numbers of steps, {} and {}",
+                before.response.len(),
+                after.response.len()
+            ));
+        }
+
+        let max = u16::MAX as f64;
+        Ok(Self {
+            deltas: before
+                .response
+                .iter()
+                .zip(&after.response)
+                .map(|((input, before), (_, after))| {
+                    (*input, (*after as f64 - *before as f64) / max * 100.)
+                })
+                .collect(),
+        })
+    }
+
+    // Mean of the absolute change across every step
+    pub fn mean_delta(&self) -> f64 {
+        self.deltas
+            .iter()
+            .map(|(_, delta)| delta.abs())
+            .sum::<f64>()
+            / self.deltas.len().max(1) as f64
+    }
+
+    // The step that changed most, as (step, input tone, change)
+    pub fn largest_delta(&self) -> Option<(usize, u16, f64)> {
+        self.deltas
+            .iter()
+            .enumerate()
+            .max_by(|(_, (_, a)), (_, (_, b))| a.abs().total_cmp(&b.abs()))
+            .map(|(step, (input, delta))| (step, *input, *delta))
+    }
+}
+
+/* Draws both scans' histograms and curves over each other, `BEFORE_COLOR` and `AFTER_COLOR`
+ *
+ * expects to draw a 1024x1024 image, like the analyze preview
+ */
+pub fn draw_comparison(before: &AnalyzeResults, after: &AnalyzeResults) -> Result<RgbImage> {
+    let mut image: RgbImage = ImageBuffer::new(1024, 1024);
+    for (results, color) in [(before, BEFORE_COLOR), (after, AFTER_COLOR)] {
+        let shade = Rgb(color.0.map(|c| c / 3));
+        analyze::draw_histogram_additive(
+            &mut image,
+            &results.histogram,
+            analyze::HISTOGRAM_BUCKETS,
+            shade,
+        )?;
+    }
+    for (results, color) in [(before, BEFORE_COLOR), (after, AFTER_COLOR)] {
+        analyze::draw_curve_colored(&mut image, &results.curve, color)?;
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::DynamicImage;
+
+    #[test]
+    fn test_comparison() {
+        // a print inverts the negative, so the digital wedge reads backwards
+        let (wedge, params) = analyze::test_wedge();
+        let before = analyze::analyze(&wedge, &params, false).unwrap();
+
+        // nothing changed, nothing to report
+        let same = Comparison::new(&before, &before).unwrap();
+        assert_eq!(same.mean_delta(), 0.);
+        assert_eq!(same.largest_delta().unwrap().2, 0.);
+
+        // the same wedge printed with a darker midtone response
+        let darker =
+            DynamicImage::ImageLuma16(imageproc::map::map_pixels(&wedge.to_luma16(), |_, _, p| {
+                let tone = p[0] as f64 / u16::MAX as f64;
+                image::Luma([(tone * tone * u16::MAX as f64) as u16])
+            }));
+        let after = analyze::analyze(&darker, &params, false).unwrap();
+        let changed = Comparison::new(&before, &after).unwrap();
+        assert!(changed.mean_delta() > 0.);
+        assert_eq!(changed.deltas.len(), before.response.len());
+
+        let image = draw_comparison(&before, &after).unwrap();
+        assert!(image.pixels().any(|p| *p == BEFORE_COLOR));
+        assert!(image.pixels().any(|p| *p == AFTER_COLOR));
+    }
+}
@@ -0,0 +1,540 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use splines::{Interpolation, Key, Spline};
+
+use super::analyze::AnalyzeParams;
+
+// Curves are built against 16bit tones, see analyze
+pub const CURVE_DOMAIN_MAX: f64 = u16::MAX as f64;
+
+// The domain of a normalized curve file, tones as fractions from 0.0 (black) to 1.0 (white)
+pub const NORMALIZED_DOMAIN_MAX: f64 = 1.;
+
+/* A curve as it's saved to disk, along with the largest tone of the domain it was built for
+ *
+ * Curves are always worked with in the 16bit domain, a curve authored for 0..255 is scaled up as
+ * it's loaded. Without this an 8bit curve applied to a 16bit image would silently squash every
+ * tone into the bottom 256.
+ *
+ * When every key shares an interpolation it's recorded for the whole curve too, and loading gives
+ * every key that interpolation. The curve then samples the same whatever interpolation curved
+ * fits with by default at the time, and editing the one field changes how the whole curve is
+ * drawn between its points. Files without it use each key's own interpolation.
+ *
+ * A curve analyze wrote also records the params it was analyzed with, so the calibration can be
+ * rerun exactly on a new scan with `analyze --params`.
+ */
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct CurveFile {
+    pub domain_max: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interpolation: Option<Interpolation<f64, f64>>,
+    pub curve: Spline<f64, f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<AnalyzeParams>,
+}
+
+// Curve files written before the domain was stored are a bare spline in the 16bit domain
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum StoredCurve {
+    File(Box<CurveFile>),
+    Bare(Spline<f64, f64>),
+}
+
+impl CurveFile {
+    pub fn new(curve: Spline<f64, f64>) -> Self {
+        Self {
+            domain_max: CURVE_DOMAIN_MAX,
+            interpolation: shared_interpolation(&curve),
+            curve,
+            params: None,
+        }
+    }
+
+    pub fn with_params(self, params: &AnalyzeParams) -> Self {
+        Self {
+            params: Some(params.clone()),
+            ..self
+        }
+    }
+
+    /* The same curve stored in the 0.0 to 1.0 domain, for tools that expect normalized float
+     * curves. Loading scales it back up like any other domain
+     */
+    pub fn normalized(self) -> Self {
+        // divided rather than multiplied by the reciprocal, so scaling back up gives the same keys
+        let normalize = |tone: f64| tone / self.domain_max * NORMALIZED_DOMAIN_MAX;
+        Self {
+            domain_max: NORMALIZED_DOMAIN_MAX,
+            curve: Spline::from_vec(
+                self.curve
+                    .keys()
+                    .iter()
+                    .map(|key| Key::new(normalize(key.t), normalize(key.value), key.interpolation))
+                    .collect(),
+            ),
+            ..self
+        }
+    }
+
+    /* The curve scaled from its stored domain onto the 16bit working domain, each key given the
+     * curve's interpolation when there is one. Catmull-Rom draws each span from the keys either
+     * side of it, so a curve given it has its first and last keys doubled to reach its ends.
+     */
+    pub fn into_working(self) -> Result<Spline<f64, f64>> {
+        if self.domain_max.is_nan() || self.domain_max <= 0. {
+            return Err(anyhow!(
+                "curve domain must be above 0, got {}",
+                self.domain_max
+            ));
+        }
+        if self.domain_max == CURVE_DOMAIN_MAX && self.interpolation.is_none() {
+            return Ok(self.curve);
+        }
+
+        let scale = CURVE_DOMAIN_MAX / self.domain_max;
+        let keys = self
+            .curve
+            .keys()
+            .iter()
+            .map(|key| {
+                Key::new(
+                    key.t * scale,
+                    key.value * scale,
+                    self.interpolation.unwrap_or(key.interpolation),
+                )
+            })
+            .collect();
+        Ok(Spline::from_vec(match self.interpolation {
+            Some(Interpolation::CatmullRom) => with_end_keys_doubled(keys),
+            _ => keys,
+        }))
+    }
+}
+
+// `keys` with the first and last repeated, unless they already are
+fn with_end_keys_doubled(mut keys: Vec<Key<f64, f64>>) -> Vec<Key<f64, f64>> {
+    let (Some(&first), Some(&last)) = (keys.first(), keys.last()) else {
+        return keys;
+    };
+    if keys.get(1).map(|key| key.t) != Some(first.t) {
+        keys.insert(0, first);
+    }
+    if keys.len() < 2 || keys[keys.len() - 2].t != last.t {
+        keys.push(last);
+    }
+    keys
+}
+
+// The interpolation every key of `curve` uses, None when they differ or there are no keys
+fn shared_interpolation(curve: &Spline<f64, f64>) -> Option<Interpolation<f64, f64>> {
+    let (first, rest) = curve.keys().split_first()?;
+    rest.iter()
+        .all(|key| key.interpolation == first.interpolation)
+        .then_some(first.interpolation)
+}
+
+/* Builds a curve from (input, output) tone pairs, the one way points become a curve
+ *
+ * The points can come in any order. Where several share an input their outputs are averaged,
+ * leaving strictly increasing inputs. A curve needs at least two distinct inputs.
+ */
+pub fn curve_from_points(
+    points: &[(u16, u16)],
+    interpolation: Interpolation<f64, f64>,
+) -> Result<Spline<f64, f64>> {
+    let mut sorted = points.to_vec();
+    sorted.sort_by_key(|(input, _)| *input);
+
+    let mut keys: Vec<Key<f64, f64>> = Vec::with_capacity(sorted.len());
+    for chunk in sorted.chunk_by(|a, b| a.0 == b.0) {
+        let output =
+            chunk.iter().map(|(_, output)| *output as f64).sum::<f64>() / chunk.len() as f64;
+        keys.push(Key::new(chunk[0].0 as f64, output, interpolation));
+    }
+
+    if keys.len() < 2 {
+        return Err(anyhow!(
+            "a curve needs at least 2 distinct inputs, got {}",
+            keys.len()
+        ));
+    }
+    Ok(Spline::from_vec(keys))
+}
+
+// Reads a curve file, in the 16bit working domain whatever domain it was saved in
+pub fn parse_curve(data: &str) -> Result<Spline<f64, f64>> {
+    match serde_json::from_str(data)? {
+        StoredCurve::File(curve_file) => (*curve_file).into_working(),
+        StoredCurve::Bare(curve) => Ok(curve),
+    }
+}
+
+pub fn load_curve(path: &Path) -> Result<Spline<f64, f64>> {
+    parse_curve(&fs::read_to_string(path)?)
+}
+
+// Writes a 16bit domain curve as a curve file
+pub fn write_curve(writer: impl Write, curve: &Spline<f64, f64>) -> Result<()> {
+    Ok(serde_json::to_writer(
+        writer,
+        &CurveFile::new(curve.clone()),
+    )?)
+}
+
+pub fn save_curve_file(path: &Path, curve_file: &CurveFile) -> Result<()> {
+    Ok(serde_json::to_writer(fs::File::create(path)?, curve_file)?)
+}
+
+pub fn save_curve(path: &Path, curve: &Spline<f64, f64>) -> Result<()> {
+    write_curve(fs::File::create(path)?, curve)
+}
+
+// Saves a curve analyze fit, along with the `params` it was analyzed with
+pub fn save_analyzed_curve(
+    path: &Path,
+    curve: &Spline<f64, f64>,
+    params: &AnalyzeParams,
+) -> Result<()> {
+    save_curve_file(path, &CurveFile::new(curve.clone()).with_params(params))
+}
+
+/* Reads analyze params, either from a curve file analyze wrote or from a json file of the params
+ * alone. Params missing from the file take their defaults, and params out of the range the
+ * command line allows are an error.
+ */
+pub fn load_params(path: &Path) -> Result<AnalyzeParams> {
+    let data = fs::read_to_string(path)?;
+    // every field of the params has a default, so a curve file would read as default params
+    let params = match serde_json::from_str::<CurveFile>(&data) {
+        Ok(curve_file) => curve_file.params.ok_or(anyhow!(
+            "{} is a curve without the params it was analyzed with",
+            path.display()
+        ))?,
+        Err(_) => serde_json::from_str::<AnalyzeParams>(&data)?,
+    };
+    params
+        .validate()
+        .with_context(|| format!("invalid params in {}", path.display()))?;
+    Ok(params)
+}
+
+/* Samples `curve` at `x`, past either end the curve holds its first or last value.
+ *
+ * This fails for a curve without any keys, and for one whose keys can't draw the span `x` falls
+ * in. Catmull-Rom draws a span from the keys either side of it, so a Catmull-Rom curve fails in
+ * its first and last spans unless its end keys are doubled.
+ */
+pub fn sample_curve(curve: &Spline<f64, f64>, x: f64) -> Result<f64> {
+    curve
+        .clamped_sample(x)
+        .ok_or(anyhow!("failed to sample spline at {}", x))
+}
+
+// How much of the domain a curve's keys have to span for it to be applied, see `check_domain`
+const MIN_DOMAIN_COVERAGE: f64 = 0.9;
+
+/* Fails unless the keys of `curve` reach most of the way to black and to white, and the curve
+ * can be sampled at both
+ *
+ * Past either end a curve holds its first or last value. That's right for a curve whose ends were
+ * trimmed a little short of black or white, but flattens nearly every tone when the curve was made
+ * for a smaller domain, 0 to 1000 say, and never scaled up into 16bit. Sampling the ends catches
+ * a Catmull-Rom curve without the doubled end keys to draw its ends with.
+ */
+pub fn check_domain(curve: &Spline<f64, f64>) -> Result<()> {
+    let (first, last) = match (curve.keys().first(), curve.keys().last()) {
+        (Some(first), Some(last)) => (first.t, last.t),
+        _ => return Err(anyhow!("the curve has no points to apply")),
+    };
+    let margin = CURVE_DOMAIN_MAX * (1. - MIN_DOMAIN_COVERAGE);
+    if first > margin || last < CURVE_DOMAIN_MAX - margin {
+        return Err(anyhow!(
+            "the curve only covers tones {} to {} of 0 to {}, was it made for another range?",
+            first,
+            last,
+            CURVE_DOMAIN_MAX
+        ));
+    }
+    sample_curve(curve, 0.)?;
+    sample_curve(curve, CURVE_DOMAIN_MAX)?;
+    Ok(())
+}
+
+/* Samples `curve` at `entries` evenly spaced inputs across its domain, the first at 0 and the
+ * last at 65535, returning (input, output) pairs. The basis of any lookup table built from a curve.
+ */
+pub fn sample_curve_lut(curve: &Spline<f64, f64>, entries: usize) -> Result<Vec<(f64, f64)>> {
+    if entries < 2 {
+        return Err(anyhow!(
+            "a lookup table needs at least 2 entries, got {}",
+            entries
+        ));
+    }
+
+    let step = CURVE_DOMAIN_MAX / (entries - 1) as f64;
+    (0..entries)
+        .map(|i| {
+            let input = i as f64 * step;
+            sample_curve(curve, input).map(|output| (input, output))
+        })
+        .collect()
+}
+
+/* Folds `curves` into the one curve that applies each of them in turn, the first curve first
+ *
+ * The chain is sampled once per 16bit tone without rounding between curves, so applying the
+ * composed curve matches applying each curve in sequence without the intermediate images losing
+ * precision. A single curve is returned as it is.
+ */
+pub fn compose_curves(curves: &[Spline<f64, f64>]) -> Result<Spline<f64, f64>> {
+    match curves {
+        [] => Err(anyhow!("no curves to compose")),
+        [curve] => Ok(curve.clone()),
+        [first, rest @ ..] => sample_curve_lut(first, CURVE_DOMAIN_MAX as usize + 1)?
+            .into_iter()
+            .map(|(input, output)| {
+                let output = rest.iter().try_fold(output, |tone, curve| {
+                    sample_curve(curve, tone.clamp(0., CURVE_DOMAIN_MAX))
+                })?;
+                Ok(Key::new(input, output, Interpolation::Linear))
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(Spline::from_vec),
+    }
+}
+
+// How many points along the curves `largest_difference` compares them at
+const DIFFERENCE_SAMPLES: usize = 1024;
+
+/* Where `curve` strays furthest from `reference`, as (input tone, curve's output - reference's),
+ * comparing them at `DIFFERENCE_SAMPLES` evenly spaced inputs
+ */
+pub fn largest_difference(
+    reference: &Spline<f64, f64>,
+    curve: &Spline<f64, f64>,
+) -> Result<(f64, f64)> {
+    sample_curve_lut(curve, DIFFERENCE_SAMPLES)?
+        .into_iter()
+        .map(|(input, output)| Ok((input, output - sample_curve(reference, input)?)))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .max_by(|(_, a), (_, b)| a.abs().total_cmp(&b.abs()))
+        .ok_or(anyhow!("no points to compare the curves at"))
+}
+
+/* The keys of `curve` as text, one "input, output" pair per line, for pasting into a report or
+ * a script. Unlike `write_small_csv` these are the control points themselves, not samples.
+ */
+pub fn control_points(curve: &Spline<f64, f64>) -> String {
+    curve
+        .keys()
+        .iter()
+        .map(|key| format!("{}, {}\n", key.t, key.value))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apply;
+    use crate::luma::LumaWeights;
+    use image::{DynamicImage, ImageBuffer, Luma};
+    use splines::{Interpolation, Key};
+
+    #[test]
+    fn test_largest_difference() {
+        let identity = Spline::from_vec(vec![
+            Key::new(0., 0., Interpolation::Linear),
+            Key::new(CURVE_DOMAIN_MAX, CURVE_DOMAIN_MAX, Interpolation::Linear),
+        ]);
+        assert_eq!(largest_difference(&identity, &identity).unwrap().1, 0.);
+
+        // a curve that dips below the identity toward the shadows
+        let darker = Spline::from_vec(vec![
+            Key::new(0., 0., Interpolation::Linear),
+            Key::new(CURVE_DOMAIN_MAX / 4., 0., Interpolation::Linear),
+            Key::new(CURVE_DOMAIN_MAX, CURVE_DOMAIN_MAX, Interpolation::Linear),
+        ]);
+        let (input, difference) = largest_difference(&identity, &darker).unwrap();
+        assert!((input - CURVE_DOMAIN_MAX / 4.).abs() < 64.);
+        assert!((difference + CURVE_DOMAIN_MAX / 4.).abs() < 64.);
+    }
+
+    #[test]
+    fn test_control_points() {
+        let curve = Spline::from_vec(vec![
+            Key::new(0., 0., Interpolation::Linear),
+            Key::new(32767.5, 30000.25, Interpolation::Linear),
+            Key::new(CURVE_DOMAIN_MAX, CURVE_DOMAIN_MAX, Interpolation::Linear),
+        ]);
+        assert_eq!(
+            control_points(&curve),
+            "0, 0\n32767.5, 30000.25\n65535, 65535\n"
+        );
+    }
+
+    #[test]
+    fn test_sample_curve_lut() {
+        let curve = Spline::from_vec(vec![
+            Key::new(0., CURVE_DOMAIN_MAX, Interpolation::Linear),
+            Key::new(CURVE_DOMAIN_MAX, 0., Interpolation::Linear),
+        ]);
+        let lut = sample_curve_lut(&curve, 5).unwrap();
+        assert_eq!(lut.len(), 5);
+        assert_eq!(lut[0], (0., CURVE_DOMAIN_MAX));
+        assert_eq!(lut[2], (CURVE_DOMAIN_MAX / 2., CURVE_DOMAIN_MAX / 2.));
+        assert_eq!(lut[4], (CURVE_DOMAIN_MAX, 0.));
+
+        assert!(sample_curve_lut(&curve, 1).is_err());
+        assert!(sample_curve(&Spline::from_vec(vec![]), 0.).is_err());
+    }
+
+    #[test]
+    fn test_curve_from_points() {
+        let curve = curve_from_points(
+            &[
+                (65535, 65535),
+                (0, 0),
+                (32768, 20000),
+                (32768, 30000),
+                (0, 0),
+            ],
+            Interpolation::Linear,
+        )
+        .unwrap();
+
+        let keys: Vec<(f64, f64)> = curve.keys().iter().map(|key| (key.t, key.value)).collect();
+        assert_eq!(keys, vec![(0., 0.), (32768., 25000.), (65535., 65535.)]);
+
+        assert!(curve_from_points(&[], Interpolation::Linear).is_err());
+        assert!(curve_from_points(&[(10, 10), (10, 20)], Interpolation::Linear).is_err());
+    }
+
+    #[test]
+    fn test_parse_curve_domains() {
+        // an inverting curve authored for 8bit tones, and the same curve saved as a bare spline
+        let eight_bit = parse_curve(
+            r#"{"domain_max": 255, "curve": [
+                {"t": 0, "value": 255, "interpolation": "linear"},
+                {"t": 255, "value": 0, "interpolation": "linear"}
+            ]}"#,
+        )
+        .unwrap();
+        let bare = parse_curve(
+            r#"[
+                {"t": 0, "value": 65535, "interpolation": "linear"},
+                {"t": 65535, "value": 0, "interpolation": "linear"}
+            ]"#,
+        )
+        .unwrap();
+
+        for curve in [eight_bit, bare] {
+            assert_eq!(sample_curve(&curve, 0.).unwrap(), CURVE_DOMAIN_MAX);
+            assert_eq!(sample_curve(&curve, CURVE_DOMAIN_MAX).unwrap(), 0.);
+        }
+
+        let mut saved = Vec::new();
+        write_curve(&mut saved, &Spline::from_vec(vec![])).unwrap();
+        assert!(String::from_utf8(saved)
+            .unwrap()
+            .contains("\"domain_max\":65535"));
+    }
+
+    #[test]
+    fn test_normalized_round_trip() {
+        let curve = curve_from_points(
+            &[(0, 0), (16000, 9000), (40000, 31000), (65535, 65535)],
+            Interpolation::Linear,
+        )
+        .unwrap();
+        let normalized = CurveFile::new(curve.clone()).normalized();
+        assert_eq!(normalized.domain_max, NORMALIZED_DOMAIN_MAX);
+        assert!(normalized
+            .curve
+            .keys()
+            .iter()
+            .all(|key| (0. ..=1.).contains(&key.t) && (0. ..=1.).contains(&key.value)));
+
+        // applies exactly like the 16bit curve it came from
+        let loaded = parse_curve(&serde_json::to_string(&normalized).unwrap()).unwrap();
+        let ramp = DynamicImage::ImageLuma16(ImageBuffer::from_fn(256, 256, |x, y| {
+            Luma([(y * 256 + x) as u16])
+        }));
+        let weights = LumaWeights::default();
+        assert_eq!(
+            apply::apply(&ramp, &loaded, weights).unwrap(),
+            apply::apply(&ramp, &curve, weights).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_interpolation_round_trip() {
+        let points = [
+            (0, 0),
+            (10000, 4000),
+            (25000, 18000),
+            (40000, 36000),
+            (55000, 54000),
+            (65535, 65535),
+        ];
+        let curve = Spline::from_vec(with_end_keys_doubled(
+            curve_from_points(&points, Interpolation::CatmullRom)
+                .unwrap()
+                .keys()
+                .to_vec(),
+        ));
+
+        let mut saved = Vec::new();
+        write_curve(&mut saved, &curve).unwrap();
+        let saved = String::from_utf8(saved).unwrap();
+        assert!(saved.contains("\"interpolation\":\"catmull_rom\",\"curve\""));
+
+        // ends that are already doubled aren't doubled again
+        let loaded = parse_curve(&saved).unwrap();
+        assert_eq!(loaded.keys(), curve.keys());
+        for x in (0..=65535).step_by(97) {
+            let x = x as f64;
+            assert!(loaded.clamped_sample(x).is_some(), "{}", x);
+            assert_eq!(curve.clamped_sample(x), loaded.clamped_sample(x), "{}", x);
+        }
+
+        // the curve's interpolation is the one every key is drawn with
+        let relinearized =
+            parse_curve(&saved.replace("\"catmull_rom\",\"curve\"", "\"linear\",\"curve\""))
+                .unwrap();
+        assert!(relinearized
+            .keys()
+            .iter()
+            .all(|key| key.interpolation == Interpolation::Linear));
+
+        // a curve of single ended keys given Catmull-Rom still samples across its whole domain
+        let mut linear = Vec::new();
+        write_curve(
+            &mut linear,
+            &curve_from_points(&points, Interpolation::Linear).unwrap(),
+        )
+        .unwrap();
+        let smoothed = parse_curve(
+            &String::from_utf8(linear)
+                .unwrap()
+                .replace("\"linear\",\"curve\"", "\"catmull_rom\",\"curve\""),
+        )
+        .unwrap();
+        for x in (0..=65535).step_by(97) {
+            assert!(sample_curve(&smoothed, x as f64).is_ok(), "{}", x);
+        }
+        assert!(apply::apply(
+            &DynamicImage::ImageLuma16(ImageBuffer::from_fn(256, 1, |x, _| Luma([
+                (x * 257) as u16
+            ]))),
+            &smoothed,
+            LumaWeights::default()
+        )
+        .is_ok());
+    }
+}
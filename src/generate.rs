@@ -1,16 +1,335 @@
-use ab_glyph::FontRef;
-use anyhow;
-use image::{DynamicImage, ImageBuffer, Luma};
-use imageproc::drawing::{draw_filled_rect_mut, draw_text_mut};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+use ab_glyph::{Font, FontRef, ScaleFont};
+use anyhow::{anyhow, Context};
+use image::{DynamicImage, ImageBuffer, Luma, Rgb};
+use imageproc::drawing::{draw_filled_circle_mut, draw_filled_rect_mut, draw_text_mut, text_size};
+use imageproc::map::map_pixels;
 use imageproc::rect::Rect;
 
-use super::step_description::StepDescription;
+use super::analyze::{check_positive, parse_positive};
+use super::apply::ToneRange;
+use super::index_strip::{self, WedgeIndex};
+use super::luma::LumaWeights;
+use super::step_description::{Layout, StepDescription, STANDARD_COLUMNS};
 
 type Gray16Image = ImageBuffer<Luma<u16>, Vec<u16>>;
 
 const BLACK: u32 = 0;
 
-const LATO_BLACK_BYTES: &[u8] = include_bytes!("../data/fonts/Lato-Black.ttf");
+// pixels on the margin of the image, the grid starts this far in from the top left
+pub const MARGIN: u32 = 10;
+
+// width in pixels of the separators drawn around each patch in the separators layout
+const SEPARATOR_WIDTH: u32 = 2;
+
+// the smallest difference in density a densitometer reliably tells apart between two patches
+const MEASURABLE_DENSITY_DIFFERENCE: f64 = 0.05;
+
+// width in pixels of the lines of the grid layout, unless asked for otherwise
+pub const DEFAULT_GRID_THICKNESS: u32 = 2;
+
+// the thickest grid lines the command line allows
+pub const MAX_GRID_THICKNESS: u32 = 20;
+
+pub const LATO_BLACK_BYTES: &[u8] = include_bytes!("../data/fonts/Lato-Black.ttf");
+
+// where and how large the step number is drawn in the top left corner of each patch
+const LABEL_OFFSET: u32 = 5;
+const LABEL_FONT_SIZE: f32 = 20.;
+// slack for how far a label's glyphs can reach past its measured width
+const LABEL_OVERHANG: u32 = 2;
+// width in pixels of the outline of the opposite tone drawn around each label
+const LABEL_OUTLINE: u32 = 1;
+
+// radius in pixels of the dots `GenerateParams::corner_dots` draws, and of the black core of each.
+// Small enough to stay clear of the labels, large enough to survive a print and scan
+pub const CORNER_DOT_RADIUS: u32 = 5;
+pub const CORNER_DOT_CORE: u32 = 2;
+
+// What each patch is labeled with in its top left corner
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, clap::ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+pub enum Label {
+    /// The step number
+    #[default]
+    Index,
+    /// The density the patch should read on a densitometer, -log10 of its tone
+    Density,
+}
+
+/* A hue to render the wedge in, so a print resembles the color of a toned process
+ *
+ * Parses from "#rrggbb" or the name of one of `NAMED_TONE_COLORS`.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ToneColor(pub [u8; 3]);
+
+// tone colors that can be given by name, roughly the color of a print in each process
+const NAMED_TONE_COLORS: [(&str, [u8; 3]); 3] = [
+    ("sepia", [112, 66, 20]),
+    ("cyanotype", [29, 78, 137]),
+    ("platinum", [96, 84, 72]),
+];
+
+impl FromStr for ToneColor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((_, color)) = NAMED_TONE_COLORS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(s))
+        {
+            return Ok(ToneColor(*color));
+        }
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if hex.len() != 6 || !hex.is_ascii() {
+            return Err(anyhow!(
+                "expected a color like #704214 or one of sepia, cyanotype, or platinum, got {}",
+                s
+            ));
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16);
+        Ok(ToneColor([channel(0)?, channel(2)?, channel(4)?]))
+    }
+}
+
+impl fmt::Display for ToneColor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [r, g, b] = self.0;
+        write!(f, "#{:02x}{:02x}{:02x}", r, g, b)
+    }
+}
+
+// Options for how a wedge is drawn beyond its layout
+#[derive(clap::Args, Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct GenerateParams {
+    /// Tone of the margin around the grid, 0 (black) to 65535 (white)
+    #[arg(long, default_value_t = 0)]
+    pub background: u16,
+
+    /// What to label each patch with
+    #[arg(long, value_enum, default_value_t)]
+    pub label: Label,
+
+    /// Blend the outermost pixel of each patch into the lines around it, softening the hard
+    /// edges that can cause moire when halftoned or scanned. Patch interiors keep their tone
+    #[arg(long)]
+    pub antialias: bool,
+
+    /// Width in pixels of the lines of the grid layout
+    #[arg(long, default_value_t = DEFAULT_GRID_THICKNESS, value_parser = clap::value_parser!(u32).range(1..=MAX_GRID_THICKNESS as i64))]
+    pub grid_thickness: u32,
+
+    /// Draw every line of the grid layout in this tone, 0 (black) to 65535 (white). By default
+    /// vertical lines are mid grey and horizontal lines white in the top half and black below
+    #[arg(long)]
+    pub grid_tone: Option<u16>,
+
+    /// Render the wedge in shades of this color rather than grey, as #rrggbb or sepia,
+    /// cyanotype, or platinum. Each patch keeps its tone as luminance, which analyze reads back
+    #[arg(long)]
+    pub tone_color: Option<ToneColor>,
+
+    /// Pull every patch's tone into this range, as fractions like 0.05,0.95, for a printer that
+    /// can't reach the extremes. Patches beyond it print alike but keep their place in the layout
+    #[arg(long)]
+    pub printable_range: Option<ToneRange>,
+
+    /// Draw only as many steps as a densitometer can tell apart across a process's dynamic range
+    /// of this many stops, rather than the standard 101. Analyze the print with the same --steps
+    #[arg(long, value_parser = parse_positive)]
+    pub optimize_for_range: Option<f64>,
+
+    /// Number of columns to lay the steps out in, e.g. 101 for a single long strip. Patches stay
+    /// the same size, more columns make a wider wedge. Analyze with the same --columns
+    #[arg(long, default_value_t = STANDARD_COLUMNS, value_parser = clap::value_parser!(u32).range(1..))]
+    pub columns: u32,
+
+    /// Number of rows to lay the steps out in, rather than as few as the columns need. Rows past
+    /// the last step are left empty. Analyze with the same --rows
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub rows: Option<u32>,
+
+    /// Mark every patch corner with a small black dot ringed in white. Analyze with the same
+    /// --corner-dots finds each patch by its dots, following any distortion in the scan
+    #[arg(long)]
+    pub corner_dots: bool,
+
+    /// Draw a strip of black and white bars along the bottom of the wedge encoding its steps,
+    /// columns, rows, and printable range. Analyze with --index-strip reads them back from the scan
+    #[arg(long)]
+    pub index_strip: bool,
+}
+
+impl Default for GenerateParams {
+    fn default() -> Self {
+        Self {
+            background: 0,
+            label: Label::default(),
+            antialias: false,
+            grid_thickness: DEFAULT_GRID_THICKNESS,
+            grid_tone: None,
+            tone_color: None,
+            printable_range: None,
+            optimize_for_range: None,
+            columns: STANDARD_COLUMNS,
+            rows: None,
+            corner_dots: false,
+            index_strip: false,
+        }
+    }
+}
+
+impl GenerateParams {
+    /* Checks the ranges the command line holds its arguments to, for params read from a batch
+     * spec that never went through it
+     */
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(1..=MAX_GRID_THICKNESS).contains(&self.grid_thickness) {
+            return Err(anyhow!(
+                "grid_thickness must be from 1 to {}, got {}",
+                MAX_GRID_THICKNESS,
+                self.grid_thickness
+            ));
+        }
+        if let Some(stops) = self.optimize_for_range {
+            check_positive(stops).context("optimize_for_range")?;
+        }
+        if let Some(range) = self.printable_range {
+            range.validate().context("printable_range")?;
+        }
+        if self.columns < 1 {
+            return Err(anyhow!("columns must be at least 1, got {}", self.columns));
+        }
+        if self.rows == Some(0) {
+            return Err(anyhow!("rows must be at least 1, got 0"));
+        }
+        Ok(())
+    }
+
+    // How many steps the wedge is drawn with, see `measurable_steps`
+    pub fn steps(&self) -> u32 {
+        self.optimize_for_range
+            .map_or(StepDescription::default().count, measurable_steps)
+    }
+
+    // The wedge these params draw with `layout`, an error if its steps don't fit in its rows
+    pub fn step_description(&self, layout: Layout) -> anyhow::Result<StepDescription> {
+        let step_description =
+            StepDescription::standard(self.steps(), self.columns).with_layout(layout);
+        match self.rows {
+            Some(rows) => step_description.with_rows(rows),
+            None => Ok(step_description),
+        }
+    }
+}
+
+/* How many evenly spaced steps a process with a dynamic range of `stops` can show apart
+ *
+ * Each stop is log10(2), about 0.3, of density. Steps closer than `MEASURABLE_DENSITY_DIFFERENCE`
+ * apart read the same on a densitometer, so the range is split into as many of those as fit and
+ * a step placed at each end of every one. Never fewer than 2 steps, or more than the standard
+ * wedge's 101.
+ */
+pub fn measurable_steps(stops: f64) -> u32 {
+    let density = stops * 2f64.log10();
+    let steps = (density / MEASURABLE_DENSITY_DIFFERENCE).floor() as u32 + 1;
+    steps.clamp(2, StepDescription::default().count)
+}
+
+// One patch of a generated wedge, its rectangle in the image and the tone it's filled with
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Patch {
+    pub index: u32,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub tone: u16,
+}
+
+/* Where every patch of a generated wedge lives, for tools that sample a scan of the print
+ *
+ * The rectangles cover the whole patch, the grid lines or separators and the step number are drawn
+ * over its edges and top left corner.
+ */
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    pub width: u32,
+    pub height: u32,
+    pub layout: Layout,
+    pub patches: Vec<Patch>,
+}
+
+// The manifest of the wedge `generate_with_params` draws with `layout` and `params`
+pub fn manifest(layout: Layout, params: &GenerateParams) -> anyhow::Result<Manifest> {
+    let step_description = params.step_description(layout)?;
+    Ok(Manifest {
+        width: step_description.width + (2 * MARGIN),
+        height: wedge_height(&step_description, params) + (2 * MARGIN),
+        layout,
+        patches: patches(&step_description, MARGIN, MARGIN)
+            .into_iter()
+            .map(|patch| Patch {
+                tone: printable_tone(patch.tone, params.printable_range),
+                ..patch
+            })
+            .collect(),
+    })
+}
+
+// `tone` clamped into `printable_range`, or left alone without one
+fn printable_tone(tone: u16, printable_range: Option<ToneRange>) -> u16 {
+    match printable_range {
+        Some(range) => {
+            let max = u16::MAX as f64;
+            tone.clamp(
+                (range.low * max).round() as u16,
+                (range.high * max).round() as u16,
+            )
+        }
+        None => tone,
+    }
+}
+
+/* How many patches of the wedge drawn with `layout` and `params` fall below and above its
+ * printable range, none without one
+ *
+ * Each of these is clamped to the range's end, so it prints the same as the patch at that end.
+ */
+pub fn clamped_patches(layout: Layout, params: &GenerateParams) -> anyhow::Result<(usize, usize)> {
+    let step_description = params.step_description(layout)?;
+    let patches = patches(&step_description, MARGIN, MARGIN);
+    let moved = |patch: &Patch| {
+        printable_tone(patch.tone, params.printable_range) as i32 - patch.tone as i32
+    };
+    Ok((
+        patches.iter().filter(|patch| moved(patch) > 0).count(),
+        patches.iter().filter(|patch| moved(patch) < 0).count(),
+    ))
+}
+
+// Every patch of `step_description` in order, with the grid starting at (start_x, start_y)
+pub fn patches(step_description: &StepDescription, start_x: u32, start_y: u32) -> Vec<Patch> {
+    (0..step_description.count)
+        .map(|n| {
+            let (col, row) = step_description.cell(n);
+            Patch {
+                index: n,
+                x: start_x + col * step_description.square_size,
+                y: start_y + row * step_description.square_size,
+                width: step_description.square_size,
+                height: step_description.square_size,
+                tone: step_description.tone(n),
+            }
+        })
+        .collect()
+}
 
 /* Creates a new step wedge image
  * 0 is black
@@ -18,41 +337,186 @@ const LATO_BLACK_BYTES: &[u8] = include_bytes!("../data/fonts/Lato-Black.ttf");
  *
  * divide the range by count then draw that value into each square
  */
-pub fn generate(process: Option<String>, notes: Option<String>) -> anyhow::Result<DynamicImage> {
-    let font_lato_black = FontRef::try_from_slice(LATO_BLACK_BYTES)?;
+pub fn generate(
+    process: Option<String>,
+    notes: Option<String>,
+    layout: Layout,
+) -> anyhow::Result<DynamicImage> {
+    generate_with_params(process, notes, layout, &GenerateParams::default())
+}
+
+// `generate`, with the background and patch labels `params` asks for
+pub fn generate_with_params(
+    process: Option<String>,
+    notes: Option<String>,
+    layout: Layout,
+    params: &GenerateParams,
+) -> anyhow::Result<DynamicImage> {
+    let step_description = params.step_description(layout)?;
+    render(&step_description, process, notes, params)
+}
 
-    //  pixels on the margin of the image
-    let start_x = 10;
-    let start_y = 10;
+/* A wedge drawn without its process and notes, so the caption can be redrawn as it's edited
+ * without drawing every patch again
+ */
+pub struct Wedge {
+    layout: Layout,
+    params: GenerateParams,
+    image: Gray16Image,
+}
 
-    let step_description = StepDescription::new(101, 10, 1000, u16::MAX as u32);
+impl Wedge {
+    pub fn new(layout: Layout, params: &GenerateParams) -> anyhow::Result<Self> {
+        let step_description = params.step_description(layout)?;
+        Ok(Self {
+            layout,
+            params: params.clone(),
+            image: draw_wedge(&step_description, params)?,
+        })
+    }
+
+    // Whether this is the wedge `generate_with_params` draws with `layout` and `params`
+    pub fn matches(&self, layout: Layout, params: &GenerateParams) -> bool {
+        self.layout == layout && self.params == *params
+    }
+
+    // The finished wedge, with `process` and `notes` drawn over a copy of this one
+    pub fn with_caption(
+        &self,
+        process: Option<String>,
+        notes: Option<String>,
+    ) -> anyhow::Result<DynamicImage> {
+        let step_description = self.params.step_description(self.layout)?;
+        caption(
+            self.image.clone(),
+            &step_description,
+            process,
+            notes,
+            &self.params,
+        )
+    }
+}
 
-    let mut image: Gray16Image =
-        ImageBuffer::new(step_description.width + 20, step_description.height + 20);
+// Draws the wedge described by `step_description` on a canvas filled with `params.background`
+fn render(
+    step_description: &StepDescription,
+    process: Option<String>,
+    notes: Option<String>,
+    params: &GenerateParams,
+) -> anyhow::Result<DynamicImage> {
+    let image = draw_wedge(step_description, params)?;
+    caption(image, step_description, process, notes, params)
+}
+
+// `render` without the process and notes, or the tone color
+fn draw_wedge(
+    step_description: &StepDescription,
+    params: &GenerateParams,
+) -> anyhow::Result<Gray16Image> {
+    let font_lato_black = FontRef::try_from_slice(LATO_BLACK_BYTES)?;
+
+    let start_x = MARGIN;
+    let start_y = MARGIN;
+
+    let mut image: Gray16Image = ImageBuffer::from_pixel(
+        step_description.width + (2 * MARGIN),
+        wedge_height(step_description, params) + (2 * MARGIN),
+        Luma([params.background]),
+    );
     draw_steps(
         &mut image,
         &font_lato_black,
-        &step_description,
+        step_description,
         start_x,
         start_y,
+        params.label,
+        params.printable_range,
     );
 
-    draw_grid(&mut image, &step_description, start_x, start_y);
+    match step_description.layout {
+        Layout::Grid => draw_grid(&mut image, step_description, start_x, start_y, params),
+        Layout::Separators => draw_separators(&mut image, step_description, start_x, start_y),
+    }
+    if params.antialias {
+        let line_width = match step_description.layout {
+            Layout::Grid => params.grid_thickness,
+            Layout::Separators => SEPARATOR_WIDTH,
+        };
+        soften_edges(&mut image, step_description, start_x, start_y, line_width);
+    }
+    if params.corner_dots {
+        draw_corner_dots(&mut image, step_description, start_x, start_y);
+    }
+    if params.index_strip {
+        index_strip::draw(
+            &mut image,
+            start_x,
+            start_y + step_description.total_height(),
+            step_description.width,
+            &WedgeIndex::new(step_description, params.printable_range),
+        )?;
+    }
+    Ok(image)
+}
+
+// Height of the grid and caption, and the index strip below them when there is one
+fn wedge_height(step_description: &StepDescription, params: &GenerateParams) -> u32 {
+    let strip = if params.index_strip {
+        index_strip::height(step_description.width)
+    } else {
+        0
+    };
+    step_description.total_height() + strip
+}
 
-    let process_and_notes_x = start_x + step_description.square_size;
-    let process_and_notes_y =
-        start_y + (step_description.square_size * (step_description.rows - 1));
+// Finishes a wedge from `draw_wedge`, drawing its process and notes then toning it
+fn caption(
+    mut image: Gray16Image,
+    step_description: &StepDescription,
+    process: Option<String>,
+    notes: Option<String>,
+    params: &GenerateParams,
+) -> anyhow::Result<DynamicImage> {
+    let font_lato_black = FontRef::try_from_slice(LATO_BLACK_BYTES)?;
     draw_process_and_notes(
         &mut image,
         &font_lato_black,
-        &step_description,
-        process_and_notes_x,
-        process_and_notes_y,
+        step_description,
+        MARGIN,
+        MARGIN,
         process,
         notes,
     );
 
-    Ok(DynamicImage::ImageLuma16(image))
+    Ok(match params.tone_color {
+        Some(color) => DynamicImage::ImageRgb16(tint(&image, color)),
+        None => DynamicImage::ImageLuma16(image),
+    })
+}
+
+/* Maps every tone of `image` onto `color`, keeping it as the luminance
+ *
+ * Tones darker than the color become shades of it, lighter ones blend it toward white. Either way
+ * each channel moves linearly with the tone, so the default luma weights analyze reads a color
+ * scan with give back the tone that was drawn.
+ */
+fn tint(image: &Gray16Image, color: ToneColor) -> ImageBuffer<Rgb<u16>, Vec<u16>> {
+    let weights = LumaWeights::default();
+    let color = color.0.map(|c| c as f32 / u8::MAX as f32);
+    let luminance = weights.r * color[0] + weights.g * color[1] + weights.b * color[2];
+    let max = u16::MAX as f32;
+
+    map_pixels(image, |_, _, p| {
+        let tone = p[0] as f32 / max;
+        Rgb(color.map(|c| {
+            let channel = if tone <= luminance && luminance > 0. {
+                c * tone / luminance
+            } else {
+                c + (1. - c) * (tone - luminance) / (1. - luminance)
+            };
+            (channel * max).round().clamp(0., max) as u16
+        }))
+    })
 }
 
 fn draw_steps(
@@ -61,62 +525,141 @@ fn draw_steps(
     step_description: &StepDescription,
     start_x: u32,
     start_y: u32,
+    label: Label,
+    printable_range: Option<ToneRange>,
 ) {
-    let mut n = 0;
-    for row in 0..step_description.rows {
-        for col in 0..step_description.columns {
-            // stop when we reach count
-            if n >= step_description.count {
-                break;
-            }
-            let x = start_x + (col * step_description.square_size);
-            let y = start_y + (row * step_description.square_size);
-            let tone = step_description.interval * n;
-
-            let rect = Rect::at(x as i32, y as i32)
-                .of_size(step_description.square_size, step_description.square_size);
-            draw_filled_rect_mut(image, rect, Luma([tone as u16]));
+    for patch in patches(step_description, start_x, start_y) {
+        let rect = Rect::at(patch.x as i32, patch.y as i32).of_size(patch.width, patch.height);
+        let tone = printable_tone(patch.tone, printable_range);
+        draw_filled_rect_mut(image, rect, Luma([tone]));
 
-            // flip the foreground color half way through to preserve contrast
-            let foreground_color = if n < step_description.count / 2 {
-                step_description.max_tone
-            } else {
-                BLACK
-            };
+        // flip the foreground color half way through to preserve contrast
+        let foreground_color = if patch.index < step_description.count / 2 {
+            step_description.max_tone
+        } else {
+            BLACK
+        };
 
-            // draw a count on the square. this i useful for hand analysis
-            draw_text_mut(
-                image,
-                Luma([foreground_color as u16]),
-                x as i32 + 5,
-                y as i32 + 5,
-                20 as f32,
-                font,
-                &format!("{}", n),
-            );
+        let outline_color = step_description.max_tone - foreground_color;
 
-            n += 1;
+        // draw a count (or density) on the square. this i useful for hand analysis
+        //
+        // the text is drawn shifted in every direction in the opposite tone first, leaving an
+        // outline that keeps it legible on patches close to the tone where the color flips
+        let text = patch_label(step_description, &patch, label);
+        let (x, y) = (
+            (patch.x + LABEL_OFFSET) as i32,
+            (patch.y + LABEL_OFFSET) as i32,
+        );
+        let outline = LABEL_OUTLINE as i32;
+        for dy in -outline..=outline {
+            for dx in -outline..=outline {
+                if (dx, dy) != (0, 0) {
+                    draw_text_mut(
+                        image,
+                        Luma([outline_color as u16]),
+                        x + dx,
+                        y + dy,
+                        LABEL_FONT_SIZE,
+                        font,
+                        &text,
+                    );
+                }
+            }
         }
+        draw_text_mut(
+            image,
+            Luma([foreground_color as u16]),
+            x,
+            y,
+            LABEL_FONT_SIZE,
+            font,
+            &text,
+        );
     }
 }
 
+// The text `label` draws in the corner of `patch`
+fn patch_label(step_description: &StepDescription, patch: &Patch, label: Label) -> String {
+    match label {
+        Label::Index => format!("{}", patch.index),
+        Label::Density => match density(patch.tone, step_description.max_tone) {
+            Some(density) => format!("{:.2}", density),
+            // pure black blocks all the light there is, as dense as the film gets
+            None => "Dmax".to_string(),
+        },
+    }
+}
+
+/* The optical density of a patch of `tone`, -log10 of the share of light it lets through
+ *
+ * Black has no finite density, None.
+ */
+pub fn density(tone: u16, max_tone: u32) -> Option<f64> {
+    (tone > 0).then(|| (max_tone as f64 / tone as f64).log10())
+}
+
+/* The (width, height) of the top left corner of a patch that its label can cover, measured
+ * from the corner of the patch to the far side of the widest label in the wedge.
+ *
+ * Analysis can't know which labels a scanned wedge was drawn with, so this covers every kind.
+ */
+pub fn label_extent(step_description: &StepDescription) -> (u32, u32) {
+    // the font is compiled in, it can't fail to load
+    let font = FontRef::try_from_slice(LATO_BLACK_BYTES).unwrap();
+    let width = patches(step_description, 0, 0)
+        .iter()
+        .flat_map(|patch| {
+            [Label::Index, Label::Density].map(|label| patch_label(step_description, patch, label))
+        })
+        // text_size rounds the advance down and glyphs like 'x' reach a little past it
+        .map(|text| text_size(LABEL_FONT_SIZE, &font, &text).0 + LABEL_OVERHANG)
+        .max()
+        .unwrap_or(0);
+    // text is laid out from the top of the font's ascent, so the full line height is what a
+    // number can reach down to, not just the height of the glyphs
+    let height = font.as_scaled(LABEL_FONT_SIZE).height().ceil() as u32;
+    (
+        LABEL_OFFSET + width + LABEL_OUTLINE,
+        LABEL_OFFSET + height + LABEL_OUTLINE,
+    )
+}
+
+/* Draws grid lines along the edges of the cells holding patches
+ *
+ * When the last row is only partly filled the lines stop at its last patch, leaving the rest of
+ * the row empty for the caption.
+ */
 fn draw_grid(
     image: &mut Gray16Image,
     step_description: &StepDescription,
     start_x: u32,
     start_y: u32,
+    params: &GenerateParams,
 ) {
+    let thickness = params.grid_thickness;
+
     // Draw the horizontal grid lines
     for row in 0..step_description.rows {
         // Flip the foreground color from white to black half way through to preserve contrast
-        let foreground_color = if row < step_description.rows / 2 {
-            step_description.max_tone
-        } else {
-            BLACK
+        let foreground_color = match params.grid_tone {
+            Some(tone) => tone as u32,
+            None if row < step_description.rows / 2 => step_description.max_tone,
+            None => BLACK,
         };
         let y = ((row * step_description.square_size) + start_y) as i32;
-        let squares_width = step_description.square_size * step_description.columns;
-        let rect = Rect::at(start_x as i32, y).of_size(squares_width, 2);
+        // the top of a row is also the bottom of the row above it, which may be the last
+        let cells = if row > 0 {
+            step_description.row_patches(row - 1)
+        } else {
+            step_description.row_patches(row)
+        };
+        // past the last row with patches in it there's nothing to draw a line along
+        if cells == 0 {
+            continue;
+        }
+        let rect =
+            Rect::at(start_x as i32, y).of_size(step_description.square_size * cells, thickness);
 
         draw_filled_rect_mut(image, rect, Luma([foreground_color as u16]));
     }
@@ -124,22 +667,144 @@ fn draw_grid(
     // Draw the vertical grid lines
     for col in 0..(step_description.columns + 1) {
         // pick a generic middle grey
-        let tone = step_description.max_tone / 2;
+        let tone = params
+            .grid_tone
+            .map_or(step_description.max_tone / 2, |tone| tone as u32);
         let x = ((col * step_description.square_size) + start_x) as i32;
 
-        // stop early after the first row so we can have some
-        // room to draw text for notes and process
-        let height = if col > 0 {
-            step_description.square_size * (step_description.rows - 1)
+        // only down through the rows with a patch on either side of the line
+        let rows = (0..step_description.rows)
+            .filter(|row| col <= step_description.row_patches(*row))
+            .count() as u32;
+        if rows == 0 {
+            continue;
+        }
+
+        let rect =
+            Rect::at(x, start_y as i32).of_size(thickness, step_description.square_size * rows);
+        draw_filled_rect_mut(image, rect, Luma([tone as u16]));
+    }
+}
+
+/* Marks every patch corner with a dot, a black core ringed in white so it stands out from any
+ * patch or line around it. Corners shared between patches get a single dot.
+ */
+fn draw_corner_dots(
+    image: &mut Gray16Image,
+    step_description: &StepDescription,
+    start_x: u32,
+    start_y: u32,
+) {
+    let corners: BTreeSet<(u32, u32)> = (0..step_description.count)
+        .flat_map(|n| step_description.patch_corners(n))
+        .collect();
+    for (col, row) in corners {
+        let center = (
+            (start_x + col * step_description.square_size) as i32,
+            (start_y + row * step_description.square_size) as i32,
+        );
+        let white = Luma([step_description.max_tone as u16]);
+        draw_filled_circle_mut(image, center, CORNER_DOT_RADIUS as i32, white);
+        draw_filled_circle_mut(image, center, CORNER_DOT_CORE as i32, Luma([0]));
+    }
+}
+
+/* Frames every patch with a separator of pure black or pure white
+ *
+ * Each patch draws the separator along its top and left edges, and along its right and bottom
+ * edges when there is no neighboring patch to do it. That way every boundary gets exactly one
+ * separator starting right on the boundary. The tone alternates in a checkerboard so that every
+ * separator line is made up of both extremes, which is what analyze keys off of.
+ */
+fn draw_separators(
+    image: &mut Gray16Image,
+    step_description: &StepDescription,
+    start_x: u32,
+    start_y: u32,
+) {
+    let size = step_description.square_size;
+
+    for n in 0..step_description.count {
+        let row = n / step_description.columns;
+        let col = n % step_description.columns;
+        let x = start_x + (col * size);
+        let y = start_y + (row * size);
+
+        let tone = if (row + col).is_multiple_of(2) {
+            step_description.max_tone
         } else {
-            step_description.square_size * step_description.rows
+            BLACK
         };
+        let color = Luma([tone as u16]);
 
-        let rect = Rect::at(x, start_y as i32).of_size(2, height);
-        draw_filled_rect_mut(image, rect, Luma([tone as u16]));
+        // top and left
+        draw_filled_rect_mut(
+            image,
+            Rect::at(x as i32, y as i32).of_size(size, SEPARATOR_WIDTH),
+            color,
+        );
+        draw_filled_rect_mut(
+            image,
+            Rect::at(x as i32, y as i32).of_size(SEPARATOR_WIDTH, size),
+            color,
+        );
+
+        // right, when this is the last patch in the row
+        if col == step_description.columns - 1 || n == step_description.count - 1 {
+            let rect = Rect::at((x + size) as i32, y as i32).of_size(SEPARATOR_WIDTH, size);
+            draw_filled_rect_mut(image, rect, color);
+        }
+
+        // bottom, when there is no patch below
+        if n + step_description.columns >= step_description.count {
+            let rect = Rect::at(x as i32, (y + size) as i32).of_size(size, SEPARATOR_WIDTH);
+            draw_filled_rect_mut(image, rect, color);
+        }
     }
 }
 
+/* Replaces the ring of pixels around the edge of every patch, just inside the grid lines or
+ * separators, with the average of the 3x3 block around each, so the hard step from line to patch
+ * becomes a one pixel ramp.
+ *
+ * Only that ring changes, everything further in keeps the patch's exact tone for analyze to sample.
+ */
+fn soften_edges(
+    image: &mut Gray16Image,
+    step_description: &StepDescription,
+    start_x: u32,
+    start_y: u32,
+    line_width: u32,
+) {
+    let hard = image.clone();
+
+    for patch in patches(step_description, start_x, start_y) {
+        let (left, top) = (patch.x + line_width, patch.y + line_width);
+        let (right, bottom) = (patch.x + patch.width - 1, patch.y + patch.height - 1);
+        let ring = (left..=right)
+            .flat_map(|x| [(x, top), (x, bottom)])
+            .chain((top..=bottom).flat_map(|y| [(left, y), (right, y)]));
+        for (x, y) in ring {
+            image.put_pixel(x, y, Luma([box_average(&hard, x, y)]));
+        }
+    }
+}
+
+// The mean of the 3x3 block of pixels centered on (x, y), cut short at the edges of the image
+fn box_average(image: &Gray16Image, x: u32, y: u32) -> u16 {
+    let xs = x.saturating_sub(1)..=(x + 1).min(image.width() - 1);
+    let ys = y.saturating_sub(1)..=(y + 1).min(image.height() - 1);
+    let pixels: Vec<u32> = ys
+        .flat_map(|y| xs.clone().map(move |x| (x, y)))
+        .map(|(x, y)| image.get_pixel(x, y)[0] as u32)
+        .collect();
+    (pixels.iter().sum::<u32>() / pixels.len() as u32) as u16
+}
+
+/* Draws the process and notes into the caption area of the wedge, wrapping long text onto more
+ * lines. Lines that don't fit in the caption area are dropped rather than spilling onto the
+ * patches, which analyze samples.
+ */
 fn draw_process_and_notes(
     image: &mut Gray16Image,
     font: &FontRef,
@@ -149,28 +814,563 @@ fn draw_process_and_notes(
     process: Option<String>,
     notes: Option<String>,
 ) {
-    let margin = 25;
+    let font_size = 20;
+    let caption_area = step_description.caption_area();
+    let margin = 10.min(caption_area.height / 4);
+    let max_width = caption_area.width.saturating_sub(2 * margin);
+    let max_lines = (caption_area.height.saturating_sub(2 * margin) / font_size) as usize;
+
+    let mut lines: Vec<String> = Vec::new();
+    if let Some(process) = process {
+        lines.extend(wrap_text(
+            font,
+            font_size,
+            &format!("Process: {}", process),
+            max_width,
+        ));
+    }
     if let Some(notes) = notes {
-        draw_text_mut(
-            image,
-            Luma([step_description.max_tone as u16]),
-            start_x as i32 + margin,
-            start_y as i32 + margin,
-            20 as f32,
+        lines.extend(wrap_text(
             font,
-            format!("Process: {}", notes).as_str(),
-        );
+            font_size,
+            &format!("Notes: {}", notes),
+            max_width,
+        ));
     }
-    if let Some(process) = process {
-        // Draw notes and process
+
+    let x = start_x + caption_area.x + margin;
+    let y = start_y + caption_area.y + margin;
+    // the caption goes on the empty background, pick whichever extreme stands out against it
+    let background = image.get_pixel_checked(x, y).map_or(0, |p| p[0] as u32);
+    let foreground_color = if background < step_description.max_tone / 2 {
+        step_description.max_tone
+    } else {
+        BLACK
+    };
+    for (i, line) in lines.iter().take(max_lines).enumerate() {
         draw_text_mut(
             image,
-            Luma([step_description.max_tone as u16]),
-            start_x as i32 + margin,
-            start_y as i32 + margin + 20,
-            20 as f32,
+            Luma([foreground_color as u16]),
+            x as i32,
+            (y + (i as u32 * font_size)) as i32,
+            font_size as f32,
             font,
-            format!("Notes: {}", process).as_str(),
+            line,
+        );
+    }
+}
+
+// Greedily breaks `text` into lines no wider than `max_width` pixels, splitting on whitespace
+pub fn wrap_text(font: &FontRef, font_size: u32, text: &str, max_width: u32) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut line = String::new();
+
+    for word in text.split_whitespace() {
+        let candidate = if line.is_empty() {
+            word.to_string()
+        } else {
+            format!("{} {}", line, word)
+        };
+        let (width, _) = text_size(font_size as f32, font, &candidate);
+        if width > max_width && !line.is_empty() {
+            lines.push(line);
+            line = word.to_string();
+        } else {
+            line = candidate;
+        }
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze::AnalyzeParams;
+
+    // true if any pixel inside a patch of `step_description` isn't black
+    fn patches_touched(image: &Gray16Image, step_description: &StepDescription) -> bool {
+        (0..step_description.count).any(|n| {
+            let x = 10 + (n % step_description.columns) * step_description.square_size;
+            let y = 10 + (n / step_description.columns) * step_description.square_size;
+            (x..x + step_description.square_size).any(|px| {
+                (y..y + step_description.square_size).any(|py| image.get_pixel(px, py)[0] != 0)
+            })
+        })
+    }
+
+    #[test]
+    fn test_caption_does_not_overlap_patches() {
+        let font = FontRef::try_from_slice(LATO_BLACK_BYTES).unwrap();
+        let process = Some("a very long process name that will need to wrap".to_string());
+        let notes = Some("and some notes that go on for a good while as well".to_string());
+
+        // a full last row, the caption needs its own strip
+        let full = StepDescription::new(12, 4, 200, u16::MAX as u32);
+        // one patch in the last row, the caption goes beside it
+        let partial = StepDescription::new(13, 4, 200, u16::MAX as u32);
+
+        for step_description in [full, partial] {
+            let mut image: Gray16Image =
+                ImageBuffer::new(220, step_description.total_height() + 20);
+            draw_process_and_notes(
+                &mut image,
+                &font,
+                &step_description,
+                10,
+                10,
+                process.clone(),
+                notes.clone(),
+            );
+
+            assert!(image.pixels().any(|p| p[0] != 0), "no caption was drawn");
+            assert!(!patches_touched(&image, &step_description));
+        }
+    }
+
+    #[test]
+    fn test_grid_partial_row() {
+        // 25 steps in 10 columns leaves 5 in the last row
+        let step_description = StepDescription::new(25, 10, 1000, u16::MAX as u32);
+        let image = render(&step_description, None, None, &GenerateParams::default())
+            .unwrap()
+            .to_luma16();
+        let grey = (step_description.max_tone / 2) as u16;
+        let size = step_description.square_size;
+        let last_row_middle = MARGIN + 2 * size + size / 2;
+
+        // the line closing off the last patch is drawn, the empty cells past it get none
+        assert_eq!(image.get_pixel(MARGIN + 5 * size, last_row_middle)[0], grey);
+        for col in 6..=10 {
+            assert_ne!(
+                image.get_pixel(MARGIN + col * size, last_row_middle)[0],
+                grey,
+                "column {}",
+                col
+            );
+        }
+        // the rows above are still closed off on the right
+        assert_eq!(
+            image.get_pixel(MARGIN + 10 * size, MARGIN + size / 2)[0],
+            grey
         );
     }
+
+    #[test]
+    fn test_wedge_caption() {
+        let params = GenerateParams {
+            tone_color: Some(ToneColor([112, 66, 20])),
+            ..GenerateParams::default()
+        };
+        let wedge = Wedge::new(Layout::Separators, &params).unwrap();
+        assert!(wedge.matches(Layout::Separators, &params));
+        assert!(!wedge.matches(Layout::Grid, &params));
+        assert!(!wedge.matches(Layout::Separators, &GenerateParams::default()));
+
+        // recaptioning the same wedge gives what generating it again would
+        for notes in [
+            None,
+            Some("a much longer note that wraps onto a second line".to_string()),
+        ] {
+            let process = Some("cyanotype".to_string());
+            let captioned = wedge.with_caption(process.clone(), notes.clone()).unwrap();
+            let generated =
+                generate_with_params(process, notes, Layout::Separators, &params).unwrap();
+            assert_eq!(captioned, generated);
+        }
+    }
+
+    #[test]
+    fn test_background() {
+        let caption = StepDescription::default().caption_area();
+        let caption_tones = |image: &Gray16Image| -> Vec<u16> {
+            (caption.x..caption.x + caption.width)
+                .flat_map(|x| (caption.y..caption.y + caption.height).map(move |y| (x, y)))
+                .map(|(x, y)| image.get_pixel(x + MARGIN, y + MARGIN)[0])
+                .collect()
+        };
+        let process = Some("cyanotype".to_string());
+
+        let params = GenerateParams {
+            background: u16::MAX,
+            ..GenerateParams::default()
+        };
+        let white = generate_with_params(process.clone(), None, Layout::Grid, &params)
+            .unwrap()
+            .to_luma16();
+        let (width, height) = white.dimensions();
+        for (x, y) in [
+            (0, 0),
+            (width - 1, 0),
+            (0, height - 1),
+            (width - 1, height - 1),
+        ] {
+            assert_eq!(white.get_pixel(x, y)[0], u16::MAX);
+        }
+        // the caption turns dark to show up on a light background
+        assert!(caption_tones(&white)
+            .iter()
+            .any(|tone| *tone < u16::MAX / 2));
+
+        let black = generate(process, None, Layout::Grid).unwrap().to_luma16();
+        assert_eq!(black.get_pixel(0, 0)[0], 0);
+        assert!(caption_tones(&black).contains(&u16::MAX));
+    }
+
+    #[test]
+    fn test_tone_color_keeps_luminance() {
+        let grey = generate(None, None, Layout::Grid).unwrap().to_luma16();
+        for color in ["sepia", "cyanotype", "#ffffff", "#000000"] {
+            let params = GenerateParams {
+                tone_color: Some(color.parse().unwrap()),
+                ..GenerateParams::default()
+            };
+            let toned = generate_with_params(None, None, Layout::Grid, &params).unwrap();
+            let luminance = crate::luma::to_luma16(&toned, LumaWeights::default());
+            for (toned, grey) in luminance.pixels().zip(grey.pixels()) {
+                assert!((toned[0] as i32 - grey[0] as i32).abs() <= 2, "{}", color);
+            }
+        }
+
+        // a mid patch really is colored, bluer than it is red
+        let cyanotype = GenerateParams {
+            tone_color: Some("cyanotype".parse().unwrap()),
+            ..GenerateParams::default()
+        };
+        let patch = &patches(&StepDescription::default(), MARGIN, MARGIN)[50];
+        let toned = generate_with_params(None, None, Layout::Grid, &cyanotype)
+            .unwrap()
+            .to_rgb16();
+        let [r, _, b] = toned
+            .get_pixel(patch.x + patch.width - 5, patch.y + patch.height - 5)
+            .0;
+        assert!(b > r);
+
+        assert_eq!(
+            "#704214".parse::<ToneColor>().unwrap(),
+            "sepia".parse().unwrap()
+        );
+        assert_eq!(ToneColor([1, 2, 255]).to_string(), "#0102ff");
+        assert!("#70421".parse::<ToneColor>().is_err());
+    }
+
+    #[test]
+    fn test_corner_dots() {
+        let step_description = StepDescription::default();
+        let params = GenerateParams {
+            corner_dots: true,
+            ..GenerateParams::default()
+        };
+        let dotted = render(&step_description, None, None, &params)
+            .unwrap()
+            .to_luma16();
+        let plain = render(&step_description, None, None, &GenerateParams::default())
+            .unwrap()
+            .to_luma16();
+
+        // a black core ringed in white at every corner of the 10 full rows, and the single patch
+        // of the last
+        let corners: BTreeSet<(u32, u32)> = (0..step_description.count)
+            .flat_map(|n| step_description.patch_corners(n))
+            .collect();
+        assert_eq!(corners.len(), 11 * 11 + 2);
+        let center = |(col, row): (u32, u32)| (MARGIN + col * 100, MARGIN + row * 100);
+        for corner in &corners {
+            let (x, y) = center(*corner);
+            assert_eq!(dotted.get_pixel(x, y)[0], 0);
+            assert_eq!(dotted.get_pixel(x + CORNER_DOT_RADIUS - 1, y)[0], u16::MAX);
+        }
+
+        // nothing else changes, the labels included
+        for (x, y, pixel) in dotted.enumerate_pixels() {
+            if pixel != plain.get_pixel(x, y) {
+                assert!(corners.iter().any(|corner| {
+                    let (cx, cy) = center(*corner);
+                    x.abs_diff(cx).max(y.abs_diff(cy)) <= CORNER_DOT_RADIUS
+                }));
+            }
+        }
+    }
+
+    #[test]
+    fn test_antialias_keeps_interiors() {
+        let step_description = StepDescription::default();
+        let params = GenerateParams {
+            antialias: true,
+            ..GenerateParams::default()
+        };
+        let (label_width, label_height) = label_extent(&step_description);
+        for layout in [Layout::Grid, Layout::Separators] {
+            let step_description = StepDescription::default().with_layout(layout);
+            let soft = render(&step_description, None, None, &params)
+                .unwrap()
+                .to_luma16();
+            let hard = render(&step_description, None, None, &GenerateParams::default())
+                .unwrap()
+                .to_luma16();
+
+            for patch in patches(&step_description, MARGIN, MARGIN) {
+                // inside the softened ring, away from the label
+                for py in patch.y + 3..patch.y + patch.height - 1 {
+                    for px in patch.x + 3..patch.x + patch.width - 1 {
+                        if px - patch.x < label_width && py - patch.y < label_height {
+                            continue;
+                        }
+                        assert_eq!(
+                            soft.get_pixel(px, py)[0],
+                            patch.tone,
+                            "patch {}",
+                            patch.index
+                        );
+                    }
+                }
+            }
+
+            // the first pixel inside a line is part way between the line and the patch
+            let (x, y) = (MARGIN + 2, MARGIN + 50);
+            let tone = soft.get_pixel(x, y)[0];
+            assert_ne!(tone, hard.get_pixel(x, y)[0]);
+            assert!(tone > hard.get_pixel(x, y)[0] && tone < hard.get_pixel(x - 1, y)[0]);
+        }
+    }
+
+    #[test]
+    fn test_label_outline() {
+        let step_description = StepDescription::default();
+        let image = generate(None, None, Layout::Grid).unwrap().to_luma16();
+        let (label_width, label_height) = label_extent(&step_description);
+
+        // the patches either side of the tone where the labels flip from white to black
+        for index in [step_description.count / 2 - 1, step_description.count / 2] {
+            let patch = &patches(&step_description, MARGIN, MARGIN)[index as usize];
+            let label: Vec<u16> = (patch.y + 2..patch.y + label_height)
+                .flat_map(|y| (patch.x + 2..patch.x + label_width).map(move |x| (x, y)))
+                .map(|(x, y)| image.get_pixel(x, y)[0])
+                .collect();
+            // the glyphs are antialiased, so the thin outline doesn't quite reach the extremes
+            assert!(
+                label.iter().any(|tone| *tone < u16::MAX / 10),
+                "patch {} has no black",
+                index
+            );
+            assert!(
+                label.iter().any(|tone| *tone > u16::MAX / 10 * 9),
+                "patch {} has no white",
+                index
+            );
+        }
+    }
+
+    #[test]
+    fn test_grid_thickness_and_tone() {
+        let params = GenerateParams {
+            grid_thickness: 6,
+            grid_tone: Some(20000),
+            ..GenerateParams::default()
+        };
+        let image = generate_with_params(None, None, Layout::Grid, &params)
+            .unwrap()
+            .to_luma16();
+        let square_size = StepDescription::default().square_size;
+
+        // the line between the first two patches, and the line below the first row
+        let line = MARGIN + square_size;
+        let middle = MARGIN + square_size / 2;
+        for offset in 0..6 {
+            assert_eq!(image.get_pixel(line + offset, middle)[0], 20000);
+            assert_eq!(image.get_pixel(middle, line + offset)[0], 20000);
+        }
+        assert_ne!(image.get_pixel(line + 6, middle)[0], 20000);
+        assert_ne!(image.get_pixel(middle, line + 6)[0], 20000);
+        assert_ne!(image.get_pixel(line - 1, middle)[0], 20000);
+    }
+
+    #[test]
+    fn test_density_labels() {
+        let step_description = StepDescription::default();
+        let label = |tone: u16| {
+            let patch = Patch {
+                index: 0,
+                x: 0,
+                y: 0,
+                width: 1,
+                height: 1,
+                tone,
+            };
+            patch_label(&step_description, &patch, Label::Density)
+        };
+        assert_eq!(label(u16::MAX), "0.00");
+        assert_eq!(label(u16::MAX / 10), "1.00");
+        assert_eq!(label(u16::MAX / 100), "2.00");
+        assert_eq!(label(0), "Dmax");
+    }
+
+    #[test]
+    fn test_printable_range() {
+        let printable_range: ToneRange = "0.1,0.9".parse().unwrap();
+        let (low, high) = (6554, 58982);
+        let params = GenerateParams {
+            printable_range: Some(printable_range),
+            ..GenerateParams::default()
+        };
+        let image = generate_with_params(None, None, Layout::Grid, &params)
+            .unwrap()
+            .into_luma16();
+
+        let manifest = manifest(Layout::Grid, &params).unwrap();
+        let (label_width, label_height) = label_extent(&StepDescription::default());
+        for patch in &manifest.patches {
+            assert!((low..=high).contains(&patch.tone), "patch {}", patch.index);
+            // sampled past the label and the grid lines
+            let tone = image.get_pixel(patch.x + label_width, patch.y + label_height)[0];
+            assert_eq!(tone, patch.tone, "patch {}", patch.index);
+        }
+        assert_eq!(manifest.patches[0].tone, low);
+        assert_eq!(manifest.patches.last().unwrap().tone, high);
+
+        // the rest keep their tone
+        let unclamped = super::manifest(Layout::Grid, &GenerateParams::default()).unwrap();
+        assert_eq!(manifest.patches[50].tone, unclamped.patches[50].tone);
+        let (below, above) = clamped_patches(Layout::Grid, &params).unwrap();
+        let moved = manifest
+            .patches
+            .iter()
+            .zip(&unclamped.patches)
+            .filter(|(clamped, patch)| clamped.tone != patch.tone)
+            .count();
+        assert!(below > 0 && above > 0);
+        assert_eq!(below + above, moved);
+    }
+
+    #[test]
+    fn test_measurable_steps() {
+        // 7 stops is about 2.1 of density, 42 measurable differences with a step at each end
+        assert_eq!(measurable_steps(7.), 43);
+        assert_eq!(measurable_steps(5.), 31);
+        // too small a range still gets both ends, too large is capped at the standard wedge
+        assert_eq!(measurable_steps(0.1), 2);
+        assert_eq!(measurable_steps(20.), 101);
+
+        let params = GenerateParams {
+            optimize_for_range: Some(7.),
+            ..GenerateParams::default()
+        };
+        let manifest = manifest(Layout::Grid, &params).unwrap();
+        assert_eq!(manifest.patches.len(), 43);
+        // spread evenly over the whole range, the tones analyze expects
+        let step_description = params.step_description(Layout::Grid).unwrap();
+        let tones: Vec<u16> = manifest.patches.iter().map(|patch| patch.tone).collect();
+        assert_eq!(tones, step_description.input_values());
+        assert_eq!(tones[42], u16::MAX);
+        let image = generate_with_params(None, None, Layout::Grid, &params).unwrap();
+        assert_eq!(image.height(), manifest.height);
+
+        // a much smaller range still reaches white, where analyze reads it
+        let params = GenerateParams {
+            optimize_for_range: Some(1.),
+            ..GenerateParams::default()
+        };
+        let tones: Vec<u16> = super::manifest(Layout::Grid, &params)
+            .unwrap()
+            .patches
+            .iter()
+            .map(|patch| patch.tone)
+            .collect();
+        let analyzed = AnalyzeParams {
+            steps: tones.len() as u32,
+            ..AnalyzeParams::default()
+        };
+        assert_eq!(tones, analyzed.step_description().unwrap().input_values());
+        assert_eq!(tones.last(), Some(&u16::MAX));
+    }
+
+    #[test]
+    fn test_strip_layout() {
+        let strip = GenerateParams {
+            columns: 101,
+            rows: Some(1),
+            ..GenerateParams::default()
+        };
+        let manifest = manifest(Layout::Grid, &strip).unwrap();
+        for (n, patch) in manifest.patches.iter().enumerate() {
+            assert_eq!((patch.x, patch.y), (MARGIN + n as u32 * 100, MARGIN));
+            assert_eq!((patch.width, patch.height), (100, 100));
+        }
+        // the caption gets a strip of its own below the patches
+        assert_eq!(manifest.width, 101 * 100 + 2 * MARGIN);
+        let image = generate_with_params(None, None, Layout::Grid, &strip).unwrap();
+        assert_eq!(
+            (image.width(), image.height()),
+            (manifest.width, manifest.height)
+        );
+        assert!(manifest.height < 200 + 2 * MARGIN);
+
+        // an extra row is left empty, too few rows don't fit the steps
+        let tall = GenerateParams {
+            columns: 51,
+            rows: Some(3),
+            ..GenerateParams::default()
+        };
+        let step_description = tall.step_description(Layout::Grid).unwrap();
+        assert_eq!(step_description.rows, 3);
+        assert_eq!(step_description.row_patches(2), 0);
+        generate_with_params(None, None, Layout::Grid, &tall).unwrap();
+        let short = GenerateParams {
+            rows: Some(2),
+            ..GenerateParams::default()
+        };
+        assert!(short.step_description(Layout::Grid).is_err());
+    }
+
+    #[test]
+    fn test_manifest_matches_draw_steps() {
+        let font = FontRef::try_from_slice(LATO_BLACK_BYTES).unwrap();
+        let manifest = manifest(Layout::Grid, &GenerateParams::default()).unwrap();
+        let step_description = StepDescription::default();
+        let mut image: Gray16Image = ImageBuffer::new(manifest.width, manifest.height);
+
+        assert_eq!(manifest.patches.len(), step_description.count as usize);
+        for label in [Label::Density, Label::Index] {
+            draw_steps(
+                &mut image,
+                &font,
+                &step_description,
+                MARGIN,
+                MARGIN,
+                label,
+                None,
+            );
+            for patch in &manifest.patches {
+                // everything but the label in the corner is the patch's tone
+                let (label_width, label_height) = label_extent(&step_description);
+                for py in patch.y..patch.y + patch.height {
+                    for px in patch.x..patch.x + patch.width {
+                        if px - patch.x < label_width && py - patch.y < label_height {
+                            continue;
+                        }
+                        assert_eq!(
+                            image.get_pixel(px, py)[0],
+                            patch.tone,
+                            "patch {} labeled with {:?}",
+                            patch.index,
+                            label
+                        );
+                    }
+                }
+            }
+        }
+
+        // and nothing was drawn outside the patches
+        let inside = |x: u32, y: u32| {
+            manifest.patches.iter().any(|patch| {
+                (patch.x..patch.x + patch.width).contains(&x)
+                    && (patch.y..patch.y + patch.height).contains(&y)
+            })
+        };
+        assert!(image
+            .enumerate_pixels()
+            .all(|(x, y, p)| inside(x, y) || p[0] == 0));
+    }
 }
@@ -0,0 +1,75 @@
+use anyhow::{anyhow, Result};
+use image::{DynamicImage, RgbaImage};
+use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions};
+
+// Untagged greyscale is assumed to be gamma 2.2, the default grey working space of most editors
+const UNTAGGED_GREY_GAMMA: f32 = 2.2;
+
+/* Converts `image` into the sRGB the preview is drawn in, so it looks the way it does in a color
+ * managed editor.
+ *
+ * `icc_profile` is the profile embedded in the image file. Without one greyscale images are
+ * treated as gamma 2.2 and color images as sRGB, which needs no conversion at all. The conversion
+ * runs from 16bit data so the shadows of 16bit scans don't band.
+ */
+pub fn to_display(image: &DynamicImage, icc_profile: Option<&[u8]>) -> Result<RgbaImage> {
+    let source = match icc_profile {
+        Some(bytes) => ColorProfile::new_from_slice(bytes)
+            .map_err(|e| anyhow!("failed to read icc profile: {:?}", e))?,
+        None if !image.color().has_color() => {
+            ColorProfile::new_gray_with_gamma(UNTAGGED_GREY_GAMMA)
+        }
+        None => return Ok(image.to_rgba8()),
+    };
+
+    let (layout, samples) = match source.color_space {
+        DataColorSpace::Gray => (Layout::Gray, image.to_luma16().into_raw()),
+        DataColorSpace::Rgb => (Layout::Rgb, image.to_rgb16().into_raw()),
+        other => return Err(anyhow!("can't preview {:?} profiles", other)),
+    };
+    let transform = source
+        .create_transform_16bit(
+            layout,
+            &ColorProfile::new_srgb(),
+            Layout::Rgb,
+            TransformOptions::default(),
+        )
+        .map_err(|e| anyhow!("failed to build display transform: {:?}", e))?;
+
+    let mut display = vec![0; image.width() as usize * image.height() as usize * 3];
+    transform
+        .transform(&samples, &mut display)
+        .map_err(|e| anyhow!("failed to transform preview: {:?}", e))?;
+
+    // the transform only covers color, carry the alpha of the original across
+    let mut rgba = image.to_rgba8();
+    for (pixel, rgb) in rgba.pixels_mut().zip(display.chunks_exact(3)) {
+        for channel in 0..3 {
+            pixel[channel] = (rgb[channel] >> 8) as u8;
+        }
+    }
+    Ok(rgba)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Luma, Rgb};
+
+    #[test]
+    fn test_to_display() {
+        // sRGB's linear toe is lighter than gamma 2.2, so deep shadows come out a little darker
+        // once converted, and the ends match
+        let grey: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_fn(3, 1, |x, _| Luma([[0, 0x1000, u16::MAX][x as usize]]));
+        let display = to_display(&DynamicImage::ImageLuma16(grey.clone()), None).unwrap();
+        let raw = DynamicImage::ImageLuma16(grey).to_rgba8();
+        assert!(display.get_pixel(0, 0)[0] <= 1);
+        assert!(display.get_pixel(1, 0)[0] < raw.get_pixel(1, 0)[0]);
+        assert!(display.get_pixel(2, 0)[0] >= 254);
+
+        // untagged color is already sRGB
+        let color = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(2, 2, Rgb([10, 128, 250])));
+        assert_eq!(to_display(&color, None).unwrap(), color.to_rgba8());
+    }
+}
@@ -0,0 +1,118 @@
+use image::DynamicImage;
+
+use crate::generate;
+use crate::luma::{self, LumaWeights};
+
+// Pixels either side of the clicked one averaged into a reading, like a densitometer's aperture
+const APERTURE: u32 = 2;
+
+// The tone of the scan around one point, and its density
+#[derive(Debug, Clone, Copy)]
+pub struct Reading {
+    pub pixel: (u32, u32),
+    pub tone: u16,
+    // None for pure black, which has no finite density
+    pub density: Option<f64>,
+}
+
+impl Reading {
+    // Reads the mean tone of `image` within `APERTURE` pixels of `(x, y)`
+    pub fn new(image: &DynamicImage, (x, y): (u32, u32), weights: LumaWeights) -> Self {
+        let left = x.saturating_sub(APERTURE);
+        let top = y.saturating_sub(APERTURE);
+        let width = (x + APERTURE + 1).min(image.width()) - left;
+        let height = (y + APERTURE + 1).min(image.height()) - top;
+        // only the aperture is converted, not the whole scan
+        let aperture = luma::to_luma16(&image.crop_imm(left, top, width, height), weights);
+
+        let total: u64 = aperture.pixels().map(|p| p[0] as u64).sum();
+        let tone = (total / (width * height).max(1) as u64) as u16;
+        Self {
+            pixel: (x, y),
+            tone,
+            density: generate::density(tone, u16::MAX as u32),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let (x, y) = self.pixel;
+        match self.density {
+            Some(density) => format!("{},{}: {} (D {:.2})", x, y, self.tone, density),
+            None => format!("{},{}: {} (Dmax)", x, y, self.tone),
+        }
+    }
+}
+
+/* Two readings taken by clicking on a scan, the second compared against the first
+ *
+ * A third click starts a new measurement.
+ */
+#[derive(Debug, Default)]
+pub struct Measurement {
+    readings: Vec<Reading>,
+}
+
+impl Measurement {
+    pub fn add(&mut self, reading: Reading) {
+        if self.readings.len() == 2 {
+            self.readings.clear();
+        }
+        self.readings.push(reading);
+    }
+
+    pub fn clear(&mut self) {
+        self.readings.clear();
+    }
+
+    // How much denser the second reading is than the first, the local contrast between them
+    pub fn density_difference(&self) -> Option<f64> {
+        match self.readings.as_slice() {
+            [first, second] => Some(second.density? - first.density?),
+            _ => None,
+        }
+    }
+
+    pub fn describe(&self) -> Option<String> {
+        match self.readings.as_slice() {
+            [] => None,
+            [first] => Some(format!("{}, click a second point", first.describe())),
+            [first, second, ..] => Some(format!(
+                "{} to {}, {}",
+                first.describe(),
+                second.describe(),
+                match self.density_difference() {
+                    Some(difference) => format!("ΔD {:+.2}", difference),
+                    None => "ΔD past Dmax".to_string(),
+                }
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Luma};
+
+    #[test]
+    fn test_measurement() {
+        // a tenth of the light on the left, a hundredth on the right
+        let image = DynamicImage::ImageLuma16(ImageBuffer::from_fn(100, 10, |x, _| {
+            Luma([if x < 50 { 6554 } else { 655 }])
+        }));
+        let weights = LumaWeights::default();
+
+        let mut measurement = Measurement::default();
+        measurement.add(Reading::new(&image, (10, 5), weights));
+        assert_eq!(measurement.density_difference(), None);
+        // right in the corner the aperture is clipped to the image
+        measurement.add(Reading::new(&image, (99, 9), weights));
+        let difference = measurement.density_difference().unwrap();
+        assert!((difference - 1.).abs() < 0.01, "{}", difference);
+
+        // a third reading starts over
+        measurement.add(Reading::new(&image, (60, 5), weights));
+        assert_eq!(measurement.readings.len(), 1);
+        assert_eq!(measurement.readings[0].tone, 655);
+    }
+}
@@ -1,41 +1,90 @@
-use regex;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
-use anyhow;
 use eframe::egui;
 use egui::{Color32, RichText};
-use image;
-use image::DynamicImage;
-use rfd;
+use image::{DynamicImage, ImageDecoder, ImageReader};
+// for Rect::contains, named apart from the regions of a sheet
+use imageproc::rect::Region as _;
 use splines::Spline;
 
 use super::analyze;
 use super::apply;
+use super::cancel::{Cancel, Cancelled};
+use super::compare::{self, Comparison};
+use super::curve;
 use super::generate;
+use super::luma::LumaWeights;
+use super::regions::{self, Region};
+use super::report;
+use super::session;
+use super::step_description::Layout;
 
+mod display_transform;
+mod measure;
+mod patch_navigation;
+mod saved_state;
+mod scan_transform;
 mod texture_buffered_image;
+mod web_export;
 
+use measure::{Measurement, Reading};
+use patch_navigation::Direction;
+use saved_state::SavedState;
+use scan_transform::ScanTransform;
 use texture_buffered_image::TextureBufferedImage;
 
 struct PreviewedImage {
     path: PathBuf,
     image: DynamicImage,
+    // the profile embedded in the file, used to color manage the preview
+    icc_profile: Option<Vec<u8>>,
     preview: TextureBufferedImage,
 }
 
-#[derive(Default)]
 struct ApplyPageState {
-    curve: Option<Spline<f64, f64>>,
+    curve: Option<(PathBuf, Spline<f64, f64>)>,
     image: Option<PreviewedImage>,
+    // a preview of the curve applied, shrunk to fit `preview_size`, see `apply::apply_preview`
     curved_image: Option<PreviewedImage>,
+    // how much of the correction to apply, see `apply::blend_with_identity`
+    strength: f64,
+    // show the image before the curve beside the curved image
+    before_after: bool,
+    // the longest side of the preview area last frame, in pixels
+    preview_size: u32,
+    // why the curve couldn't be applied or saved
+    status: Option<String>,
+}
+
+impl Default for ApplyPageState {
+    fn default() -> Self {
+        Self {
+            curve: None,
+            image: None,
+            curved_image: None,
+            strength: 1.0,
+            before_after: false,
+            preview_size: DEFAULT_PREVIEW_SIZE,
+            status: None,
+        }
+    }
 }
 
 #[derive(Default)]
 struct GeneratePageState {
     process: String,
     notes: String,
+    layout: Layout,
+    params: generate::GenerateParams,
     image: Option<PreviewedImage>,
+    // the previewed wedge without its caption, so editing the process and notes only redraws that
+    wedge: Option<generate::Wedge>,
+    // when the process or notes were last edited, the preview catches up once typing pauses
+    caption_edited: Option<f64>,
 }
 
 #[derive(Default, PartialEq)]
@@ -44,77 +93,320 @@ enum AnalyzePreviewTab {
     Scan,
     Results,
     Normalized,
+    // the grid lines found, by Detect Grid or by an analysis when debugging
+    Lines,
 }
 
+#[derive(Default)]
 struct AnalyzePageState {
+    // the scan exactly as loaded, `scan` is this with `transforms` applied
+    original_scan: Option<DynamicImage>,
+    transforms: Vec<ScanTransform>,
     scan: Option<PreviewedImage>,
     analysis: Option<analyze::AnalyzeResults>,
     analysis_preview: Option<TextureBufferedImage>,
     normalized_preview: Option<TextureBufferedImage>,
+    lines_preview: Option<TextureBufferedImage>,
+    // the grid found by Detect Grid, cleared by the next analysis or change to the scan
+    grid_detection: Option<analyze::GridDetection>,
     preview_tab: AnalyzePreviewTab,
-    inverted: bool,
+    params: analyze::AnalyzeParams,
+    // the params `analysis` was made with, refitting has to use the same ones
+    analysis_params: analyze::AnalyzeParams,
+    // steps of `analysis` left out of the curve, see `toggle_patch`
+    excluded: Vec<usize>,
+    // the step the arrow keys move from and space includes or excludes
+    selected: Option<usize>,
+    show_uncertainty: bool,
+    // a curve to draw the analyzed curve against, kept from one analysis to the next
+    reference: Option<(PathBuf, Spline<f64, f64>)>,
+    // show the curve's control points as text, in a window over the page
+    show_control_points: bool,
+    // buckets the results histogram is drawn with, None for `analyze::HISTOGRAM_BUCKETS`
+    histogram_buckets: Option<usize>,
+    // receives the results of an analysis running on a worker thread, along with its cancel token
+    running: Option<(
+        mpsc::Receiver<anyhow::Result<analyze::AnalyzeResults>>,
+        Cancel,
+    )>,
+    // receives the stages of the running analysis as it reaches them, and the latest one
+    stages: Option<mpsc::Receiver<&'static str>>,
+    stage: Option<&'static str>,
+    // why the last analysis didn't produce results
+    status: Option<String>,
+    // wedges on a sheet carrying several, each analyzed to its own curve
+    regions: Vec<Region>,
+    // receives how many curves were written once a region analysis finishes
+    regions_running: Option<(mpsc::Receiver<anyhow::Result<usize>>, Cancel)>,
+    // printed at the top of a saved report
+    report_process: String,
+    report_notes: String,
+    // tones read by clicking on the scan, cleared whenever the scan changes
+    measurement: Measurement,
 }
 
-impl Default for AnalyzePageState {
-    fn default() -> Self {
-        Self {
-            scan: None,
-            analysis: None,
-            analysis_preview: None,
-            normalized_preview: None,
-            preview_tab: AnalyzePreviewTab::default(),
-            inverted: false,
-        }
+impl AnalyzePageState {
+    fn histogram_buckets(&self) -> usize {
+        self.histogram_buckets.unwrap_or(analyze::HISTOGRAM_BUCKETS)
+    }
+
+    // The cancel token of whichever analysis is running, if any
+    fn running_cancel(&self) -> Option<&Cancel> {
+        self.running
+            .as_ref()
+            .map(|(_, cancel)| cancel)
+            .or(self.regions_running.as_ref().map(|(_, cancel)| cancel))
     }
 }
 
-#[derive(Default, PartialEq)]
+enum BatchStatus {
+    Pending,
+    Running,
+    Done(PathBuf),
+    Failed(String),
+    Cancelled,
+}
+
+struct BatchItem {
+    path: PathBuf,
+    status: BatchStatus,
+}
+
+#[derive(Default)]
+struct BatchPageState {
+    curve: Option<(PathBuf, Spline<f64, f64>)>,
+    output_dir: Option<PathBuf>,
+    queue: Vec<BatchItem>,
+    // receives (queue index, status) updates from the worker thread while a batch is running
+    progress: Option<mpsc::Receiver<(usize, BatchStatus)>>,
+    cancel: Cancel,
+    // why the last curve picked couldn't be used
+    status: Option<String>,
+}
+
+// The analyses of a before and an after scan
+type ScanPair = (analyze::AnalyzeResults, analyze::AnalyzeResults);
+
+// Two scans of the same wedge, from before and after a change to the process
+#[derive(Default)]
+struct ComparePageState {
+    before: Option<PreviewedImage>,
+    after: Option<PreviewedImage>,
+    params: analyze::AnalyzeParams,
+    comparison: Option<Comparison>,
+    preview: Option<TextureBufferedImage>,
+    // receives the analyses of both scans from a worker thread, along with its cancel token
+    running: Option<(mpsc::Receiver<anyhow::Result<ScanPair>>, Cancel)>,
+    // why the last comparison didn't produce results
+    status: Option<String>,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
 enum Page {
     #[default]
     Generate,
     Analyze,
     Apply,
+    Batch,
+    Compare,
 }
 
 #[derive(Default)]
 struct CurvedApp {
     debug: bool,
+    // show previews without the display transform
+    raw_previews: bool,
 
     page: Page,
     generate_page_state: GeneratePageState,
     analyze_page_state: AnalyzePageState,
     apply_page_state: ApplyPageState,
+    batch_page_state: BatchPageState,
+    compare_page_state: ComparePageState,
 }
 
-fn action_button(text: &str) -> egui::Button {
+fn action_button(text: &str) -> egui::Button<'_> {
     egui::Button::new(RichText::new(text).color(Color32::from_gray(16)))
         .fill(Color32::from_rgb(255, 143, 0))
 }
 
-fn draw_analyze_preview(
+// Shown in place of a preview when there is nothing to show yet
+fn empty_state(ui: &mut egui::Ui, text: &str) {
+    ui.centered_and_justified(|ui| {
+        ui.label(RichText::new(text).color(Color32::from_gray(140)));
+    });
+}
+
+// Draws the per-page reset button, returns true when clicked
+fn reset_button(ui: &mut egui::Ui) -> bool {
+    ui.separator();
+    ui.add_space(12.0);
+    ui.button("Reset").clicked()
+}
+
+fn layout_picker(ui: &mut egui::Ui, layout: &mut Layout) {
+    ui.label("Layout: ");
+    ui.horizontal(|ui| {
+        ui.radio_value(layout, Layout::Grid, "Grid lines");
+        ui.radio_value(layout, Layout::Separators, "Separators");
+    });
+}
+
+// Picks the one tone every grid line is drawn in, if any, see `generate::GenerateParams`
+fn grid_tone_picker(ui: &mut egui::Ui, grid_tone: &mut Option<u16>) {
+    let mut toned = grid_tone.is_some();
+    ui.checkbox(&mut toned, "Tone: ")
+        .on_hover_text("Draw every grid line in one tone, and find the lines by it");
+    let mut tone = grid_tone.unwrap_or(u16::MAX / 2);
+    ui.add_enabled(toned, egui::DragValue::new(&mut tone).speed(64));
+    *grid_tone = toned.then_some(tone);
+}
+
+fn load_previewed(path: PathBuf) -> anyhow::Result<PreviewedImage> {
+    let mut decoder = ImageReader::open(&path)?
+        .with_guessed_format()?
+        .into_decoder()?;
+    let icc_profile = decoder.icc_profile()?;
+    let image = DynamicImage::from_decoder(decoder)?;
+    let preview = TextureBufferedImage::new_managed(
+        path.to_string_lossy().to_string(),
+        &image,
+        icc_profile.as_deref(),
+    );
+    Ok(PreviewedImage {
+        path,
+        image,
+        icc_profile,
+        preview,
+    })
+}
+
+/* A freshly generated wedge to try curves on, its ramp shows the shape of a correction at a glance
+ *
+ * It was never loaded from anywhere, so its path is left empty.
+ */
+fn generated_wedge() -> anyhow::Result<PreviewedImage> {
+    let image = generate::generate(None, None, Layout::default())?;
+    let preview = TextureBufferedImage::new_managed("apply_wedge".to_string(), &image, None);
+    Ok(PreviewedImage {
+        path: PathBuf::new(),
+        image,
+        icc_profile: None,
+        preview,
+    })
+}
+
+// `wedge` captioned with `process` and `notes`, either left off when empty
+fn captioned_wedge(wedge: &generate::Wedge, process: &str, notes: &str) -> PreviewedImage {
+    let optional = |text: &str| (!text.is_empty()).then(|| text.to_string());
+    let image = wedge
+        .with_caption(optional(process), optional(notes))
+        .unwrap();
+    let preview = TextureBufferedImage::new(
+        format!("generated_step_wedge_{}_{}", process, notes),
+        &image,
+    );
+    PreviewedImage {
+        path: PathBuf::new(),
+        image,
+        icc_profile: None,
+        preview,
+    }
+}
+
+// Seconds typing has to pause for before the generate preview's caption is redrawn
+const CAPTION_DEBOUNCE: f64 = 0.3;
+
+fn load_curve(path: &Path) -> anyhow::Result<Spline<f64, f64>> {
+    curve::load_curve(path)
+}
+
+// Previews are sized to fit before the page has been laid out once
+const DEFAULT_PREVIEW_SIZE: u32 = 2048;
+
+// `image` with `strength` of `curve` applied at full resolution, for saving
+fn apply_curve(
+    image: &PreviewedImage,
+    curve: &Spline<f64, f64>,
+    strength: f64,
+) -> anyhow::Result<DynamicImage> {
+    let curve = apply::blend_with_identity(curve, strength);
+    apply::apply(&image.image, &curve, LumaWeights::default())
+}
+
+/* A quick preview of `apply_curve`, shrunk to fit within `preview_size` pixels
+ *
+ * The preview's image is only the shrunk copy, save with `apply_curve`.
+ */
+fn preview_curve(
+    image: &PreviewedImage,
     curve: &Spline<f64, f64>,
-    histogram: &Vec<u32>,
+    strength: f64,
+    preview_size: u32,
+) -> anyhow::Result<PreviewedImage> {
+    let curve = apply::blend_with_identity(curve, strength);
+    let curved_image =
+        apply::apply_preview(&image.image, &curve, LumaWeights::default(), preview_size)?;
+    // the curve moves tones around but leaves them in the same space, so the profile still holds
+    let preview = TextureBufferedImage::new_managed(
+        "curved_image_preview".to_string(),
+        &curved_image,
+        image.icc_profile.as_deref(),
+    );
+    Ok(PreviewedImage {
+        path: image.path.clone(),
+        image: curved_image,
+        icc_profile: image.icc_profile.clone(),
+        preview,
+    })
+}
+
+/* Draws the histogram and curve of `analysis`, over `reference` in `compare::BEFORE_COLOR` when
+ * there is one so the new curve stays on top
+ */
+fn draw_analyze_preview(
+    analysis: &analyze::AnalyzeResults,
+    show_uncertainty: bool,
+    histogram_buckets: usize,
+    reference: Option<&Spline<f64, f64>>,
 ) -> anyhow::Result<TextureBufferedImage> {
     let mut image: image::ImageBuffer<image::Rgb<u8>, Vec<u8>> =
         image::ImageBuffer::new(1024, 1024);
-    analyze::draw_histogram(&mut image, &histogram)?;
-    analyze::draw_curve(&mut image, &curve)?;
+    // color scans get a histogram per channel so a single channel clipping is easy to spot
+    if let Some(rgb_histograms) = &analysis.rgb_histograms {
+        analyze::draw_histograms_rgb(&mut image, rgb_histograms, histogram_buckets)?;
+    } else {
+        analyze::draw_histogram(&mut image, &analysis.histogram, histogram_buckets)?;
+    }
+    if show_uncertainty {
+        analyze::draw_uncertainty_band(&mut image, &analysis.curve, &analysis.uncertainty)?;
+    }
+    if let Some(reference) = reference {
+        analyze::draw_curve_colored(&mut image, reference, compare::BEFORE_COLOR)?;
+    }
+    analyze::draw_curve(&mut image, &analysis.curve)?;
     Ok(TextureBufferedImage::new(
-        format!("curve_and_histogram"),
+        "curve_and_histogram".to_string(),
         &DynamicImage::ImageRgb8(image),
     ))
 }
 
 impl CurvedApp {
-    fn new(_cc: &eframe::CreationContext<'_>, debug: bool) -> Self {
+    fn new(cc: &eframe::CreationContext<'_>, debug: bool) -> Self {
         // Customize egui here with cc.egui_ctx.set_fonts and cc.egui_ctx.set_visuals.
-        // Restore app state using cc.storage (requires the "persistence" feature).
         // Use the cc.gl (a glow::Context) to create graphics shaders and buffers that you can use
         // for e.g. egui::PaintCallback.
-        Self {
+        let mut app = Self {
             debug,
             ..Self::default()
+        };
+        if let Some(saved) = cc
+            .storage
+            .and_then(|storage| eframe::get_value::<SavedState>(storage, eframe::APP_KEY))
+        {
+            saved.restore(&mut app);
         }
+        app
     }
 }
 
@@ -124,8 +416,6 @@ impl CurvedApp {
  *
  * Maybe a sqlite database with previously stored curves? Could store them with date, process used,
  * maybe a snapshot of the scan?
- *
- * Maybe store the prevoius state of the app there so restarts are nicer?
  */
 
 pub fn start(debug: bool) {
@@ -149,7 +439,7 @@ fn slugify(s: String) -> anyhow::Result<String> {
     let re = regex::Regex::new("\\s")?;
     let downcased = str::to_lowercase(&s);
     let trimmed = str::trim(&downcased);
-    let no_white_space = re.replace_all(&trimmed, "-");
+    let no_white_space = re.replace_all(trimmed, "-");
     Ok(no_white_space.to_string())
 }
 
@@ -164,6 +454,15 @@ fn tab_bar(ui: &mut egui::Ui, app: &mut CurvedApp) {
                 ui.selectable_value(&mut app.page, Page::Generate, "Generate");
                 ui.selectable_value(&mut app.page, Page::Analyze, "Analyze");
                 ui.selectable_value(&mut app.page, Page::Apply, "Apply");
+                ui.selectable_value(&mut app.page, Page::Batch, "Batch");
+                ui.selectable_value(&mut app.page, Page::Compare, "Compare");
+                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                    ui.checkbox(&mut app.raw_previews, "Raw preview")
+                        .on_hover_text(
+                            "Show image values as they are, without converting from the \
+                             image's profile for display",
+                        );
+                });
             });
         });
 }
@@ -171,6 +470,7 @@ fn tab_bar(ui: &mut egui::Ui, app: &mut CurvedApp) {
 fn generate_page(ui: &mut egui::Ui, state: &mut GeneratePageState) {
     let mut process = state.process.clone();
     let mut notes = state.notes.clone();
+    let mut reset = false;
 
     egui::SidePanel::left("side_bar")
         .default_width(325.0)
@@ -178,7 +478,7 @@ fn generate_page(ui: &mut egui::Ui, state: &mut GeneratePageState) {
             ui.add_space(12.0);
             ui.label(
                 "Generates a step wedge for printing as a transparency. Output will be a 16bit \
-                 greyscale image. It is generated in its \"inverted\" form and does not need to \
+                 greyscale image, or color with a tone color. It is generated in its \"inverted\" form and does not need to \
                  be further inverted before printing. A 300 dpi print should be about 5\"x5.25\".",
             );
 
@@ -195,28 +495,86 @@ fn generate_page(ui: &mut egui::Ui, state: &mut GeneratePageState) {
                 .labelled_by(notes_label.id);
             ui.add_space(12.0);
 
-            if ui.button("Generate").clicked() {
-                let no = if notes == "" {
-                    None
-                } else {
-                    Some(notes.clone())
-                };
-                let pr = if process == "" {
-                    None
-                } else {
-                    Some(process.clone())
-                };
-                let image = generate::generate(no, pr).unwrap();
-                let preview = TextureBufferedImage::new(
-                    format!("generated_step_wedge_{}_{}", state.process, state.notes),
-                    &image,
+            layout_picker(ui, &mut state.layout);
+            ui.add_space(12.0);
+
+            ui.horizontal(|ui| {
+                ui.label("Background: ");
+                ui.add(egui::Slider::new(
+                    &mut state.params.background,
+                    0..=u16::MAX,
+                ))
+                .on_hover_text("Tone of the margin around the grid, 0 is black");
+            });
+            ui.label("Label patches with: ");
+            ui.horizontal(|ui| {
+                ui.radio_value(
+                    &mut state.params.label,
+                    generate::Label::Index,
+                    "Step number",
                 );
-                state.image = Some(PreviewedImage {
-                    path: PathBuf::new(),
-                    image,
-                    preview,
+                ui.radio_value(&mut state.params.label, generate::Label::Density, "Density")
+                    .on_hover_text("The density each patch should read on a densitometer");
+            });
+            if state.layout == Layout::Grid {
+                ui.horizontal(|ui| {
+                    ui.label("Grid lines: ");
+                    ui.add(egui::DragValue::new(&mut state.params.grid_thickness).range(1..=20))
+                        .on_hover_text("Width of the grid lines in pixels");
+                    grid_tone_picker(ui, &mut state.params.grid_tone);
                 });
             }
+            ui.checkbox(&mut state.params.antialias, "Soften patch edges")
+                .on_hover_text("Blends each patch's edge into the lines around it to avoid moire");
+            ui.checkbox(&mut state.params.corner_dots, "Corner dots")
+                .on_hover_text("Marks every patch corner with a dot analyze can find them by");
+            ui.checkbox(&mut state.params.index_strip, "Index strip")
+                .on_hover_text("Encodes the wedge's layout in bars along its bottom for analyze");
+            ui.horizontal(|ui| {
+                let mut toned = state.params.tone_color.is_some();
+                ui.checkbox(&mut toned, "Tone color: ").on_hover_text(
+                    "Render the wedge in shades of a color, like the print of a toned process",
+                );
+                let mut color = state
+                    .params
+                    .tone_color
+                    .unwrap_or(generate::ToneColor([112, 66, 20]))
+                    .0;
+                ui.add_enabled_ui(toned, |ui| ui.color_edit_button_srgb(&mut color));
+                state.params.tone_color = toned.then_some(generate::ToneColor(color));
+            });
+            ui.add_space(12.0);
+
+            let now = ui.input(|input| input.time);
+            if process != state.process || notes != state.notes {
+                state.caption_edited = Some(now);
+            }
+
+            if ui.button("Generate").clicked() {
+                if !state
+                    .wedge
+                    .as_ref()
+                    .is_some_and(|wedge| wedge.matches(state.layout, &state.params))
+                {
+                    state.wedge = Some(generate::Wedge::new(state.layout, &state.params).unwrap());
+                }
+                if let Some(wedge) = &state.wedge {
+                    state.image = Some(captioned_wedge(wedge, &process, &notes));
+                }
+                state.caption_edited = None;
+            } else if let (Some(wedge), Some(edited)) = (&state.wedge, state.caption_edited) {
+                // once there's a preview its caption follows the process and notes as they're
+                // typed, everything else waits for Generate
+                if now - edited < CAPTION_DEBOUNCE {
+                    ui.ctx()
+                        .request_repaint_after(Duration::from_secs_f64(CAPTION_DEBOUNCE - (now - edited)));
+                } else {
+                    state.image = Some(captioned_wedge(wedge, &process, &notes));
+                    state.caption_edited = None;
+                }
+            }
+
+            reset = reset_button(ui);
         });
 
     egui::CentralPanel::default().show_inside(ui, |ui| {
@@ -225,7 +583,7 @@ fn generate_page(ui: &mut egui::Ui, state: &mut GeneratePageState) {
             .show_inside(ui, |ui| {
                 ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
                     if let Some(image) = &state.image {
-                        let filename = if state.process == "" {
+                        let filename = if state.process.is_empty() {
                             "step-wedge.png".to_string()
                         } else {
                             format!("{}-step-wedge.png", slugify(state.process.clone()).unwrap())
@@ -237,23 +595,29 @@ fn generate_page(ui: &mut egui::Ui, state: &mut GeneratePageState) {
                                 image.image.save(path).unwrap();
                             }
                         };
-                    } else {
-                        ui.add_enabled(false, egui::Button::new("Save"));
                     }
                 });
             });
         egui::CentralPanel::default().show_inside(ui, |ui| {
             if let Some(image) = &mut state.image {
                 image.preview.ui(ui);
+            } else {
+                empty_state(ui, "Press Generate to create a step wedge");
             }
         });
     });
 
     state.process = process.to_string();
     state.notes = notes.to_string();
+
+    if reset {
+        *state = GeneratePageState::default();
+    }
 }
 
 fn apply_page(ui: &mut egui::Ui, state: &mut ApplyPageState) {
+    let mut reset = false;
+
     egui::SidePanel::left("side_bar")
         .min_width(325.0)
         .show_inside(ui, |ui| {
@@ -262,18 +626,44 @@ fn apply_page(ui: &mut egui::Ui, state: &mut ApplyPageState) {
             ui.add_space(12.0);
             if ui.button("Select Image").clicked() {
                 if let Some(path) = rfd::FileDialog::new().pick_file() {
-                    let image = image::open(&path).unwrap();
-                    let preview = TextureBufferedImage::new(
-                        path.clone().into_os_string().into_string().unwrap(),
-                        &image,
-                    );
-                    state.image = Some(PreviewedImage {
-                        path,
-                        image,
-                        preview,
-                    });
+                    state.image = Some(load_previewed(path).unwrap());
+                    state.curved_image = None;
                 }
             };
+            if ui
+                .button("Use Generated Wedge")
+                .on_hover_text("Check what a curve does to a fresh step wedge")
+                .clicked()
+            {
+                state.image = Some(generated_wedge().unwrap());
+                state.curved_image = None;
+            }
+
+            if let Some(image) = &state.image {
+                ui.add_space(12.0);
+                ui.label("Strength: ");
+                let response = ui
+                    .add(egui::Slider::new(&mut state.strength, 0.0..=1.0))
+                    .on_hover_text("How much of the correction to apply");
+                // only re-apply once a drag finishes, re-applying every frame is too slow
+                let committed =
+                    response.drag_stopped() || (response.changed() && !response.dragged());
+                if let (Some((_, curve)), Some(_)) = (&state.curve, &state.curved_image) {
+                    if committed {
+                        match preview_curve(image, curve, state.strength, state.preview_size) {
+                            Ok(curved_image) => state.curved_image = Some(curved_image),
+                            Err(e) => state.status = Some(format!("Couldn't apply curve: {:#}", e)),
+                        }
+                    }
+                }
+                if state.curved_image.is_some() {
+                    ui.add_space(12.0);
+                    ui.checkbox(&mut state.before_after, "Before / after")
+                        .on_hover_text("Show the image without the curve beside the curved image");
+                }
+
+                reset = reset_button(ui);
+            }
         });
 
     egui::CentralPanel::default().show_inside(ui, |ui| {
@@ -283,15 +673,43 @@ fn apply_page(ui: &mut egui::Ui, state: &mut ApplyPageState) {
                 ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
                     if state.curved_image.is_some() {
                         ui.horizontal(|ui| {
-                            if state.curved_image.is_some() {
-                                if ui.add(action_button("Undo")).clicked() {
-                                    state.curved_image = None;
-                                }
+                            if state.curved_image.is_some()
+                                && ui.add(action_button("Undo")).clicked()
+                            {
+                                state.curved_image = None;
+                                state.curve = None;
                             }
-                            if let Some(ci) = &state.curved_image {
+                            if let (Some(image), Some((_, curve))) = (&state.image, &state.curve) {
                                 if ui.add(action_button("Save")).clicked() {
                                     if let Some(path) = rfd::FileDialog::new().save_file() {
-                                        ci.image.save(path).unwrap();
+                                        // the preview is shrunk, save the curve applied in full
+                                        let saved = apply_curve(image, curve, state.strength)
+                                            .and_then(|curved| Ok(curved.save(path)?));
+                                        state.status =
+                                            saved.err().map(|e| format!("Couldn't save: {:#}", e));
+                                    }
+                                }
+                                if ui
+                                    .add(action_button("Export for Web"))
+                                    .on_hover_text("Save an 8bit sRGB png to share online")
+                                    .clicked()
+                                {
+                                    if let Some(path) = rfd::FileDialog::new()
+                                        .add_filter("png", &["png"])
+                                        .set_file_name("curved-web.png")
+                                        .save_file()
+                                    {
+                                        let saved = apply_curve(image, curve, state.strength)
+                                            .and_then(|curved| {
+                                                web_export::save_for_web(
+                                                    &curved,
+                                                    image.icc_profile.as_deref(),
+                                                    &path,
+                                                )
+                                            });
+                                        state.status = saved
+                                            .err()
+                                            .map(|e| format!("Couldn't export: {:#}", e));
                                     }
                                 }
                             }
@@ -299,194 +717,1693 @@ fn apply_page(ui: &mut egui::Ui, state: &mut ApplyPageState) {
                     } else if let Some(image) = &state.image {
                         if ui.add(action_button("Apply Curve")).clicked() {
                             if let Some(curve_file) = rfd::FileDialog::new().pick_file() {
-                                let curve_data = fs::read_to_string(curve_file).unwrap();
-                                let curve =
-                                    serde_json::from_str::<Spline<f64, f64>>(&curve_data).unwrap();
-                                let curved_image = apply::apply(&image.image, &curve);
-                                state.curve = Some(curve);
-
-                                let preview = TextureBufferedImage::new(
-                                    "curved_image_preview".to_string(),
-                                    &curved_image,
-                                );
-                                state.curved_image = Some(PreviewedImage {
-                                    path: image.path.clone(),
-                                    image: curved_image,
-                                    preview,
+                                let curved = load_curve(&curve_file).and_then(|curve| {
+                                    let curved_image = preview_curve(
+                                        image,
+                                        &curve,
+                                        state.strength,
+                                        state.preview_size,
+                                    )?;
+                                    Ok((curve, curved_image))
                                 });
+                                match curved {
+                                    Ok((curve, curved_image)) => {
+                                        state.curved_image = Some(curved_image);
+                                        state.curve = Some((curve_file, curve));
+                                        state.status = None;
+                                    }
+                                    Err(e) => {
+                                        state.status =
+                                            Some(format!("Couldn't apply curve: {:#}", e))
+                                    }
+                                }
                             }
                         };
                     }
+                    if let Some(status) = &state.status {
+                        ui.label(status);
+                    }
                 })
             });
         egui::CentralPanel::default().show_inside(ui, |ui| {
-            if let Some(ci) = &mut state.curved_image {
+            let available = ui.available_size() * ui.ctx().pixels_per_point();
+            state.preview_size = (available.max_elem() as u32).max(1);
+            if let (true, Some(image), Some(ci)) = (
+                state.before_after,
+                &mut state.image,
+                &mut state.curved_image,
+            ) {
+                ui.columns(2, |columns| {
+                    image.preview.ui(&mut columns[0]);
+                    ci.preview.ui(&mut columns[1]);
+                });
+            } else if let Some(ci) = &mut state.curved_image {
                 ci.preview.ui(ui);
             } else if let Some(image) = &mut state.image {
                 image.preview.ui(ui);
+            } else {
+                empty_state(ui, "Select an image to begin");
             }
         });
     });
+
+    if reset {
+        *state = ApplyPageState::default();
+    }
 }
 
-fn analyze_page(ui: &mut egui::Ui, state: &mut AnalyzePageState, debug: bool) {
-    egui::SidePanel::left("side_bar")
-        .min_width(325.0)
-        .show_inside(ui, |ui| {
-            ui.add_space(12.0);
-            ui.label(
-                "Analyze evaluates a scan of the generated step wedge to generate a new curve \
-                 file.",
-            );
-            ui.separator();
-            ui.add_space(12.0);
-            if ui.button("Select Scan").clicked() {
-                if let Some(file) = rfd::FileDialog::new().pick_file() {
-                    let path = PathBuf::from(file.display().to_string());
-                    let image = image::open(&path).unwrap();
+// Re-runs the transform pipeline against the original scan and refreshes the preview
+fn refresh_scan(state: &mut AnalyzePageState) {
+    if let (Some(original), Some(scan)) = (&state.original_scan, &mut state.scan) {
+        scan.image = scan_transform::apply_all(original, &state.transforms);
+        refresh_scan_preview(scan, &state.regions);
+    }
+    state.measurement.clear();
+    state.grid_detection = None;
+}
 
-                    let preview = TextureBufferedImage::new(
-                        path.clone().into_os_string().into_string().unwrap(),
-                        &image,
-                    );
+// Rebuilds the scan's preview, outlining any regions on top of it
+fn refresh_scan_preview(scan: &mut PreviewedImage, regions: &[Region]) {
+    let handle = format!("{}_transformed", scan.path.to_string_lossy());
+    scan.preview = TextureBufferedImage::new_managed_with_overlay(
+        handle,
+        &scan.image,
+        scan.icc_profile.as_deref(),
+        |preview| regions::draw_regions(preview, regions),
+    );
+}
 
-                    state.scan = Some(PreviewedImage {
-                        path: path.clone(),
-                        image,
-                        preview,
-                    });
-                }
+/* Lists the regions with controls to name, place, or remove each one, returns true if any of them
+ * changed and the outlines need to be redrawn.
+ */
+fn regions_editor(ui: &mut egui::Ui, regions: &mut Vec<Region>, scan_size: (u32, u32)) -> bool {
+    let mut changed = false;
+    if ui.button("Add region").clicked() {
+        regions.push(Region {
+            name: format!("region-{}", regions.len() + 1),
+            x: 0,
+            y: 0,
+            width: scan_size.0,
+            height: scan_size.1,
+        });
+        changed = true;
+    }
+
+    let mut removed = None;
+    for (i, region) in regions.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            if ui.small_button("x").clicked() {
+                removed = Some(i);
             }
-            if let Some(scan) = &mut state.scan {
-                if ui.button("left").clicked() {
-                    scan.image = scan.image.rotate270();
-                    scan.preview =
-                        TextureBufferedImage::new(format!("image_rotated_270"), &scan.image)
-                };
-                if ui.button("right").clicked() {
-                    scan.image = scan.image.rotate90();
-                    scan.preview =
-                        TextureBufferedImage::new(format!("image_rotated_90"), &scan.image)
-                };
-                if state.inverted {
-                    if ui.button("uninvert").clicked() {
-                        state.inverted = false
-                    };
-                } else {
-                    if ui.button("invert").clicked() {
-                        state.inverted = true
-                    };
-                }
+            ui.add(egui::TextEdit::singleline(&mut region.name).desired_width(80.));
+            for (value, max) in [
+                (&mut region.x, scan_size.0),
+                (&mut region.y, scan_size.1),
+                (&mut region.width, scan_size.0),
+                (&mut region.height, scan_size.1),
+            ] {
+                changed |= ui.add(egui::DragValue::new(value).range(0..=max)).changed();
             }
         });
+    }
 
-    egui::CentralPanel::default().show_inside(ui, |ui| {
-        egui::TopBottomPanel::top("analyze_tabs")
-            .min_height(32.0)
-            .show_inside(ui, |ui| {
-                ui.horizontal(|ui| {
-                    ui.selectable_value(&mut state.preview_tab, AnalyzePreviewTab::Scan, "Scan");
-                    ui.selectable_value(
-                        &mut state.preview_tab,
-                        AnalyzePreviewTab::Results,
-                        "Results",
-                    );
-                    ui.selectable_value(
-                        &mut state.preview_tab,
-                        AnalyzePreviewTab::Normalized,
-                        "Normalized",
-                    );
-                });
+    if let Some(i) = removed {
+        regions.remove(i);
+        changed = true;
+    }
+    changed
+}
+
+/* Analyzes each region of the current scan on a worker thread, writing curve-<name>.json for each
+ * into `output_dir`, see `poll_regions_analysis`
+ */
+fn start_regions_analysis(
+    ctx: &egui::Context,
+    state: &mut AnalyzePageState,
+    output_dir: PathBuf,
+    debug: bool,
+) {
+    let Some(scan) = &state.scan else {
+        return;
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let image = scan.image.clone();
+    let regions = state.regions.clone();
+    let params = state.params.clone();
+    let cancel = Cancel::default();
+    let worker_cancel = cancel.clone();
+    let ctx = ctx.clone();
+
+    state.status = None;
+    state.regions_running = Some((receiver, cancel));
+
+    thread::spawn(move || {
+        let written = || -> anyhow::Result<usize> {
+            let results =
+                regions::analyze_regions(&image, &regions, &params, debug, &worker_cancel)?;
+            for (name, analyze_results) in &results {
+                let path = output_dir.join(format!("curve-{}.json", name));
+                curve::save_analyzed_curve(&path, &analyze_results.curve, &params)?;
+            }
+            Ok(results.len())
+        };
+        let _ = sender.send(written());
+        ctx.request_repaint();
+    });
+}
+
+// Reports how a running region analysis went once the worker has finished
+fn poll_regions_analysis(state: &mut AnalyzePageState) {
+    let Some((receiver, _)) = &state.regions_running else {
+        return;
+    };
+    let result = match receiver.try_recv() {
+        Ok(result) => result,
+        Err(mpsc::TryRecvError::Empty) => return,
+        Err(mpsc::TryRecvError::Disconnected) => Err(anyhow::anyhow!("analysis stopped")),
+    };
+    state.regions_running = None;
+
+    state.status = Some(match result {
+        Ok(written) => format!("Saved {} curves", written),
+        Err(e) if e.is::<Cancelled>() => "Analysis cancelled".to_string(),
+        Err(e) => format!("Analysis failed: {:#}", e),
+    });
+}
+
+/* Lists the scan transforms with controls to edit or remove each one, returns true if the
+ * pipeline changed and the scan needs to be refreshed.
+ */
+fn transforms_editor(ui: &mut egui::Ui, transforms: &mut Vec<ScanTransform>) -> bool {
+    let mut changed = false;
+    // only refresh once a drag finishes, re-running the pipeline every frame is too slow
+    let committed = |response: egui::Response| {
+        response.drag_stopped() || (response.changed() && !response.dragged())
+    };
+
+    ui.horizontal_wrapped(|ui| {
+        if ui.button("Rotate left").clicked() {
+            transforms.push(ScanTransform::RotateLeft);
+            changed = true;
+        }
+        if ui.button("Rotate right").clicked() {
+            transforms.push(ScanTransform::RotateRight);
+            changed = true;
+        }
+        if ui.button("Crop").clicked() {
+            transforms.push(ScanTransform::Crop {
+                left: 0,
+                top: 0,
+                right: 0,
+                bottom: 0,
             });
-        egui::TopBottomPanel::bottom("actions")
-            .min_height(32.0)
-            .show_inside(ui, |ui| {
-                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
-                    match state.preview_tab {
-                        AnalyzePreviewTab::Scan => {
-                            if let Some(scan) = &state.scan {
-                                if ui.add_enabled(true, action_button("Analyze")).clicked() {
-                                    let analyze_results =
-                                        analyze::analyze(&scan.image, state.inverted, debug)
-                                            .unwrap();
-                                    state.analysis_preview = Some(
-                                        draw_analyze_preview(
-                                            &analyze_results.curve,
-                                            &analyze_results.histogram,
-                                        )
-                                        .unwrap(),
-                                    );
-                                    state.normalized_preview = Some(TextureBufferedImage::new(
-                                        "normalized_image".to_string(),
-                                        &analyze_results.normalized_image,
-                                    ));
-                                    state.analysis = Some(analyze_results);
-                                    state.preview_tab = AnalyzePreviewTab::Results;
-                                }
-                            } else {
-                                ui.add_enabled(false, action_button("Analyze"));
-                            }
-                            if let Some(scan) = &mut state.scan {
-                                let path: String = scan.path.to_string_lossy().to_string();
-                                ui.monospace(path);
-                            }
-                        }
-                        AnalyzePreviewTab::Results => {
-                            if let Some(analysis) = &state.analysis {
-                                if ui.add(action_button("Save JSON")).clicked() {
-                                    if let Some(path) = rfd::FileDialog::new()
-                                        .set_file_name("curve.json")
-                                        .save_file()
-                                    {
-                                        let curve_file = fs::File::create(path).unwrap();
-                                        serde_json::to_writer(&curve_file, &analysis.curve)
-                                            .unwrap();
-                                    }
-                                };
-                                if ui.add(action_button("Save CSV")).clicked() {
-                                    if let Some(path) = rfd::FileDialog::new()
-                                        .set_file_name("curve.csv")
-                                        .save_file()
-                                    {
-                                        let mut csv_file = fs::File::create(path).unwrap();
-                                        analyze::write_small_csv(&mut csv_file, &analysis.curve)
-                                            .unwrap();
-                                    }
-                                };
-                            } else {
-                                let _ = ui.button("Save");
-                            }
-                        }
-                        AnalyzePreviewTab::Normalized => {}
-                    };
-                });
+            changed = true;
+        }
+        if ui.button("Levels").clicked() {
+            transforms.push(ScanTransform::Levels {
+                black: 0,
+                white: u16::MAX,
             });
-        egui::CentralPanel::default().show_inside(ui, |ui| match state.preview_tab {
-            AnalyzePreviewTab::Scan => {
-                if let Some(scan) = &mut state.scan {
-                    scan.preview.ui(ui);
-                }
+            changed = true;
+        }
+    });
+
+    let mut removed = None;
+    for (i, transform) in transforms.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            if ui.small_button("x").clicked() {
+                removed = Some(i);
             }
-            AnalyzePreviewTab::Results => {
-                if let Some(preview) = &mut state.analysis_preview {
-                    preview.ui(ui);
+            ui.label(transform.label());
+            match transform {
+                ScanTransform::Crop {
+                    left,
+                    top,
+                    right,
+                    bottom,
+                } => {
+                    for value in [left, top, right, bottom] {
+                        changed |= committed(ui.add(egui::DragValue::new(value)));
+                    }
                 }
-            }
-            AnalyzePreviewTab::Normalized => {
-                if let Some(preview) = &mut state.normalized_preview {
-                    preview.ui(ui);
+                ScanTransform::Levels { black, white } => {
+                    changed |= committed(ui.add(egui::DragValue::new(black).speed(64)));
+                    changed |= committed(ui.add(egui::DragValue::new(white).speed(64)));
                 }
+                ScanTransform::RotateLeft | ScanTransform::RotateRight => {}
             }
         });
-    });
+    }
+
+    if let Some(i) = removed {
+        transforms.remove(i);
+        changed = true;
+    }
+    changed
 }
 
-impl eframe::App for CurvedApp {
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        egui::CentralPanel::default().show(ctx, |ui| {
-            tab_bar(ui, self);
-            match &mut self.page {
-                Page::Generate => {
+// Starts analyzing the current scan on a worker thread, see `poll_analysis`
+fn start_analysis(ctx: &egui::Context, state: &mut AnalyzePageState, debug: bool) {
+    let Some(scan) = &state.scan else {
+        return;
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let image = scan.image.clone();
+    let params = state.params.clone();
+    let cancel = Cancel::default();
+    let worker_cancel = cancel.clone();
+    let ctx = ctx.clone();
+
+    state.status = None;
+    state.analysis_params = params.clone();
+    state.running = Some((receiver, cancel));
+
+    let (stage_sender, stages) = mpsc::channel();
+    state.stages = Some(stages);
+    state.stage = None;
+
+    thread::spawn(move || {
+        let mut observer = StageObserver {
+            sender: stage_sender,
+            ctx: ctx.clone(),
+        };
+        let _ = sender.send(analyze::analyze_observed(
+            &image,
+            &params,
+            debug,
+            &worker_cancel,
+            &mut observer,
+        ));
+        ctx.request_repaint();
+    });
+}
+
+/* Finds the grid in the current scan and shows it on the Lines tab, along with where each patch
+ * will be sampled, without running the rest of the analysis
+ */
+fn detect_grid(state: &mut AnalyzePageState) {
+    let Some(scan) = &state.scan else {
+        return;
+    };
+    match analyze::detect_grid(&scan.image, &state.params) {
+        Ok(detection) => {
+            state.lines_preview = Some(TextureBufferedImage::new(
+                "detected_lines_preview".to_string(),
+                &DynamicImage::ImageRgb8(analyze::draw_grid_detection(&scan.image, &detection)),
+            ));
+            state.grid_detection = Some(detection);
+            state.preview_tab = AnalyzePreviewTab::Lines;
+            state.status = None;
+        }
+        Err(e) => state.status = Some(format!("Grid detection failed: {:#}", e)),
+    }
+}
+
+// Passes each stage of an analysis on to the page showing its progress
+struct StageObserver {
+    sender: mpsc::Sender<&'static str>,
+    ctx: egui::Context,
+}
+
+impl analyze::AnalyzeObserver for StageObserver {
+    fn stage(&mut self, name: &'static str) {
+        let _ = self.sender.send(name);
+        self.ctx.request_repaint();
+    }
+}
+
+/* Packs the scan, its analysis, and the report details into a session archive at `path`
+ *
+ * The scan file is packed as it is, unless it's been transformed, then the transformed scan the
+ * analysis was actually made from is packed instead.
+ */
+fn save_session(
+    path: &Path,
+    state: &AnalyzePageState,
+    scan: &PreviewedImage,
+    analysis: &analyze::AnalyzeResults,
+) -> anyhow::Result<()> {
+    let (scan_name, scan_data) = match scan.path.file_name() {
+        Some(name) if state.transforms.is_empty() => {
+            (name.to_string_lossy().to_string(), fs::read(&scan.path)?)
+        }
+        _ => {
+            let mut data = Vec::new();
+            scan.image.write_to(
+                &mut std::io::Cursor::new(&mut data),
+                image::ImageFormat::Tiff,
+            )?;
+            ("scan.tif".to_string(), data)
+        }
+    };
+    let mut archive = fs::File::create(path)?;
+    session::pack(
+        &mut archive,
+        &session::Session {
+            scan_name: &scan_name,
+            scan: &scan_data,
+            image: &scan.image,
+            results: analysis,
+            params: &state.analysis_params,
+            process: Some(state.report_process.as_str()).filter(|s| !s.is_empty()),
+            notes: Some(state.report_notes.as_str()).filter(|s| !s.is_empty()),
+        },
+    )
+}
+
+/* A new directory under the system's temporary directory, named from `prefix` and made unique
+ * so sessions that share a name don't unpack over each other
+ */
+fn unique_temp_dir(prefix: &str) -> std::io::Result<PathBuf> {
+    let mut n = 0;
+    loop {
+        let dir = std::env::temp_dir().join(format!("{}-{}-{}", prefix, std::process::id(), n));
+        match fs::create_dir(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/* Unpacks the session archive at `path` next to the other temporary files and analyzes its scan
+ * again with the params it was saved with
+ */
+fn open_session(
+    ctx: &egui::Context,
+    state: &mut AnalyzePageState,
+    path: &Path,
+    debug: bool,
+) -> anyhow::Result<()> {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let output_dir = unique_temp_dir(&format!("curved-session-{}", stem))?;
+    let manifest = session::unpack(&fs::read(path)?, &output_dir)?;
+    manifest.params.validate()?;
+
+    let scan = load_previewed(output_dir.join(&manifest.scan))?;
+    state.original_scan = Some(scan.image.clone());
+    state.transforms.clear();
+    state.regions.clear();
+    state.measurement.clear();
+    state.scan = Some(scan);
+    state.params = manifest.params;
+    state.report_process = manifest.process.unwrap_or_default();
+    state.report_notes = manifest.notes.unwrap_or_default();
+    start_analysis(ctx, state, debug);
+    Ok(())
+}
+
+// Picks up the results of a running analysis once the worker has finished
+fn poll_analysis(state: &mut AnalyzePageState) {
+    let Some((receiver, _)) = &state.running else {
+        return;
+    };
+    if let Some(stages) = &state.stages {
+        if let Some(stage) = stages.try_iter().last() {
+            state.stage = Some(stage);
+        }
+    }
+    let result = match receiver.try_recv() {
+        Ok(result) => result,
+        Err(mpsc::TryRecvError::Empty) => return,
+        Err(mpsc::TryRecvError::Disconnected) => Err(anyhow::anyhow!("analysis stopped")),
+    };
+    state.running = None;
+    state.stages = None;
+    state.stage = None;
+
+    match result {
+        Ok(analyze_results) => {
+            state.analysis_preview = Some(
+                draw_analyze_preview(
+                    &analyze_results,
+                    state.show_uncertainty,
+                    state.histogram_buckets(),
+                    state.reference.as_ref().map(|(_, curve)| curve),
+                )
+                .unwrap(),
+            );
+            // steps analyze already rejected as outliers start out excluded, clicking brings them back
+            state.excluded = analyze_results.rejected.clone();
+            state.selected = None;
+            state.normalized_preview = Some(draw_normalized_preview(
+                &analyze_results,
+                &state.excluded,
+                None,
+            ));
+            state.grid_detection = None;
+            state.lines_preview = analyze_results.detected_lines.as_ref().map(|lines| {
+                TextureBufferedImage::new(
+                    "detected_lines_preview".to_string(),
+                    &DynamicImage::ImageRgb8(lines.clone()),
+                )
+            });
+            state.analysis = Some(analyze_results);
+            state.preview_tab = AnalyzePreviewTab::Results;
+        }
+        Err(e) if e.is::<Cancelled>() => state.status = Some("Analysis cancelled".to_string()),
+        Err(e) => state.status = Some(format!("Analysis failed: {}", e)),
+    }
+}
+
+// Pixels of the outline drawn around the selected patch
+const SELECTED_OUTLINE: i32 = 4;
+
+/* The normalized scan with each excluded patch crossed out in red, and a thick outline around the
+ * selected one
+ */
+fn draw_normalized_preview(
+    analysis: &analyze::AnalyzeResults,
+    excluded: &[usize],
+    selected: Option<usize>,
+) -> TextureBufferedImage {
+    let mut image = analysis.normalized_image.to_rgb8();
+    let red = image::Rgb([255, 0, 0]);
+    for area in excluded
+        .iter()
+        .filter_map(|step| analysis.patch_areas.get(*step))
+    {
+        let (left, top) = (area.left() as f32, area.top() as f32);
+        let (right, bottom) = (area.right() as f32, area.bottom() as f32);
+        imageproc::drawing::draw_hollow_rect_mut(&mut image, *area, red);
+        imageproc::drawing::draw_line_segment_mut(&mut image, (left, top), (right, bottom), red);
+        imageproc::drawing::draw_line_segment_mut(&mut image, (right, top), (left, bottom), red);
+    }
+    if let Some(area) = selected.and_then(|step| analysis.patch_areas.get(step)) {
+        // cyan stands out against grey patches and the red crosses alike
+        let cyan = image::Rgb([0, 255, 255]);
+        for inset in 0..SELECTED_OUTLINE {
+            let width = area.width() as i32 - 2 * inset;
+            let height = area.height() as i32 - 2 * inset;
+            if width > 0 && height > 0 {
+                let outline = imageproc::rect::Rect::at(area.left() + inset, area.top() + inset)
+                    .of_size(width as u32, height as u32);
+                imageproc::drawing::draw_hollow_rect_mut(&mut image, outline, cyan);
+            }
+        }
+    }
+    TextureBufferedImage::new(
+        "normalized_image".to_string(),
+        &DynamicImage::ImageRgb8(image),
+    )
+}
+
+// How many patches have each status, in the color their windows are outlined in
+fn patch_status_legend(ui: &mut egui::Ui, statuses: &[analyze::PatchStatus]) {
+    for (status, name) in [
+        (analyze::PatchStatus::Rejected, "rejected"),
+        (analyze::PatchStatus::Clipped, "clipped"),
+        (analyze::PatchStatus::HighVariance, "high variance"),
+        (analyze::PatchStatus::Good, "good"),
+    ] {
+        let count = statuses.iter().filter(|s| **s == status).count();
+        if count > 0 {
+            let [r, g, b] = status.color().0;
+            ui.colored_label(
+                egui::Color32::from_rgb(r, g, b),
+                format!("{} {}", count, name),
+            );
+        }
+    }
+}
+
+/* Includes or excludes a step of the analysis from the curve and refits it from the steps that
+ * are left. The exclusion is undone if the curve can't be refit without it.
+ */
+fn toggle_patch(state: &mut AnalyzePageState, step: usize) {
+    let histogram_buckets = state.histogram_buckets();
+    let Some(analysis) = &mut state.analysis else {
+        return;
+    };
+
+    if let Some(i) = state.excluded.iter().position(|excluded| *excluded == step) {
+        state.excluded.remove(i);
+    } else {
+        state.excluded.push(step);
+    }
+
+    match analyze::refit(analysis, &state.analysis_params, &state.excluded) {
+        Ok(curve) => {
+            analysis.curve = curve;
+            state.status = None;
+        }
+        Err(e) => {
+            state.excluded.retain(|excluded| *excluded != step);
+            state.status = Some(format!("Can't exclude step {}: {}", step, e));
+            return;
+        }
+    }
+    let reference = state.reference.as_ref().map(|(_, curve)| curve);
+    state.analysis_preview = Some(
+        draw_analyze_preview(
+            analysis,
+            state.show_uncertainty,
+            histogram_buckets,
+            reference,
+        )
+        .unwrap(),
+    );
+    state.normalized_preview = Some(draw_normalized_preview(
+        analysis,
+        &state.excluded,
+        state.selected,
+    ));
+}
+
+// Selects `step`, redrawing the normalized preview to outline it
+fn select_patch(state: &mut AnalyzePageState, step: usize) {
+    let Some(analysis) = &state.analysis else {
+        return;
+    };
+    state.selected = Some(step);
+    state.normalized_preview = Some(draw_normalized_preview(
+        analysis,
+        &state.excluded,
+        state.selected,
+    ));
+}
+
+/* Moves the selection with the arrow keys and includes or excludes the selected patch with space,
+ * so a scan can be cleaned up without the mouse. The first arrow press selects the first step.
+ */
+fn navigate_patches(ui: &egui::Ui, state: &mut AnalyzePageState) {
+    // keys typed into a text field are for the field
+    if ui.ctx().wants_keyboard_input() {
+        return;
+    }
+    let Some(analysis) = &state.analysis else {
+        return;
+    };
+    let (direction, toggle) = ui.input(|input| {
+        let direction = [
+            (egui::Key::ArrowLeft, Direction::Left),
+            (egui::Key::ArrowRight, Direction::Right),
+            (egui::Key::ArrowUp, Direction::Up),
+            (egui::Key::ArrowDown, Direction::Down),
+        ]
+        .into_iter()
+        .find(|(key, _)| input.key_pressed(*key))
+        .map(|(_, direction)| direction);
+        (direction, input.key_pressed(egui::Key::Space))
+    });
+
+    if let Some(direction) = direction {
+        let step = match state.selected {
+            Some(selected) => {
+                patch_navigation::neighboring_patch(&analysis.patch_areas, selected, direction)
+            }
+            None => (!analysis.patch_areas.is_empty()).then_some(0),
+        };
+        if let Some(step) = step {
+            select_patch(state, step);
+        }
+    }
+    if toggle {
+        if let Some(selected) = state.selected {
+            toggle_patch(state, selected);
+        }
+    }
+}
+
+fn analyze_page(ui: &mut egui::Ui, state: &mut AnalyzePageState, debug: bool) {
+    let mut reset = false;
+    poll_analysis(state);
+    poll_regions_analysis(state);
+
+    egui::SidePanel::left("side_bar")
+        .min_width(325.0)
+        .show_inside(ui, |ui| {
+            ui.add_space(12.0);
+            ui.label(
+                "Analyze evaluates a scan of the generated step wedge to generate a new curve \
+                 file.",
+            );
+            ui.separator();
+            ui.add_space(12.0);
+            if ui.button("Select Scan").clicked() {
+                if let Some(path) = rfd::FileDialog::new().pick_file() {
+                    let scan = load_previewed(path).unwrap();
+                    state.original_scan = Some(scan.image.clone());
+                    state.transforms.clear();
+                    state.measurement.clear();
+                    state.grid_detection = None;
+                    state.scan = Some(scan);
+                }
+            }
+            if ui.button("Open Session").clicked() {
+                if let Some(path) = rfd::FileDialog::new()
+                    .add_filter("curved session", &["curved"])
+                    .pick_file()
+                {
+                    if let Err(e) = open_session(ui.ctx(), state, &path, debug) {
+                        state.status = Some(format!("Couldn't open session: {:#}", e));
+                    }
+                }
+            }
+            if state.scan.is_some() {
+                ui.add_space(12.0);
+                if transforms_editor(ui, &mut state.transforms) {
+                    refresh_scan(state);
+                }
+                ui.add_space(12.0);
+                ui.label("Regions, for sheets carrying several wedges: ")
+                    .on_hover_text("name, x, y, width, height");
+                if let Some(scan) = &mut state.scan {
+                    let scan_size = (scan.image.width(), scan.image.height());
+                    if regions_editor(ui, &mut state.regions, scan_size) {
+                        refresh_scan_preview(scan, &state.regions);
+                    }
+                }
+                ui.add_space(12.0);
+
+                if state.params.invert {
+                    if ui.button("uninvert").clicked() {
+                        state.params.invert = false
+                    };
+                } else {
+                    if ui.button("invert").clicked() {
+                        state.params.invert = true
+                    };
+                }
+
+                ui.add_space(12.0);
+                layout_picker(ui, &mut state.params.layout);
+                ui.horizontal(|ui| {
+                    ui.label("Steps: ");
+                    ui.add(egui::DragValue::new(&mut state.params.steps).range(2..=1000))
+                        .on_hover_text("Number of steps in the scanned wedge");
+                    ui.label("Columns: ");
+                    ui.add(
+                        egui::DragValue::new(&mut state.params.columns)
+                            .range(1..=state.params.steps),
+                    );
+                });
+                if state.params.layout == Layout::Grid {
+                    ui.horizontal(|ui| {
+                        ui.label("Grid lines: ");
+                        ui.add(
+                            egui::DragValue::new(&mut state.params.grid_thickness).range(1..=20),
+                        )
+                        .on_hover_text("Width of the grid lines in pixels, as generated");
+                        grid_tone_picker(ui, &mut state.params.grid_tone);
+                    });
+                }
+                ui.checkbox(&mut state.params.anchor_endpoints, "Anchor endpoints");
+                ui.horizontal(|ui| {
+                    use analyze::SampleStrategy;
+                    ui.label("Sample by: ");
+                    ui.radio_value(
+                        &mut state.params.sample_strategy,
+                        SampleStrategy::Median,
+                        "Median",
+                    )
+                    .on_hover_text("Ignore dust and scratches covering a few pixels of a patch");
+                    ui.radio_value(
+                        &mut state.params.sample_strategy,
+                        SampleStrategy::Mean,
+                        "Mean",
+                    );
+                });
+                ui.checkbox(&mut state.params.robust, "Robust black/white points")
+                    .on_hover_text("Ignore stray dust and glare when normalizing the scan");
+                ui.checkbox(&mut state.params.weight_by_area, "Weight by sampled area")
+                    .on_hover_text("Trust steps partly covered by grid lines less than the rest");
+                ui.checkbox(&mut state.params.alpha_mask, "Skip transparent pixels")
+                    .on_hover_text("Leave pixels masked out by hand out of every sample");
+                ui.checkbox(&mut state.params.debanding, "Remove scanner banding")
+                    .on_hover_text("Take a periodic ripple of horizontal bands out of the scan");
+                ui.checkbox(&mut state.params.corner_dots, "Find patches by corner dots")
+                    .on_hover_text("For wedges generated with corner dots, follows a warped scan");
+                ui.checkbox(
+                    &mut state.params.index_strip,
+                    "Read layout from index strip",
+                )
+                .on_hover_text(
+                    "For wedges generated with an index strip, ignores steps and columns",
+                );
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut state.params.deskew, "Straighten up to: ")
+                        .on_hover_text("Rotate a crooked scan straight along its grid lines");
+                    ui.add_enabled(
+                        state.params.deskew,
+                        egui::DragValue::new(&mut state.params.max_skew_degrees)
+                            .range(0.0..=45.)
+                            .speed(0.1)
+                            .suffix("°"),
+                    )
+                    .on_hover_text(
+                        "Straighten a scan rotated up to this many degrees, refuse one turned more",
+                    );
+                });
+                ui.checkbox(&mut state.params.detect_ink_limit, "Detect ink limit")
+                    .on_hover_text("Cap the curve where the response stops gaining density");
+                ui.horizontal(|ui| {
+                    let mut limited = state.params.max_slope.is_some();
+                    ui.checkbox(&mut limited, "Max slope: ").on_hover_text(
+                        "Keep a noisy scan from producing a curve that exaggerates grain",
+                    );
+                    let mut max_slope = state.params.max_slope.unwrap_or(3.);
+                    ui.add_enabled(
+                        limited,
+                        egui::DragValue::new(&mut max_slope)
+                            .range(0.1..=20.)
+                            .speed(0.05),
+                    );
+                    state.params.max_slope = limited.then_some(max_slope);
+                });
+                ui.horizontal(|ui| {
+                    let mut rejecting = state.params.reject_outliers.is_some();
+                    ui.checkbox(&mut rejecting, "Reject outliers: ")
+                        .on_hover_text(
+                            "Leave out steps far out of line with their neighbors, in median \
+                         absolute deviations",
+                        );
+                    let mut threshold = state.params.reject_outliers.unwrap_or(5.);
+                    ui.add_enabled(
+                        rejecting,
+                        egui::DragValue::new(&mut threshold)
+                            .range(1.0..=50.)
+                            .speed(0.1),
+                    );
+                    state.params.reject_outliers = rejecting.then_some(threshold);
+                });
+                ui.horizontal(|ui| {
+                    if ui
+                        .button("Target curve")
+                        .on_hover_text("Correct toward this curve's response instead of linear")
+                        .clicked()
+                    {
+                        if let Some(path) = rfd::FileDialog::new()
+                            .add_filter("curve", &["json"])
+                            .pick_file()
+                        {
+                            state.params.target = Some(path);
+                        }
+                    }
+                    match &state.params.target {
+                        Some(target) => {
+                            ui.label(target.file_name().unwrap_or_default().to_string_lossy());
+                            if ui.button("Linear").clicked() {
+                                state.params.target = None;
+                            }
+                        }
+                        None => {
+                            ui.label("linear");
+                        }
+                    }
+                });
+                ui.horizontal(|ui| {
+                    if ui
+                        .button("Scanner profile")
+                        .on_hover_text(
+                            "A curve correcting the scanner's response, applied to the scan first",
+                        )
+                        .clicked()
+                    {
+                        if let Some(path) = rfd::FileDialog::new()
+                            .add_filter("curve", &["json"])
+                            .pick_file()
+                        {
+                            state.params.scanner_profile = Some(path);
+                        }
+                    }
+                    if let Some(profile) = &state.params.scanner_profile {
+                        ui.label(profile.file_name().unwrap_or_default().to_string_lossy());
+                        if ui.button("None").clicked() {
+                            state.params.scanner_profile = None;
+                        }
+                    }
+                });
+                ui.horizontal(|ui| {
+                    if ui
+                        .button("Base scan")
+                        .on_hover_text(
+                            "A scan of the blank film base, its density is taken out of the scan",
+                        )
+                        .clicked()
+                    {
+                        if let Some(path) = rfd::FileDialog::new().pick_file() {
+                            state.params.base = Some(path);
+                        }
+                    }
+                    if let Some(base) = &state.params.base {
+                        ui.label(base.file_name().unwrap_or_default().to_string_lossy());
+                        if ui.button("None").clicked() {
+                            state.params.base = None;
+                        }
+                    }
+                });
+                ui.add_space(12.0);
+                let process_label = ui.label("Report process: ");
+                ui.text_edit_singleline(&mut state.report_process)
+                    .labelled_by(process_label.id);
+                let notes_label = ui.label("Report notes: ");
+                ui.text_edit_singleline(&mut state.report_notes)
+                    .labelled_by(notes_label.id);
+                ui.add_space(12.0);
+                ui.horizontal(|ui| {
+                    ui.label("Subsample: ");
+                    ui.add(egui::DragValue::new(&mut state.params.subsample_factor).range(1..=64))
+                        .on_hover_text(
+                            "Only read every Nth pixel when sampling, faster on large scans",
+                        );
+                });
+
+                reset = reset_button(ui);
+            }
+        });
+
+    egui::CentralPanel::default().show_inside(ui, |ui| {
+        egui::TopBottomPanel::top("analyze_tabs")
+            .min_height(32.0)
+            .show_inside(ui, |ui| {
+                ui.horizontal(|ui| {
+                    ui.selectable_value(&mut state.preview_tab, AnalyzePreviewTab::Scan, "Scan");
+                    ui.selectable_value(
+                        &mut state.preview_tab,
+                        AnalyzePreviewTab::Results,
+                        "Results",
+                    );
+                    ui.selectable_value(
+                        &mut state.preview_tab,
+                        AnalyzePreviewTab::Normalized,
+                        "Normalized",
+                    );
+                    if state.lines_preview.is_some() {
+                        ui.selectable_value(
+                            &mut state.preview_tab,
+                            AnalyzePreviewTab::Lines,
+                            "Lines",
+                        )
+                        .on_hover_text(
+                            "The grid lines found in magenta, and where each patch is sampled in \
+                             green when detected on its own",
+                        );
+                    }
+                });
+            });
+        egui::TopBottomPanel::bottom("actions")
+            .min_height(32.0)
+            .show_inside(ui, |ui| {
+                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                    match state.preview_tab {
+                        AnalyzePreviewTab::Scan => {
+                            if let Some(cancel) = state.running_cancel() {
+                                if ui.add(action_button("Cancel")).clicked() {
+                                    cancel.cancel();
+                                }
+                                ui.spinner();
+                                if let Some(stage) = state.stage {
+                                    ui.label(stage);
+                                }
+                            } else if state.scan.is_some() && !state.regions.is_empty() {
+                                if ui.add(action_button("Analyze Regions")).clicked() {
+                                    if let Some(output_dir) = rfd::FileDialog::new().pick_folder() {
+                                        start_regions_analysis(ui.ctx(), state, output_dir, debug);
+                                    }
+                                }
+                            } else if state.scan.is_some() {
+                                if ui.add(action_button("Analyze")).clicked() {
+                                    start_analysis(ui.ctx(), state, debug);
+                                }
+                                if ui
+                                    .add(action_button("Detect Grid"))
+                                    .on_hover_text(
+                                        "Find the grid and where each patch will be sampled, \
+                                         without analyzing the scan",
+                                    )
+                                    .clicked()
+                                {
+                                    detect_grid(state);
+                                }
+                            }
+                            if let Some(status) = &state.status {
+                                ui.label(status);
+                            }
+                            if let Some(measurement) = state.measurement.describe() {
+                                ui.label(measurement);
+                            } else if let Some(scan) = &mut state.scan {
+                                let path: String = scan.path.to_string_lossy().to_string();
+                                ui.monospace(path).on_hover_text(
+                                    "Click two points on the scan to compare their density",
+                                );
+                            }
+                        }
+                        AnalyzePreviewTab::Results => {
+                            if let Some(analysis) = &state.analysis {
+                                if let Some(status) = &state.status {
+                                    ui.label(status);
+                                }
+                                let reference_changed = reference_controls(
+                                    ui,
+                                    &mut state.reference,
+                                    &mut state.status,
+                                    &analysis.curve,
+                                );
+                                let uncertainty_changed = ui
+                                    .checkbox(&mut state.show_uncertainty, "Uncertainty")
+                                    .on_hover_text("Shade one standard deviation of sample noise")
+                                    .changed();
+                                let mut buckets = state.histogram_buckets();
+                                let buckets_changed = ui
+                                    .add(
+                                        egui::Slider::new(&mut buckets, 16..=1024)
+                                            .logarithmic(true)
+                                            .text("Buckets"),
+                                    )
+                                    .on_hover_text(
+                                        "Buckets in the histogram, more show fine posterization",
+                                    )
+                                    .changed();
+                                if reference_changed || uncertainty_changed || buckets_changed {
+                                    state.histogram_buckets = Some(buckets);
+                                    // the histogram is kept for every tone, redrawing only
+                                    // rebuckets it
+                                    state.analysis_preview = Some(
+                                        draw_analyze_preview(
+                                            analysis,
+                                            state.show_uncertainty,
+                                            buckets,
+                                            state.reference.as_ref().map(|(_, curve)| curve),
+                                        )
+                                        .unwrap(),
+                                    );
+                                }
+                                if ui.add(action_button("Save JSON")).clicked() {
+                                    if let Some(path) = rfd::FileDialog::new()
+                                        .set_file_name("curve.json")
+                                        .save_file()
+                                    {
+                                        curve::save_analyzed_curve(
+                                            &path,
+                                            &analysis.curve,
+                                            &state.analysis_params,
+                                        )
+                                        .unwrap();
+                                    }
+                                };
+                                if ui
+                                    .add(action_button("Save Response"))
+                                    .on_hover_text(
+                                        "The measured response of the process, which the curve \
+                                         undoes",
+                                    )
+                                    .clicked()
+                                {
+                                    if let Some(path) = rfd::FileDialog::new()
+                                        .set_file_name("response.json")
+                                        .save_file()
+                                    {
+                                        curve::save_curve(&path, &analysis.response_curve).unwrap();
+                                    }
+                                };
+                                if ui
+                                    .add(action_button("Control Points"))
+                                    .on_hover_text("List the curve's control points as text")
+                                    .clicked()
+                                {
+                                    state.show_control_points = !state.show_control_points;
+                                }
+                                if ui.add(action_button("Save CSV")).clicked() {
+                                    if let Some(path) = rfd::FileDialog::new()
+                                        .set_file_name("curve.csv")
+                                        .save_file()
+                                    {
+                                        let mut csv_file = fs::File::create(path).unwrap();
+                                        analyze::write_small_csv(&mut csv_file, &analysis.curve)
+                                            .unwrap();
+                                    }
+                                };
+                                if ui.add(action_button("Save CAL")).clicked() {
+                                    if let Some(path) = rfd::FileDialog::new()
+                                        .set_file_name("curve.cal")
+                                        .save_file()
+                                    {
+                                        let mut cal_file = fs::File::create(path).unwrap();
+                                        analyze::write_cal(&mut cal_file, &analysis.curve).unwrap();
+                                    }
+                                };
+                                if let Some(scan) = &state.scan {
+                                    if ui.add(action_button("Save Report")).clicked() {
+                                        if let Some(path) = rfd::FileDialog::new()
+                                            .set_file_name("report.pdf")
+                                            .save_file()
+                                        {
+                                            let saved = report::render(
+                                                &scan.image,
+                                                analysis,
+                                                Some(state.report_process.as_str())
+                                                    .filter(|s| !s.is_empty()),
+                                                Some(state.report_notes.as_str())
+                                                    .filter(|s| !s.is_empty()),
+                                            )
+                                            .and_then(|page| report::save(&page, &path));
+                                            state.status = saved
+                                                .err()
+                                                .map(|e| format!("Couldn't save report: {:#}", e));
+                                        }
+                                    }
+                                    if ui.add(action_button("Save Session")).clicked() {
+                                        if let Some(path) = rfd::FileDialog::new()
+                                            .set_file_name("session.curved")
+                                            .save_file()
+                                        {
+                                            let saved = save_session(&path, state, scan, analysis);
+                                            state.status = saved
+                                                .err()
+                                                .map(|e| format!("Couldn't save session: {:#}", e));
+                                        }
+                                    };
+                                }
+                            }
+                        }
+                        AnalyzePreviewTab::Normalized => {
+                            if let Some(analysis) = &state.analysis {
+                                if let Some(status) = &state.status {
+                                    ui.label(status);
+                                }
+                                let normalization = analysis.normalization;
+                                ui.label(format!(
+                                    "Stretched {}..{} to the full range, x{:.3}",
+                                    normalization.black, normalization.white, normalization.factor
+                                ))
+                                .on_hover_text(format!(
+                                    "Samples ran from {} to {}{}",
+                                    normalization.sample_min,
+                                    normalization.sample_max,
+                                    if normalization.inverted {
+                                        ", read as a negative"
+                                    } else {
+                                        ""
+                                    }
+                                ));
+                                if let Some(gamma) = analysis.gamma {
+                                    ui.label(format!("Measured gamma: {:.2}", gamma));
+                                }
+                                patch_status_legend(ui, &analysis.patch_status);
+                                if let Some(ink_limit) = analysis.ink_limit {
+                                    ui.label(format!(
+                                        "Ink limit at {}, the curve is capped there",
+                                        ink_limit
+                                    ));
+                                }
+                                ui.label(format!(
+                                    "Click a patch, or select it with the arrow keys and press \
+                                     space, to leave it out of the curve, {} excluded",
+                                    state.excluded.len()
+                                ));
+                                if let Some(selected) = state.selected {
+                                    ui.label(format!(
+                                        "Step {} selected, {}",
+                                        selected,
+                                        if state.excluded.contains(&selected) {
+                                            "excluded"
+                                        } else {
+                                            "included"
+                                        }
+                                    ));
+                                }
+                            }
+                        }
+                        AnalyzePreviewTab::Lines => {
+                            if let Some(detection) = &state.grid_detection {
+                                ui.label(format!(
+                                    "Grid at ({}, {}), {} pixel squares, {} patches",
+                                    detection.grid.origin_x,
+                                    detection.grid.origin_y,
+                                    detection.grid.square_size,
+                                    detection.sampled_areas.len()
+                                ));
+                            }
+                        }
+                    };
+                });
+            });
+        egui::CentralPanel::default().show_inside(ui, |ui| match state.preview_tab {
+            AnalyzePreviewTab::Scan => {
+                if let Some(scan) = &mut state.scan {
+                    if let Some(pixel) = scan.preview.clicked_pixel(ui) {
+                        state.measurement.add(Reading::new(
+                            &scan.image,
+                            pixel,
+                            state.params.luma_weights,
+                        ));
+                    }
+                } else {
+                    empty_state(ui, "Select a scan to begin");
+                }
+            }
+            AnalyzePreviewTab::Results => {
+                if let Some(preview) = &mut state.analysis_preview {
+                    preview.ui(ui);
+                } else if state.scan.is_some() {
+                    empty_state(ui, "Analyze the scan to see results");
+                } else {
+                    empty_state(ui, "Select a scan to begin");
+                }
+            }
+            AnalyzePreviewTab::Normalized => {
+                navigate_patches(ui, state);
+                if let Some(preview) = &mut state.normalized_preview {
+                    let clicked = preview.clicked_pixel(ui);
+                    let step = clicked.and_then(|(x, y)| {
+                        state.analysis.as_ref().and_then(|analysis| {
+                            analysis
+                                .patch_areas
+                                .iter()
+                                .position(|area| area.contains(x as i32, y as i32))
+                        })
+                    });
+                    if let Some(step) = step {
+                        state.selected = Some(step);
+                        toggle_patch(state, step);
+                    }
+                } else if state.scan.is_some() {
+                    empty_state(ui, "Analyze the scan to see the normalized image");
+                } else {
+                    empty_state(ui, "Select a scan to begin");
+                }
+            }
+            AnalyzePreviewTab::Lines => {
+                if let Some(preview) = &mut state.lines_preview {
+                    preview.ui(ui);
+                } else {
+                    empty_state(ui, "Detect the grid to see where it was found");
+                }
+            }
+        });
+    });
+
+    if let Some(analysis) = &state.analysis {
+        control_points_window(ui.ctx(), &mut state.show_control_points, analysis);
+    }
+
+    if reset {
+        if let Some(cancel) = state.running_cancel() {
+            cancel.cancel();
+        }
+        *state = AnalyzePageState::default();
+    }
+}
+
+/* Loads or clears the reference curve `curve` is drawn against, and calls out how far `curve`
+ * strays from it. Returns whether the reference changed and the preview needs redrawing.
+ */
+fn reference_controls(
+    ui: &mut egui::Ui,
+    reference: &mut Option<(PathBuf, Spline<f64, f64>)>,
+    status: &mut Option<String>,
+    curve: &Spline<f64, f64>,
+) -> bool {
+    let mut changed = false;
+    ui.horizontal(|ui| {
+        if ui
+            .add(action_button("Load Reference"))
+            .on_hover_text("Draw a stored curve under this one to see how far the process drifted")
+            .clicked()
+        {
+            if let Some(path) = rfd::FileDialog::new().pick_file() {
+                match load_curve(&path) {
+                    Ok(loaded) => {
+                        *reference = Some((path, loaded));
+                        changed = true;
+                    }
+                    Err(e) => *status = Some(format!("Can't load the reference: {}", e)),
+                }
+            }
+        }
+        if reference.is_some() && ui.add(action_button("Clear Reference")).clicked() {
+            *reference = None;
+            changed = true;
+        }
+    });
+    if let Some((path, reference)) = reference {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        match curve::largest_difference(reference, curve) {
+            Ok((input, difference)) => ui.label(
+                RichText::new(format!(
+                    "Differs from {} by at most {:+.2}% at tone {:.0}",
+                    name,
+                    difference / u16::MAX as f64 * 100.,
+                    input
+                ))
+                .color(Color32::from_rgb(
+                    compare::BEFORE_COLOR[0],
+                    compare::BEFORE_COLOR[1],
+                    compare::BEFORE_COLOR[2],
+                )),
+            ),
+            Err(e) => ui.label(format!("Can't compare against {}: {}", name, e)),
+        };
+    }
+    changed
+}
+
+// A window listing the control points of `analysis`'s curve, see `curve::control_points`
+fn control_points_window(ctx: &egui::Context, open: &mut bool, analysis: &analyze::AnalyzeResults) {
+    egui::Window::new("Control Points")
+        .open(open)
+        .default_height(400.0)
+        .show(ctx, |ui| {
+            // input, output pairs, same as the curve file's keys
+            let text = curve::control_points(&analysis.curve);
+            if ui.add(action_button("Copy to Clipboard")).clicked() {
+                ui.ctx().copy_text(text.clone());
+            }
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                // editing a &mut str can select and copy but never change the text
+                ui.add(
+                    egui::TextEdit::multiline(&mut text.as_str())
+                        .font(egui::TextStyle::Monospace)
+                        .desired_width(f32::INFINITY),
+                );
+            });
+        });
+}
+
+/* Applies `curve` to the image at `path` and writes it into `output_dir` as "curved-<name>",
+ * matching what the CLI apply produces.
+ */
+fn apply_to_file(
+    path: &Path,
+    curve: &Spline<f64, f64>,
+    output_dir: &Path,
+    cancel: &Cancel,
+) -> anyhow::Result<PathBuf> {
+    let file_name = path
+        .file_name()
+        .ok_or(anyhow::anyhow!("{} is not a file", path.display()))?;
+    let output_path = output_dir.join(format!("curved-{}", file_name.to_string_lossy()));
+
+    let image = image::open(path)?;
+    apply::apply_cancellable(&image, curve, LumaWeights::default(), cancel)?.save(&output_path)?;
+    Ok(output_path)
+}
+
+/* Runs the queue on a worker thread so the UI stays responsive, reporting the status of each
+ * file back over a channel as it goes.
+ */
+fn run_batch(ctx: &egui::Context, state: &mut BatchPageState) {
+    let (Some((_, curve)), Some(output_dir)) = (&state.curve, &state.output_dir) else {
+        return;
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let paths: Vec<PathBuf> = state.queue.iter().map(|item| item.path.clone()).collect();
+    let curve = curve.clone();
+    let output_dir = output_dir.clone();
+    let ctx = ctx.clone();
+
+    let cancel = Cancel::default();
+
+    for item in state.queue.iter_mut() {
+        item.status = BatchStatus::Pending;
+    }
+    state.progress = Some(receiver);
+    state.cancel = cancel.clone();
+
+    // once cancelled the file in progress is abandoned and the rest of the queue left pending
+    thread::spawn(move || {
+        for (i, path) in paths.iter().enumerate() {
+            if cancel.is_cancelled() {
+                break;
+            }
+            let _ = sender.send((i, BatchStatus::Running));
+            ctx.request_repaint();
+
+            let status = match apply_to_file(path, &curve, &output_dir, &cancel) {
+                Ok(output_path) => BatchStatus::Done(output_path),
+                Err(e) if e.is::<Cancelled>() => BatchStatus::Cancelled,
+                Err(e) => BatchStatus::Failed(e.to_string()),
+            };
+            let _ = sender.send((i, status));
+            ctx.request_repaint();
+        }
+    });
+}
+
+fn batch_page(ui: &mut egui::Ui, state: &mut BatchPageState) {
+    let mut reset = false;
+
+    // pick up any progress from a running batch, the batch is finished once the worker hangs up
+    if let Some(progress) = &state.progress {
+        loop {
+            match progress.try_recv() {
+                Ok((i, status)) => {
+                    if let Some(item) = state.queue.get_mut(i) {
+                        item.status = status;
+                    }
+                }
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    state.progress = None;
+                    break;
+                }
+            }
+        }
+    }
+    let running = state.progress.is_some();
+
+    egui::SidePanel::left("side_bar")
+        .min_width(325.0)
+        .show_inside(ui, |ui| {
+            ui.add_space(12.0);
+            ui.label(
+                "Apply a curve to a queue of images. Each corrected image is written to the \
+                 output directory as curved-<name>.",
+            );
+            ui.separator();
+            ui.add_space(12.0);
+
+            ui.add_enabled_ui(!running, |ui| {
+                if ui.button("Select Curve").clicked() {
+                    if let Some(path) = rfd::FileDialog::new().pick_file() {
+                        match load_curve(&path) {
+                            Ok(curve) => {
+                                state.curve = Some((path, curve));
+                                state.status = None;
+                            }
+                            Err(e) => state.status = Some(format!("Couldn't load curve: {:#}", e)),
+                        }
+                    }
+                }
+                if let Some((path, _)) = &state.curve {
+                    ui.monospace(path.to_string_lossy());
+                }
+                if let Some(status) = &state.status {
+                    ui.label(status);
+                }
+                ui.add_space(12.0);
+
+                if ui.button("Select Output Directory").clicked() {
+                    if let Some(path) = rfd::FileDialog::new().pick_folder() {
+                        state.output_dir = Some(path);
+                    }
+                }
+                if let Some(path) = &state.output_dir {
+                    ui.monospace(path.to_string_lossy());
+                }
+                ui.add_space(12.0);
+
+                if ui.button("Add Images").clicked() {
+                    if let Some(paths) = rfd::FileDialog::new().pick_files() {
+                        state.queue.extend(paths.into_iter().map(|path| BatchItem {
+                            path,
+                            status: BatchStatus::Pending,
+                        }));
+                    }
+                }
+
+                reset = reset_button(ui);
+            });
+        });
+
+    egui::CentralPanel::default().show_inside(ui, |ui| {
+        egui::TopBottomPanel::bottom("controls")
+            .min_height(32.0)
+            .show_inside(ui, |ui| {
+                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                    if running {
+                        if ui.add(action_button("Cancel")).clicked() {
+                            state.cancel.cancel();
+                        }
+                        let finished = state
+                            .queue
+                            .iter()
+                            .filter(|item| {
+                                matches!(item.status, BatchStatus::Done(_) | BatchStatus::Failed(_))
+                            })
+                            .count();
+                        ui.add(
+                            egui::ProgressBar::new(finished as f32 / state.queue.len() as f32)
+                                .desired_width(200.0)
+                                .text(format!("{} / {}", finished, state.queue.len())),
+                        );
+                    } else if state.curve.is_some()
+                        && state.output_dir.is_some()
+                        && !state.queue.is_empty()
+                        && ui.add(action_button("Run")).clicked()
+                    {
+                        run_batch(ui.ctx(), state);
+                    }
+                });
+            });
+
+        egui::CentralPanel::default().show_inside(ui, |ui| {
+            if state.queue.is_empty() {
+                empty_state(ui, "Add images to build a queue");
+                return;
+            }
+
+            // queue index being dragged and the index it was dropped on
+            let mut moved: Option<(usize, usize)> = None;
+            let mut removed: Option<usize> = None;
+
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for (i, item) in state.queue.iter().enumerate() {
+                    let row = ui.horizontal(|ui| {
+                        if running {
+                            ui.label("::");
+                        } else {
+                            ui.dnd_drag_source(egui::Id::new(("batch_item", i)), i, |ui| {
+                                ui.label("::");
+                            });
+                        }
+                        ui.add_enabled_ui(!running, |ui| {
+                            if ui.small_button("Remove").clicked() {
+                                removed = Some(i);
+                            }
+                        });
+                        let status = match &item.status {
+                            BatchStatus::Pending => RichText::new("pending"),
+                            BatchStatus::Running => RichText::new("running"),
+                            BatchStatus::Done(path) => {
+                                RichText::new(format!("done: {}", path.display()))
+                                    .color(Color32::from_rgb(0, 200, 0))
+                            }
+                            BatchStatus::Failed(e) => RichText::new(format!("failed: {}", e))
+                                .color(Color32::from_rgb(220, 0, 0)),
+                            BatchStatus::Cancelled => RichText::new("cancelled"),
+                        };
+                        ui.monospace(item.path.to_string_lossy());
+                        ui.label(status);
+                    });
+
+                    if let Some(dragged) = row.response.dnd_release_payload::<usize>() {
+                        moved = Some((*dragged, i));
+                    }
+                }
+            });
+
+            if let Some(i) = removed {
+                state.queue.remove(i);
+            }
+            if let Some((from, to)) = moved {
+                let item = state.queue.remove(from);
+                state.queue.insert(to, item);
+            }
+        });
+    });
+
+    if reset && !running {
+        *state = BatchPageState::default();
+    }
+}
+
+// Analyzes both scans on a worker thread, see `poll_comparison`
+fn start_comparison(ctx: &egui::Context, state: &mut ComparePageState, debug: bool) {
+    let (Some(before), Some(after)) = (&state.before, &state.after) else {
+        return;
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let before = before.image.clone();
+    let after = after.image.clone();
+    let params = state.params.clone();
+    let cancel = Cancel::default();
+    let worker_cancel = cancel.clone();
+    let ctx = ctx.clone();
+
+    state.status = None;
+    state.running = Some((receiver, cancel));
+
+    thread::spawn(move || {
+        let analyze_both = || {
+            Ok((
+                analyze::analyze_cancellable(&before, &params, debug, &worker_cancel)?,
+                analyze::analyze_cancellable(&after, &params, debug, &worker_cancel)?,
+            ))
+        };
+        let _ = sender.send(analyze_both());
+        ctx.request_repaint();
+    });
+}
+
+// Picks up both analyses once the worker has finished and lays them over each other
+fn poll_comparison(state: &mut ComparePageState) {
+    let Some((receiver, _)) = &state.running else {
+        return;
+    };
+    let result = match receiver.try_recv() {
+        Ok(result) => result,
+        Err(mpsc::TryRecvError::Empty) => return,
+        Err(mpsc::TryRecvError::Disconnected) => Err(anyhow::anyhow!("analysis stopped")),
+    };
+    state.running = None;
+
+    let compared = result.and_then(|(before, after)| {
+        let comparison = Comparison::new(&before, &after)?;
+        let image = compare::draw_comparison(&before, &after)?;
+        Ok((comparison, image))
+    });
+    match compared {
+        Ok((comparison, image)) => {
+            state.comparison = Some(comparison);
+            state.preview = Some(TextureBufferedImage::new(
+                "comparison".to_string(),
+                &DynamicImage::ImageRgb8(image),
+            ));
+        }
+        Err(e) if e.is::<Cancelled>() => state.status = Some("Comparison cancelled".to_string()),
+        Err(e) => state.status = Some(format!("Comparison failed: {}", e)),
+    }
+}
+
+// A legend entry, a swatch of the color the scan is drawn in next to its name
+fn legend_entry(ui: &mut egui::Ui, color: image::Rgb<u8>, label: &str, scan: &PreviewedImage) {
+    let name = scan
+        .path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    ui.horizontal(|ui| {
+        ui.label(RichText::new("■").color(Color32::from_rgb(color[0], color[1], color[2])));
+        ui.label(format!("{}: {}", label, name));
+    });
+}
+
+fn compare_page(ui: &mut egui::Ui, state: &mut ComparePageState, debug: bool) {
+    let mut reset = false;
+    poll_comparison(state);
+
+    egui::SidePanel::left("side_bar")
+        .min_width(325.0)
+        .show_inside(ui, |ui| {
+            ui.add_space(12.0);
+            ui.label(
+                "Compare analyzes two scans of the same wedge, from before and after a change to \
+                 the process, and lays their curves and histograms over each other.",
+            );
+            ui.separator();
+            ui.add_space(12.0);
+            for (label, scan) in [
+                ("Select Before Scan", &mut state.before),
+                ("Select After Scan", &mut state.after),
+            ] {
+                if ui.button(label).clicked() {
+                    if let Some(path) = rfd::FileDialog::new().pick_file() {
+                        match load_previewed(path) {
+                            Ok(loaded) => {
+                                *scan = Some(loaded);
+                                state.comparison = None;
+                                state.preview = None;
+                                state.status = None;
+                            }
+                            Err(e) => state.status = Some(format!("Couldn't load scan: {:#}", e)),
+                        }
+                    }
+                }
+            }
+
+            ui.add_space(12.0);
+            ui.checkbox(&mut state.params.invert, "Invert");
+            layout_picker(ui, &mut state.params.layout);
+            ui.horizontal(|ui| {
+                ui.label("Steps: ");
+                ui.add(egui::DragValue::new(&mut state.params.steps).range(2..=1000));
+                ui.label("Columns: ");
+                ui.add(
+                    egui::DragValue::new(&mut state.params.columns).range(1..=state.params.steps),
+                );
+            });
+
+            ui.add_space(12.0);
+            if let Some(before) = &state.before {
+                legend_entry(ui, compare::BEFORE_COLOR, "Before", before);
+            }
+            if let Some(after) = &state.after {
+                legend_entry(ui, compare::AFTER_COLOR, "After", after);
+            }
+            if let Some(comparison) = &state.comparison {
+                ui.add_space(12.0);
+                ui.label(format!("Mean change: {:.2}%", comparison.mean_delta()));
+                if let Some((step, input, delta)) = comparison.largest_delta() {
+                    ui.label(format!(
+                        "Largest change: {:+.2}% at step {} (tone {})",
+                        delta, step, input
+                    ));
+                }
+            }
+
+            reset = reset_button(ui);
+        });
+
+    egui::CentralPanel::default().show_inside(ui, |ui| {
+        egui::TopBottomPanel::bottom("actions")
+            .min_height(32.0)
+            .show_inside(ui, |ui| {
+                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                    if let Some((_, cancel)) = &state.running {
+                        if ui.add(action_button("Cancel")).clicked() {
+                            cancel.cancel();
+                        }
+                        ui.spinner();
+                    } else if state.before.is_some()
+                        && state.after.is_some()
+                        && ui.add(action_button("Compare")).clicked()
+                    {
+                        start_comparison(ui.ctx(), state, debug);
+                    }
+                    if let Some(status) = &state.status {
+                        ui.label(status);
+                    }
+                });
+            });
+        egui::CentralPanel::default().show_inside(ui, |ui| {
+            if let Some(preview) = &mut state.preview {
+                preview.ui(ui);
+            } else if state.before.is_some() && state.after.is_some() {
+                empty_state(ui, "Compare the scans to see how the response changed");
+            } else {
+                empty_state(ui, "Select a before and an after scan to begin");
+            }
+        });
+    });
+
+    if reset {
+        if let Some((_, cancel)) = &state.running {
+            cancel.cancel();
+        }
+        *state = ComparePageState::default();
+    }
+}
+
+impl eframe::App for CurvedApp {
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        eframe::set_value(storage, eframe::APP_KEY, &SavedState::from_app(self));
+    }
+
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        texture_buffered_image::set_raw_previews(ctx, self.raw_previews);
+        egui::CentralPanel::default().show(ctx, |ui| {
+            tab_bar(ui, self);
+            match &mut self.page {
+                Page::Generate => {
                     generate_page(ui, &mut self.generate_page_state);
                 }
                 Page::Apply => {
@@ -495,6 +2412,12 @@ impl eframe::App for CurvedApp {
                 Page::Analyze => {
                     analyze_page(ui, &mut self.analyze_page_state, self.debug);
                 }
+                Page::Batch => {
+                    batch_page(ui, &mut self.batch_page_state);
+                }
+                Page::Compare => {
+                    compare_page(ui, &mut self.compare_page_state, self.debug);
+                }
             }
         });
     }
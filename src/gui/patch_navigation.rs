@@ -0,0 +1,81 @@
+use imageproc::rect::Rect;
+
+// A way the selection can move across the patches of an analysis, one per arrow key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+impl Direction {
+    // The unit step on screen, y counting down
+    fn offset(self) -> (i64, i64) {
+        match self {
+            Direction::Left => (-1, 0),
+            Direction::Right => (1, 0),
+            Direction::Up => (0, -1),
+            Direction::Down => (0, 1),
+        }
+    }
+}
+
+/* The patch next to `from` in `direction`, or None at the edge of the wedge
+ *
+ * Patches are found by where they lie rather than by their step, since the steps of a flipped or
+ * rotated scan no longer run left to right. Of the patches ahead of `from` the nearest wins, with
+ * straying off to the side counting against a patch more than distance ahead, so moving down the
+ * last short row of a grid doesn't jump to a patch across the wedge.
+ */
+pub fn neighboring_patch(areas: &[Rect], from: usize, direction: Direction) -> Option<usize> {
+    // doubled centers, to stay in whole pixels
+    let center = |area: &Rect| {
+        (
+            2 * area.left() as i64 + area.width() as i64,
+            2 * area.top() as i64 + area.height() as i64,
+        )
+    };
+    let (from_x, from_y) = center(areas.get(from)?);
+    let (dx, dy) = direction.offset();
+
+    areas
+        .iter()
+        .enumerate()
+        .filter_map(|(step, area)| {
+            let (x, y) = center(area);
+            let (offset_x, offset_y) = (x - from_x, y - from_y);
+            let ahead = offset_x * dx + offset_y * dy;
+            let aside = (offset_x * dy - offset_y * dx).abs();
+            (ahead > 0 && aside < ahead).then_some((ahead + 4 * aside, step))
+        })
+        .min()
+        .map(|(_, step)| step)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_neighboring_patch() {
+        // three columns of 10 pixel patches, the last row short one
+        let areas: Vec<Rect> = (0..5)
+            .map(|step| Rect::at(step % 3 * 10, step / 3 * 10).of_size(10, 10))
+            .collect();
+
+        assert_eq!(neighboring_patch(&areas, 0, Direction::Right), Some(1));
+        assert_eq!(neighboring_patch(&areas, 1, Direction::Left), Some(0));
+        assert_eq!(neighboring_patch(&areas, 0, Direction::Left), None);
+        assert_eq!(neighboring_patch(&areas, 1, Direction::Down), Some(4));
+        assert_eq!(neighboring_patch(&areas, 4, Direction::Up), Some(1));
+        // nothing below the end of the top row, and the short row ends at its last patch
+        assert_eq!(neighboring_patch(&areas, 2, Direction::Down), None);
+        assert_eq!(neighboring_patch(&areas, 4, Direction::Right), None);
+        assert_eq!(neighboring_patch(&areas, 5, Direction::Right), None);
+
+        // a flipped scan's steps run right to left, and moving right still moves right
+        let flipped: Vec<Rect> = areas.iter().rev().copied().collect();
+        assert_eq!(neighboring_patch(&flipped, 1, Direction::Right), Some(0));
+    }
+}
@@ -0,0 +1,191 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use super::scan_transform::{self, ScanTransform};
+use super::{
+    generated_wedge, load_curve, load_previewed, preview_curve, refresh_scan_preview, BatchItem,
+    BatchStatus, CurvedApp, Page,
+};
+use crate::analyze::AnalyzeParams;
+use crate::generate::{Label, ToneColor};
+use crate::regions::Region;
+use crate::step_description::Layout;
+
+/* What the app remembers between launches
+ *
+ * Only paths and settings are stored, never image buffers. Images are loaded again from their
+ * paths on startup, anything that has since moved or been deleted is quietly left out.
+ */
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SavedState {
+    page: Page,
+    raw_previews: bool,
+
+    process: String,
+    notes: String,
+    generate_layout: Layout,
+    generate_background: u16,
+    generate_label: Label,
+    generate_antialias: bool,
+    generate_corner_dots: bool,
+    generate_index_strip: bool,
+    generate_grid_thickness: Option<u32>,
+    generate_grid_tone: Option<u16>,
+    generate_tone_color: Option<ToneColor>,
+
+    scan: Option<PathBuf>,
+    transforms: Vec<ScanTransform>,
+    regions: Vec<Region>,
+    params: AnalyzeParams,
+    show_uncertainty: bool,
+
+    apply_image: Option<PathBuf>,
+    // the curve was being tried on a generated wedge rather than an image from disk
+    apply_wedge: bool,
+    apply_curve: Option<PathBuf>,
+    apply_strength: Option<f64>,
+    apply_before_after: bool,
+
+    batch_curve: Option<PathBuf>,
+    batch_output_dir: Option<PathBuf>,
+    batch_queue: Vec<PathBuf>,
+}
+
+impl SavedState {
+    pub fn from_app(app: &CurvedApp) -> Self {
+        let generate = &app.generate_page_state;
+        let analyze = &app.analyze_page_state;
+        let apply = &app.apply_page_state;
+        let batch = &app.batch_page_state;
+
+        Self {
+            page: app.page,
+            raw_previews: app.raw_previews,
+            process: generate.process.clone(),
+            notes: generate.notes.clone(),
+            generate_layout: generate.layout,
+            generate_background: generate.params.background,
+            generate_label: generate.params.label,
+            generate_antialias: generate.params.antialias,
+            generate_corner_dots: generate.params.corner_dots,
+            generate_index_strip: generate.params.index_strip,
+            generate_grid_thickness: Some(generate.params.grid_thickness),
+            generate_grid_tone: generate.params.grid_tone,
+            generate_tone_color: generate.params.tone_color,
+            scan: analyze.scan.as_ref().map(|scan| scan.path.clone()),
+            transforms: analyze.transforms.clone(),
+            regions: analyze.regions.clone(),
+            params: analyze.params.clone(),
+            show_uncertainty: analyze.show_uncertainty,
+            apply_image: apply.image.as_ref().map(|image| image.path.clone()),
+            apply_wedge: apply
+                .image
+                .as_ref()
+                .is_some_and(|image| image.path.as_os_str().is_empty()),
+            apply_curve: apply.curve.as_ref().map(|(path, _)| path.clone()),
+            apply_strength: Some(apply.strength),
+            apply_before_after: apply.before_after,
+            batch_curve: batch.curve.as_ref().map(|(path, _)| path.clone()),
+            batch_output_dir: batch.output_dir.clone(),
+            batch_queue: batch.queue.iter().map(|item| item.path.clone()).collect(),
+        }
+    }
+
+    pub fn restore(self, app: &mut CurvedApp) {
+        app.page = self.page;
+        app.raw_previews = self.raw_previews;
+
+        let generate = &mut app.generate_page_state;
+        generate.process = self.process;
+        generate.notes = self.notes;
+        generate.layout = self.generate_layout;
+        generate.params.background = self.generate_background;
+        generate.params.label = self.generate_label;
+        generate.params.antialias = self.generate_antialias;
+        generate.params.corner_dots = self.generate_corner_dots;
+        generate.params.index_strip = self.generate_index_strip;
+        if let Some(grid_thickness) = self.generate_grid_thickness {
+            generate.params.grid_thickness = grid_thickness;
+        }
+        generate.params.grid_tone = self.generate_grid_tone;
+        generate.params.tone_color = self.generate_tone_color;
+
+        let analyze = &mut app.analyze_page_state;
+        // params edited out of range by hand fall back to the defaults rather than fail analysis
+        if self.params.validate().is_ok() {
+            analyze.params = self.params;
+        }
+        analyze.show_uncertainty = self.show_uncertainty;
+        if let Some(mut scan) = self.scan.and_then(|path| load_previewed(path).ok()) {
+            analyze.original_scan = Some(scan.image.clone());
+            if !self.transforms.is_empty() || !self.regions.is_empty() {
+                scan.image = scan_transform::apply_all(&scan.image, &self.transforms);
+                refresh_scan_preview(&mut scan, &self.regions);
+            }
+            analyze.transforms = self.transforms;
+            analyze.regions = self.regions;
+            analyze.scan = Some(scan);
+        }
+
+        let apply = &mut app.apply_page_state;
+        if let Some(strength) = self.apply_strength {
+            apply.strength = strength;
+        }
+        apply.before_after = self.apply_before_after;
+        apply.image = if self.apply_wedge {
+            generated_wedge().ok()
+        } else {
+            self.apply_image.and_then(|path| load_previewed(path).ok())
+        };
+        apply.curve = self
+            .apply_curve
+            .and_then(|path| load_curve(&path).ok().map(|curve| (path, curve)));
+        if let (Some(image), Some((_, curve))) = (&apply.image, &apply.curve) {
+            apply.curved_image =
+                preview_curve(image, curve, apply.strength, apply.preview_size).ok();
+        }
+
+        let batch = &mut app.batch_page_state;
+        batch.curve = self
+            .batch_curve
+            .and_then(|path| load_curve(&path).ok().map(|curve| (path, curve)));
+        batch.output_dir = self.batch_output_dir.filter(|path| path.is_dir());
+        batch.queue = self
+            .batch_queue
+            .into_iter()
+            .filter(|path| path.is_file())
+            .map(|path| BatchItem {
+                path,
+                status: BatchStatus::Pending,
+            })
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restore_skips_missing_files() {
+        let saved: SavedState = serde_json::from_str(
+            r#"{
+                "page": "Analyze",
+                "process": "cyanotype",
+                "scan": "/does/not/exist.tif",
+                "batch_queue": ["/does/not/exist.png"]
+            }"#,
+        )
+        .unwrap();
+
+        let mut app = CurvedApp::default();
+        saved.restore(&mut app);
+
+        assert!(app.page == Page::Analyze);
+        assert_eq!(app.generate_page_state.process, "cyanotype");
+        assert!(app.analyze_page_state.scan.is_none());
+        assert!(app.batch_page_state.queue.is_empty());
+    }
+}
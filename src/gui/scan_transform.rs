@@ -0,0 +1,81 @@
+use image::{DynamicImage, Luma, Rgb};
+use imageproc::map::map_pixels;
+
+/* A single non destructive edit to a scan
+ *
+ * The analyze page keeps the scan exactly as it was loaded and a list of these. Every time the
+ * list changes the whole pipeline is re-run against the pristine original, so removing or tweaking
+ * any one step never compounds losses from the others.
+ */
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ScanTransform {
+    RotateLeft,
+    RotateRight,
+    // pixels trimmed from each edge
+    Crop {
+        left: u32,
+        top: u32,
+        right: u32,
+        bottom: u32,
+    },
+    // stretch [black, white] out to the full 16bit range
+    Levels {
+        black: u16,
+        white: u16,
+    },
+}
+
+impl ScanTransform {
+    pub fn label(&self) -> &'static str {
+        match self {
+            ScanTransform::RotateLeft => "Rotate left",
+            ScanTransform::RotateRight => "Rotate right",
+            ScanTransform::Crop { .. } => "Crop",
+            ScanTransform::Levels { .. } => "Levels",
+        }
+    }
+
+    pub fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        match self {
+            ScanTransform::RotateLeft => image.rotate270(),
+            ScanTransform::RotateRight => image.rotate90(),
+            ScanTransform::Crop {
+                left,
+                top,
+                right,
+                bottom,
+            } => {
+                let left = (*left).min(image.width().saturating_sub(1));
+                let top = (*top).min(image.height().saturating_sub(1));
+                let width = image.width().saturating_sub(left + right).max(1);
+                let height = image.height().saturating_sub(top + bottom).max(1);
+                image.crop_imm(left, top, width, height)
+            }
+            ScanTransform::Levels { black, white } => {
+                let black = *black as f32;
+                let range = (*white as f32 - black).max(1.);
+                let level = |v: u16| {
+                    ((v as f32 - black) / range * u16::MAX as f32).clamp(0., u16::MAX as f32) as u16
+                };
+
+                // keep color scans in color so the per channel histograms still work
+                if image.color().has_color() {
+                    DynamicImage::ImageRgb16(map_pixels(&image.to_rgb16(), |_, _, p| {
+                        Rgb([level(p[0]), level(p[1]), level(p[2])])
+                    }))
+                } else {
+                    DynamicImage::ImageLuma16(map_pixels(&image.to_luma16(), |_, _, p| {
+                        Luma([level(p[0])])
+                    }))
+                }
+            }
+        }
+    }
+}
+
+// Runs every transform, in order, against the original scan
+pub fn apply_all(original: &DynamicImage, transforms: &[ScanTransform]) -> DynamicImage {
+    transforms
+        .iter()
+        .fold(original.clone(), |image, transform| transform.apply(&image))
+}
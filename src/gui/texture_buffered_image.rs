@@ -3,11 +3,22 @@ use egui::widgets;
 use egui::widgets::Widget;
 
 use eframe::egui;
-use image::DynamicImage;
+use image::{DynamicImage, RgbaImage};
+
+use super::display_transform;
 
 pub struct TextureBufferedImage {
-    texture: Option<egui::TextureHandle>,
+    /* the texture, along with whether it was made from the raw color image
+     *
+     * The context frees a texture once the last handle to it is dropped, and this is the only
+     * handle, so dropping or replacing a TextureBufferedImage (or clearing this when the preview
+     * switches between raw and managed) frees its texture right away. Never hand out clones of
+     * it, a stray clone would keep the texture alive for as long as the clone lives.
+     */
+    texture: Option<(bool, egui::TextureHandle)>,
     color_image: epaint::ColorImage,
+    // the color image passed through the display transform, when there is one
+    managed_image: Option<epaint::ColorImage>,
     handle: String,
 }
 
@@ -15,39 +26,163 @@ pub struct TextureBufferedImage {
  * Normally you would use something like egui::image or the image widget except that since
  * we want to use our image.rs processes we want to own the original image bytes and simply
  * present egui with some data to render.
+ *
+ * egui is immediate mode but uploading a texture is not something to do every frame, so the
+ * texture is made the first time the image is shown and kept until the TextureBufferedImage is
+ * dropped. Keep one around for as long as its image is on screen rather than making a new one each
+ * frame, and drop it as soon as the image is replaced so its texture goes with it.
  */
 impl TextureBufferedImage {
     pub fn new(handle: String, image: &DynamicImage) -> Self {
         // Our firs step is to convert from that image.rs dynamic image into a egui color image so
         // that we can send it to the gpu to get a texture handle
-        let buffer = image.to_rgba8();
-        let pixels = buffer.as_flat_samples();
+        Self {
+            texture: None,
+            handle,
+            color_image: to_color_image(&image.to_rgba8()),
+            managed_image: None,
+        }
+    }
 
-        let color_image = epaint::ColorImage::from_rgba_unmultiplied(
-            [image.width() as usize, image.height() as usize],
-            pixels.as_slice(),
-        );
+    /* Like `new` but also keeps a color managed version of the image, drawn unless raw previews
+     * are turned on. See `display_transform::to_display`.
+     *
+     * Only the display copy is converted, `image` itself is left alone.
+     */
+    pub fn new_managed(handle: String, image: &DynamicImage, icc_profile: Option<&[u8]>) -> Self {
+        Self::new_managed_with_overlay(handle, image, icc_profile, |_| {})
+    }
 
+    // `new_managed`, with `overlay` drawn over both versions after the display transform
+    pub fn new_managed_with_overlay(
+        handle: String,
+        image: &DynamicImage,
+        icc_profile: Option<&[u8]>,
+        overlay: impl Fn(&mut RgbaImage),
+    ) -> Self {
+        let mut raw = image.to_rgba8();
+        overlay(&mut raw);
+        let managed_image = display_transform::to_display(image, icc_profile)
+            .map(|mut display| {
+                overlay(&mut display);
+                to_color_image(&display)
+            })
+            .ok();
         Self {
             texture: None,
             handle,
-            color_image,
+            color_image: to_color_image(&raw),
+            managed_image,
         }
     }
 
     pub fn ui(&mut self, ui: &mut egui::Ui) {
+        self.show(ui, egui::Sense::hover());
+    }
+
+    // Shows the image like `ui`, returning the pixel of the image that was clicked on, if any
+    pub fn clicked_pixel(&mut self, ui: &mut egui::Ui) -> Option<(u32, u32)> {
+        let response = self.show(ui, egui::Sense::click());
+        let position = response
+            .interact_pointer_pos()
+            .filter(|_| response.clicked())?;
+        // the image is shrunk to fit, scale back up to the image's own pixels
+        let relative = (position - response.rect.min) / response.rect.size();
+        let [width, height] = self.color_image.size;
+        Some((
+            ((relative.x * width as f32) as u32).min(width as u32 - 1),
+            ((relative.y * height as f32) as u32).min(height as u32 - 1),
+        ))
+    }
+
+    fn show(&mut self, ui: &mut egui::Ui, sense: egui::Sense) -> egui::Response {
+        let raw = raw_previews(ui.ctx()) || self.managed_image.is_none();
+        if self
+            .texture
+            .as_ref()
+            .is_some_and(|(texture_raw, _)| *texture_raw != raw)
+        {
+            self.texture = None;
+        }
+
         let handle = self.handle.clone();
-        let ci = &self.color_image;
+        let ci = match &self.managed_image {
+            Some(managed_image) if !raw => managed_image,
+            _ => &self.color_image,
+        };
 
         // Careful! This isn't safe to run in immediate mode this generates the texture
-        // and memoizes it
-        let texture: &egui::TextureHandle = self.texture.get_or_insert_with(|| {
-            ui.ctx()
-                .load_texture(handle, ci.clone(), Default::default())
+        // and memoizes it, see the note on TextureBufferedImage
+        let (_, texture) = self.texture.get_or_insert_with(|| {
+            (
+                raw,
+                ui.ctx()
+                    .load_texture(handle, ci.clone(), Default::default()),
+            )
         });
 
         widgets::Image::new((texture.id(), texture.size_vec2()))
             .shrink_to_fit()
-            .ui(ui);
+            .sense(sense)
+            .ui(ui)
+    }
+}
+
+fn to_color_image(buffer: &RgbaImage) -> epaint::ColorImage {
+    epaint::ColorImage::from_rgba_unmultiplied(
+        [buffer.width() as usize, buffer.height() as usize],
+        buffer.as_flat_samples().as_slice(),
+    )
+}
+
+fn raw_previews_id() -> egui::Id {
+    egui::Id::new("raw_previews")
+}
+
+// Whether previews skip the display transform and show the image's values as they are
+pub fn raw_previews(ctx: &egui::Context) -> bool {
+    ctx.data(|data| data.get_temp(raw_previews_id()).unwrap_or(false))
+}
+
+pub fn set_raw_previews(ctx: &egui::Context, raw: bool) {
+    ctx.data_mut(|data| data.insert_temp(raw_previews_id(), raw));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Shows `image` for a frame, the first frame it's shown uploads its texture
+    fn show(ctx: &egui::Context, image: &mut TextureBufferedImage) {
+        let _ = ctx.run(Default::default(), |ctx| {
+            egui::CentralPanel::default().show(ctx, |ui| image.ui(ui));
+        });
+    }
+
+    #[test]
+    fn test_texture_freed_on_drop() {
+        let ctx = egui::Context::default();
+        let allocated = || ctx.tex_manager().read().num_allocated();
+        let image = DynamicImage::new_luma16(8, 8);
+
+        // the context starts out with its own font texture
+        let baseline = allocated();
+        let mut preview = TextureBufferedImage::new_managed("preview".to_string(), &image, None);
+        show(&ctx, &mut preview);
+        assert_eq!(allocated(), baseline + 1);
+
+        // switching to the raw preview swaps one texture for the other
+        set_raw_previews(&ctx, true);
+        show(&ctx, &mut preview);
+        assert_eq!(allocated(), baseline + 1);
+
+        // replacing the preview, like loading a new scan, frees the old texture
+        preview = TextureBufferedImage::new("replacement".to_string(), &image);
+        assert_eq!(allocated(), baseline);
+        show(&ctx, &mut preview);
+        assert_eq!(allocated(), baseline + 1);
+
+        drop(preview);
+        assert_eq!(allocated(), baseline);
     }
 }
@@ -0,0 +1,89 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::{DynamicImage, ImageEncoder};
+use moxcms::ColorProfile;
+
+use super::display_transform;
+
+/* Encodes `image` as an 8bit sRGB png tagged with an sRGB profile, for sharing on the web
+ *
+ * `icc_profile` is the profile embedded in the file the image came from, the image is converted
+ * from it the same way the preview is so the export looks like what's on screen. Browsers that
+ * ignore untagged files' color still honor the profile, and the png is compressed as small as the
+ * encoder goes since it only has to be written once.
+ */
+pub fn export_for_web<W: Write>(
+    image: &DynamicImage,
+    icc_profile: Option<&[u8]>,
+    writer: W,
+) -> Result<()> {
+    let rgba = display_transform::to_display(image, icc_profile)?;
+    let srgb = ColorProfile::new_srgb()
+        .encode()
+        .map_err(|e| anyhow!("failed to encode srgb profile: {:?}", e))?;
+
+    let mut encoder =
+        PngEncoder::new_with_quality(writer, CompressionType::Best, FilterType::Adaptive);
+    encoder
+        .set_icc_profile(srgb)
+        .map_err(|e| anyhow!("failed to embed srgb profile: {}", e))?;
+
+    // drop the alpha channel when there's nothing in it
+    if image.color().has_alpha() {
+        encoder.write_image(
+            rgba.as_raw(),
+            rgba.width(),
+            rgba.height(),
+            image::ExtendedColorType::Rgba8,
+        )?;
+    } else {
+        let rgb = DynamicImage::ImageRgba8(rgba).to_rgb8();
+        encoder.write_image(
+            rgb.as_raw(),
+            rgb.width(),
+            rgb.height(),
+            image::ExtendedColorType::Rgb8,
+        )?;
+    }
+    Ok(())
+}
+
+// `export_for_web` written out to `path`
+pub fn save_for_web(image: &DynamicImage, icc_profile: Option<&[u8]>, path: &Path) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    export_for_web(image, icc_profile, &mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::png::PngDecoder;
+    use image::{ImageBuffer, ImageDecoder, Luma};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_export_for_web() {
+        let grey: ImageBuffer<Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_fn(4, 2, |x, _| Luma([x as u16 * 0x5555]));
+        let mut png = Vec::new();
+        export_for_web(&DynamicImage::ImageLuma16(grey), None, &mut png).unwrap();
+
+        let mut decoder = PngDecoder::new(Cursor::new(&png)).unwrap();
+        assert_eq!(decoder.color_type(), image::ColorType::Rgb8);
+        assert_eq!(decoder.dimensions(), (4, 2));
+        let profile = decoder.icc_profile().unwrap().unwrap();
+        let profile = ColorProfile::new_from_slice(&profile).unwrap();
+        assert_eq!(profile.color_space, moxcms::DataColorSpace::Rgb);
+
+        // the ends of the range survive the conversion
+        let decoded = image::load_from_memory(&png).unwrap().to_rgb8();
+        assert!(decoded.get_pixel(0, 0)[0] <= 1);
+        assert!(decoded.get_pixel(3, 1)[0] >= 254);
+    }
+}
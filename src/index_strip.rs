@@ -0,0 +1,261 @@
+use anyhow::{anyhow, Result};
+use image::{ImageBuffer, Luma};
+use imageproc::drawing::draw_filled_rect_mut;
+use imageproc::rect::Rect;
+
+use super::apply::ToneRange;
+use super::step_description::StepDescription;
+
+type Gray16Image = ImageBuffer<Luma<u16>, Vec<u16>>;
+
+// bits given to the step, column, and row counts, to each end of the printable range (in
+// thousandths of the full range), and to the checksum of all of them
+const COUNT_BITS: u32 = 8;
+const RANGE_BITS: u32 = 10;
+const CHECKSUM_BITS: u32 = 8;
+const RANGE_SCALE: f64 = 1000.;
+
+// the modules at either end of the strip, true is light. The start tells a decoder which way
+// round the print came out, the end that it read the whole strip
+const START: [bool; 2] = [false, true];
+const END: [bool; 2] = [true, false];
+
+// how many modules wide the strip is, every one as wide as the others
+pub const MODULES: u32 =
+    (START.len() + END.len()) as u32 + 3 * COUNT_BITS + 2 * RANGE_BITS + CHECKSUM_BITS;
+
+// modules narrower than this many pixels don't survive a print and scan
+const MIN_MODULE_WIDTH: u32 = 3;
+
+// the least difference between the light and dark modules at the start that reads as a strip
+const MIN_CONTRAST: f64 = u16::MAX as f64 / 8.;
+
+/* The layout of a wedge, as encoded in the strip `--index-strip` draws along its bottom edge
+ *
+ * Enough for analyze to find every patch of the scan without being told how the wedge was
+ * generated.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WedgeIndex {
+    pub steps: u32,
+    pub columns: u32,
+    pub rows: u32,
+    // the range generate pulled every patch's tone into, None for the full range
+    pub printable_range: Option<ToneRange>,
+}
+
+impl WedgeIndex {
+    pub fn new(step_description: &StepDescription, printable_range: Option<ToneRange>) -> Self {
+        WedgeIndex {
+            steps: step_description.count,
+            columns: step_description.columns,
+            rows: step_description.rows,
+            printable_range,
+        }
+    }
+
+    // each field and its width in bits, the same order they're laid out in
+    fn fields(&self) -> [(u32, u32); 5] {
+        let (low, high) = self
+            .printable_range
+            .map_or((0., 1.), |range| (range.low, range.high));
+        let thousandths = |fraction: f64| (fraction * RANGE_SCALE).round() as u32;
+        [
+            (self.steps, COUNT_BITS),
+            (self.columns, COUNT_BITS),
+            (self.rows, COUNT_BITS),
+            (thousandths(low), RANGE_BITS),
+            (thousandths(high), RANGE_BITS),
+        ]
+    }
+
+    // every module of the strip left to right, an error if a count is too large to encode
+    fn modules(&self) -> Result<Vec<bool>> {
+        let fields = self.fields();
+        let mut modules = START.to_vec();
+        for (value, bits) in fields {
+            if value >= 1 << bits {
+                return Err(anyhow!(
+                    "the index strip can't encode more than {} steps, columns, or rows",
+                    (1 << bits) - 1
+                ));
+            }
+            push_bits(&mut modules, value, bits);
+        }
+        push_bits(&mut modules, checksum(&fields), CHECKSUM_BITS);
+        modules.extend(END);
+        Ok(modules)
+    }
+}
+
+// `value` as `bits` modules, most significant first
+fn push_bits(modules: &mut Vec<bool>, value: u32, bits: u32) {
+    modules.extend((0..bits).rev().map(|bit| value >> bit & 1 == 1));
+}
+
+fn checksum(fields: &[(u32, u32)]) -> u32 {
+    fields.iter().map(|(value, _)| value).sum::<u32>() % (1 << CHECKSUM_BITS)
+}
+
+// The pixels below the patches and caption the strip takes up, a gap of one module then the
+// strip two modules tall
+pub fn height(width: u32) -> u32 {
+    3 * width / MODULES
+}
+
+/* Draws the strip encoding `index` in black and white across `width` pixels, in the `height` tall
+ * band starting at (x, y)
+ */
+pub fn draw(image: &mut Gray16Image, x: u32, y: u32, width: u32, index: &WedgeIndex) -> Result<()> {
+    if width / MODULES < MIN_MODULE_WIDTH {
+        return Err(anyhow!(
+            "the index strip needs a wedge at least {} pixels wide, lay it out in more columns",
+            MODULES * MIN_MODULE_WIDTH
+        ));
+    }
+    let module_height = height(width) / 3;
+    let edge = |n: u32| x + n * width / MODULES;
+    for (n, light) in index.modules()?.into_iter().enumerate() {
+        let n = n as u32;
+        let rect = Rect::at(edge(n) as i32, (y + module_height) as i32)
+            .of_size(edge(n + 1) - edge(n), 2 * module_height);
+        let tone = if light { u16::MAX } else { 0 };
+        draw_filled_rect_mut(image, rect, Luma([tone]));
+    }
+    Ok(())
+}
+
+/* Reads the strip along the bottom of a scan cropped to the wedge, as generate draws it with
+ * `--index-strip`
+ *
+ * Each module is sampled across the middle of its width and the strip's height, so the scan can be
+ * any size and cropped a little loosely. The start of the strip says which tone is which, a print
+ * that came out as a negative reads the same.
+ */
+pub fn decode(image: &Gray16Image) -> Result<WedgeIndex> {
+    let not_found = || anyhow!("couldn't find an index strip along the bottom of the scan");
+    let (width, height) = image.dimensions();
+    let module = width as f64 / MODULES as f64;
+    let (top, bottom) = (height as f64 - 1.5 * module, height as f64 - 0.5 * module);
+    if module < 1. || top < 0. {
+        return Err(not_found());
+    }
+
+    let means: Vec<f64> = (0..MODULES)
+        .map(|n| {
+            let left = ((n as f64 + 0.25) * module) as u32;
+            let right = (((n as f64 + 0.75) * module) as u32).max(left + 1);
+            let (top, bottom) = (top as u32, (bottom as u32).max(top as u32 + 1));
+            let pixels = (top..bottom).flat_map(|y| (left..right).map(move |x| (x, y)));
+            let sum: f64 = pixels
+                .clone()
+                .map(|(x, y)| image.get_pixel(x, y)[0] as f64)
+                .sum();
+            sum / pixels.count() as f64
+        })
+        .collect();
+
+    let (dark, light) = (means[0], means[1]);
+    if (light - dark).abs() < MIN_CONTRAST {
+        return Err(not_found());
+    }
+    let threshold = (dark + light) / 2.;
+    let modules: Vec<bool> = means
+        .iter()
+        .map(|mean| (*mean > threshold) == (light > dark))
+        .collect();
+    if modules[modules.len() - END.len()..] != END {
+        return Err(not_found());
+    }
+
+    let mut bits = modules[START.len()..].iter();
+    let mut read = |count: u32| {
+        bits.by_ref()
+            .take(count as usize)
+            .fold(0, |value, bit| value << 1 | *bit as u32)
+    };
+    let fields =
+        [COUNT_BITS, COUNT_BITS, COUNT_BITS, RANGE_BITS, RANGE_BITS].map(|bits| (read(bits), bits));
+    if read(CHECKSUM_BITS) != checksum(&fields) {
+        return Err(anyhow!(
+            "the index strip along the bottom of the scan is unreadable, its checksum doesn't match"
+        ));
+    }
+
+    let [(steps, _), (columns, _), (rows, _), (low, _), (high, _)] = fields;
+    if low >= high || high > RANGE_SCALE as u32 {
+        return Err(anyhow!(
+            "the index strip along the bottom of the scan holds an impossible tone range"
+        ));
+    }
+    let printable_range = (low > 0 || high < RANGE_SCALE as u32).then(|| ToneRange {
+        low: low as f64 / RANGE_SCALE,
+        high: high as f64 / RANGE_SCALE,
+    });
+    Ok(WedgeIndex {
+        steps,
+        columns,
+        rows,
+        printable_range,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::{self, GenerateParams, MARGIN};
+    use crate::step_description::Layout;
+    use image::imageops::{self, FilterType};
+    use image::DynamicImage;
+
+    #[test]
+    fn test_index_strip_round_trip() {
+        let params = GenerateParams {
+            index_strip: true,
+            columns: 7,
+            rows: Some(9),
+            optimize_for_range: Some(8.),
+            printable_range: Some("0.05,0.95".parse().unwrap()),
+            ..GenerateParams::default()
+        };
+        let step_description = params.step_description(Layout::Grid).unwrap();
+        let expected = WedgeIndex::new(&step_description, params.printable_range);
+
+        let wedge = generate::generate_with_params(None, None, Layout::Grid, &params).unwrap();
+        let scan = wedge
+            .crop_imm(
+                MARGIN,
+                MARGIN,
+                step_description.width,
+                step_description.total_height() + height(step_description.width),
+            )
+            .to_luma16();
+        assert_eq!(decode(&scan).unwrap(), expected);
+
+        // printed as a negative and scanned at a different size
+        let mut negative = scan.clone();
+        imageops::invert(&mut negative);
+        let (width, height) = negative.dimensions();
+        let resized = imageops::resize(
+            &negative,
+            width * 3 / 2,
+            height * 3 / 2,
+            FilterType::Triangle,
+        );
+        assert_eq!(decode(&resized).unwrap(), expected);
+
+        // a wedge without a strip has nothing to read
+        let plain = generate::generate(None, None, Layout::Grid)
+            .unwrap()
+            .crop_imm(MARGIN, MARGIN, 1000, 1100);
+        assert!(decode(&plain.to_luma16()).is_err());
+
+        // too many columns to encode
+        let wide = WedgeIndex {
+            columns: 256,
+            ..expected
+        };
+        let mut image = DynamicImage::new_luma16(2000, 100).to_luma16();
+        assert!(draw(&mut image, 0, 0, 2000, &wide).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use image::{DynamicImage, ImageBuffer, Luma};
+use imageproc::map::map_pixels;
+
+/* How much each of red, green, and blue contribute to the tone of a color image
+ *
+ * The defaults are the Rec. 709 coefficients image uses for `to_luma16`. Alternative processes
+ * rarely see color the way a monitor does, cyanotype for example is mostly sensitive to blue and
+ * UV, so measuring or correcting a color original with weights closer to the paper's sensitivity
+ * gives tones closer to what the print will show.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct LumaWeights {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+}
+
+impl Default for LumaWeights {
+    fn default() -> Self {
+        Self {
+            r: 0.2126,
+            g: 0.7152,
+            b: 0.0722,
+        }
+    }
+}
+
+// parses "r,g,b", as in `--luma-weights 0.1,0.2,0.7`
+impl FromStr for LumaWeights {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let weights = s
+            .split(',')
+            .map(|w| w.trim().parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()?;
+        let [r, g, b] = weights[..] else {
+            return Err(anyhow!(
+                "expected three weights like 0.2,0.7,0.1, got {}",
+                s
+            ));
+        };
+        LumaWeights { r, g, b }.validate()
+    }
+}
+
+impl LumaWeights {
+    // The weights back, or an error when they don't add up to a tone
+    pub fn validate(self) -> anyhow::Result<Self> {
+        let LumaWeights { r, g, b } = self;
+        if [r, g, b].iter().any(|w| w.is_nan() || *w < 0.) || r + g + b <= 0. {
+            return Err(anyhow!("weights must be positive, got {}", self));
+        }
+        Ok(self)
+    }
+}
+
+impl fmt::Display for LumaWeights {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{},{}", self.r, self.g, self.b)
+    }
+}
+
+impl LumaWeights {
+    // The weights scaled to sum to 1, so a white pixel stays white whatever the weights
+    fn normalized(&self) -> (f32, f32, f32) {
+        let sum = self.r + self.g + self.b;
+        (self.r / sum, self.g / sum, self.b / sum)
+    }
+}
+
+// Converts `image` to 16bit greyscale, color images are weighted by `weights`
+pub fn to_luma16(image: &DynamicImage, weights: LumaWeights) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+    if !image.color().has_color() {
+        return image.to_luma16();
+    }
+
+    let (r, g, b) = weights.normalized();
+    map_pixels(&image.to_rgb16(), |_, _, p| {
+        let luma = r * p[0] as f32 + g * p[1] as f32 + b * p[2] as f32;
+        Luma([luma.round().clamp(0., u16::MAX as f32) as u16])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    #[test]
+    fn test_luma_weights_from_str() {
+        assert_eq!(
+            "0.1, 0.2,0.7".parse::<LumaWeights>().unwrap(),
+            LumaWeights {
+                r: 0.1,
+                g: 0.2,
+                b: 0.7
+            }
+        );
+        assert!("0.1,0.2".parse::<LumaWeights>().is_err());
+        assert!("0,0,0".parse::<LumaWeights>().is_err());
+        assert!("a,b,c".parse::<LumaWeights>().is_err());
+    }
+
+    #[test]
+    fn test_to_luma16_favoring_blue() {
+        // a saturated blue next to a saturated yellow
+        let buffer: ImageBuffer<Rgb<u16>, Vec<u16>> = ImageBuffer::from_fn(2, 1, |x, _| {
+            if x == 0 {
+                Rgb([0, 0, u16::MAX])
+            } else {
+                Rgb([u16::MAX, u16::MAX, 0])
+            }
+        });
+        let image = DynamicImage::ImageRgb16(buffer);
+
+        // the defaults match image's own conversion, give or take rounding
+        let default = to_luma16(&image, LumaWeights::default());
+        for (ours, theirs) in default.pixels().zip(image.to_luma16().pixels()) {
+            assert!(ours[0].abs_diff(theirs[0]) <= 1);
+        }
+
+        // by default blue is nearly black and yellow nearly white, a blue sensitive process
+        // sees it the other way around
+        assert!(default.get_pixel(0, 0)[0] < default.get_pixel(1, 0)[0]);
+        let blue = to_luma16(&image, "0.05,0.15,0.8".parse().unwrap());
+        assert!(blue.get_pixel(0, 0)[0] > blue.get_pixel(1, 0)[0]);
+    }
+}
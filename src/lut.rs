@@ -0,0 +1,87 @@
+use std::io::Write;
+
+use anyhow::Result;
+use splines::Spline;
+
+use super::curve::{sample_curve_lut, CURVE_DOMAIN_MAX};
+
+// Identifies a curved lut file, followed by the version of the layout
+pub const LUT_MAGIC: &[u8; 4] = b"CLUT";
+pub const LUT_VERSION: u32 = 1;
+
+#[derive(Debug, Default, Clone, Copy, clap::ValueEnum)]
+pub enum LutFormat {
+    /// A 12 byte header (magic "CLUT", version, entry count, each a little endian u32) and then
+    /// the entries
+    #[default]
+    Bin,
+    /// Just the entries, for loaders that already know the size
+    Raw,
+}
+
+/* Writes `curve` as `size` evenly spaced samples, ready to upload as a 1D texture for previewing
+ * the correction on the gpu.
+ *
+ * Each entry is a little endian f32 in 0..1, the curve's output for an input of i / (size - 1).
+ */
+pub fn write_lut(
+    writer: &mut impl Write,
+    curve: &Spline<f64, f64>,
+    size: u32,
+    format: LutFormat,
+) -> Result<()> {
+    let lut = sample_curve_lut(curve, size as usize)?;
+
+    let mut bytes = Vec::with_capacity(12 + lut.len() * 4);
+    if let LutFormat::Bin = format {
+        bytes.extend_from_slice(LUT_MAGIC);
+        bytes.extend_from_slice(&LUT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&size.to_le_bytes());
+    }
+    for (_, output) in lut {
+        let normalized = (output / CURVE_DOMAIN_MAX).clamp(0., 1.) as f32;
+        bytes.extend_from_slice(&normalized.to_le_bytes());
+    }
+    writer.write_all(&bytes)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::sample_curve;
+    use splines::{Interpolation, Key};
+
+    #[test]
+    fn test_write_lut() {
+        let max = CURVE_DOMAIN_MAX;
+        let curve = Spline::from_vec(vec![
+            Key::new(0., 0., Interpolation::Linear),
+            Key::new(max * 0.5, max * 0.3, Interpolation::Linear),
+            Key::new(max, max, Interpolation::Linear),
+        ]);
+
+        let mut bin = Vec::new();
+        write_lut(&mut bin, &curve, 1024, LutFormat::Bin).unwrap();
+        assert_eq!(bin.len(), 12 + 1024 * 4);
+        assert_eq!(&bin[0..4], LUT_MAGIC);
+        assert_eq!(bin[4..8], LUT_VERSION.to_le_bytes());
+        assert_eq!(bin[8..12], 1024u32.to_le_bytes());
+
+        let entries: Vec<f32> = bin[12..]
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        for (i, entry) in entries.iter().enumerate() {
+            let expected = sample_curve(&curve, i as f64 / 1023. * max).unwrap() / max;
+            assert!((*entry as f64 - expected).abs() < 1e-6, "{}", i);
+        }
+        assert_eq!(entries[0], 0.);
+        assert_eq!(entries[1023], 1.);
+
+        // raw is the same entries without the header
+        let mut raw = Vec::new();
+        write_lut(&mut raw, &curve, 1024, LutFormat::Raw).unwrap();
+        assert_eq!(raw, bin[12..]);
+    }
+}
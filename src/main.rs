@@ -1,19 +1,39 @@
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
 
-use anyhow;
+use anyhow::{bail, Context};
 use clap::{Parser, Subcommand};
-use serde_json;
 use splines::Spline;
 
 mod analyze;
 mod apply;
+mod cancel;
+mod compare;
+mod curve;
 mod generate;
 mod gui;
+mod index_strip;
+mod luma;
+mod lut;
+mod multipage;
+mod pdf;
+mod quantize;
+mod recover;
+mod regions;
+mod report;
+mod selftest;
+mod session;
 mod step_description;
+mod strips;
+mod svg;
+mod verify;
+mod version;
+
+use step_description::Layout;
 
 #[derive(Parser, Debug)]
-#[command()]
+#[command(version, long_version = version::LONG_VERSION)]
 struct Args {
     #[command(subcommand)]
     command: Commands,
@@ -22,6 +42,33 @@ struct Args {
     debug: bool,
 }
 
+#[derive(Debug, Default, Clone, Copy, clap::ValueEnum)]
+enum CurveFormat {
+    /// The curve's spline as json, used by apply
+    #[default]
+    Json,
+    /// An Argyll CMS calibration file
+    Cal,
+}
+
+#[derive(Debug, Default, Clone, Copy, clap::ValueEnum, serde::Deserialize)]
+enum WedgeFormat {
+    /// An image, in the format matching the output's extension
+    #[default]
+    Image,
+    /// A pdf page the physical size of the wedge at --dpi, for print paths that rescale images
+    Pdf,
+}
+
+#[derive(Debug, Default, Clone, Copy, clap::ValueEnum)]
+enum PlotFormat {
+    /// An image, in the format matching the output's extension
+    #[default]
+    Image,
+    /// Vector paths that stay crisp at any size, for printed documentation
+    Svg,
+}
+
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// Adds files to myapp
@@ -32,18 +79,60 @@ enum Commands {
         #[arg(short, long)]
         output_dir: PathBuf,
 
-        #[arg(short, long)]
-        invert: bool,
+        /// Format to write the curve in
+        #[arg(short, long, value_enum, default_value_t)]
+        format: CurveFormat,
+
+        /// Also write the curve sampled at this many evenly spaced input levels, as
+        /// curve-levels.csv or .json, for devices that take a table rather than a spline
+        #[arg(long, value_parser = clap::value_parser!(u32).range(2..=65536))]
+        output_levels: Option<u32>,
+
+        /// Format to write --output-levels in
+        #[arg(long, value_enum, default_value_t, requires = "output_levels")]
+        levels_format: quantize::LevelsFormat,
+
+        /// Also write the measured response of the process, each input tone to the normalized
+        /// tone it printed as, as a curve in response.json. The correction curve is its inverse
+        #[arg(long)]
+        response: bool,
+
+        /// Write json curves in the 0.0 to 1.0 float domain rather than 0 to 65535, for tools
+        /// that expect normalized curves. Apply reads either from the domain recorded in the file
+        #[arg(long)]
+        normalized: bool,
+
+        /// A json list of the wedges on the sheet, each {"name", "x", "y", "width", "height"}.
+        /// Each is analyzed on its own and written to curve-<name>
+        #[arg(long)]
+        regions: Option<PathBuf>,
+
+        /// The digital wedge that was printed, as generate wrote it. Its steps, columns, and
+        /// layout are measured from it in place of --steps, --columns, and --layout
+        #[arg(long)]
+        layout_from: Option<PathBuf>,
+
+        /// Analyze with the params saved in a curve analyze wrote, or a json file of params, in
+        /// place of the options given here. Reruns a calibration exactly on a new scan
+        #[arg(long = "params", conflicts_with_all = ["AnalyzeParams", "layout_from"])]
+        params_file: Option<PathBuf>,
+
+        #[command(flatten)]
+        params: analyze::AnalyzeParams,
     },
     Apply {
         #[arg(short, long)]
         input: PathBuf,
 
-        #[arg(short, long)]
-        curve: PathBuf,
+        /// Curve to apply, repeat to apply several curves in order in one pass
+        #[arg(short, long, required = true)]
+        curve: Vec<PathBuf>,
 
         #[arg(short, long)]
         output: PathBuf,
+
+        #[command(flatten)]
+        params: apply::ApplyParams,
     },
     Generate {
         #[arg(short, long)]
@@ -52,44 +141,535 @@ enum Commands {
         process: Option<String>,
         #[arg(short, long)]
         notes: Option<String>,
+        /// How to mark the boundaries between steps
+        #[arg(short, long, value_enum, default_value_t)]
+        layout: Layout,
+        /// Also write a json manifest of every patch's index, rectangle, and tone
+        #[arg(long)]
+        manifest: Option<PathBuf>,
+        /// Format to write the wedge in
+        #[arg(short, long, value_enum, default_value_t)]
+        format: WedgeFormat,
+        /// Resolution the wedge is printed at, sets the size of the pdf page
+        #[arg(long, default_value_t = 300.)]
+        dpi: f32,
+        #[command(flatten)]
+        params: generate::GenerateParams,
+    },
+    /// Generates every wedge listed in a json spec, for a family of wedges in one go
+    GenerateBatch {
+        /// The spec, a json object whose "wedges" list takes the same options as generate
+        #[arg(short, long)]
+        spec: PathBuf,
+    },
+    /// Analyzes a scan and writes a one page report of the calibration, as a pdf or an image
+    Report {
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Where to write the report, a .pdf or an image format like .png
+        #[arg(short, long)]
+        output: PathBuf,
+
+        #[arg(short, long)]
+        process: Option<String>,
+
+        #[arg(short, long)]
+        notes: Option<String>,
+
+        #[command(flatten)]
+        params: analyze::AnalyzeParams,
+    },
+    /// Analyzes a scan and bundles it with its curve, report, and params into a .curved archive
+    Pack {
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Where to write the archive, e.g. session.curved
+        #[arg(short, long)]
+        output: PathBuf,
+
+        #[arg(short, long)]
+        process: Option<String>,
+
+        #[arg(short, long)]
+        notes: Option<String>,
+
+        #[command(flatten)]
+        params: analyze::AnalyzeParams,
+    },
+    /// Extracts the scan, curve, report, and manifest of a .curved archive into a directory
+    Unpack {
+        #[arg(short, long)]
+        input: PathBuf,
+
+        #[arg(short, long)]
+        output_dir: PathBuf,
+    },
+    /// Reduces a curve to a fixed number of levels for devices that only accept coarse curves
+    Quantize {
+        #[arg(short, long)]
+        input: PathBuf,
+
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Number of entries (and output tones) in the quantized curve
+        #[arg(short, long, default_value_t = 256, value_parser = clap::value_parser!(u32).range(2..=65536))]
+        levels: u32,
+
+        /// Carry each entry's rounding error into the next to avoid banding
+        #[arg(long)]
+        diffuse: bool,
+    },
+    /// Applies a curve to a freshly generated wedge and reports how close the result is to linear
+    Verify {
+        #[arg(short, long)]
+        curve: PathBuf,
+
+        /// A scan of the original wedge, the corrected tones are predicted through its response
+        #[arg(short, long)]
+        scan: Option<PathBuf>,
+
+        /// Fail if any step is further than this percentage of the tonal range from linear
+        #[arg(short, long)]
+        tolerance: Option<f64>,
+
+        #[command(flatten)]
+        params: analyze::AnalyzeParams,
+    },
+    /// Calibrates a simulated process end to end and checks the corrected response is linear
+    Selftest {
+        /// Fail if any corrected step is further than this percentage of the tonal range from linear
+        #[arg(short, long, default_value_t = 2.)]
+        tolerance: f64,
+    },
+    /// Writes a curve as a lookup table of floats, ready to load as a 1D texture on the gpu
+    ExportLut {
+        #[arg(short, long)]
+        input: PathBuf,
+
+        #[arg(short, long)]
+        output: PathBuf,
+
+        #[arg(short, long, value_enum, default_value_t)]
+        format: lut::LutFormat,
+
+        /// Number of entries in the lookup table
+        #[arg(short, long, default_value_t = 1024, value_parser = clap::value_parser!(u32).range(2..))]
+        size: u32,
+    },
+    /// Plots a curve like the analyze preview, optionally over the histogram of a scan
+    Plot {
+        #[arg(short, long)]
+        input: PathBuf,
+
+        #[arg(short, long)]
+        output: PathBuf,
+
+        #[arg(short, long, value_enum, default_value_t)]
+        format: PlotFormat,
+
+        /// A scan to draw the histogram of behind the curve, analyzed with the params below
+        #[arg(short, long)]
+        scan: Option<PathBuf>,
+
+        #[command(flatten)]
+        params: analyze::AnalyzeParams,
     },
     Gui {},
+    /// Prints the version, commit, features, and key dependency versions, for bug reports
+    Version {},
 }
 
 fn apply(
     input_pathbuf: &PathBuf,
-    curve_pathbuf: &PathBuf,
+    curve_pathbufs: &[PathBuf],
     output_pathbuf: &PathBuf,
+    params: &apply::ApplyParams,
     _debug: bool,
 ) -> anyhow::Result<()> {
-    let input_file_path = fs::canonicalize(&input_pathbuf)?;
-    let curve_file_path = fs::canonicalize(&curve_pathbuf)?;
-    let output_file_path = fs::canonicalize(&output_pathbuf)?;
+    let input_file_path = fs::canonicalize(input_pathbuf)?;
+    let curves = curve_pathbufs
+        .iter()
+        .map(|path| curve::load_curve(&fs::canonicalize(path)?))
+        .collect::<anyhow::Result<Vec<_>>>()?;
 
-    let image = image::open(&input_file_path)?;
-    let curve_data = fs::read_to_string(curve_file_path)?;
-    let curve = serde_json::from_str::<Spline<f64, f64>>(&curve_data)?;
+    // a chain of curves is folded into one so the image is only sampled once
+    let curve = params.adjust_curve(&curve::compose_curves(&curves)?)?;
+    if params.strips {
+        if !is_tiff(&input_file_path) || !is_tiff(output_pathbuf) {
+            bail!("--strips reads and writes tiffs only");
+        }
+        return strips::apply_strips(
+            io::BufReader::new(fs::File::open(&input_file_path)?),
+            io::BufWriter::new(fs::File::create(output_pathbuf)?),
+            &curve,
+            params.luma_weights,
+            &cancel::Cancel::default(),
+        );
+    }
+    let correct = |image: &image::DynamicImage| -> anyhow::Result<image::DynamicImage> {
+        // a greyscale image has no channels to correct apart
+        let domain = match params.domain {
+            apply::Domain::Rgb if !image.color().has_color() => apply::Domain::Luma,
+            domain => domain,
+        };
+        let curved_image = match (domain, params.size()) {
+            (apply::Domain::Luma, Some(size)) => {
+                apply::apply_resized(image, &curve, params.luma_weights, size)?
+            }
+            (apply::Domain::Luma, None) => apply::apply(image, &curve, params.luma_weights)?,
+            (apply::Domain::Hsv, Some(size)) => {
+                apply::resize(&apply::apply_hsv(image, &curve)?, size)
+            }
+            (apply::Domain::Hsv, None) => apply::apply_hsv(image, &curve)?,
+            (apply::Domain::Rgb, Some(size)) => {
+                apply::resize(&apply::apply_rgb(image, [&curve; 3])?, size)
+            }
+            (apply::Domain::Rgb, None) => apply::apply_rgb(image, [&curve; 3])?,
+        };
+        Ok(match params.output_color {
+            Some(color) => apply::convert_output(curved_image, color),
+            None => curved_image,
+        })
+    };
 
-    let curved_image = apply::apply(&image, &curve);
+    // every page of a multi-page tiff is corrected into a multi-page tiff
+    if is_tiff(&input_file_path) && multipage::is_multipage(fs::File::open(&input_file_path)?)? {
+        if !is_tiff(output_pathbuf) {
+            bail!("a multi-page tiff can only be written out as a tiff");
+        }
+        let pages = multipage::read_pages(fs::File::open(&input_file_path)?)?;
+        let corrected = pages
+            .iter()
+            .map(correct)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        multipage::write_pages(fs::File::create(output_pathbuf)?, &corrected)?;
+        return Ok(());
+    }
 
-    curved_image.save(output_file_path)?;
+    correct(&image::open(&input_file_path)?)?.save(output_pathbuf)?;
     Ok(())
 }
 
+fn is_tiff(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            extension.eq_ignore_ascii_case("tif") || extension.eq_ignore_ascii_case("tiff")
+        })
+}
+
+// What analyze writes out besides the curve, and the format of the curve
+struct AnalyzeOutputs {
+    format: CurveFormat,
+    levels: Option<(u32, quantize::LevelsFormat)>,
+    response: bool,
+    normalized: bool,
+}
+
 fn analyze(
     input: &PathBuf,
     output_dir: &PathBuf,
-    invert_image: bool,
+    outputs: &AnalyzeOutputs,
+    regions: Option<&PathBuf>,
+    params: &analyze::AnalyzeParams,
     debug: bool,
 ) -> anyhow::Result<()> {
-    let input_file_path = fs::canonicalize(&input)?;
-    let output_dir = fs::canonicalize(&output_dir)?;
+    let input_file_path = fs::canonicalize(input)?;
+    let output_dir = fs::canonicalize(output_dir)?;
 
-    let curve_file = fs::File::create(output_dir.join("curve.json"))?;
     let image = image::open(input_file_path)?;
-    let analyze_results = analyze::analyze(&image, invert_image, debug)?;
+    match regions {
+        Some(regions) => {
+            let regions = regions::load_regions(regions)?;
+            let results = regions::analyze_regions(
+                &image,
+                &regions,
+                params,
+                debug,
+                &cancel::Cancel::default(),
+            )?;
+            for (name, analyze_results) in results {
+                write_results(
+                    &output_dir,
+                    &format!("-{}", name),
+                    outputs,
+                    &analyze_results,
+                    params,
+                )?;
+            }
+        }
+        None => {
+            let analyze_results = analyze::analyze(&image, params, debug)?;
+            write_results(&output_dir, "", outputs, &analyze_results, params)?;
+        }
+    }
+    Ok(())
+}
 
-    serde_json::to_writer(&curve_file, &analyze_results.curve)?;
+/* Writes everything `outputs` asks for from `results` into `output_dir`, each file's name ending
+ * in `suffix` so the results of several regions can sit side by side
+ */
+fn write_results(
+    output_dir: &Path,
+    suffix: &str,
+    outputs: &AnalyzeOutputs,
+    results: &analyze::AnalyzeResults,
+    params: &analyze::AnalyzeParams,
+) -> anyhow::Result<()> {
+    let curve_name = format!("curve{}", suffix);
+    if let Some(ink_limit) = results.ink_limit {
+        println!("{}: ink limit at {}", curve_name, ink_limit);
+    }
+    if let Some(gamma) = results.gamma {
+        println!("{}: measured gamma {:.2}", curve_name, gamma);
+    }
+    write_curve(output_dir, &curve_name, outputs, &results.curve, params)?;
+    if let Some(levels) = outputs.levels {
+        write_levels(output_dir, &curve_name, levels, &results.curve)?;
+    }
+    if outputs.response {
+        curve::save_curve_file(
+            &output_dir.join(format!("response{}.json", suffix)),
+            &in_output_domain(
+                curve::CurveFile::new(results.response_curve.clone()),
+                outputs,
+            ),
+        )?;
+    }
+    write_detected_lines(output_dir, &format!("lines{}", suffix), results)
+}
+
+// Writes the overlay of the grid lines analyze found as `name`.png, when it drew one for debugging
+fn write_detected_lines(
+    output_dir: &Path,
+    name: &str,
+    results: &analyze::AnalyzeResults,
+) -> anyhow::Result<()> {
+    if let Some(detected_lines) = &results.detected_lines {
+        detected_lines.save(output_dir.join(format!("{}.png", name)))?;
+    }
+    Ok(())
+}
+
+/* Writes `curve` into `output_dir` as `name` with the extension for the output format, a json
+ * curve recording the `params` it was analyzed with
+ */
+fn write_curve(
+    output_dir: &Path,
+    name: &str,
+    outputs: &AnalyzeOutputs,
+    curve: &Spline<f64, f64>,
+    params: &analyze::AnalyzeParams,
+) -> anyhow::Result<()> {
+    match outputs.format {
+        CurveFormat::Json => {
+            let curve_file = curve::CurveFile::new(curve.clone()).with_params(params);
+            curve::save_curve_file(
+                &output_dir.join(format!("{}.json", name)),
+                &in_output_domain(curve_file, outputs),
+            )?;
+        }
+        CurveFormat::Cal => {
+            let mut curve_file = fs::File::create(output_dir.join(format!("{}.cal", name)))?;
+            analyze::write_cal(&mut curve_file, curve)?;
+        }
+    }
+    Ok(())
+}
+
+// `curve_file` normalized to the 0.0 to 1.0 domain when `outputs` asks for it
+fn in_output_domain(curve_file: curve::CurveFile, outputs: &AnalyzeOutputs) -> curve::CurveFile {
+    if outputs.normalized {
+        curve_file.normalized()
+    } else {
+        curve_file
+    }
+}
+
+// Writes `curve` sampled at `levels` input levels into `output_dir` as `name`-levels
+fn write_levels(
+    output_dir: &Path,
+    name: &str,
+    (levels, format): (u32, quantize::LevelsFormat),
+    curve: &Spline<f64, f64>,
+) -> anyhow::Result<()> {
+    let table = quantize::level_table(curve, levels)?;
+    let mut levels_file =
+        fs::File::create(output_dir.join(format!("{}-levels.{}", name, format.extension())))?;
+    quantize::write_levels(&mut levels_file, &table, format)
+}
+
+fn verify(
+    curve_pathbuf: &PathBuf,
+    scan: Option<&PathBuf>,
+    tolerance: Option<f64>,
+    params: &analyze::AnalyzeParams,
+    debug: bool,
+) -> anyhow::Result<()> {
+    let curve = curve::load_curve(&fs::canonicalize(curve_pathbuf)?)?;
+
+    let response = match scan {
+        Some(scan) => {
+            let image = image::open(fs::canonicalize(scan)?)?;
+            Some(analyze::analyze(&image, params, debug)?.response)
+        }
+        None => None,
+    };
+
+    let results = verify::verify(&curve, response.as_deref())?;
+    if debug {
+        println!("step\tideal\tactual");
+        for (n, (ideal, actual)) in results.steps.iter().enumerate() {
+            println!("{}\t{}\t{}", n, ideal, actual);
+        }
+    }
+    println!("max error: {:.2}%", results.max_error());
+    println!("rms error: {:.2}%", results.rms_error());
+
+    if let Some(tolerance) = tolerance {
+        if results.max_error() > tolerance {
+            bail!(
+                "curve is {:.2}% from linear, more than the {}% tolerance",
+                results.max_error(),
+                tolerance
+            );
+        }
+    }
+    Ok(())
+}
+
+fn selftest(tolerance: f64, debug: bool) -> anyhow::Result<()> {
+    let results = selftest::selftest()?;
+    if debug {
+        println!("step\tideal\tactual");
+        for (n, (ideal, actual)) in results.steps.iter().enumerate() {
+            println!("{}\t{}\t{}", n, ideal, actual);
+        }
+    }
+    println!("max error: {:.2}%", results.max_error());
+    println!("rms error: {:.2}%", results.rms_error());
+
+    if results.max_error() > tolerance {
+        println!("FAIL");
+        bail!(
+            "corrected response is {:.2}% from linear, more than the {}% tolerance",
+            results.max_error(),
+            tolerance
+        );
+    }
+    println!("PASS");
+    Ok(())
+}
+
+fn report(
+    input: &PathBuf,
+    output: &Path,
+    process: Option<&str>,
+    notes: Option<&str>,
+    params: &analyze::AnalyzeParams,
+    debug: bool,
+) -> anyhow::Result<()> {
+    let image = image::open(fs::canonicalize(input)?)?;
+    let analyze_results = analyze::analyze(&image, params, debug)?;
+    let page = report::render(&image, &analyze_results, process, notes)?;
+    report::save(&page, output)
+}
+
+fn pack(
+    input: &PathBuf,
+    output: &Path,
+    process: Option<&str>,
+    notes: Option<&str>,
+    params: &analyze::AnalyzeParams,
+    debug: bool,
+) -> anyhow::Result<()> {
+    let input_file_path = fs::canonicalize(input)?;
+    let scan = fs::read(&input_file_path)?;
+    let image = image::open(&input_file_path)?;
+    let results = analyze::analyze(&image, params, debug)?;
+    let Some(scan_name) = input_file_path.file_name().and_then(|name| name.to_str()) else {
+        bail!("scan path isn't valid utf-8");
+    };
+
+    let mut archive = fs::File::create(output)?;
+    session::pack(
+        &mut archive,
+        &session::Session {
+            scan_name,
+            scan: &scan,
+            image: &image,
+            results: &results,
+            params,
+            process,
+            notes,
+        },
+    )
+}
+
+fn unpack(input: &PathBuf, output_dir: &Path) -> anyhow::Result<()> {
+    fs::create_dir_all(output_dir)?;
+    let manifest = session::unpack(&fs::read(input)?, output_dir)?;
+    println!(
+        "unpacked {}, {}, and {} into {}",
+        manifest.scan,
+        manifest.curve,
+        manifest.report,
+        output_dir.display()
+    );
+    Ok(())
+}
+
+fn quantize(input: &PathBuf, output: &Path, levels: u32, diffuse: bool) -> anyhow::Result<()> {
+    let curve = curve::load_curve(&fs::canonicalize(input)?)?;
+    let quantized = quantize::quantize(&curve, levels, diffuse)?;
+    curve::save_curve(output, &quantized)
+}
+
+fn export_lut(
+    input: &PathBuf,
+    output: &Path,
+    format: lut::LutFormat,
+    size: u32,
+) -> anyhow::Result<()> {
+    let curve = curve::load_curve(&fs::canonicalize(input)?)?;
+    let mut lut_file = fs::File::create(output)?;
+    lut::write_lut(&mut lut_file, &curve, size, format)
+}
+
+fn plot(
+    input: &PathBuf,
+    output: &Path,
+    format: PlotFormat,
+    scan: Option<&PathBuf>,
+    params: &analyze::AnalyzeParams,
+    debug: bool,
+) -> anyhow::Result<()> {
+    let curve = curve::load_curve(&fs::canonicalize(input)?)?;
+    let histogram = match scan {
+        Some(scan) => {
+            let image = image::open(fs::canonicalize(scan)?)?;
+            Some(analyze::analyze(&image, params, debug)?.histogram)
+        }
+        None => None,
+    };
+
+    match format {
+        PlotFormat::Image => {
+            let mut image: image::RgbImage = image::ImageBuffer::new(1024, 1024);
+            if let Some(histogram) = &histogram {
+                analyze::draw_histogram(&mut image, histogram, analyze::HISTOGRAM_BUCKETS)?;
+            }
+            analyze::draw_curve(&mut image, &curve)?;
+            image.save(output)?;
+        }
+        PlotFormat::Svg => {
+            let mut svg_file = fs::File::create(output)?;
+            svg::write_plot(&mut svg_file, &curve, histogram.as_deref())?;
+        }
+    }
     Ok(())
 }
 
@@ -97,12 +677,103 @@ fn generate(
     output_path: &PathBuf,
     process: Option<String>,
     notes: Option<String>,
+    layout: Layout,
+    manifest_path: Option<&PathBuf>,
+    pdf_dpi: Option<f32>,
+    params: &generate::GenerateParams,
 ) -> anyhow::Result<()> {
-    let image = generate::generate(process, notes)?;
-    image.save(output_path)?;
+    let (below, above) = generate::clamped_patches(layout, params)?;
+    if below + above > 0 {
+        eprintln!(
+            "warning: {} patches below and {} above the printable range are clamped to its \
+             ends, and will print the same as the patches there",
+            below, above
+        );
+    }
+    if params.optimize_for_range.is_some() {
+        println!(
+            "drew {} steps, analyze the print with --steps {}",
+            params.steps(),
+            params.steps()
+        );
+    }
+    let image = generate::generate_with_params(process, notes, layout, params)?;
+    match pdf_dpi {
+        None => image.save(output_path)?,
+        Some(dpi) => {
+            if dpi.is_nan() || dpi <= 0. {
+                bail!("dpi must be above 0, got {}", dpi);
+            }
+            let mut pdf_file = fs::File::create(output_path)?;
+            pdf::write_image_at_dpi(&mut pdf_file, &image, dpi)?;
+        }
+    }
+    if let Some(manifest_path) = manifest_path {
+        let manifest_file = fs::File::create(manifest_path)?;
+        serde_json::to_writer_pretty(&manifest_file, &generate::manifest(layout, params)?)?;
+    }
     Ok(())
 }
 
+fn default_dpi() -> f32 {
+    300.
+}
+
+// One wedge of a `generate-batch` spec, with the same options and defaults as `generate`
+#[derive(Debug, serde::Deserialize)]
+struct BatchWedge {
+    output: PathBuf,
+    #[serde(default)]
+    process: Option<String>,
+    #[serde(default)]
+    notes: Option<String>,
+    #[serde(default)]
+    layout: Layout,
+    #[serde(default)]
+    manifest: Option<PathBuf>,
+    #[serde(default)]
+    format: WedgeFormat,
+    #[serde(default = "default_dpi")]
+    dpi: f32,
+    #[serde(flatten)]
+    params: generate::GenerateParams,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct BatchSpec {
+    wedges: Vec<BatchWedge>,
+}
+
+/* Generates every wedge in the spec at `spec_path`, returning how many were written
+ *
+ * Relative output and manifest paths are taken from the spec's directory, so a spec and the
+ * wedges it describes can be moved around together.
+ */
+fn generate_batch(spec_path: &Path) -> anyhow::Result<usize> {
+    let spec: BatchSpec = serde_json::from_reader(fs::File::open(spec_path)?)
+        .with_context(|| format!("reading spec {}", spec_path.display()))?;
+    let base = spec_path.parent().unwrap_or(Path::new(""));
+    for (i, wedge) in spec.wedges.iter().enumerate() {
+        let output = base.join(&wedge.output);
+        wedge
+            .params
+            .validate()
+            .and_then(|()| {
+                generate(
+                    &output,
+                    wedge.process.clone(),
+                    wedge.notes.clone(),
+                    wedge.layout,
+                    wedge.manifest.as_ref().map(|path| base.join(path)).as_ref(),
+                    matches!(wedge.format, WedgeFormat::Pdf).then_some(wedge.dpi),
+                    &wedge.params,
+                )
+            })
+            .with_context(|| format!("generating wedge {}, {}", i + 1, output.display()))?;
+    }
+    Ok(spec.wedges.len())
+}
+
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
@@ -110,27 +781,214 @@ fn main() -> anyhow::Result<()> {
         Commands::Analyze {
             input,
             output_dir,
-            invert,
+            format,
+            output_levels,
+            levels_format,
+            response,
+            normalized,
+            regions,
+            layout_from,
+            params_file,
+            params,
         } => {
-            analyze(&input, &output_dir, *invert, args.debug)?;
+            let mut params = match params_file {
+                Some(path) => curve::load_params(path)?,
+                None => params.clone(),
+            };
+            if let Some(layout_from) = layout_from {
+                let step_description = recover::recover_layout(&image::open(layout_from)?)?;
+                params.steps = step_description.count;
+                params.columns = step_description.columns;
+                params.rows = Some(step_description.rows);
+                params.layout = step_description.layout;
+            }
+            analyze(
+                input,
+                output_dir,
+                &AnalyzeOutputs {
+                    format: *format,
+                    levels: output_levels.map(|levels| (levels, *levels_format)),
+                    response: *response,
+                    normalized: *normalized,
+                },
+                regions.as_ref(),
+                &params,
+                args.debug,
+            )?;
         }
         Commands::Generate {
             process,
             notes,
             output,
+            layout,
+            manifest,
+            format,
+            dpi,
+            params,
         } => {
-            generate(output, process.clone(), notes.clone())?;
+            generate(
+                output,
+                process.clone(),
+                notes.clone(),
+                *layout,
+                manifest.as_ref(),
+                matches!(format, WedgeFormat::Pdf).then_some(*dpi),
+                params,
+            )?;
+        }
+        Commands::GenerateBatch { spec } => {
+            let written = generate_batch(spec)?;
+            println!("generated {} wedges", written);
         }
         Commands::Apply {
             input,
             output,
             curve,
+            params,
         } => {
-            apply(input, curve, output, args.debug)?;
+            apply(input, curve, output, params, args.debug)?;
+        }
+        Commands::Report {
+            input,
+            output,
+            process,
+            notes,
+            params,
+        } => {
+            report(
+                input,
+                output,
+                process.as_deref(),
+                notes.as_deref(),
+                params,
+                args.debug,
+            )?;
+        }
+        Commands::Pack {
+            input,
+            output,
+            process,
+            notes,
+            params,
+        } => {
+            pack(
+                input,
+                output,
+                process.as_deref(),
+                notes.as_deref(),
+                params,
+                args.debug,
+            )?;
+        }
+        Commands::Unpack { input, output_dir } => {
+            unpack(input, output_dir)?;
+        }
+        Commands::Quantize {
+            input,
+            output,
+            levels,
+            diffuse,
+        } => {
+            quantize(input, output, *levels, *diffuse)?;
+        }
+        Commands::ExportLut {
+            input,
+            output,
+            format,
+            size,
+        } => {
+            export_lut(input, output, *format, *size)?;
+        }
+        Commands::Verify {
+            curve,
+            scan,
+            tolerance,
+            params,
+        } => {
+            verify(curve, scan.as_ref(), *tolerance, params, args.debug)?;
+        }
+        Commands::Selftest { tolerance } => {
+            selftest(*tolerance, args.debug)?;
+        }
+        Commands::Plot {
+            input,
+            output,
+            format,
+            scan,
+            params,
+        } => {
+            plot(input, output, *format, scan.as_ref(), params, args.debug)?;
         }
         Commands::Gui {} => {
             gui::start(args.debug);
         }
+        Commands::Version {} => {
+            println!("curved {}", version::LONG_VERSION);
+        }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_args() {
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn test_generate_batch() {
+        let dir = std::env::temp_dir().join("curved-test-generate-batch");
+        fs::create_dir_all(&dir).unwrap();
+        let spec = dir.join("spec.json");
+        fs::write(
+            &spec,
+            r#"{"wedges": [
+                {"output": "standard.png"},
+                {"output": "strip.png", "columns": 101, "manifest": "strip.json"},
+                {"output": "short.png", "optimize_for_range": 3, "layout": "Separators"}
+            ]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(generate_batch(&spec).unwrap(), 3);
+        let dimensions = |name: &str| image::image_dimensions(dir.join(name)).unwrap();
+        let standard = generate::generate(None, None, Layout::Grid).unwrap();
+        assert_eq!(
+            dimensions("standard.png"),
+            (standard.width(), standard.height())
+        );
+        // a single strip is wider than it is tall
+        let (width, height) = dimensions("strip.png");
+        assert!(width > standard.width() && height < width);
+        assert!(dir.join("strip.json").exists());
+        // 3 stops only needs 19 steps, two rows of them
+        let (_, height) = dimensions("short.png");
+        assert!(height < standard.height());
+
+        // a bad entry is reported by its place in the spec
+        fs::write(&spec, r#"{"wedges": [{"output": "bad.png", "rows": 1}]}"#).unwrap();
+        let error = generate_batch(&spec).unwrap_err();
+        assert!(format!("{:#}", error).contains("wedge 1"));
+        // as is one out of the range the command line allows, before anything is drawn
+        for bad in [
+            r#"{"output": "bad.png", "columns": 0}"#,
+            r#"{"output": "bad.png", "grid_thickness": 500}"#,
+            r#"{"output": "bad.png", "optimize_for_range": -3}"#,
+        ] {
+            fs::write(
+                &spec,
+                format!(r#"{{"wedges": [{{"output": "fine.png"}}, {}]}}"#, bad),
+            )
+            .unwrap();
+            let error = format!("{:#}", generate_batch(&spec).unwrap_err());
+            assert!(error.contains("wedge 2"), "{}", error);
+        }
+        assert!(!dir.join("bad.png").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
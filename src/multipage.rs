@@ -0,0 +1,139 @@
+use std::io::{Read, Seek, Write};
+
+use anyhow::{anyhow, Result};
+use image::{DynamicImage, ImageBuffer};
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::encoder::{colortype, TiffEncoder};
+use tiff::ColorType;
+
+/* image only ever reads the first page of a tiff, scanned batches can come as a single tiff
+ * holding a page per scan. These read and write every page.
+ */
+
+// Whether the tiff in `reader` holds more than one page
+pub fn is_multipage(reader: impl Read + Seek) -> Result<bool> {
+    Ok(Decoder::new(reader)?.more_images())
+}
+
+// Every page of the tiff in `reader`, in order
+pub fn read_pages(reader: impl Read + Seek) -> Result<Vec<DynamicImage>> {
+    let mut decoder = Decoder::new(reader)?;
+    let mut pages = vec![read_page(&mut decoder)?];
+    while decoder.more_images() {
+        decoder.next_image()?;
+        pages.push(read_page(&mut decoder)?);
+    }
+    Ok(pages)
+}
+
+fn read_page<R: Read + Seek>(decoder: &mut Decoder<R>) -> Result<DynamicImage> {
+    let (width, height) = decoder.dimensions()?;
+    let color_type = decoder.colortype()?;
+    decoded_image(color_type, width, height, decoder.read_image()?)
+}
+
+// The `width` by `height` image held by `data`, decoded from a tiff of `color_type`
+pub fn decoded_image(
+    color_type: ColorType,
+    width: u32,
+    height: u32,
+    data: DecodingResult,
+) -> Result<DynamicImage> {
+    let too_small = || anyhow!("tiff page is smaller than its {}x{} size", width, height);
+
+    Ok(match (color_type, data) {
+        (ColorType::Gray(8), DecodingResult::U8(data)) => DynamicImage::ImageLuma8(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(too_small)?,
+        ),
+        (ColorType::Gray(16), DecodingResult::U16(data)) => DynamicImage::ImageLuma16(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(too_small)?,
+        ),
+        (ColorType::RGB(8), DecodingResult::U8(data)) => DynamicImage::ImageRgb8(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(too_small)?,
+        ),
+        (ColorType::RGB(16), DecodingResult::U16(data)) => DynamicImage::ImageRgb16(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(too_small)?,
+        ),
+        (ColorType::RGBA(8), DecodingResult::U8(data)) => DynamicImage::ImageRgba8(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(too_small)?,
+        ),
+        (ColorType::RGBA(16), DecodingResult::U16(data)) => DynamicImage::ImageRgba16(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(too_small)?,
+        ),
+        (color_type, _) => return Err(anyhow!("can't read {:?} tiff pages", color_type)),
+    })
+}
+
+/* Writes `pages` out as a single tiff, a page each
+ *
+ * Greyscale and RGB pages keep their layout and bit depth, anything else is written as RGBA.
+ */
+pub fn write_pages(writer: impl Write + Seek, pages: &[DynamicImage]) -> Result<()> {
+    let mut encoder = TiffEncoder::new(writer)?;
+    for page in pages {
+        let (width, height) = (page.width(), page.height());
+        match page {
+            DynamicImage::ImageLuma8(buffer) => {
+                encoder.write_image::<colortype::Gray8>(width, height, buffer)?
+            }
+            DynamicImage::ImageLuma16(buffer) => {
+                encoder.write_image::<colortype::Gray16>(width, height, buffer)?
+            }
+            DynamicImage::ImageRgb8(buffer) => {
+                encoder.write_image::<colortype::RGB8>(width, height, buffer)?
+            }
+            DynamicImage::ImageRgb16(buffer) => {
+                encoder.write_image::<colortype::RGB16>(width, height, buffer)?
+            }
+            DynamicImage::ImageRgba8(buffer) => {
+                encoder.write_image::<colortype::RGBA8>(width, height, buffer)?
+            }
+            _ => encoder.write_image::<colortype::RGBA16>(width, height, &page.to_rgba16())?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apply;
+    use crate::curve::CURVE_DOMAIN_MAX;
+    use crate::luma::LumaWeights;
+    use image::Luma;
+    use splines::{Interpolation, Key, Spline};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_apply_to_every_page() {
+        let pages = [1000, 20000]
+            .map(|tone| DynamicImage::ImageLuma16(ImageBuffer::from_pixel(4, 3, Luma([tone]))));
+        let mut tiff = Cursor::new(Vec::new());
+        write_pages(&mut tiff, &pages).unwrap();
+        tiff.set_position(0);
+        assert!(is_multipage(&mut tiff).unwrap());
+        tiff.set_position(0);
+
+        let inverting = Spline::from_vec(vec![
+            Key::new(0., CURVE_DOMAIN_MAX, Interpolation::Linear),
+            Key::new(CURVE_DOMAIN_MAX, 0., Interpolation::Linear),
+        ]);
+        let corrected: Vec<DynamicImage> = read_pages(&mut tiff)
+            .unwrap()
+            .iter()
+            .map(|page| apply::apply(page, &inverting, LumaWeights::default()).unwrap())
+            .collect();
+        let mut output = Cursor::new(Vec::new());
+        write_pages(&mut output, &corrected).unwrap();
+        output.set_position(0);
+
+        let read_back = read_pages(&mut output).unwrap();
+        assert_eq!(read_back.len(), 2);
+        for (page, tone) in read_back.iter().zip([1000, 20000]) {
+            assert_eq!(
+                page.as_luma16().unwrap().get_pixel(3, 2)[0],
+                u16::MAX - tone
+            );
+        }
+    }
+}
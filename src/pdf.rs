@@ -0,0 +1,105 @@
+use std::io::Write;
+
+use anyhow::Result;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use image::DynamicImage;
+use pdf_writer::{Content, Filter, Finish, Name, Pdf, Ref};
+
+// pdf pages are measured in points, 72 to the inch
+pub const POINTS_PER_INCH: f32 = 72.;
+
+/* Wraps `image` up as a single page pdf, stretched to fill a page of `page_size` points
+ *
+ * 16bit greyscale images are embedded as 16bit greyscale so none of their tones are lost, 8bit
+ * greyscale as 8bit greyscale, and anything else as 8bit RGB.
+ */
+pub fn write_image_page(
+    writer: &mut impl Write,
+    image: &DynamicImage,
+    page_size: (f32, f32),
+) -> Result<()> {
+    let catalog_id = Ref::new(1);
+    let page_tree_id = Ref::new(2);
+    let page_id = Ref::new(3);
+    let image_id = Ref::new(4);
+    let content_id = Ref::new(5);
+    let image_name = Name(b"Page");
+
+    let mut pdf = Pdf::new();
+    pdf.catalog(catalog_id).pages(page_tree_id);
+    pdf.pages(page_tree_id).kids([page_id]).count(1);
+
+    let mut pdf_page = pdf.page(page_id);
+    pdf_page.media_box(pdf_writer::Rect::new(0., 0., page_size.0, page_size.1));
+    pdf_page.parent(page_tree_id);
+    pdf_page.contents(content_id);
+    pdf_page.resources().x_objects().pair(image_name, image_id);
+    pdf_page.finish();
+
+    // pdf samples are big endian
+    let (samples, grey, bits) = match image {
+        DynamicImage::ImageLuma16(buffer) => (
+            buffer.iter().flat_map(|v| v.to_be_bytes()).collect(),
+            true,
+            16,
+        ),
+        DynamicImage::ImageLuma8(buffer) => (buffer.as_raw().clone(), true, 8),
+        _ => (image.to_rgb8().into_raw(), false, 8),
+    };
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&samples)?;
+    let data = encoder.finish()?;
+
+    let mut pdf_image = pdf.image_xobject(image_id, &data);
+    pdf_image.filter(Filter::FlateDecode);
+    pdf_image.width(image.width() as i32);
+    pdf_image.height(image.height() as i32);
+    if grey {
+        pdf_image.color_space().device_gray();
+    } else {
+        pdf_image.color_space().device_rgb();
+    }
+    pdf_image.bits_per_component(bits);
+    pdf_image.finish();
+
+    let mut content = Content::new();
+    content.save_state();
+    content.transform([page_size.0, 0., 0., page_size.1, 0., 0.]);
+    content.x_object(image_name);
+    content.restore_state();
+    pdf.stream(content_id, &content.finish());
+
+    writer.write_all(&pdf.finish())?;
+    Ok(())
+}
+
+/* Wraps `image` up as a pdf page exactly its physical size when printed at `dpi`, so printing the
+ * pdf at 100% reproduces the image pixel for pixel.
+ */
+pub fn write_image_at_dpi(writer: &mut impl Write, image: &DynamicImage, dpi: f32) -> Result<()> {
+    let page_size = (
+        image.width() as f32 / dpi * POINTS_PER_INCH,
+        image.height() as f32 / dpi * POINTS_PER_INCH,
+    );
+    write_image_page(writer, image, page_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Luma};
+
+    #[test]
+    fn test_write_image_at_dpi() {
+        // 3 by 2 inches at 300 dpi
+        let image = DynamicImage::ImageLuma16(ImageBuffer::from_pixel(900, 600, Luma([40000])));
+        let mut pdf = Vec::new();
+        write_image_at_dpi(&mut pdf, &image, 300.).unwrap();
+
+        let text = String::from_utf8_lossy(&pdf);
+        assert!(text.contains("/MediaBox [0 0 216 144]"), "{}", text);
+        assert!(text.contains("/BitsPerComponent 16"));
+        assert!(text.contains("/ColorSpace /DeviceGray"));
+    }
+}
@@ -0,0 +1,135 @@
+use std::io::Write;
+
+use anyhow::{anyhow, Result};
+use splines::{Interpolation, Key, Spline};
+
+use super::curve::sample_curve_lut;
+
+/* Reduces `curve` to a lookup table of exactly `levels` entries for devices that only take coarse
+ * curves, 256 entries for an 8bit target.
+ *
+ * Inputs are spaced evenly across the 16bit range and every output is rounded onto the same grid
+ * of `levels` tones, so the result stays a curve apply can use directly. Rounding each entry on
+ * its own can leave long runs that all round the same way, with `diffuse` the rounding error of
+ * each entry is carried into the next so the error averages out along the curve instead of
+ * piling up into visible bands.
+ */
+pub fn quantize(curve: &Spline<f64, f64>, levels: u32, diffuse: bool) -> Result<Spline<f64, f64>> {
+    if levels < 2 {
+        return Err(anyhow!("need at least 2 levels, got {}", levels));
+    }
+
+    let max = u16::MAX as f64;
+    let step = max / (levels - 1) as f64;
+    let mut error = 0.;
+    let mut keys = Vec::with_capacity(levels as usize);
+
+    for (input, sample) in sample_curve_lut(curve, levels as usize)? {
+        let target = sample / step + if diffuse { error } else { 0. };
+        let level = target.round().clamp(0., (levels - 1) as f64);
+        error = target - level;
+
+        keys.push(Key::new(input, level * step, Interpolation::Linear));
+    }
+
+    Ok(Spline::from_vec(keys))
+}
+
+/* `curve` in a device's own levels, the output level for each of `levels` evenly spaced input
+ * levels. Entry n is what input level n should be printed as, 0 to levels - 1.
+ */
+pub fn level_table(curve: &Spline<f64, f64>, levels: u32) -> Result<Vec<u32>> {
+    quantize(curve, levels, false).map(|quantized| {
+        let step = u16::MAX as f64 / (levels - 1) as f64;
+        quantized
+            .keys()
+            .iter()
+            .map(|key| (key.value / step).round() as u32)
+            .collect()
+    })
+}
+
+// How a table of levels is written out
+#[derive(Debug, Default, Clone, Copy, clap::ValueEnum)]
+pub enum LevelsFormat {
+    /// One "input, output" line per level
+    #[default]
+    Csv,
+    /// A json array of the output levels, indexed by input level
+    Json,
+}
+
+impl LevelsFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            LevelsFormat::Csv => "csv",
+            LevelsFormat::Json => "json",
+        }
+    }
+}
+
+pub fn write_levels(writer: &mut impl Write, table: &[u32], format: LevelsFormat) -> Result<()> {
+    match format {
+        LevelsFormat::Csv => {
+            for (input, output) in table.iter().enumerate() {
+                writeln!(writer, "{}, {}", input, output)?;
+            }
+        }
+        LevelsFormat::Json => serde_json::to_writer(writer, table)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a gentle s-curve, not something that lands on any grid by accident
+    fn s_curve() -> Spline<f64, f64> {
+        let max = u16::MAX as f64;
+        Spline::from_vec(vec![
+            Key::new(0., 0., Interpolation::CatmullRom),
+            Key::new(0., 0., Interpolation::CatmullRom),
+            Key::new(max * 0.25, max * 0.18, Interpolation::CatmullRom),
+            Key::new(max * 0.75, max * 0.82, Interpolation::CatmullRom),
+            Key::new(max, max, Interpolation::CatmullRom),
+            Key::new(max, max, Interpolation::CatmullRom),
+        ])
+    }
+
+    #[test]
+    fn test_quantize_levels() {
+        for diffuse in [false, true] {
+            let quantized = quantize(&s_curve(), 256, diffuse).unwrap();
+            let keys = quantized.keys();
+            assert_eq!(keys.len(), 256);
+
+            let step = u16::MAX as f64 / 255.;
+            for key in keys {
+                // every input and output sits exactly on one of the 256 levels
+                assert!((key.t / step - (key.t / step).round()).abs() < 1e-9);
+                assert!((key.value / step - (key.value / step).round()).abs() < 1e-9);
+            }
+        }
+
+        assert!(quantize(&s_curve(), 1, false).is_err());
+    }
+
+    #[test]
+    fn test_level_table() {
+        for levels in [2, 17, 256, 1024] {
+            let table = level_table(&s_curve(), levels).unwrap();
+            assert_eq!(table.len(), levels as usize);
+            assert_eq!(table[0], 0);
+            assert_eq!(table[levels as usize - 1], levels - 1);
+        }
+
+        let table = level_table(&s_curve(), 4).unwrap();
+        let mut csv = Vec::new();
+        write_levels(&mut csv, &table, LevelsFormat::Csv).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap().lines().count(), 4);
+        let mut json = Vec::new();
+        write_levels(&mut json, &table, LevelsFormat::Json).unwrap();
+        assert_eq!(serde_json::from_slice::<Vec<u32>>(&json).unwrap(), table);
+    }
+}
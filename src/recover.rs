@@ -0,0 +1,87 @@
+use anyhow::{anyhow, Result};
+use image::{DynamicImage, ImageBuffer, Luma};
+
+use super::generate::MARGIN;
+use super::step_description::{Layout, StepDescription};
+
+type Gray16Image = ImageBuffer<Luma<u16>, Vec<u16>>;
+
+// patches smaller than this can't hold their label, no wedge is generated with them
+const MIN_SQUARE_SIZE: u32 = 8;
+
+/* Works out the StepDescription a clean generated wedge was drawn from, by measuring its patches
+ *
+ * The image has to be the wedge exactly as `generate` wrote it, margin and all, not a print or a
+ * scan. Every layout of patches that fits the image is tried, fewest columns first, and the first
+ * one whose patches all hold the tones it would have drawn is the one returned.
+ */
+pub fn recover_layout(image: &DynamicImage) -> Result<StepDescription> {
+    let image = image.to_luma16();
+    let no_layout = || anyhow!("couldn't find a generated wedge in the image");
+    if image.width() <= 2 * MARGIN || image.height() <= 2 * MARGIN {
+        return Err(no_layout());
+    }
+    let width = image.width() - 2 * MARGIN;
+    let total_height = image.height() - 2 * MARGIN;
+
+    // the left edge of the first patch is a mid grey grid line, or a white separator
+    let layout = match image.get_pixel(MARGIN, MARGIN + 2)[0] {
+        u16::MAX => Layout::Separators,
+        _ => Layout::Grid,
+    };
+
+    for columns in 1..=width / MIN_SQUARE_SIZE {
+        let square_size = width.div_ceil(columns);
+        // the caption can add a strip below the grid but the grid is never shorter than the image
+        let max_rows = total_height / square_size;
+        for count in 2..=max_rows * columns {
+            let step_description =
+                StepDescription::new(count, columns, width, u16::MAX as u32).with_layout(layout);
+            if step_description.total_height() == total_height
+                && patches_match(&image, &step_description)
+            {
+                return Ok(step_description);
+            }
+        }
+    }
+    Err(no_layout())
+}
+
+/* Whether every patch `step_description` describes holds the tone generate fills it with
+ *
+ * Each patch is checked toward its bottom right, away from the label in its top left corner and
+ * the grid lines or separators along its edges.
+ */
+fn patches_match(image: &Gray16Image, step_description: &StepDescription) -> bool {
+    let offset = step_description.square_size * 3 / 4;
+    (0..step_description.count).all(|n| {
+        let (col, row) = step_description.cell(n);
+        let x = MARGIN + col * step_description.square_size + offset;
+        let y = MARGIN + row * step_description.square_size + offset;
+        x < image.width()
+            && y < image.height()
+            && image.get_pixel(x, y)[0] == step_description.tone(n)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate;
+
+    #[test]
+    fn test_recover_layout() {
+        for layout in [Layout::Grid, Layout::Separators] {
+            let wedge = generate::generate(Some("cyanotype".to_string()), None, layout).unwrap();
+            let recovered = recover_layout(&wedge).unwrap();
+            let expected = StepDescription::default();
+            assert_eq!(recovered.count, expected.count);
+            assert_eq!(recovered.columns, expected.columns);
+            assert_eq!(recovered.width, expected.width);
+            assert_eq!(recovered.input_values(), expected.input_values());
+            assert_eq!(recovered.layout, layout);
+        }
+
+        assert!(recover_layout(&DynamicImage::new_luma16(1020, 1120)).is_err());
+    }
+}
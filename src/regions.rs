@@ -0,0 +1,187 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use image::{DynamicImage, Rgba, RgbaImage};
+use imageproc::drawing::draw_hollow_rect_mut;
+use imageproc::rect::Rect;
+
+use super::analyze::{self, AnalyzeParams, AnalyzeResults};
+use super::cancel::Cancel;
+
+/* One wedge on a sheet carrying several, the pixel rectangle it occupies in the scan
+ *
+ * The name labels the curve analyzed from the region and ends up in its file name, so it's kept to
+ * letters, digits, '-' and '_'.
+ */
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Region {
+    pub name: String,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    pub fn validate(&self) -> Result<()> {
+        if self.name.is_empty()
+            || !self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(anyhow!(
+                "region name {:?} must be letters, digits, '-' or '_'",
+                self.name
+            ));
+        }
+        if self.width == 0 || self.height == 0 {
+            return Err(anyhow!("region {} is empty", self.name));
+        }
+        Ok(())
+    }
+
+    // The part of `image` covered by the region, an error if the region runs off the image
+    pub fn crop(&self, image: &DynamicImage) -> Result<DynamicImage> {
+        if self.x + self.width > image.width() || self.y + self.height > image.height() {
+            return Err(anyhow!(
+                "region {} ({}x{} at {},{}) doesn't fit in the {}x{} scan",
+                self.name,
+                self.width,
+                self.height,
+                self.x,
+                self.y,
+                image.width(),
+                image.height()
+            ));
+        }
+        Ok(image.crop_imm(self.x, self.y, self.width, self.height))
+    }
+}
+
+/* Reads a json list of regions, e.g.
+ *
+ *   [{"name": "cyanotype", "x": 0, "y": 0, "width": 1020, "height": 1120}, ...]
+ */
+pub fn load_regions(path: &Path) -> Result<Vec<Region>> {
+    let data = fs::read_to_string(path)?;
+    let regions: Vec<Region> = serde_json::from_str(&data)?;
+    validate_all(&regions)?;
+    Ok(regions)
+}
+
+// Every region must be valid on its own and named differently from the rest
+pub fn validate_all(regions: &[Region]) -> Result<()> {
+    if regions.is_empty() {
+        return Err(anyhow!("no regions given"));
+    }
+    for (i, region) in regions.iter().enumerate() {
+        region.validate()?;
+        if regions[..i].iter().any(|other| other.name == region.name) {
+            return Err(anyhow!("more than one region named {}", region.name));
+        }
+    }
+    Ok(())
+}
+
+/* Analyzes each region of `image` as its own wedge, returning each region's name with its results.
+ *
+ * Every region is analyzed with the same params, a failure in any one region fails the lot.
+ */
+pub fn analyze_regions(
+    image: &DynamicImage,
+    regions: &[Region],
+    params: &AnalyzeParams,
+    debug: bool,
+    cancel: &Cancel,
+) -> Result<Vec<(String, AnalyzeResults)>> {
+    validate_all(regions)?;
+    regions
+        .iter()
+        .map(|region| {
+            if debug {
+                println!("analyzing region: {}", region.name);
+            }
+            let results = analyze::analyze_cancellable(&region.crop(image)?, params, debug, cancel)
+                .with_context(|| format!("analyzing region {}", region.name))?;
+            Ok((region.name.clone(), results))
+        })
+        .collect()
+}
+
+// Outlines each region on `image`, for checking the regions line up with the scan
+pub fn draw_regions(image: &mut RgbaImage, regions: &[Region]) {
+    let red = Rgba([255, 0, 0, 255]);
+    for region in regions.iter().filter(|region| region.validate().is_ok()) {
+        // a couple of pixels thick so it stays visible on a scaled down preview
+        for inset in 0..3 {
+            if region.width <= 2 * inset || region.height <= 2 * inset {
+                break;
+            }
+            draw_hollow_rect_mut(
+                image,
+                Rect::at((region.x + inset) as i32, (region.y + inset) as i32)
+                    .of_size(region.width - 2 * inset, region.height - 2 * inset),
+                red,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::imageops;
+
+    fn region(name: &str, x: u32, y: u32) -> Region {
+        Region {
+            name: name.to_string(),
+            x,
+            y,
+            width: 1000,
+            height: 1100,
+        }
+    }
+
+    #[test]
+    fn test_validate_regions() {
+        assert!(validate_all(&[region("a", 0, 0), region("b", 0, 0)]).is_ok());
+        assert!(validate_all(&[region("a", 0, 0), region("a", 0, 0)]).is_err());
+        assert!(validate_all(&[region("../a", 0, 0)]).is_err());
+        assert!(validate_all(&[]).is_err());
+    }
+
+    #[test]
+    fn test_analyze_regions() {
+        // two wedges side by side, the second inverted
+        let wedge = analyze::test_wedge().0.to_luma16();
+        let mut inverted = wedge.clone();
+        imageops::invert(&mut inverted);
+        let mut sheet = image::ImageBuffer::from_pixel(2000, 1100, image::Luma([u16::MAX]));
+        imageops::overlay(&mut sheet, &wedge, 0, 0);
+        imageops::overlay(&mut sheet, &inverted, 1000, 0);
+
+        let results = analyze_regions(
+            &DynamicImage::ImageLuma16(sheet),
+            &[region("plain", 0, 0), region("inverted", 1000, 0)],
+            &AnalyzeParams::default(),
+            false,
+            &Cancel::default(),
+        )
+        .unwrap();
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, "plain");
+        assert_eq!(results[1].0, "inverted");
+        // the two curves run in opposite directions
+        let first = &results[0].1.response;
+        let second = &results[1].1.response;
+        let rising = |response: &[(u16, u16)]| response.last().unwrap().1 > response[0].1;
+        assert_ne!(rising(first), rising(second));
+
+        assert!(region("off", 1500, 0)
+            .crop(&DynamicImage::new_luma8(2000, 1100))
+            .is_err());
+    }
+}
@@ -0,0 +1,268 @@
+use std::io::Write;
+use std::path::Path;
+
+use ab_glyph::FontRef;
+use anyhow::Result;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, ImageBuffer, Rgb, RgbImage};
+use imageproc::drawing::{draw_hollow_rect_mut, draw_text_mut};
+use imageproc::rect::Rect;
+
+use super::analyze::{self, AnalyzeResults};
+use super::curve::sample_curve;
+use super::generate::{self, LATO_BLACK_BYTES};
+use super::pdf;
+
+// A letter sized page at 200 dpi
+const PAGE_WIDTH: u32 = 1700;
+const PAGE_HEIGHT: u32 = 2200;
+const PAGE_MARGIN: u32 = 80;
+// letter in points, the unit pdf pages are measured in
+const PDF_PAGE_SIZE: (f32, f32) = (612., 792.);
+
+const THUMBNAIL_SIZE: u32 = 620;
+const PLOT_SIZE: u32 = 860;
+const TABLE_ROWS: usize = 34;
+
+const BLACK: Rgb<u8> = Rgb([0, 0, 0]);
+const GREY: Rgb<u8> = Rgb([110, 110, 110]);
+
+// Numbers summarizing how well behaved a scan was
+pub struct Metrics {
+    // mean standard deviation of the sampled squares, as a percentage of the tonal range
+    pub mean_noise: f64,
+    // furthest any measured step was from a straight ramp, as a percentage of the tonal range
+    pub max_nonlinearity: f64,
+    // how many steps went the opposite way to the ramp as a whole
+    pub reversals: usize,
+}
+
+impl Metrics {
+    pub fn new(results: &AnalyzeResults) -> Self {
+        let max = u16::MAX as f64;
+        let mean_noise = results.uncertainty.iter().map(|(_, d)| d).sum::<f64>()
+            / results.uncertainty.len().max(1) as f64
+            / max
+            * 100.;
+        // depending on inversion the measured tones can run either way, compare them against a
+        // ramp running the same way
+        let rising = match (results.response.first(), results.response.last()) {
+            (Some(first), Some(last)) => last.1 >= first.1,
+            _ => true,
+        };
+        let max_nonlinearity = results
+            .response
+            .iter()
+            .map(|(input, measured)| {
+                let expected = if rising { *input } else { u16::MAX - input };
+                (expected as f64 - *measured as f64).abs() / max * 100.
+            })
+            .fold(0., f64::max);
+        let reversals = results
+            .response
+            .windows(2)
+            .filter(|pair| (pair[1].1 < pair[0].1) == rising && pair[1].1 != pair[0].1)
+            .count();
+
+        Self {
+            mean_noise,
+            max_nonlinearity,
+            reversals,
+        }
+    }
+}
+
+/* Lays out a single page documenting a calibration: process and notes, a thumbnail of the scan,
+ * the curve over the histogram, a few quality metrics, and the table of sampled steps.
+ */
+pub fn render(
+    scan: &DynamicImage,
+    results: &AnalyzeResults,
+    process: Option<&str>,
+    notes: Option<&str>,
+) -> Result<RgbImage> {
+    let font = FontRef::try_from_slice(LATO_BLACK_BYTES)?;
+    let mut page = ImageBuffer::from_pixel(PAGE_WIDTH, PAGE_HEIGHT, Rgb([255, 255, 255]));
+    let text_width = PAGE_WIDTH - 2 * PAGE_MARGIN;
+
+    let mut y = PAGE_MARGIN;
+    draw_text_mut(
+        &mut page,
+        BLACK,
+        PAGE_MARGIN as i32,
+        y as i32,
+        48.,
+        &font,
+        "Calibration report",
+    );
+    y += 70;
+    for (label, text) in [("Process", process), ("Notes", notes)] {
+        let text = format!("{}: {}", label, text.unwrap_or("-"));
+        for line in generate::wrap_text(&font, 28, &text, text_width)
+            .iter()
+            .take(2)
+        {
+            draw_text_mut(
+                &mut page,
+                BLACK,
+                PAGE_MARGIN as i32,
+                y as i32,
+                28.,
+                &font,
+                line,
+            );
+            y += 36;
+        }
+    }
+
+    // the scan on the left, centered in its box
+    let figures_y = 300;
+    let thumbnail = scan.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgb8();
+    let thumbnail_x = PAGE_MARGIN + (THUMBNAIL_SIZE - thumbnail.width()) / 2;
+    let thumbnail_y = figures_y + (THUMBNAIL_SIZE - thumbnail.height()) / 2;
+    imageops::overlay(
+        &mut page,
+        &thumbnail,
+        thumbnail_x as i64,
+        thumbnail_y as i64,
+    );
+
+    // the curve over its histogram on the right
+    let mut plot: RgbImage = ImageBuffer::new(1024, 1024);
+    if let Some(rgb_histograms) = &results.rgb_histograms {
+        analyze::draw_histograms_rgb(&mut plot, rgb_histograms, analyze::HISTOGRAM_BUCKETS)?;
+    } else {
+        analyze::draw_histogram(&mut plot, &results.histogram, analyze::HISTOGRAM_BUCKETS)?;
+    }
+    analyze::draw_curve(&mut plot, &results.curve)?;
+    let plot = imageops::resize(&plot, PLOT_SIZE, PLOT_SIZE, FilterType::Triangle);
+    let plot_x = PAGE_WIDTH - PAGE_MARGIN - PLOT_SIZE;
+    imageops::overlay(&mut page, &plot, plot_x as i64, figures_y as i64);
+    draw_hollow_rect_mut(
+        &mut page,
+        Rect::at(plot_x as i32, figures_y as i32).of_size(PLOT_SIZE, PLOT_SIZE),
+        GREY,
+    );
+
+    let metrics = Metrics::new(results);
+    let mut y = figures_y + PLOT_SIZE + 40;
+    let mut lines = vec![
+        format!("Mean sample noise: {:.2}%", metrics.mean_noise),
+        format!(
+            "Furthest step from linear before correction: {:.2}%",
+            metrics.max_nonlinearity
+        ),
+        format!("Steps darker than the step before: {}", metrics.reversals),
+        format!(
+            "Stretched from {} to {} (samples {} to {}){}",
+            results.normalization.black,
+            results.normalization.white,
+            results.normalization.sample_min,
+            results.normalization.sample_max,
+            if results.normalization.inverted {
+                ", read as a negative"
+            } else {
+                ""
+            }
+        ),
+    ];
+    if let Some(gamma) = results.gamma {
+        lines.push(format!("Measured gamma of the process: {:.2}", gamma));
+    }
+    if let Some(ink_limit) = results.ink_limit {
+        lines.push(format!(
+            "Ink limit at {}, the curve is capped there",
+            ink_limit
+        ));
+    }
+    for line in lines {
+        draw_text_mut(
+            &mut page,
+            BLACK,
+            PAGE_MARGIN as i32,
+            y as i32,
+            26.,
+            &font,
+            &line,
+        );
+        y += 34;
+    }
+
+    draw_sample_table(&mut page, &font, results, y + 30)?;
+    Ok(page)
+}
+
+// Every step's printed tone, what was measured, and what the curve maps it to, in columns
+fn draw_sample_table(
+    page: &mut RgbImage,
+    font: &FontRef,
+    results: &AnalyzeResults,
+    top: u32,
+) -> Result<()> {
+    let column_width = (PAGE_WIDTH - 2 * PAGE_MARGIN) / 3;
+    let row_height = 22;
+    // x offset of each field within a column
+    let fields = [0, 70, 160, 270];
+
+    for (column, steps) in results.response.chunks(TABLE_ROWS).enumerate() {
+        let x = (PAGE_MARGIN + column as u32 * column_width) as i32;
+        for (offset, header) in fields.iter().zip(["step", "input", "measured", "curve"]) {
+            draw_text_mut(page, GREY, x + offset, top as i32, 18., font, header);
+        }
+
+        for (row, (input, measured)) in steps.iter().enumerate() {
+            let step = column * TABLE_ROWS + row;
+            let curve = sample_curve(&results.curve, *input as f64)?;
+            let y = (top + (row as u32 + 1) * row_height) as i32;
+            for (offset, text) in fields.iter().zip([
+                format!("{}", step),
+                format!("{}", input),
+                format!("{}", measured),
+                format!("{:.0}", curve),
+            ]) {
+                draw_text_mut(page, BLACK, x + offset, y, 18., font, &text);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Wraps a rendered page up as a single page pdf, the page fills a sheet of letter paper
+pub fn write_pdf(writer: &mut impl Write, page: &RgbImage) -> Result<()> {
+    pdf::write_image_page(
+        writer,
+        &DynamicImage::ImageRgb8(page.clone()),
+        PDF_PAGE_SIZE,
+    )
+}
+
+// Writes the report to `path`, as a pdf if it ends in .pdf otherwise as an image
+pub fn save(page: &RgbImage, path: &Path) -> Result<()> {
+    let is_pdf = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("pdf"));
+    if is_pdf {
+        let mut file = std::fs::File::create(path)?;
+        write_pdf(&mut file, page)
+    } else {
+        Ok(page.save(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_pdf() {
+        let page: RgbImage = ImageBuffer::from_pixel(17, 22, Rgb([255, 0, 0]));
+        let mut pdf = Vec::new();
+        write_pdf(&mut pdf, &page).unwrap();
+
+        assert!(pdf.starts_with(b"%PDF-"));
+        let text = String::from_utf8_lossy(&pdf);
+        assert!(text.contains("/Width 17"));
+        assert!(text.contains("/Height 22"));
+        assert!(text.trim_end().ends_with("%%EOF"));
+    }
+}
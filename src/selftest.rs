@@ -0,0 +1,68 @@
+use anyhow::Result;
+use image::{DynamicImage, Luma};
+use imageproc::map::map_pixels;
+
+use super::analyze::{self, AnalyzeParams};
+use super::apply;
+use super::generate::{self, MARGIN};
+use super::luma::LumaWeights;
+use super::step_description::{Layout, StepDescription};
+use super::verify::VerifyResults;
+
+// the response of the simulated process, darkening the mid tones like most printing does
+pub const SIMULATED_GAMMA: f64 = 2.2;
+
+/* Runs the whole calibration loop against a process whose response is known, to check curved
+ * itself rather than a print.
+ *
+ * A fresh wedge is pushed through `SIMULATED_GAMMA` and analyzed for a curve, then the curve is
+ * applied to another wedge which goes through the same process and is analyzed again. A working
+ * install measures that second response as linear, the distance from it is the residual error.
+ */
+pub fn selftest() -> Result<VerifyResults> {
+    let step_description = StepDescription::default();
+    // a print reads backwards from the negative it was made from
+    let params = AnalyzeParams {
+        invert: true,
+        ..AnalyzeParams::default()
+    };
+
+    let wedge = generate::generate(None, None, Layout::Grid)?;
+    let printed = simulate_process(&wedge, &step_description);
+    let curve = analyze::analyze(&printed, &params, false)?.curve;
+
+    let corrected = apply::apply(&wedge, &curve, LumaWeights::default())?;
+    let printed = simulate_process(&corrected, &step_description);
+    let response = analyze::analyze(&printed, &params, false)?.response;
+
+    Ok(VerifyResults { steps: response })
+}
+
+// what printing and scanning `wedge` would produce, trimmed to the steps like a scan would be
+fn simulate_process(wedge: &DynamicImage, step_description: &StepDescription) -> DynamicImage {
+    let printed = map_pixels(&wedge.to_luma16(), |_, _, p| {
+        let tone = p[0] as f64 / u16::MAX as f64;
+        Luma([(tone.powf(SIMULATED_GAMMA) * u16::MAX as f64).round() as u16])
+    });
+    DynamicImage::ImageLuma16(printed).crop_imm(
+        MARGIN,
+        MARGIN,
+        step_description.width,
+        step_description.total_height(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selftest() {
+        let results = selftest().unwrap();
+        assert_eq!(
+            results.steps.len(),
+            StepDescription::default().input_values().len()
+        );
+        assert!(results.max_error() < 2., "{}", results.max_error());
+    }
+}
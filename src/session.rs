@@ -0,0 +1,386 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use image::DynamicImage;
+
+use super::analyze::{AnalyzeParams, AnalyzeResults};
+use super::curve;
+use super::report;
+
+// Name of the manifest inside a session archive, it lists every other file
+const MANIFEST_NAME: &str = "manifest.json";
+
+const SESSION_VERSION: u32 = 1;
+const CURVE_NAME: &str = "curve.json";
+const REPORT_NAME: &str = "report.pdf";
+
+// (name, contents) of every file in an archive
+type Files = Vec<(String, Vec<u8>)>;
+
+/* What a session archive holds, and under which names
+ *
+ * The params are the ones the scan was analyzed with, analyzing the scan again with them gives
+ * back the curve.
+ */
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub scan: String,
+    pub curve: String,
+    pub report: String,
+    pub params: AnalyzeParams,
+    pub process: Option<String>,
+    pub notes: Option<String>,
+}
+
+// Everything that goes into a session archive
+pub struct Session<'a> {
+    // the scan's file name, its extension is kept so it opens as the same format again
+    pub scan_name: &'a str,
+    // the scan file exactly as it was read, and decoded
+    pub scan: &'a [u8],
+    pub image: &'a DynamicImage,
+    pub results: &'a AnalyzeResults,
+    pub params: &'a AnalyzeParams,
+    pub process: Option<&'a str>,
+    pub notes: Option<&'a str>,
+}
+
+/* Bundles a calibration into a `.curved` archive, a zip of the scan, the curve, a report, and a
+ * manifest recording the params and notes the scan was analyzed with
+ */
+pub fn pack(writer: &mut impl Write, session: &Session) -> Result<()> {
+    let scan_name = Path::new(session.scan_name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| format!("scan.{}", extension))
+        .ok_or(anyhow!("can't tell the format of {}", session.scan_name))?;
+    let manifest = Manifest {
+        version: SESSION_VERSION,
+        scan: scan_name.clone(),
+        curve: CURVE_NAME.to_string(),
+        report: REPORT_NAME.to_string(),
+        params: session.params.clone(),
+        process: session.process.map(str::to_string),
+        notes: session.notes.map(str::to_string),
+    };
+
+    let mut curve = Vec::new();
+    curve::write_curve(&mut curve, &session.results.curve)?;
+    let page = report::render(
+        session.image,
+        session.results,
+        session.process,
+        session.notes,
+    )?;
+    let mut report = Vec::new();
+    report::write_pdf(&mut report, &page)?;
+
+    writer.write_all(&write_zip(&[
+        (MANIFEST_NAME, &serde_json::to_vec_pretty(&manifest)?),
+        (&scan_name, session.scan),
+        (CURVE_NAME, &curve),
+        (REPORT_NAME, &report),
+    ])?)?;
+    Ok(())
+}
+
+/* Reads a session archive back into its manifest and files
+ *
+ * Fails unless every file the manifest names is in the archive.
+ */
+pub fn read(data: &[u8]) -> Result<(Manifest, Files)> {
+    let files = read_zip(data)?;
+    let find = |name: &str| {
+        files
+            .iter()
+            .find(|(file_name, _)| file_name == name)
+            .map(|(_, data)| data)
+            .ok_or(anyhow!("session archive is missing {}", name))
+    };
+
+    let manifest: Manifest = serde_json::from_slice(find(MANIFEST_NAME)?)?;
+    if manifest.version > SESSION_VERSION {
+        return Err(anyhow!(
+            "session archive is version {}, this version of curved reads up to {}",
+            manifest.version,
+            SESSION_VERSION
+        ));
+    }
+    for name in [&manifest.scan, &manifest.curve, &manifest.report] {
+        find(name)?;
+    }
+    Ok((manifest, files))
+}
+
+// Writes every file of a session archive into `output_dir`, returning its manifest
+pub fn unpack(data: &[u8], output_dir: &Path) -> Result<Manifest> {
+    let (manifest, files) = read(data)?;
+    for (name, data) in files {
+        fs::write(output_dir.join(name), data)?;
+    }
+    Ok(manifest)
+}
+
+// zip record signatures
+const LOCAL_HEADER: u32 = 0x04034b50;
+const CENTRAL_HEADER: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+const END_OF_CENTRAL_DIRECTORY_SIZE: usize = 22;
+
+// zip compression methods
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+// version 2.0 of the zip spec, the first with deflate
+const ZIP_VERSION: u16 = 20;
+// general purpose flag marking names as utf-8
+const UTF8_NAMES: u16 = 1 << 11;
+// 1980-01-01, the earliest date a zip can hold, so archives of the same files come out the same
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+/* The largest file a session holds, checked before anything is read or inflated. A 16bit
+ * greyscale scan of a whole 8x10 sheet at 2400dpi is a little under 1GB.
+ */
+const MAX_FILE_SIZE: usize = 1 << 30;
+
+/* Writes `files` as a zip archive, each deflated
+ *
+ * Only the bare minimum of the format is written, no zip64, so archives are limited to 4GB.
+ */
+fn write_zip(files: &[(&str, &[u8])]) -> Result<Vec<u8>> {
+    let mut archive = Vec::new();
+    let mut central_directory = Vec::new();
+
+    for (name, data) in files {
+        if data.len() > MAX_FILE_SIZE {
+            return Err(anyhow!(
+                "{} is {} bytes, more than a session holds",
+                name,
+                data.len()
+            ));
+        }
+        let mut crc = Crc::new();
+        crc.update(data);
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data)?;
+        let compressed = encoder.finish()?;
+
+        let offset = u32::try_from(archive.len())?;
+        let (compressed_size, size) =
+            (u32::try_from(compressed.len())?, u32::try_from(data.len())?);
+        let name_length = u16::try_from(name.len())?;
+
+        // the fields the local and central headers share
+        let mut common = Vec::new();
+        for field in [ZIP_VERSION, UTF8_NAMES, DEFLATED, 0, DOS_DATE] {
+            common.extend(field.to_le_bytes());
+        }
+        for field in [crc.sum(), compressed_size, size] {
+            common.extend(field.to_le_bytes());
+        }
+        common.extend(name_length.to_le_bytes());
+        // no extra field
+        common.extend(0u16.to_le_bytes());
+
+        archive.extend(LOCAL_HEADER.to_le_bytes());
+        archive.extend(&common);
+        archive.extend(name.as_bytes());
+        archive.extend(&compressed);
+
+        central_directory.extend(CENTRAL_HEADER.to_le_bytes());
+        // made by
+        central_directory.extend(ZIP_VERSION.to_le_bytes());
+        central_directory.extend(&common);
+        // comment length, disk, internal attributes
+        for field in [0u16, 0, 0] {
+            central_directory.extend(field.to_le_bytes());
+        }
+        // external attributes
+        central_directory.extend(0u32.to_le_bytes());
+        central_directory.extend(offset.to_le_bytes());
+        central_directory.extend(name.as_bytes());
+    }
+
+    let directory_offset = u32::try_from(archive.len())?;
+    let count = u16::try_from(files.len())?;
+    archive.extend(&central_directory);
+    archive.extend(END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+    // this disk, the disk the directory starts on, entries on this disk, entries in all
+    for field in [0, 0, count, count] {
+        archive.extend(field.to_le_bytes());
+    }
+    archive.extend(u32::try_from(central_directory.len())?.to_le_bytes());
+    archive.extend(directory_offset.to_le_bytes());
+    // comment length
+    archive.extend(0u16.to_le_bytes());
+    Ok(archive)
+}
+
+/* Reads every file out of a zip archive, as (name, contents)
+ *
+ * Handles stored and deflated files, enough for archives written by `write_zip` or most zip
+ * tools. Names are only accepted as plain file names so unpacking can't write outside its
+ * directory.
+ */
+fn read_zip(data: &[u8]) -> Result<Files> {
+    let invalid = || anyhow!("not a valid session archive");
+    let u16_at = |offset: usize| -> Result<u16> {
+        let bytes = data.get(offset..offset + 2).ok_or_else(invalid)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    };
+    let u32_at = |offset: usize| -> Result<u32> {
+        let bytes = data.get(offset..offset + 4).ok_or_else(invalid)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+
+    // the end of central directory record is last, before a comment of up to 64k
+    let end = (0..=data.len().saturating_sub(END_OF_CENTRAL_DIRECTORY_SIZE))
+        .rev()
+        .take(u16::MAX as usize + 1)
+        .find(|offset| u32_at(*offset).ok() == Some(END_OF_CENTRAL_DIRECTORY))
+        .ok_or_else(invalid)?;
+    let count = u16_at(end + 10)? as usize;
+    let mut entry = u32_at(end + 16)? as usize;
+
+    let mut files = Vec::with_capacity(count);
+    for _ in 0..count {
+        if u32_at(entry)? != CENTRAL_HEADER {
+            return Err(invalid());
+        }
+        let method = u16_at(entry + 10)?;
+        let crc = u32_at(entry + 16)?;
+        let compressed_size = u32_at(entry + 20)? as usize;
+        let size = u32_at(entry + 24)? as usize;
+        let name_length = u16_at(entry + 28)? as usize;
+        let extra_length = u16_at(entry + 30)? as usize;
+        let comment_length = u16_at(entry + 32)? as usize;
+        let local = u32_at(entry + 42)? as usize;
+        let name = data
+            .get(entry + 46..entry + 46 + name_length)
+            .ok_or_else(invalid)?;
+        let name = String::from_utf8(name.to_vec())?;
+        entry += 46 + name_length + extra_length + comment_length;
+
+        if name.is_empty() || name.contains(['/', '\\']) || name == "." || name == ".." {
+            return Err(anyhow!(
+                "session archive holds an unexpected file {:?}",
+                name
+            ));
+        }
+
+        if size > MAX_FILE_SIZE {
+            return Err(anyhow!(
+                "{} is {} bytes, more than a session holds",
+                name,
+                size
+            ));
+        }
+
+        if u32_at(local)? != LOCAL_HEADER {
+            return Err(invalid());
+        }
+        let start = local + 30 + u16_at(local + 26)? as usize + u16_at(local + 28)? as usize;
+        let compressed = data
+            .get(start..start + compressed_size)
+            .ok_or_else(invalid)?;
+        let contents = match method {
+            STORED => compressed.to_vec(),
+            DEFLATED => {
+                // inflated no further than one byte past its size, the data can hold more than
+                // the archive claims
+                let mut contents = Vec::new();
+                DeflateDecoder::new(compressed)
+                    .take(size as u64 + 1)
+                    .read_to_end(&mut contents)?;
+                if contents.len() > size {
+                    return Err(anyhow!("{} is larger than the archive says", name));
+                }
+                contents
+            }
+            other => return Err(anyhow!("{} uses unsupported compression {}", name, other)),
+        };
+
+        let mut check = Crc::new();
+        check.update(&contents);
+        if contents.len() != size || check.sum() != crc {
+            return Err(anyhow!("{} is corrupt", name));
+        }
+        files.push((name, contents));
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_pack_round_trip() {
+        let (image, params) = analyze::test_wedge();
+        let mut scan = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut scan), image::ImageFormat::Png)
+            .unwrap();
+        let results = analyze::analyze(&image, &params, false).unwrap();
+
+        let mut archive = Vec::new();
+        pack(
+            &mut archive,
+            &Session {
+                scan_name: "wedge.png",
+                scan: &scan,
+                image: &image,
+                results: &results,
+                params: &params,
+                process: Some("cyanotype"),
+                notes: None,
+            },
+        )
+        .unwrap();
+
+        let (manifest, files) = read(&archive).unwrap();
+        assert_eq!(manifest.scan, "scan.png");
+        assert_eq!(manifest.process.as_deref(), Some("cyanotype"));
+        assert!(manifest.params.invert);
+        let file = |name: &str| &files.iter().find(|(n, _)| n == name).unwrap().1;
+        assert_eq!(file(&manifest.scan), &scan);
+        assert!(file(&manifest.report).starts_with(b"%PDF-"));
+        let curve =
+            curve::parse_curve(std::str::from_utf8(file(&manifest.curve)).unwrap()).unwrap();
+        assert_eq!(curve.keys(), results.curve.keys());
+
+        // a flipped bit is caught rather than unpacked
+        let mut corrupt = archive.clone();
+        let middle = corrupt.len() / 2;
+        corrupt[middle] ^= 1;
+        assert!(read(&corrupt).is_err());
+        assert!(read_zip(&write_zip(&[("../escape", b"")]).unwrap()).is_err());
+
+        // as is a file that inflates to more than its recorded size
+        let zeros = vec![0; 100_000];
+        let mut bomb = write_zip(&[("zeros", &zeros)]).unwrap();
+        let entry = bomb
+            .windows(4)
+            .position(|bytes| bytes == CENTRAL_HEADER.to_le_bytes())
+            .unwrap();
+        bomb[entry + 24..entry + 28].copy_from_slice(&10u32.to_le_bytes());
+        let error = read_zip(&bomb).unwrap_err();
+        assert!(error.to_string().contains("larger"), "{}", error);
+        // and one that claims to be too large is never inflated at all
+        bomb[entry + 24..entry + 28].copy_from_slice(&u32::MAX.to_le_bytes());
+        let error = read_zip(&bomb).unwrap_err();
+        assert!(
+            error.to_string().contains("more than a session holds"),
+            "{}",
+            error
+        );
+    }
+}
@@ -1,3 +1,41 @@
+use anyhow::{anyhow, Result};
+
+/* How the boundaries between steps are marked
+ *
+ * Grid: thin grid lines, mid grey verticals and black/white horizontals
+ * Separators: each patch is framed by a thin separator of pure black or pure white, alternating
+ * in a checkerboard so every boundary is a line of mixed extreme tones that analyze can find
+ */
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, clap::ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+pub enum Layout {
+    #[default]
+    Grid,
+    Separators,
+}
+
+// The caption needs at least this many empty cells in the last row to fit beside the patches
+const MIN_CAPTION_COLUMNS: u32 = 3;
+
+// Height of the caption strip added below the grid when the last row is too full to hold it
+const CAPTION_STRIP_HEIGHT: u32 = 60;
+
+// Pixels on a side of every patch of a generated wedge, more columns make a wider wedge
+pub const STANDARD_SQUARE_SIZE: u32 = 100;
+
+// Columns the standard wedge is laid out in
+pub const STANDARD_COLUMNS: u32 = 10;
+
+// A rectangle, relative to the top left of the grid, that is guaranteed not to hold any patches
+#[derive(Debug, PartialEq)]
+pub struct CaptionArea {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
 #[derive(Debug)]
 pub struct StepDescription {
     pub count: u32,
@@ -5,19 +43,23 @@ pub struct StepDescription {
     pub width: u32,
     pub height: u32,
     pub max_tone: u32,
-    pub interval: u32,
     pub square_size: u32,
     pub rows: u32,
-    pub expected_interval: u16,
+    pub layout: Layout,
+}
+
+// The standard wedge, 101 steps in 10 columns 1000 pixels wide
+impl Default for StepDescription {
+    fn default() -> Self {
+        StepDescription::standard(101, STANDARD_COLUMNS)
+    }
 }
 
 impl StepDescription {
     pub fn new(count: u32, columns: u32, width: u32, max_tone: u32) -> Self {
-        let interval = (max_tone as f32 / count as f32).ceil() as u32;
         let square_size = (width as f32 / columns as f32).ceil() as u32;
         let rows = (count as f32 / columns as f32).ceil() as u32;
         let height = rows * square_size;
-        let expected_interval = (max_tone / (count - 1)) as u16;
 
         StepDescription {
             count,
@@ -25,16 +67,112 @@ impl StepDescription {
             width,
             height,
             max_tone,
-            interval,
             square_size,
             rows,
-            expected_interval,
+            layout: Layout::default(),
+        }
+    }
+
+    // `count` 16bit steps in `columns`, every patch the size of the standard wedge's
+    pub fn standard(count: u32, columns: u32) -> Self {
+        StepDescription::new(
+            count,
+            columns,
+            columns * STANDARD_SQUARE_SIZE,
+            u16::MAX as u32,
+        )
+    }
+
+    pub fn with_layout(self, layout: Layout) -> Self {
+        StepDescription { layout, ..self }
+    }
+
+    /* Lays the steps out in exactly `rows` rather than as few as the columns allow, for fitting
+     * the wedge to the shape of the paper. Rows past the last step are left empty, it's an error
+     * for the steps not to fit.
+     */
+    pub fn with_rows(self, rows: u32) -> Result<Self> {
+        if rows as u64 * self.columns as u64 > u32::MAX as u64 || rows * self.columns < self.count {
+            return Err(anyhow!(
+                "{} steps don't fit in {} rows of {} columns",
+                self.count,
+                rows,
+                self.columns
+            ));
+        }
+        Ok(StepDescription {
+            rows,
+            height: rows * self.square_size,
+            ..self
+        })
+    }
+
+    // The (column, row) of the `n`th patch, patches fill each row left to right
+    pub fn cell(&self, n: u32) -> (u32, u32) {
+        (n % self.columns, n / self.columns)
+    }
+
+    // The corners of the `n`th patch as the (column, row) of the lines meeting there, top left,
+    // top right, bottom left, then bottom right
+    pub fn patch_corners(&self, n: u32) -> [(u32, u32); 4] {
+        let (col, row) = self.cell(n);
+        [
+            (col, row),
+            (col + 1, row),
+            (col, row + 1),
+            (col + 1, row + 1),
+        ]
+    }
+
+    // How many patches are in `row`, every row but the last is full
+    pub fn row_patches(&self, row: u32) -> u32 {
+        self.count
+            .saturating_sub(row * self.columns)
+            .min(self.columns)
+    }
+
+    /* Where the process and notes caption goes
+     *
+     * Preferably in the empty cells at the end of the last row, when there are too few of those
+     * the caption gets its own strip below the grid.
+     */
+    pub fn caption_area(&self) -> CaptionArea {
+        let last_row_patches = self.row_patches(self.rows - 1);
+        let empty_columns = self.columns - last_row_patches;
+
+        if empty_columns >= MIN_CAPTION_COLUMNS {
+            CaptionArea {
+                x: last_row_patches * self.square_size,
+                y: (self.rows - 1) * self.square_size,
+                width: empty_columns * self.square_size,
+                height: self.square_size,
+            }
+        } else {
+            CaptionArea {
+                x: 0,
+                y: self.height,
+                width: self.columns * self.square_size,
+                height: CAPTION_STRIP_HEIGHT,
+            }
         }
     }
 
+    // Height of the grid plus the caption strip, if it needs one
+    pub fn total_height(&self) -> u32 {
+        let caption_area = self.caption_area();
+        self.height.max(caption_area.y + caption_area.height)
+    }
+
+    /* The tone generate fills the `n`th patch with, the steps spread evenly from black at the first
+     * to `max_tone` at the last
+     */
+    pub fn tone(&self, n: u32) -> u16 {
+        let last = self.count.saturating_sub(1).max(1) as u64;
+        ((n as u64 * self.max_tone as u64 + last / 2) / last) as u16
+    }
+
+    // The tone of every patch in order, the inputs analyze measures the response to
     pub fn input_values(&self) -> Vec<u16> {
-        (0..self.count)
-            .map(|x| x as u16 * &self.expected_interval)
-            .collect()
+        (0..self.count).map(|n| self.tone(n)).collect()
     }
 }
@@ -0,0 +1,202 @@
+use std::io::{Read, Seek, Write};
+
+use anyhow::{anyhow, Result};
+use image::DynamicImage;
+use splines::Spline;
+use tiff::decoder::{ChunkType, Decoder};
+use tiff::encoder::{colortype, TiffEncoder, TiffValue};
+use tiff::ColorType;
+
+use super::apply;
+use super::cancel::Cancel;
+use super::luma::{self, LumaWeights};
+use super::multipage;
+
+/* Applies `curve` to the tiff in `reader` a strip at a time, writing the result to `writer`
+ *
+ * `apply` needs the whole image decoded, and then holds a corrected copy of it too. Here each
+ * strip is read, corrected, and written out before the next is read, so only a strip of each is
+ * ever in memory. The output matches `apply` on the whole image exactly, 8bit images stay 8bit
+ * and deeper ones come out 16bit, greyscale either way and laid out in the same strips as the
+ * input.
+ *
+ * Tiffs stored as a single strip gain nothing, but most scanners write many. Tiled tiffs and
+ * tiffs with each channel stored separately aren't read.
+ */
+pub fn apply_strips(
+    reader: impl Read + Seek,
+    writer: impl Write + Seek,
+    curve: &Spline<f64, f64>,
+    luma_weights: LumaWeights,
+    cancel: &Cancel,
+) -> Result<()> {
+    let mut decoder = Decoder::new(reader)?;
+    if decoder.get_chunk_type() != ChunkType::Strip {
+        return Err(anyhow!(
+            "can only correct tiffs stored in strips, not tiles"
+        ));
+    }
+    let (_, height) = decoder.dimensions()?;
+    let (_, rows_per_strip) = decoder.chunk_dimensions();
+    if decoder.strip_count()? != height.div_ceil(rows_per_strip) {
+        return Err(anyhow!(
+            "can only correct tiffs with every channel of a pixel stored together"
+        ));
+    }
+
+    let mut encoder = TiffEncoder::new(writer)?;
+    match decoder.colortype()? {
+        ColorType::Gray(8) => {
+            let lut = apply::lut_8bit(curve)?;
+            correct_strips::<_, _, colortype::Gray8>(&mut decoder, &mut encoder, cancel, |strip| {
+                strip.to_luma8().iter().map(|v| lut[*v as usize]).collect()
+            })
+        }
+        ColorType::RGB(8) | ColorType::RGBA(8) => {
+            let lut = apply::lut_16bit(curve)?;
+            correct_strips::<_, _, colortype::Gray8>(&mut decoder, &mut encoder, cancel, |strip| {
+                let mut curved = luma::to_luma16(strip, luma_weights);
+                curved.iter_mut().for_each(|v| *v = lut[*v as usize]);
+                DynamicImage::ImageLuma16(curved).to_luma8().into_raw()
+            })
+        }
+        _ => {
+            let lut = apply::lut_16bit(curve)?;
+            correct_strips::<_, _, colortype::Gray16>(&mut decoder, &mut encoder, cancel, |strip| {
+                luma::to_luma16(strip, luma_weights)
+                    .iter()
+                    .map(|v| lut[*v as usize])
+                    .collect()
+            })
+        }
+    }
+}
+
+// Writes every strip of `decoder` to `encoder` as a `C` image, passed through `correct`
+fn correct_strips<R: Read + Seek, W: Write + Seek, C: colortype::ColorType>(
+    decoder: &mut Decoder<R>,
+    encoder: &mut TiffEncoder<W>,
+    cancel: &Cancel,
+    correct: impl Fn(&DynamicImage) -> Vec<C::Inner>,
+) -> Result<()>
+where
+    [C::Inner]: TiffValue,
+{
+    let (width, height) = decoder.dimensions()?;
+    let color_type = decoder.colortype()?;
+    let (_, rows_per_strip) = decoder.chunk_dimensions();
+
+    let mut image = encoder.new_image::<C>(width, height)?;
+    image.rows_per_strip(rows_per_strip)?;
+    for strip in 0..decoder.strip_count()? {
+        cancel.check()?;
+        let (strip_width, strip_height) = decoder.chunk_data_dimensions(strip);
+        let data = decoder.read_chunk(strip)?;
+        let strip = multipage::decoded_image(color_type, strip_width, strip_height, data)?;
+        image.write_strip(&correct(&strip))?;
+    }
+    image.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::CURVE_DOMAIN_MAX;
+    use image::{ImageBuffer, Luma, Rgb};
+    use splines::{Interpolation, Key};
+    use std::io::Cursor;
+
+    // `image` as a tiff stored in strips of `rows` rows
+    fn write_tiff(image: &DynamicImage, rows: u32) -> Cursor<Vec<u8>> {
+        fn write<C: colortype::ColorType>(
+            tiff: &mut Cursor<Vec<u8>>,
+            (width, height): (u32, u32),
+            rows: u32,
+            data: &[C::Inner],
+        ) where
+            [C::Inner]: TiffValue,
+        {
+            let mut encoder = TiffEncoder::new(tiff).unwrap();
+            let mut image = encoder.new_image::<C>(width, height).unwrap();
+            image.rows_per_strip(rows).unwrap();
+            image.write_data(data).unwrap();
+        }
+
+        let mut tiff = Cursor::new(Vec::new());
+        let size = (image.width(), image.height());
+        match image {
+            DynamicImage::ImageLuma8(buffer) => {
+                write::<colortype::Gray8>(&mut tiff, size, rows, buffer)
+            }
+            DynamicImage::ImageLuma16(buffer) => {
+                write::<colortype::Gray16>(&mut tiff, size, rows, buffer)
+            }
+            DynamicImage::ImageRgb8(buffer) => {
+                write::<colortype::RGB8>(&mut tiff, size, rows, buffer)
+            }
+            _ => write::<colortype::RGB16>(&mut tiff, size, rows, &image.to_rgb16()),
+        }
+        tiff.set_position(0);
+        tiff
+    }
+
+    #[test]
+    fn test_strips_match_whole_image() {
+        let curve = Spline::from_vec(vec![
+            Key::new(0., 0., Interpolation::CatmullRom),
+            Key::new(0., 0., Interpolation::CatmullRom),
+            Key::new(
+                CURVE_DOMAIN_MAX * 0.3,
+                CURVE_DOMAIN_MAX * 0.5,
+                Interpolation::CatmullRom,
+            ),
+            Key::new(
+                CURVE_DOMAIN_MAX,
+                CURVE_DOMAIN_MAX,
+                Interpolation::CatmullRom,
+            ),
+            Key::new(
+                CURVE_DOMAIN_MAX,
+                CURVE_DOMAIN_MAX,
+                Interpolation::CatmullRom,
+            ),
+        ]);
+        let (width, height) = (300, 200);
+        let images = [
+            DynamicImage::ImageLuma16(ImageBuffer::from_fn(width, height, |x, y| {
+                Luma([(x * 200 + y * 7) as u16])
+            })),
+            DynamicImage::ImageLuma8(ImageBuffer::from_fn(width, height, |x, y| {
+                Luma([((x + y) % 256) as u8])
+            })),
+            DynamicImage::ImageRgb16(ImageBuffer::from_fn(width, height, |x, y| {
+                Rgb([(x * 200) as u16, (y * 300) as u16, ((x + y) * 100) as u16])
+            })),
+            DynamicImage::ImageRgb8(ImageBuffer::from_fn(width, height, |x, y| {
+                Rgb([x as u8, y as u8, ((x + y) / 2) as u8])
+            })),
+        ];
+
+        for image in images {
+            // the last strip comes up short
+            let mut tiff = write_tiff(&image, 16);
+
+            let mut output = Cursor::new(Vec::new());
+            apply_strips(
+                &mut tiff,
+                &mut output,
+                &curve,
+                LumaWeights::default(),
+                &Cancel::default(),
+            )
+            .unwrap();
+            output.set_position(0);
+
+            let streamed = multipage::read_pages(&mut output).unwrap().remove(0);
+            let whole = apply::apply(&image, &curve, LumaWeights::default()).unwrap();
+            assert_eq!(streamed.color(), whole.color());
+            assert_eq!(streamed.as_bytes(), whole.as_bytes());
+        }
+    }
+}
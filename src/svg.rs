@@ -0,0 +1,190 @@
+use std::fmt::Write as _;
+use std::io::Write;
+
+use anyhow::Result;
+use splines::Spline;
+
+use super::analyze;
+
+// The plot is drawn on the same 1024x1024 chart as the png previews, scaled by the viewer
+const PLOT_SIZE: u32 = 1024;
+// Room around the chart for the axes' labels
+const PLOT_MARGIN: u32 = 48;
+
+const CURVE_COLOR: &str = "#1a7f1a";
+const HISTOGRAM_COLOR: &str = "#c8c8c8";
+const AXIS_COLOR: &str = "#404040";
+const GRID_COLOR: &str = "#e0e0e0";
+const CLIPPING_COLOR: &str = "#dc0000";
+// Width of the band marking a clipped end of the histogram, as on the png plot
+const CLIPPING_BAND_WIDTH: u32 = 12;
+
+/* Writes `curve` as an svg plot, input tone along the bottom and output tone up the side, over
+ * `histogram` when there is one
+ *
+ * The curve is sampled exactly as the png plot is, one point per column of the chart, but drawn as
+ * a vector path so it stays crisp at any size.
+ */
+pub fn write_plot(
+    writer: &mut impl Write,
+    curve: &Spline<f64, f64>,
+    histogram: Option<&[u32]>,
+) -> Result<()> {
+    let size = PLOT_SIZE + 2 * PLOT_MARGIN;
+    let mut svg = String::new();
+    writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{0}" height="{0}" viewBox="0 0 {0} {0}">"#,
+        size
+    )?;
+    writeln!(
+        svg,
+        r#"<rect width="{0}" height="{0}" fill="white"/>"#,
+        size
+    )?;
+    // the chart's origin at the bottom left, y counting up like the tones do
+    writeln!(
+        svg,
+        r#"<g transform="translate({} {}) scale(1 -1)">"#,
+        PLOT_MARGIN,
+        PLOT_MARGIN + PLOT_SIZE
+    )?;
+
+    // quarter tones
+    let mut grid = String::new();
+    for quarter in 1..4 {
+        let at = quarter * PLOT_SIZE / 4;
+        write!(grid, "M{0} 0V{1}M0 {0}H{1}", at, PLOT_SIZE)?;
+    }
+    writeln!(
+        svg,
+        r#"<path id="grid" d="{}" stroke="{}" stroke-width="1" fill="none"/>"#,
+        grid, GRID_COLOR
+    )?;
+
+    if let Some(histogram) = histogram {
+        let mut bars = String::new();
+        for (left, width, height) in analyze::histogram_bars(histogram, analyze::HISTOGRAM_BUCKETS)
+        {
+            write!(bars, "M{0} 0h{1}v{2}h-{1}Z", left, width, height)?;
+        }
+        writeln!(
+            svg,
+            r#"<path id="histogram" d="{}" fill="{}"/>"#,
+            bars, HISTOGRAM_COLOR
+        )?;
+
+        let (dark, light) = analyze::clipped_ends(histogram);
+        for (clipped, id, left) in [
+            (dark, "clipped-dark", 0),
+            (light, "clipped-light", PLOT_SIZE - CLIPPING_BAND_WIDTH),
+        ] {
+            if clipped {
+                writeln!(
+                    svg,
+                    r#"<rect id="{}" x="{}" width="{}" height="{}" fill="{}"/>"#,
+                    id, left, CLIPPING_BAND_WIDTH, PLOT_SIZE, CLIPPING_COLOR
+                )?;
+            }
+        }
+    }
+
+    let mut path = String::new();
+    for (i, (x, y)) in analyze::curve_plot_points(curve)?.iter().enumerate() {
+        let command = if i == 0 { 'M' } else { 'L' };
+        write!(path, "{}{:.2} {:.2}", command, x, y)?;
+    }
+    writeln!(
+        svg,
+        r#"<path id="curve" d="{}" stroke="{}" stroke-width="3" fill="none" stroke-linejoin="round"/>"#,
+        path, CURVE_COLOR
+    )?;
+
+    writeln!(
+        svg,
+        r#"<path id="axes" d="M0 {0}V0H{0}" stroke="{1}" stroke-width="2" fill="none"/>"#,
+        PLOT_SIZE, AXIS_COLOR
+    )?;
+    writeln!(svg, "</g>")?;
+
+    // labels go outside the flipped group so their text reads the right way up
+    let bottom = PLOT_MARGIN + PLOT_SIZE;
+    for quarter in 0..=4 {
+        let offset = quarter * PLOT_SIZE / 4;
+        let tone = quarter * u16::MAX as u32 / 4;
+        writeln!(
+            svg,
+            r#"<text x="{}" y="{}" font-family="sans-serif" font-size="14" text-anchor="middle" fill="{}">{}</text>"#,
+            PLOT_MARGIN + offset,
+            bottom + 20,
+            AXIS_COLOR,
+            tone
+        )?;
+        writeln!(
+            svg,
+            r#"<text x="{}" y="{}" font-family="sans-serif" font-size="14" text-anchor="end" fill="{}">{}</text>"#,
+            PLOT_MARGIN - 6,
+            bottom - offset + 5,
+            AXIS_COLOR,
+            tone
+        )?;
+    }
+    writeln!(svg, "</svg>")?;
+
+    writer.write_all(svg.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::CURVE_DOMAIN_MAX;
+    use splines::{Interpolation, Key};
+
+    // The value of the `d` attribute of the path with `id`
+    fn path_data<'a>(svg: &'a str, id: &str) -> Option<&'a str> {
+        let start = svg.find(&format!(r#"id="{}" d=""#, id))? + id.len() + 9;
+        let length = svg[start..].find('"')?;
+        Some(&svg[start..start + length])
+    }
+
+    #[test]
+    fn test_write_plot() {
+        let curve = Spline::from_vec(vec![
+            Key::new(0., 0., Interpolation::Linear),
+            Key::new(CURVE_DOMAIN_MAX, CURVE_DOMAIN_MAX, Interpolation::Linear),
+        ]);
+
+        let mut plain = Vec::new();
+        write_plot(&mut plain, &curve, None).unwrap();
+        let plain = String::from_utf8(plain).unwrap();
+        assert!(plain.starts_with("<svg"));
+        assert!(plain.trim_end().ends_with("</svg>"));
+        assert!(path_data(&plain, "histogram").is_none());
+
+        // one point per column of the chart, the first a move and the rest lines
+        let curve_path = path_data(&plain, "curve").unwrap();
+        assert!(curve_path.starts_with("M0.00 0.00"));
+        assert_eq!(curve_path.matches('M').count(), 1);
+        assert_eq!(curve_path.matches('L').count(), 1023);
+        assert!(curve_path.ends_with("L1023.00 1023.00"));
+
+        let mut histogram = vec![0; 256];
+        histogram[10] = 4;
+        histogram[200] = 2;
+        let mut with_histogram = Vec::new();
+        write_plot(&mut with_histogram, &curve, Some(&histogram)).unwrap();
+        let with_histogram = String::from_utf8(with_histogram).unwrap();
+        let bars = path_data(&with_histogram, "histogram").unwrap();
+        assert_eq!(bars, "M40 0h4v1024h-4ZM800 0h4v512h-4Z");
+        assert!(!with_histogram.contains("clipped"));
+
+        // most of the tones piled on white
+        histogram[255] = 10;
+        let mut clipped = Vec::new();
+        write_plot(&mut clipped, &curve, Some(&histogram)).unwrap();
+        let clipped = String::from_utf8(clipped).unwrap();
+        assert!(clipped.contains(r#"id="clipped-light""#));
+        assert!(!clipped.contains(r#"id="clipped-dark""#));
+    }
+}
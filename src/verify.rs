@@ -0,0 +1,121 @@
+use anyhow::{anyhow, Result};
+use splines::Spline;
+
+use super::analyze;
+use super::apply;
+use super::generate;
+use super::luma::LumaWeights;
+use super::step_description::{Layout, StepDescription};
+
+pub struct VerifyResults {
+    // (ideal tone, tone after correction) for each step of the wedge
+    pub steps: Vec<(u16, u16)>,
+}
+
+impl VerifyResults {
+    // largest distance from linear, as a percentage of the full tonal range
+    pub fn max_error(&self) -> f64 {
+        self.errors().fold(0., f64::max)
+    }
+
+    // root mean squared distance from linear, as a percentage of the full tonal range
+    pub fn rms_error(&self) -> f64 {
+        let sum: f64 = self.errors().map(|e| e * e).sum();
+        (sum / self.steps.len() as f64).sqrt()
+    }
+
+    fn errors(&self) -> impl Iterator<Item = f64> + '_ {
+        self.steps
+            .iter()
+            .map(|(ideal, actual)| (*ideal as f64 - *actual as f64).abs() / u16::MAX as f64 * 100.)
+    }
+}
+
+/* Closes the loop on a curve: generates a fresh wedge, applies `curve` to it, and samples every
+ * patch again to see where each tone ended up.
+ *
+ * With the `response` measured from a scan of the original wedge (see `AnalyzeResults`) the
+ * corrected tones are pushed through the process to predict what printing the corrected wedge
+ * would produce, a good curve lands each step back on its original tone. Without a response the
+ * corrected ramp itself is compared against linear.
+ */
+pub fn verify(curve: &Spline<f64, f64>, response: Option<&[(u16, u16)]>) -> Result<VerifyResults> {
+    let step_description = StepDescription::default();
+    let wedge = generate::generate(None, None, Layout::Grid)?;
+    let corrected = apply::apply(&wedge, curve, LumaWeights::default())?;
+
+    let ideal = analyze::sample_generated(&wedge, &step_description)?;
+    let corrected = analyze::sample_generated(&corrected, &step_description)?;
+
+    let actual = match response {
+        Some(response) => corrected
+            .iter()
+            .map(|tone| through_response(response, *tone))
+            .collect::<Result<Vec<u16>>>()?,
+        None => corrected,
+    };
+
+    Ok(VerifyResults {
+        steps: ideal.into_iter().zip(actual).collect(),
+    })
+}
+
+// linearly interpolates the observed tone for `tone` between the measured steps around it
+fn through_response(response: &[(u16, u16)], tone: u16) -> Result<u16> {
+    let (first, last) = match (response.first(), response.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return Err(anyhow!("the measured response has no steps")),
+    };
+    if tone <= first.0 {
+        return Ok(first.1);
+    }
+    if tone >= last.0 {
+        return Ok(last.1);
+    }
+
+    let upper = response.partition_point(|(input, _)| *input < tone);
+    let (x0, y0) = response[upper - 1];
+    let (x1, y1) = response[upper];
+    let t = (tone - x0) as f64 / (x1 - x0) as f64;
+    Ok((y0 as f64 + (y1 as f64 - y0 as f64) * t).round() as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use splines::{Interpolation, Key};
+
+    const MAX: f64 = u16::MAX as f64;
+
+    // a process that darkens the midtones, output = input^2
+    fn squared_response() -> Vec<(u16, u16)> {
+        StepDescription::default()
+            .input_values()
+            .into_iter()
+            .map(|v| (v, ((v as f64 / MAX).powi(2) * MAX) as u16))
+            .collect()
+    }
+
+    fn curve_from(f: impl Fn(f64) -> f64) -> Spline<f64, f64> {
+        Spline::from_vec(
+            (0..=64)
+                .map(|i| {
+                    let x = i as f64 / 64. * MAX;
+                    Key::new(x, f(x / MAX) * MAX, Interpolation::Linear)
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_verify_correcting_curve_is_linear() {
+        let response = squared_response();
+
+        let identity = verify(&curve_from(|x| x), Some(&response)).unwrap();
+        assert!(identity.max_error() > 20., "{}", identity.max_error());
+
+        let correcting = verify(&curve_from(f64::sqrt), Some(&response)).unwrap();
+        assert!(correcting.max_error() < 2., "{}", correcting.max_error());
+        assert!(correcting.rms_error() <= correcting.max_error());
+    }
+}
@@ -0,0 +1,28 @@
+/* Everything `curved version` and `--version` report, filled in by build.rs
+ *
+ * Enough to tell exactly what someone is running when their curves come out wrong.
+ */
+pub const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    "\ncommit: ",
+    env!("CURVED_GIT_HASH"),
+    "\nfeatures: ",
+    env!("CURVED_FEATURES"),
+    "\nimage: ",
+    env!("CURVED_IMAGE_VERSION"),
+    "\nsplines: ",
+    env!("CURVED_SPLINES_VERSION"),
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_long_version() {
+        assert!(LONG_VERSION.starts_with(env!("CARGO_PKG_VERSION")));
+        for field in ["commit: ", "features: ", "image: 0.25", "splines: 4."] {
+            assert!(LONG_VERSION.contains(field), "{}", LONG_VERSION);
+        }
+    }
+}